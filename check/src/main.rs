@@ -1,27 +1,49 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::TryStreamExt;
 use log::{debug, error, info, warn};
 use pcap_file_tokio::pcapng::{Block, PcapNgReader};
 use rayhunter::{
     analysis::analyzer::{AnalysisRow, AnalyzerConfig, EventType, Harness},
     diag::DataType,
+    encryption::{self, RecordingSecretKey},
+    geo_export::GeoExport,
+    gps::LocationOptions,
+    gps_log::GpsLogReader,
     gsmtap_parser,
     pcap::GsmtapPcapWriter,
     qmdl::QmdlReader,
 };
-use std::{collections::HashMap, future, path::PathBuf, pin::pin};
+use std::{
+    collections::HashMap,
+    future,
+    path::{Path, PathBuf},
+    pin::pin,
+};
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use walkdir::WalkDir;
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short = 'p', long)]
-    path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short = 'p', long, required = true)]
+    path: Option<PathBuf>,
 
     #[arg(short = 'P', long)]
     pcapify: bool,
 
+    /// Write a GeoJSON map of each QMDL's GPS track, warnings and cells,
+    /// using the .gps file next to it
+    #[arg(long)]
+    geojson: bool,
+
+    /// Same as --geojson, but writes KML
+    #[arg(long)]
+    kml: bool,
+
     #[arg(long)]
     show_skipped: bool,
 
@@ -32,6 +54,33 @@ struct Args {
     debug: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a key pair for encrypting recordings. The secret key is
+    /// written to a file, and the public key is printed for the device's
+    /// `encryption_public_key` setting.
+    Keygen {
+        /// Where to write the secret key
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Decrypt recordings that were encrypted on the device
+    Decrypt {
+        /// File containing the secret key, as written by keygen
+        #[arg(short, long)]
+        key: PathBuf,
+
+        /// Directory to write the decrypted files to
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Encrypted files, or directories to search for them. Files which
+        /// aren't encrypted are skipped.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+}
+
 #[derive(Default)]
 struct Report {
     skipped_reasons: HashMap<String, u32>,
@@ -110,8 +159,9 @@ async fn analyze_pcap(pcap_path: &str, show_skipped: bool) {
     report.print_summary(show_skipped);
 }
 
-async fn analyze_qmdl(qmdl_path: &str, show_skipped: bool) {
+async fn analyze_qmdl(qmdl_path: &str, show_skipped: bool, mut export: Option<&mut GeoExport>) {
     let mut harness = Harness::new_with_config(&AnalyzerConfig::default());
+    let analyzers = harness.get_metadata().analyzers;
     let qmdl_file = &mut File::open(&qmdl_path).await.expect("failed to open file");
     let file_size = qmdl_file
         .metadata()
@@ -130,7 +180,13 @@ async fn analyze_qmdl(qmdl_path: &str, show_skipped: bool) {
         .await
         .expect("failed getting QMDL container")
     {
+        if let Some(export) = export.as_deref_mut() {
+            export.add_qmdl_messages(container.clone());
+        }
         for row in harness.analyze_qmdl_messages(container) {
+            if let Some(export) = export.as_deref_mut() {
+                export.add_analysis_row(&row, &analyzers);
+            }
             report.process_row(row);
         }
     }
@@ -167,6 +223,116 @@ async fn pcapify(qmdl_path: &PathBuf) {
     info!("wrote pcap to {:?}", &pcap_path);
 }
 
+// Loads the GPS log recorded alongside a QMDL file, if there is one
+async fn load_geo_export(qmdl_path: &std::path::Path) -> Option<GeoExport> {
+    let gps_path = qmdl_path.with_extension("gps");
+    let Ok(gps_file) = File::open(&gps_path).await else {
+        warn!("no GPS log found at {gps_path:?}, skipping map export");
+        return None;
+    };
+    let mut gps_reader = GpsLogReader::new(gps_file);
+    let track = match gps_reader.read_track().await {
+        Ok(track) => track,
+        Err(e) => {
+            warn!("failed to read GPS log {gps_path:?}, skipping map export: {e}");
+            return None;
+        }
+    };
+    if gps_reader.skipped_lines() > 0 {
+        debug!("{gps_path:?}: skipped {} unparseable lines", gps_reader.skipped_lines());
+    }
+    if track.is_empty() {
+        warn!("GPS log {gps_path:?} has no fixes, skipping map export");
+        return None;
+    }
+    let name = qmdl_path.file_stem()?.to_string_lossy();
+    Some(GeoExport::new(&name, track, LocationOptions::default()))
+}
+
+async fn write_geo_export(qmdl_path: &std::path::Path, export: &GeoExport, geojson: bool, kml: bool) {
+    if geojson {
+        let geojson_path = qmdl_path.with_extension("geojson");
+        tokio::fs::write(&geojson_path, format!("{:#}", export.to_geojson()))
+            .await
+            .expect("failed to write GeoJSON file");
+        info!("wrote GeoJSON to {geojson_path:?}");
+    }
+    if kml {
+        let kml_path = qmdl_path.with_extension("kml");
+        tokio::fs::write(&kml_path, export.to_kml())
+            .await
+            .expect("failed to write KML file");
+        info!("wrote KML to {kml_path:?}");
+    }
+}
+
+// Whether a file was encrypted on the device, and needs decrypting first
+async fn is_encrypted_file(path: &Path) -> bool {
+    let Ok(file) = File::open(path).await else {
+        return false;
+    };
+    let mut magic = Vec::new();
+    let read = file
+        .take(encryption::ENCRYPTION_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .await;
+    read.is_ok() && encryption::is_encrypted(&magic)
+}
+
+async fn keygen(output: &Path) {
+    let key = RecordingSecretKey::generate();
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(output)
+        .await
+        .expect("failed to create secret key file");
+    file.write_all(format!("{}\n", key.to_base64()).as_bytes())
+        .await
+        .expect("failed to write secret key file");
+    info!("wrote secret key to {output:?}, keep it somewhere safe");
+    info!("set this in the device's config.toml:");
+    println!("encryption_public_key = \"{}\"", key.public_key().to_base64());
+}
+
+async fn decrypt(key_path: &Path, output: &Path, inputs: &[PathBuf]) {
+    let encoded_key = tokio::fs::read_to_string(key_path)
+        .await
+        .expect("failed to read secret key file");
+    let key = RecordingSecretKey::from_base64(&encoded_key).expect("invalid secret key");
+    tokio::fs::create_dir_all(output)
+        .await
+        .expect("failed to create output directory");
+
+    for input in inputs {
+        for maybe_entry in WalkDir::new(input) {
+            let Ok(entry) = maybe_entry else {
+                error!("failed to open dir entry {maybe_entry:?}");
+                continue;
+            };
+            let path = entry.path();
+            if !entry.file_type().is_file() || !is_encrypted_file(path).await {
+                debug!("{path:?} isn't encrypted, skipping");
+                continue;
+            }
+            let output_path = output.join(entry.file_name());
+            let encrypted_file = File::open(path).await.expect("failed to open file");
+            let decrypted_file = File::create(&output_path)
+                .await
+                .expect("failed to create output file");
+            match encryption::decrypt(encrypted_file, decrypted_file, &key).await {
+                Ok(len) => info!("decrypted {path:?} to {output_path:?} ({len} bytes)"),
+                Err(e) => {
+                    error!("failed to decrypt {path:?}: {e}");
+                    let _ = tokio::fs::remove_file(&output_path).await;
+                }
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -186,6 +352,17 @@ async fn main() {
         .init()
         .unwrap();
 
+    match &args.command {
+        Some(Command::Keygen { output }) => return keygen(output).await,
+        Some(Command::Decrypt {
+            key,
+            output,
+            inputs,
+        }) => return decrypt(key, output, inputs).await,
+        None => {}
+    }
+    let root_path = args.path.as_ref().expect("--path is required");
+
     let harness = Harness::new_with_config(&AnalyzerConfig::default());
     info!("Analyzers:");
     for analyzer in harness.get_metadata().analyzers {
@@ -195,7 +372,7 @@ async fn main() {
         );
     }
 
-    for maybe_entry in WalkDir::new(&args.path) {
+    for maybe_entry in WalkDir::new(root_path) {
         let Ok(entry) = maybe_entry else {
             error!("failed to open dir entry {maybe_entry:?}");
             continue;
@@ -207,8 +384,20 @@ async fn main() {
         // instead of relying on the QMDL extension, can we check if a file is
         // QMDL by inspecting the contents?
         if name_str.ends_with(".qmdl") {
+            if is_encrypted_file(path).await {
+                warn!("{name_str} is encrypted, decrypt it with the decrypt subcommand first");
+                continue;
+            }
             info!("**** Beginning analysis of {name_str}");
-            analyze_qmdl(path_str, args.show_skipped).await;
+            let mut export = if args.geojson || args.kml {
+                load_geo_export(path).await
+            } else {
+                None
+            };
+            analyze_qmdl(path_str, args.show_skipped, export.as_mut()).await;
+            if let Some(export) = export {
+                write_geo_export(path, &export, args.geojson, args.kml).await;
+            }
             if args.pcapify {
                 pcapify(&path.to_path_buf()).await;
             }
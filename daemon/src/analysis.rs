@@ -1,5 +1,5 @@
 use std::sync::Arc;
-use std::{cmp, future, pin};
+use std::{future, pin};
 
 use axum::Json;
 use axum::{
@@ -8,9 +8,13 @@ use axum::{
 };
 use futures::TryStreamExt;
 use log::{error, info};
-use rayhunter::analysis::analyzer::{AnalyzerConfig, EventType, Harness};
+use rayhunter::analysis::analyzer::{AnalyzerConfig, EventType, Harness, ModemFirmware};
+use rayhunter::baseline::SharedBaseline;
 use rayhunter::diag::{DataType, MessagesContainer};
+use rayhunter::encryption::{EncryptingWriter, RecordingPublicKey};
+use rayhunter::gps::{EventLocation, TrackLocator};
 use rayhunter::qmdl::QmdlReader;
+use rayhunter::signal::ServingCell;
 use serde::Serialize;
 use tokio::fs::File;
 use tokio::io::{AsyncWriteExt, BufWriter};
@@ -18,12 +22,24 @@ use tokio::sync::mpsc::Receiver;
 use tokio::sync::{RwLock, RwLockWriteGuard};
 use tokio_util::task::TaskTracker;
 
+use crate::gps_logger::GpsLogger;
 use crate::qmdl_store::RecordingStore;
 use crate::server::ServerState;
 
 pub struct AnalysisWriter {
-    writer: BufWriter<File>,
+    writer: BufWriter<EncryptingWriter<File>>,
+    signal_writer: Option<BufWriter<EncryptingWriter<File>>>,
     harness: Harness,
+    analyzer_names: Vec<String>,
+}
+
+/// A warning found by [`AnalysisWriter::analyze`]
+#[derive(Debug, Clone)]
+pub struct DetectedWarning {
+    pub analyzer: String,
+    pub event_type: EventType,
+    pub message: String,
+    pub location: Option<EventLocation>,
 }
 
 // We write our analysis results to a file immediately to minimize the amount of
@@ -31,35 +47,102 @@ pub struct AnalysisWriter {
 // Newline Delimited JSON
 // (https://docs.mulesoft.com/dataweave/latest/dataweave-formats-ndjson), which
 // lets us simply append new rows to the end without parsing the entire JSON
-// object beforehand.
+// object beforehand. If the recording is encrypted, each row is encrypted as
+// it's written, and the file can only be read once it's been decrypted.
 impl AnalysisWriter {
-    pub async fn new(file: File, analyzer_config: &AnalyzerConfig) -> Result<Self, std::io::Error> {
-        let harness = Harness::new_with_config(analyzer_config);
+    pub async fn new(
+        file: File,
+        encryption_key: Option<&RecordingPublicKey>,
+        analyzer_config: &AnalyzerConfig,
+        locator: Option<TrackLocator>,
+        modem_firmware: ModemFirmware,
+    ) -> Result<Self, std::io::Error> {
+        let mut harness = Harness::new_with_config(analyzer_config);
+        if let Some(locator) = locator {
+            harness.set_locator(locator);
+        }
+        harness.set_modem_firmware(modem_firmware);
 
+        let metadata = harness.get_metadata();
+        let analyzer_names = metadata
+            .analyzers
+            .iter()
+            .map(|analyzer| analyzer.name.clone())
+            .collect();
         let mut result = Self {
-            writer: BufWriter::new(file),
+            writer: BufWriter::new(EncryptingWriter::new(file, encryption_key)),
+            signal_writer: None,
             harness,
+            analyzer_names,
         };
-        let metadata = result.harness.get_metadata();
         result.write(&metadata).await?;
         Ok(result)
     }
 
+    /// Also write the signal samples taken during analysis to the given file,
+    /// one JSON object per line
+    pub fn with_signal_file(
+        mut self,
+        file: File,
+        encryption_key: Option<&RecordingPublicKey>,
+    ) -> Self {
+        self.harness.set_collect_signal_samples(true);
+        self.signal_writer = Some(BufWriter::new(EncryptingWriter::new(file, encryption_key)));
+        self
+    }
+
+    /// Compare what's seen against, and add it to, the given baseline
+    pub fn with_baseline(mut self, baseline: SharedBaseline) -> Self {
+        self.harness.set_baseline(baseline);
+        self
+    }
+
     // Runs the analysis harness on the given container, serializing the results
-    // to the analysis file, returning the whether any warnings were detected
+    // to the analysis file, returning any warnings that were detected
     pub async fn analyze(
         &mut self,
         container: MessagesContainer,
-    ) -> Result<EventType, std::io::Error> {
-        let mut max_type = EventType::Informational;
+    ) -> Result<Vec<DetectedWarning>, std::io::Error> {
+        let mut warnings = Vec::new();
 
         for row in self.harness.analyze_qmdl_messages(container) {
             if !row.is_empty() {
                 self.write(&row).await?;
             }
-            max_type = cmp::max(max_type, row.get_max_event_type());
+            for (analyzer_index, event) in row.events.iter().enumerate() {
+                let Some(event) = event else { continue };
+                if event.event_type == EventType::Informational {
+                    continue;
+                }
+                warnings.push(DetectedWarning {
+                    analyzer: self
+                        .analyzer_names
+                        .get(analyzer_index)
+                        .cloned()
+                        .unwrap_or_default(),
+                    event_type: event.event_type,
+                    message: event.message.clone(),
+                    location: row.location.clone(),
+                });
+            }
         }
-        Ok(max_type)
+        let samples = self.harness.take_signal_samples();
+        if let Some(signal_writer) = self.signal_writer.as_mut() {
+            if !samples.is_empty() {
+                for sample in samples {
+                    let mut line = serde_json::to_string(&sample).unwrap();
+                    line.push('\n');
+                    signal_writer.write_all(line.as_bytes()).await?;
+                }
+                signal_writer.flush().await?;
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// The LTE cell the modem was last measured on, in what's been analyzed
+    pub fn serving_cell(&self) -> Option<ServingCell> {
+        self.harness.serving_cell()
     }
 
     async fn write<T: Serialize>(&mut self, value: &T) -> Result<(), std::io::Error> {
@@ -73,6 +156,9 @@ impl AnalysisWriter {
     // Flushes any pending I/O to disk before dropping the writer
     pub async fn close(mut self) -> Result<(), std::io::Error> {
         self.writer.flush().await?;
+        if let Some(signal_writer) = self.signal_writer.as_mut() {
+            signal_writer.flush().await?;
+        }
         Ok(())
     }
 }
@@ -128,28 +214,88 @@ async fn perform_analysis(
     name: &str,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     analyzer_config: &AnalyzerConfig,
+    gps_logger: &GpsLogger,
+    baseline: Option<&SharedBaseline>,
 ) -> Result<(), String> {
     info!("Opening QMDL and analysis file for {name}...");
-    let (analysis_file, qmdl_file) = {
+    let qmdl_file = {
+        let qmdl_store = qmdl_store_lock.read().await;
+        let (entry_index, entry) = qmdl_store
+            .entry_for_name(name)
+            .ok_or(format!("failed to find QMDL store entry for {name}"))?;
+        // the device can't read an encrypted QMDL, and re-analyzing it would
+        // throw away the analysis done while recording
+        if entry.encrypted {
+            return Err(format!("{name} is encrypted and can't be analyzed on the device"));
+        }
+        qmdl_store
+            .open_entry_qmdl(entry_index)
+            .await
+            .map_err(|e| format!("{e:?}"))?
+    };
+    analyze_entry(
+        name,
+        qmdl_file,
+        None,
+        qmdl_store_lock,
+        analyzer_config,
+        gps_logger,
+        baseline,
+    )
+    .await
+}
+
+/// Analyzes a plain text QMDL file into the named entry's analysis and signal
+/// files, replacing what was in them. They're encrypted to `encryption_key`
+/// if it's set, which is how imported recordings are analyzed before only
+/// their encrypted copy is kept.
+pub async fn analyze_entry(
+    name: &str,
+    qmdl_file: File,
+    encryption_key: Option<&RecordingPublicKey>,
+    qmdl_store_lock: Arc<RwLock<RecordingStore>>,
+    analyzer_config: &AnalyzerConfig,
+    gps_logger: &GpsLogger,
+    baseline: Option<&SharedBaseline>,
+) -> Result<(), String> {
+    let (analysis_file, signal_file, modem_firmware) = {
         let mut qmdl_store = qmdl_store_lock.write().await;
-        let (entry_index, _) = qmdl_store
+        let (entry_index, entry) = qmdl_store
             .entry_for_name(name)
             .ok_or(format!("failed to find QMDL store entry for {name}"))?;
+        let modem_firmware = entry.modem_firmware();
         let analysis_file = qmdl_store
             .clear_and_open_entry_analysis(entry_index)
             .await
             .map_err(|e| format!("{e:?}"))?;
-        let qmdl_file = qmdl_store
-            .open_entry_qmdl(entry_index)
+        let signal_file = qmdl_store
+            .clear_and_open_entry_signal(entry_index)
             .await
             .map_err(|e| format!("{e:?}"))?;
 
-        (analysis_file, qmdl_file)
+        (analysis_file, signal_file, modem_firmware)
     };
 
-    let mut analysis_writer = AnalysisWriter::new(analysis_file, analyzer_config)
-        .await
-        .map_err(|e| format!("{e:?}"))?;
+    let locator = gps_logger.entry_locator(name).await;
+    let mut analysis_writer = AnalysisWriter::new(
+        analysis_file,
+        encryption_key,
+        analyzer_config,
+        Some(locator),
+        modem_firmware,
+    )
+    .await
+    .map_err(|e| format!("{e:?}"))?
+    .with_signal_file(signal_file, encryption_key);
+    // compared against a copy, so re-analyzing an old recording doesn't teach
+    // the baseline things it's already learned, or make it forget newer ones
+    if let Some(baseline) = baseline {
+        let snapshot = baseline.read().ok().map(|baseline| baseline.clone());
+        if let Some(snapshot) = snapshot {
+            analysis_writer =
+                analysis_writer.with_baseline(Arc::new(std::sync::RwLock::new(snapshot)));
+        }
+    }
     let file_size = qmdl_file
         .metadata()
         .await
@@ -163,21 +309,32 @@ async fn perform_analysis(
     );
 
     info!("Starting analysis for {name}...");
+    let mut has_warnings = false;
     while let Some(container) = qmdl_stream
         .try_next()
         .await
         .expect("failed getting QMDL container")
     {
-        let _ = analysis_writer
+        let warnings = analysis_writer
             .analyze(container)
             .await
             .map_err(|e| format!("{e:?}"))?;
+        has_warnings |= !warnings.is_empty();
     }
 
     analysis_writer
         .close()
         .await
         .map_err(|e| format!("{e:?}"))?;
+    // this also fills in whether recordings from before warnings were kept
+    // track of have any
+    let mut qmdl_store = qmdl_store_lock.write().await;
+    if let Some((entry_index, _)) = qmdl_store.entry_for_name(name) {
+        qmdl_store
+            .set_entry_has_warnings(entry_index, has_warnings)
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+    }
     info!("Analysis for {name} complete!");
 
     Ok(())
@@ -189,6 +346,8 @@ pub fn run_analysis_thread(
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     analysis_status_lock: Arc<RwLock<AnalysisStatus>>,
     analyzer_config: AnalyzerConfig,
+    gps_logger: Arc<GpsLogger>,
+    baseline: Option<SharedBaseline>,
 ) {
     task_tracker.spawn(async move {
         loop {
@@ -198,7 +357,14 @@ pub fn run_analysis_thread(
                     for _ in 0..count {
                         let name = dequeue_to_running(analysis_status_lock.clone()).await;
                         if let Err(err) =
-                            perform_analysis(&name, qmdl_store_lock.clone(), &analyzer_config).await
+                            perform_analysis(
+                                &name,
+                                qmdl_store_lock.clone(),
+                                &analyzer_config,
+                                &gps_logger,
+                                baseline.as_ref(),
+                            )
+                            .await
                         {
                             error!("failed to analyze {name}: {err}");
                         }
@@ -221,7 +387,7 @@ pub async fn get_analysis_status(
     Ok(Json(state.analysis_status_lock.read().await.clone()))
 }
 
-fn queue_qmdl(name: &str, analysis_status: &mut RwLockWriteGuard<AnalysisStatus>) -> bool {
+pub fn queue_qmdl(name: &str, analysis_status: &mut RwLockWriteGuard<AnalysisStatus>) -> bool {
     if analysis_status.queued.iter().any(|n| n == name)
         || analysis_status.running.iter().any(|n| n == name)
     {
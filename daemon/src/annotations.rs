@@ -0,0 +1,142 @@
+//! User-editable labels, notes, locations and tags on recordings.
+
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use rayhunter::util::RecordingAnnotations;
+
+use crate::server::ServerState;
+
+const MAX_LABEL_LEN: usize = 128;
+const MAX_LOCATION_LEN: usize = 256;
+const MAX_NOTES_LEN: usize = 8192;
+const MAX_TAG_LEN: usize = 64;
+const MAX_TAGS: usize = 32;
+
+// Trims a text field, treating an empty one as unset
+fn normalize_text(
+    field: &str,
+    value: Option<String>,
+    max_len: usize,
+) -> Result<Option<String>, String> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    if value.chars().count() > max_len {
+        return Err(format!("{field} can be at most {max_len} characters"));
+    }
+    Ok(Some(value.to_string()).filter(|value| !value.is_empty()))
+}
+
+/// Checks the length of each field, and normalizes tags to be lowercase and
+/// unique, so that recordings can be searched by them
+pub fn normalize_annotations(
+    annotations: RecordingAnnotations,
+) -> Result<RecordingAnnotations, String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in annotations.tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tags.contains(&tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(format!("tags can be at most {MAX_TAG_LEN} characters"));
+        }
+        if tag.chars().any(|c| c.is_whitespace() || c.is_control() || c == ',') {
+            return Err(format!(
+                "tag \"{tag}\" can't contain whitespace or commas, use \"-\" instead"
+            ));
+        }
+        tags.push(tag);
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("a recording can have at most {MAX_TAGS} tags"));
+    }
+    Ok(RecordingAnnotations {
+        label: normalize_text("label", annotations.label, MAX_LABEL_LEN)?,
+        notes: normalize_text("notes", annotations.notes, MAX_NOTES_LEN)?,
+        location: normalize_text("location", annotations.location, MAX_LOCATION_LEN)?,
+        tags,
+    })
+}
+
+pub async fn get_annotations(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+) -> Result<Json<RecordingAnnotations>, (StatusCode, String)> {
+    let qmdl_store = state.qmdl_store_lock.read().await;
+    let (_, entry) = qmdl_store.entry_for_name(&name).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("couldn't find entry with name {name}"),
+    ))?;
+    Ok(Json(entry.annotations.clone()))
+}
+
+/// Replaces a recording's annotations, returning them as they were saved
+pub async fn set_annotations(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    Json(annotations): Json<RecordingAnnotations>,
+) -> Result<Json<RecordingAnnotations>, (StatusCode, String)> {
+    let annotations =
+        normalize_annotations(annotations).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut qmdl_store = state.qmdl_store_lock.write().await;
+    let (entry_index, _) = qmdl_store.entry_for_name(&name).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("couldn't find entry with name {name}"),
+    ))?;
+    qmdl_store
+        .set_entry_annotations(entry_index, annotations.clone())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("couldn't save annotations: {e}"),
+            )
+        })?;
+    Ok(Json(annotations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_annotations() {
+        let annotations = normalize_annotations(RecordingAnnotations {
+            label: Some("  downtown march ".to_string()),
+            notes: Some("".to_string()),
+            location: None,
+            tags: vec![
+                "Protest".to_string(),
+                " control".to_string(),
+                "protest".to_string(),
+                "".to_string(),
+            ],
+        })
+        .unwrap();
+        assert_eq!(
+            annotations,
+            RecordingAnnotations {
+                label: Some("downtown march".to_string()),
+                notes: None,
+                location: None,
+                tags: vec!["protest".to_string(), "control".to_string()],
+            }
+        );
+
+        let bad_tag = RecordingAnnotations {
+            tags: vec!["false positive".to_string()],
+            ..Default::default()
+        };
+        assert!(normalize_annotations(bad_tag).is_err());
+        let long_label = RecordingAnnotations {
+            label: Some("a".repeat(MAX_LABEL_LEN + 1)),
+            ..Default::default()
+        };
+        assert!(normalize_annotations(long_label).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Inspecting and resetting the baseline of cells usually seen, which is
+//! learned across recordings when `baseline_enabled` is set.
+
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use rayhunter::baseline::{Baseline, BaselineSummary, SharedBaseline};
+use serde::Serialize;
+
+use crate::server::ServerState;
+
+#[derive(Serialize)]
+pub struct BaselineResponse {
+    pub summary: BaselineSummary,
+    pub baseline: Baseline,
+}
+
+fn enabled_baseline(state: &ServerState) -> Result<&SharedBaseline, (StatusCode, String)> {
+    state.baseline.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        "the baseline isn't enabled, set baseline_enabled in the config".to_string(),
+    ))
+}
+
+pub async fn get_baseline(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<BaselineResponse>, (StatusCode, String)> {
+    let baseline = enabled_baseline(&state)?.read().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "baseline lock poisoned".to_string(),
+        )
+    })?;
+    Ok(Json(BaselineResponse {
+        summary: baseline.summary(),
+        baseline: baseline.clone(),
+    }))
+}
+
+/// Forgets everything learned so far, both in memory and on disk
+pub async fn reset_baseline(
+    State(state): State<Arc<ServerState>>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let cells = match enabled_baseline(&state)?.write() {
+        Ok(mut baseline) => {
+            let cells = baseline.summary().cells;
+            baseline.clear();
+            cells
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "baseline lock poisoned".to_string(),
+            ));
+        }
+    };
+    state
+        .qmdl_store_lock
+        .read()
+        .await
+        .delete_baseline()
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("couldn't delete baseline file: {e}"),
+            )
+        })?;
+    Ok((
+        StatusCode::ACCEPTED,
+        format!("forgot the baseline of {cells} cells"),
+    ))
+}
@@ -2,9 +2,19 @@ use log::warn;
 use serde::{Deserialize, Serialize};
 
 use rayhunter::Device;
-use rayhunter::analysis::analyzer::AnalyzerConfig;
+use rayhunter::analysis::analyzer::{AnalyzerConfig, EventType};
+use rayhunter::diag_device::LogCodeSelection;
+use rayhunter::diag_transport::DiagTransportConfig;
+use rayhunter::encryption::RecordingPublicKey;
+use rayhunter::gps::LocationOptions;
+pub use rayhunter::gps_log::GpsLogFormat;
+use rayhunter::qmdl::QmdlCompression;
 
 use crate::error::RayhunterError;
+use crate::gps_source::GpsSourceConfig;
+use crate::notifications::{NotificationSinkConfig, NotificationSinkKind};
+use crate::qmdl_store::RecordingPolicy;
+use crate::upload::UploadConfig;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
@@ -13,13 +23,35 @@ pub struct Config {
     pub port: u16,
     pub debug_mode: bool,
     pub device: Device,
+    /// Where to read diag messages from
+    pub diag_transport: DiagTransportConfig,
+    /// Which diag log codes to record
+    pub diag_logs: LogCodeSelection,
     pub ui_level: u8,
     pub colorblind_mode: bool,
     pub key_input_mode: u8,
     pub ntfy_url: Option<String>,
+    /// Where to send notifications, in addition to `ntfy_url`
+    pub notifications: Vec<NotificationSinkConfig>,
+    /// Send a notification once the disk holding recordings is this full, in
+    /// percent. 0 disables the check.
+    pub disk_warning_percent: u8,
     pub jwt_secret: Option<String>,
     pub jwt_key_file: Option<String>,
     pub analyzers: AnalyzerConfig,
+    /// Learn the cells, PLMNs and neighbor relations usually seen across
+    /// recordings, so analyzers can tell what's new. Off by default, since it
+    /// keeps a record of the areas the device has been in.
+    pub baseline_enabled: bool,
+    /// Compression of new QMDL files
+    pub qmdl_compression: QmdlCompression,
+    /// Rotation and retention of recordings
+    pub recordings: RecordingPolicy,
+    /// Base64 X25519 public key to encrypt new recordings to, as printed by
+    /// `rayhunter-check keygen`. Recordings aren't encrypted if unset.
+    pub encryption_public_key: Option<String>,
+    /// Where to upload finished recordings, if anywhere
+    pub upload: Option<UploadConfig>,
     // GPS Configuration
     #[serde(default)]
     pub gps: GpsConfig,
@@ -30,6 +62,30 @@ pub struct Config {
 pub struct GpsConfig {
     pub gps_logging_enabled: bool,
     pub gps_log_format: GpsLogFormat,
+    /// Oldest fix (in seconds) accepted by the batch backfill endpoint
+    pub backfill_max_age_secs: u64,
+    /// Optional local GPS receiver to read fixes from
+    pub source: Option<GpsSourceConfig>,
+    /// Maximum time in seconds between a warning and the GPS fix used to
+    /// locate it
+    pub correlation_max_gap_secs: u64,
+    /// Interpolate between the fixes before and after a warning instead of
+    /// using the nearest one
+    pub correlation_interpolate: bool,
+}
+
+impl GpsConfig {
+    /// What to record as the source in structured GPS log headers
+    pub fn log_source(&self) -> &'static str {
+        self.source.as_ref().map_or("api", GpsSourceConfig::name)
+    }
+
+    pub fn location_options(&self) -> LocationOptions {
+        LocationOptions {
+            max_gap_secs: self.correlation_max_gap_secs,
+            interpolate: self.correlation_interpolate,
+        }
+    }
 }
 
 impl Default for GpsConfig {
@@ -37,24 +93,14 @@ impl Default for GpsConfig {
         Self {
             gps_logging_enabled: true,
             gps_log_format: GpsLogFormat::Simple,
+            backfill_max_age_secs: 7 * 24 * 60 * 60,
+            source: None,
+            correlation_max_gap_secs: 60,
+            correlation_interpolate: true,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
-pub enum GpsLogFormat {
-    Json,
-    Csv,
-    Raw,
-    Simple,
-}
-
-impl Default for GpsLogFormat {
-    fn default() -> Self {
-        GpsLogFormat::Json
-    }
-}
 
 impl Default for Config {
     fn default() -> Self {
@@ -63,11 +109,20 @@ impl Default for Config {
             port: 8080,
             debug_mode: false,
             device: Device::Orbic,
+            diag_transport: DiagTransportConfig::default(),
+            diag_logs: LogCodeSelection::default(),
             ui_level: 1,
             colorblind_mode: false,
             key_input_mode: 0,
             analyzers: AnalyzerConfig::default(),
+            baseline_enabled: false,
+            qmdl_compression: QmdlCompression::None,
+            recordings: RecordingPolicy::default(),
+            encryption_public_key: None,
+            upload: None,
             ntfy_url: None,
+            notifications: Vec::new(),
+            disk_warning_percent: 90,
             jwt_secret: None,
             jwt_key_file: None,
             gps: GpsConfig::default(),
@@ -75,12 +130,44 @@ impl Default for Config {
     }
 }
 
+impl Config {
+    /// The key to encrypt new recordings to, if encryption is enabled
+    pub fn encryption_key(&self) -> Result<Option<RecordingPublicKey>, RayhunterError> {
+        self.encryption_public_key
+            .as_deref()
+            .filter(|key| !key.is_empty())
+            .map(RecordingPublicKey::from_base64)
+            .transpose()
+            .map_err(RayhunterError::InvalidEncryptionKey)
+    }
+
+    /// All configured notification sinks. A bare `ntfy_url` is treated as an
+    /// ntfy sink with default settings.
+    pub fn notification_sinks(&self) -> Vec<NotificationSinkConfig> {
+        let mut sinks = self.notifications.clone();
+        if let Some(url) = self.ntfy_url.as_ref().filter(|url| !url.is_empty()) {
+            sinks.push(NotificationSinkConfig {
+                kind: NotificationSinkKind::Ntfy { url: url.clone() },
+                min_severity: EventType::Low,
+                template: None,
+            });
+        }
+        sinks
+    }
+}
+
 pub async fn parse_config<P>(path: P) -> Result<Config, RayhunterError>
 where
     P: AsRef<std::path::Path>,
 {
     if let Ok(config_file) = tokio::fs::read_to_string(&path).await {
-        Ok(toml::from_str(&config_file).map_err(RayhunterError::ConfigFileParsingError)?)
+        let config: Config =
+            toml::from_str(&config_file).map_err(RayhunterError::ConfigFileParsingError)?;
+        config
+            .diag_logs
+            .validate()
+            .map_err(RayhunterError::InvalidLogCodeSelection)?;
+        Ok(config)
     } else {
         warn!("unable to read config file, using default config");
         Ok(Config::default())
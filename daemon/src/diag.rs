@@ -1,8 +1,12 @@
-use std::ops::DerefMut;
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
 use std::pin::pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Local, Utc};
+
+use axum::Json;
 use axum::body::Body;
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
@@ -10,23 +14,50 @@ use axum::http::header::CONTENT_TYPE;
 use axum::response::{IntoResponse, Response};
 use futures::{StreamExt, TryStreamExt, future};
 use log::{debug, error, info, warn};
+use serde::Serialize;
 use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{RwLock, oneshot};
 use tokio_stream::wrappers::LinesStream;
+use tokio_util::io::ReaderStream;
 use tokio_util::task::TaskTracker;
 
+use rayhunter::Device;
 use rayhunter::analysis::analyzer::{AnalysisLineNormalizer, AnalyzerConfig, EventType};
+use rayhunter::baseline::SharedBaseline;
 use rayhunter::diag::{DataType, MessagesContainer};
-use rayhunter::diag_device::DiagDevice;
+use rayhunter::diag_device::{
+    DiagDevice, DiagDeviceError, LogCodeHandle, LogCodeSelection, ModemInfoHandle,
+};
+use rayhunter::diag_transport::DiagTransportConfig;
+use rayhunter::encryption::EncryptingWriter;
+use rayhunter::gps::TrackLocator;
 use rayhunter::qmdl::QmdlWriter;
 
-use crate::analysis::{AnalysisCtrlMessage, AnalysisWriter};
+use crate::analysis::{AnalysisCtrlMessage, AnalysisWriter, DetectedWarning};
 use crate::display;
+use crate::error::RecordingError;
+use crate::integrity::ChecksumWriter;
 use crate::notifications::Notification;
 use crate::qmdl_store::{RecordingStore, RecordingStoreError};
-use crate::server::ServerState;
+use crate::server::{ServerState, ensure_not_encrypted};
+
+/// How often each analyzer can trigger a notification
+const WARNING_DEBOUNCE: Duration = Duration::from_secs(60 * 5);
+
+/// How long to keep trying to re-open the diag device after a read error
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many times in a row the diag device can be re-opened without anything
+/// being read from it before giving up
+const MAX_CONSECUTIVE_REOPENS: u32 = 3;
+
+/// How often the baseline is saved during a recording, so a crash or power
+/// loss doesn't lose everything learned since the last recording ended
+const BASELINE_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+type RecordingWriter = QmdlWriter<EncryptingWriter<ChecksumWriter<File>>>;
 
 pub enum DiagDeviceCtrlMessage {
     StopRecording,
@@ -41,52 +72,160 @@ pub enum DiagDeviceCtrlMessage {
     Exit,
 }
 
+/// How reading from the modem and recording have been going since Rayhunter
+/// started, shown in `/api/system-stats`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiagHealth {
+    pub containers_read: u64,
+    /// Errors reading from the diag device, each followed by re-opening it
+    pub read_errors: u64,
+    /// Containers from the diag device that couldn't be parsed and were
+    /// skipped
+    pub parse_errors: u64,
+    /// Times the diag device was successfully re-opened
+    pub reopens: u64,
+    /// Errors writing recordings to storage, each of which stopped recording
+    pub storage_errors: u64,
+    pub last_read_error: Option<String>,
+    pub last_read_error_time: Option<DateTime<Local>>,
+    /// Why recording stopped unexpectedly, until the next recording starts
+    pub recording_error: Option<String>,
+}
+
+impl DiagHealth {
+    fn record_read_error(&mut self, err: &DiagDeviceError) {
+        self.read_errors += 1;
+        self.last_read_error = Some(err.to_string());
+        self.last_read_error_time = Some(Local::now());
+    }
+}
+
 pub struct DiagTask {
     ui_update_sender: Sender<display::DisplayState>,
     analysis_sender: Sender<AnalysisCtrlMessage>,
     analyzer_config: AnalyzerConfig,
     notification_channel: tokio::sync::mpsc::Sender<Notification>,
+    gps_locator: TrackLocator,
+    // the cells usually seen, kept across recordings and saved to the store
+    // periodically and after each one, if the baseline is enabled
+    baseline: Option<SharedBaseline>,
+    last_baseline_save: Instant,
+    log_code_selection_lock: Arc<RwLock<LogCodeSelection>>,
+    log_code_handle: LogCodeHandle,
+    // the log codes the modem was last configured with
+    enabled_log_codes: Vec<u32>,
+    modem_info_handle: ModemInfoHandle,
     state: DiagState,
     max_type_seen: EventType,
+    diag_health_lock: Arc<RwLock<DiagHealth>>,
 }
 
 enum DiagState {
     Recording {
-        qmdl_writer: QmdlWriter<File>,
+        qmdl_writer: RecordingWriter,
         analysis_writer: Box<AnalysisWriter>,
     },
     Stopped,
 }
 
 impl DiagTask {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         ui_update_sender: Sender<display::DisplayState>,
         analysis_sender: Sender<AnalysisCtrlMessage>,
         analyzer_config: AnalyzerConfig,
         notification_channel: tokio::sync::mpsc::Sender<Notification>,
+        gps_locator: TrackLocator,
+        baseline: Option<SharedBaseline>,
+        log_code_selection_lock: Arc<RwLock<LogCodeSelection>>,
+        log_code_handle: LogCodeHandle,
+        enabled_log_codes: Vec<u32>,
+        modem_info_handle: ModemInfoHandle,
+        diag_health_lock: Arc<RwLock<DiagHealth>>,
     ) -> Self {
         Self {
             ui_update_sender,
             analysis_sender,
             analyzer_config,
             notification_channel,
+            gps_locator,
+            baseline,
+            last_baseline_save: Instant::now(),
+            log_code_selection_lock,
+            log_code_handle,
+            enabled_log_codes,
+            modem_info_handle,
             state: DiagState::Stopped,
             max_type_seen: EventType::Informational,
+            diag_health_lock,
         }
     }
 
-    /// Start recording
+    /// Start recording, stopping again if the recording can't be created
     async fn start(&mut self, qmdl_store: &mut RecordingStore) {
+        match self.try_start(qmdl_store).await {
+            Ok(()) => self.diag_health_lock.write().await.recording_error = None,
+            Err(err) => self.fail_storage(qmdl_store, err).await,
+        }
+    }
+
+    async fn try_start(&mut self, qmdl_store: &mut RecordingStore) -> Result<(), RecordingError> {
+        let log_codes = self.log_code_selection_lock.read().await.log_codes();
+        if log_codes != self.enabled_log_codes {
+            info!("enabling {} diag log codes for the new recording", log_codes.len());
+            self.log_code_handle.reconfigure(log_codes.clone());
+            self.enabled_log_codes = log_codes;
+        }
+        match qmdl_store.enforce_retention().await {
+            Ok(deleted) if !deleted.is_empty() => {
+                info!("deleted {} old recordings: {}", deleted.len(), deleted.join(", "));
+            }
+            Ok(_) => {}
+            Err(e) => error!("couldn't delete old recordings: {e}"),
+        }
+        qmdl_store.modem_firmware = self
+            .modem_info_handle
+            .latest()
+            .map(|info| info.firmware())
+            .unwrap_or_default();
         let (qmdl_file, analysis_file) = qmdl_store
             .new_entry()
             .await
-            .expect("failed creating QMDL file entry");
+            .map_err(RecordingError::CreateEntryError)?;
         self.stop_current_recording().await;
-        let qmdl_writer = QmdlWriter::new(qmdl_file);
-        let analysis_writer = AnalysisWriter::new(analysis_file, &self.analyzer_config)
-            .await
-            .map(Box::new)
-            .expect("failed to write to analysis file");
+        let compression = qmdl_store
+            .get_current_entry()
+            .map(|(_, entry)| entry.compression)
+            .unwrap_or_default();
+        // new entries are encrypted whenever there's a key
+        let encryption_key = qmdl_store.encryption_key;
+        let qmdl_writer = QmdlWriter::new(EncryptingWriter::new(
+            ChecksumWriter::new(qmdl_file),
+            encryption_key.as_ref(),
+        ))
+        .with_compression(compression);
+        let mut analysis_writer = AnalysisWriter::new(
+            analysis_file,
+            encryption_key.as_ref(),
+            &self.analyzer_config,
+            Some(self.gps_locator.clone()),
+            qmdl_store.modem_firmware.clone(),
+        )
+        .await
+        .map_err(RecordingError::CreateAnalysisFileError)?;
+        if let Some(baseline) = self.baseline.as_ref() {
+            analysis_writer = analysis_writer.with_baseline(baseline.clone());
+        }
+        if let Some((index, _)) = qmdl_store.get_current_entry() {
+            match qmdl_store.clear_and_open_entry_signal(index).await {
+                Ok(signal_file) => {
+                    analysis_writer =
+                        analysis_writer.with_signal_file(signal_file, encryption_key.as_ref());
+                }
+                Err(e) => error!("couldn't create signal samples file: {e}"),
+            }
+        }
+        let analysis_writer = Box::new(analysis_writer);
         self.state = DiagState::Recording {
             qmdl_writer,
             analysis_writer,
@@ -98,11 +237,29 @@ impl DiagTask {
         {
             warn!("couldn't send ui update message: {e}");
         }
+        Ok(())
     }
 
-    /// Stop recording
-    async fn stop(&mut self, qmdl_store: &mut RecordingStore) {
+    /// Finish the current recording and start a new one in its place
+    async fn rotate(&mut self, qmdl_store: &mut RecordingStore) {
+        if let Some((_, entry)) = qmdl_store.get_current_entry() {
+            info!("recording {} reached its size or duration limit, starting a new one", entry.name);
+        }
+        self.finish_current_entry(qmdl_store).await;
+        self.start(qmdl_store).await;
+    }
+
+    async fn finish_current_entry(&mut self, qmdl_store: &mut RecordingStore) {
+        if let (DiagState::Recording { qmdl_writer, .. }, Some(index)) =
+            (&self.state, qmdl_store.current_entry)
+        {
+            let checksums = qmdl_writer.get_ref().get_ref().checksums().all();
+            if let Err(e) = qmdl_store.update_entry_checksums(index, &checksums).await {
+                error!("couldn't save QMDL checksums: {e}");
+            }
+        }
         self.stop_current_recording().await;
+        self.save_baseline(qmdl_store).await;
         if let Some((_, entry)) = qmdl_store.get_current_entry() {
             let result = self
                 .analysis_sender
@@ -110,23 +267,68 @@ impl DiagTask {
                     entry.name.to_string(),
                 ))
                 .await;
-                
             if let Err(e) = result {
                 warn!("couldn't send analysis message: {e}");
             }
         }
+    }
+
+    /// Stop recording
+    async fn stop(&mut self, qmdl_store: &mut RecordingStore) {
+        self.close_current_entry(qmdl_store).await;
+        if let Err(e) = self
+            .ui_update_sender
+            .send(display::DisplayState::Paused)
+            .await
+        {
+            warn!("couldn't send ui update message: {e}");
+        }
+    }
+
+    async fn close_current_entry(&mut self, qmdl_store: &mut RecordingStore) {
+        self.finish_current_entry(qmdl_store).await;
         if let Err(e) = qmdl_store.close_current_entry().await {
             error!("couldn't close current entry: {e}");
         }
+    }
+
+    /// Stop recording because something went wrong, keeping what was recorded
+    /// so far and showing the failure on the display and in the API
+    async fn fail(&mut self, qmdl_store: &mut RecordingStore, reason: String) {
+        self.close_current_entry(qmdl_store).await;
+        self.diag_health_lock.write().await.recording_error = Some(reason);
         if let Err(e) = self
             .ui_update_sender
-            .send(display::DisplayState::Paused)
+            .send(display::DisplayState::RecordingFailed)
             .await
         {
             warn!("couldn't send ui update message: {e}");
         }
     }
 
+    async fn fail_diag(&mut self, qmdl_store: &mut RecordingStore, err: &DiagDeviceError) {
+        error!("error reading diag device: {err}");
+        let notification = Notification::new(
+            "diag-error".to_string(),
+            format!("Rayhunter can no longer read from the modem and has stopped recording: {err}"),
+            None,
+        );
+        self.notify(qmdl_store, notification).await;
+        self.fail(qmdl_store, format!("can't read from the modem: {err}")).await;
+    }
+
+    async fn fail_storage(&mut self, qmdl_store: &mut RecordingStore, err: RecordingError) {
+        error!("stopping recording: {err}");
+        self.diag_health_lock.write().await.storage_errors += 1;
+        let notification = Notification::new(
+            "recording-stopped".to_string(),
+            format!("Rayhunter stopped recording unexpectedly: {err}"),
+            None,
+        );
+        self.notify(qmdl_store, notification).await;
+        self.fail(qmdl_store, err.to_string()).await;
+    }
+
     async fn delete_entry(
         &mut self,
         qmdl_store: &mut RecordingStore,
@@ -154,6 +356,20 @@ impl DiagTask {
         res
     }
 
+    async fn save_baseline(&mut self, qmdl_store: &RecordingStore) {
+        let Some(baseline) = self.baseline.as_ref() else {
+            return;
+        };
+        self.last_baseline_save = Instant::now();
+        // cloned so the lock isn't held across the write
+        let baseline = baseline.read().ok().map(|baseline| baseline.clone());
+        if let Some(baseline) = baseline {
+            if let Err(e) = qmdl_store.save_baseline(&baseline).await {
+                error!("couldn't save baseline: {e}");
+            }
+        }
+    }
+
     async fn stop_current_recording(&mut self) {
         let mut state = DiagState::Stopped;
         std::mem::swap(&mut self.state, &mut state);
@@ -161,10 +377,9 @@ impl DiagTask {
             analysis_writer, ..
         } = state
         {
-            analysis_writer
-                .close()
-                .await
-                .expect("failed to close analysis writer");
+            if let Err(e) = analysis_writer.close().await {
+                error!("couldn't close analysis file: {e}");
+            }
         }
     }
 
@@ -184,60 +399,162 @@ impl DiagTask {
             analysis_writer,
         } = &mut self.state
         {
-            qmdl_writer
-                .write_container(&container)
-                .await
-                .expect("failed to write to QMDL writer");
-            debug!(
-                "total QMDL bytes written: {}, updating manifest...",
-                qmdl_writer.total_written
-            );
-            let index = qmdl_store
-                .current_entry
-                .expect("DiagDevice had qmdl_writer, but QmdlStore didn't have current entry???");
-            qmdl_store
-                .update_entry_qmdl_size(index, qmdl_writer.total_written)
-                .await
-                .expect("failed to update qmdl file size");
-            debug!("done!");
-            let max_type = analysis_writer
-                .analyze(container)
-                .await
-                .expect("failed to analyze container");
-
-            if max_type > EventType::Informational {
-                info!("a heuristic triggered on this run!");
-                self.notification_channel
-                    .send(Notification::new(
-                        "heuristic-warning".to_string(),
-                        format!("Rayhunter has detected a {:?} severity event", max_type),
-                        Some(Duration::from_secs(60 * 5)),
-                    ))
-                    .await
-                    .expect("Failed to send to notification channel");
+            let result =
+                Self::record_container(qmdl_store, qmdl_writer, analysis_writer, container).await;
+            if let Some(serving_cell) = analysis_writer.serving_cell() {
+                self.modem_info_handle.set_serving_cell(serving_cell);
             }
-
-            if max_type > self.max_type_seen {
-                self.max_type_seen = max_type;
-                if self.max_type_seen > EventType::Informational {
-                    self.ui_update_sender
-                        .send(display::DisplayState::WarningDetected {
-                            event_type: self.max_type_seen,
-                        })
-                        .await
-                        .expect("couldn't send ui update message: {}");
+            match result {
+                Ok(warnings) => {
+                    self.handle_warnings(qmdl_store, warnings).await;
+                    if self.last_baseline_save.elapsed() >= BASELINE_SAVE_INTERVAL {
+                        self.save_baseline(qmdl_store).await;
+                    }
+                    if qmdl_store.should_rotate() {
+                        self.rotate(qmdl_store).await;
+                    }
                 }
+                Err(err) => self.fail_storage(qmdl_store, err).await,
             }
         } else {
             debug!("no qmdl_writer set, continuing...");
         }
     }
+
+    // Writes a container to the current recording and analyzes it, returning
+    // any warnings
+    async fn record_container(
+        qmdl_store: &mut RecordingStore,
+        qmdl_writer: &mut RecordingWriter,
+        analysis_writer: &mut AnalysisWriter,
+        container: MessagesContainer,
+    ) -> Result<Vec<DetectedWarning>, RecordingError> {
+        qmdl_writer
+            .write_container(&container)
+            .await
+            .map_err(RecordingError::WriteQmdlError)?;
+        // encryption holds data back until it's flushed, and its framing means
+        // the file is bigger than what was written to it
+        qmdl_writer
+            .flush()
+            .await
+            .map_err(RecordingError::WriteQmdlError)?;
+        let checksum_writer = qmdl_writer.get_ref().get_ref();
+        debug!(
+            "total QMDL bytes written: {}, updating manifest...",
+            checksum_writer.bytes_written()
+        );
+        let index = qmdl_store
+            .current_entry
+            .expect("DiagDevice had qmdl_writer, but QmdlStore didn't have current entry???");
+        qmdl_store
+            .update_entry_qmdl_size(index, checksum_writer.bytes_written())
+            .await
+            .map_err(RecordingError::UpdateQmdlSizeError)?;
+        qmdl_store
+            .update_entry_checksums(index, checksum_writer.checksums().complete())
+            .await
+            .map_err(RecordingError::UpdateChecksumsError)?;
+        debug!("done!");
+        analysis_writer
+            .analyze(container)
+            .await
+            .map_err(RecordingError::WriteAnalysisError)
+    }
+
+    async fn handle_warnings(
+        &mut self,
+        qmdl_store: &mut RecordingStore,
+        warnings: Vec<DetectedWarning>,
+    ) {
+        let Some(max_type) = warnings.iter().map(|warning| warning.event_type).max() else {
+            return;
+        };
+        info!("a heuristic triggered on this run!");
+        if let Some(index) = qmdl_store.current_entry {
+            if let Err(e) = qmdl_store.set_entry_has_warnings(index, true).await {
+                error!("couldn't mark recording as having warnings: {e}");
+            }
+        }
+
+        // Send one notification per analyzer, for its most severe warning.
+        // Each analyzer is debounced separately, so a noisy one doesn't hide
+        // warnings from the others.
+        let mut by_analyzer: BTreeMap<&str, &DetectedWarning> = BTreeMap::new();
+        for warning in &warnings {
+            let most_severe = by_analyzer.entry(&warning.analyzer).or_insert(warning);
+            if warning.event_type > most_severe.event_type {
+                *most_severe = warning;
+            }
+        }
+        for warning in by_analyzer.into_values() {
+            let mut notification = Notification::new(
+                format!("heuristic-warning:{}", warning.analyzer),
+                warning.message.clone(),
+                Some(WARNING_DEBOUNCE),
+            )
+            .with_severity(warning.event_type)
+            .with_analyzer(warning.analyzer.clone());
+            if warning.location.is_some() {
+                notification = notification.with_location(warning.location.clone());
+            }
+            self.notify(qmdl_store, notification).await;
+        }
+
+        if max_type > self.max_type_seen {
+            self.max_type_seen = max_type;
+            if let Err(e) = self
+                .ui_update_sender
+                .send(display::DisplayState::WarningDetected {
+                    event_type: self.max_type_seen,
+                })
+                .await
+            {
+                warn!("couldn't send ui update message: {e}");
+            }
+        }
+    }
+
+    /// Sends a notification, filling in the current recording and, unless it
+    /// already has one, the latest GPS fix
+    async fn notify(&self, qmdl_store: &RecordingStore, mut notification: Notification) {
+        let recording = qmdl_store
+            .get_current_entry()
+            .map(|(_, entry)| entry.name.clone());
+        notification = notification.with_recording(recording);
+        if !notification.has_location() {
+            notification = notification.with_location(self.gps_locator.latest(&Utc::now()));
+        }
+        if let Err(e) = self.notification_channel.send(notification).await {
+            warn!("couldn't send notification: {e}");
+        }
+    }
+}
+
+// Opens the diag device again after reading from it failed, with the given
+// log codes enabled. The modem's queried again first, since it might have
+// restarted with different firmware.
+async fn reopen_diag_device(
+    transport_config: &DiagTransportConfig,
+    device: &Device,
+    log_codes: &[u32],
+    modem_info_handle: &ModemInfoHandle,
+) -> Result<DiagDevice, DiagDeviceError> {
+    let mut dev = DiagDevice::new_with_retries(REOPEN_TIMEOUT, transport_config, device).await?;
+    match dev.modem_info().await {
+        Ok(info) => modem_info_handle.update(info),
+        Err(e) => warn!("couldn't query modem info: {e}"),
+    }
+    dev.config_logs(log_codes).await?;
+    Ok(dev)
 }
 
 #[allow(clippy::too_many_arguments)]
 pub fn run_diag_read_thread(
     task_tracker: &TaskTracker,
     mut dev: DiagDevice,
+    transport_config: DiagTransportConfig,
+    device: Device,
     mut qmdl_file_rx: Receiver<DiagDeviceCtrlMessage>,
     qmdl_file_tx: Sender<DiagDeviceCtrlMessage>,
     ui_update_sender: Sender<display::DisplayState>,
@@ -245,61 +562,144 @@ pub fn run_diag_read_thread(
     analysis_sender: Sender<AnalysisCtrlMessage>,
     analyzer_config: AnalyzerConfig,
     notification_channel: tokio::sync::mpsc::Sender<Notification>,
+    gps_locator: TrackLocator,
+    baseline: Option<SharedBaseline>,
+    log_code_selection_lock: Arc<RwLock<LogCodeSelection>>,
+    modem_info_handle: ModemInfoHandle,
+    diag_health_lock: Arc<RwLock<DiagHealth>>,
 ) {
     task_tracker.spawn(async move {
-        let mut diag_stream = pin!(dev.as_stream().into_stream());
-        let mut diag_task = DiagTask::new(ui_update_sender, analysis_sender, analyzer_config, notification_channel);
+        let log_code_handle = dev.log_code_handle();
+        // main configures the device with the initial selection
+        let enabled_log_codes = log_code_selection_lock.read().await.log_codes();
+        let mut diag_task = DiagTask::new(
+            ui_update_sender,
+            analysis_sender,
+            analyzer_config,
+            notification_channel,
+            gps_locator,
+            baseline,
+            log_code_selection_lock,
+            log_code_handle,
+            enabled_log_codes,
+            modem_info_handle,
+            diag_health_lock.clone(),
+        );
         qmdl_file_tx
             .send(DiagDeviceCtrlMessage::StartRecording)
             .await
             .unwrap();
+        let mut consecutive_reopens = 0;
         loop {
-            tokio::select! {
-                msg = qmdl_file_rx.recv() => {
-                    match msg {
-                        Some(DiagDeviceCtrlMessage::StartRecording) => {
-                            let mut qmdl_store = qmdl_store_lock.write().await;
-                            diag_task.start(qmdl_store.deref_mut()).await;
-                        },
-                        Some(DiagDeviceCtrlMessage::StopRecording) => {
-                            let mut qmdl_store = qmdl_store_lock.write().await;
-                            diag_task.stop(qmdl_store.deref_mut()).await;
-                        },
-                        // None means all the Senders have been dropped, so it's
-                        // time to go
-                        Some(DiagDeviceCtrlMessage::Exit) | None => {
-                            info!("Diag reader thread exiting...");
-                            diag_task.stop_current_recording().await;
-                            return Ok(())
-                        },
-                        Some(DiagDeviceCtrlMessage::DeleteEntry { name, response_tx }) => {
-                            let mut qmdl_store = qmdl_store_lock.write().await;
-                            let resp = diag_task.delete_entry(qmdl_store.deref_mut(), name.as_str()).await;
-                            if response_tx.send(resp).is_err() {
-                                error!("Failed to send delete entry respons, receiver dropped");
+            // the stream borrows the device, so it has to be dropped before the
+            // device can be re-opened
+            let read_error = {
+                let mut diag_stream = pin!(dev.as_stream().into_stream());
+                // set once a replay's been played back, after which only
+                // control messages are handled
+                let mut stream_ended = false;
+                loop {
+                    tokio::select! {
+                        msg = qmdl_file_rx.recv() => {
+                            match msg {
+                                Some(DiagDeviceCtrlMessage::StartRecording) => {
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    diag_task.start(qmdl_store.deref_mut()).await;
+                                },
+                                Some(DiagDeviceCtrlMessage::StopRecording) => {
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    diag_task.stop(qmdl_store.deref_mut()).await;
+                                },
+                                // None means all the Senders have been dropped, so it's
+                                // time to go
+                                Some(DiagDeviceCtrlMessage::Exit) | None => {
+                                    info!("Diag reader thread exiting...");
+                                    // saves the checksums and baseline, which
+                                    // would otherwise be lost on restart
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    diag_task.finish_current_entry(qmdl_store.deref_mut()).await;
+                                    return Ok(())
+                                },
+                                Some(DiagDeviceCtrlMessage::DeleteEntry { name, response_tx }) => {
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    let resp = diag_task.delete_entry(qmdl_store.deref_mut(), name.as_str()).await;
+                                    if response_tx.send(resp).is_err() {
+                                        error!("Failed to send delete entry respons, receiver dropped");
+                                    }
+                                },
+                                Some(DiagDeviceCtrlMessage::DeleteAllEntries { response_tx }) => {
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    let resp = diag_task.delete_all_entries(qmdl_store.deref_mut()).await;
+                                    if response_tx.send(resp).is_err() {
+                                        error!("Failed to send delete all entries respons, receiver dropped");
+                                    }
+                                },
                             }
-                        },
-                        Some(DiagDeviceCtrlMessage::DeleteAllEntries { response_tx }) => {
-                            let mut qmdl_store = qmdl_store_lock.write().await;
-                            let resp = diag_task.delete_all_entries(qmdl_store.deref_mut()).await;
-                            if response_tx.send(resp).is_err() {
-                                error!("Failed to send delete all entries respons, receiver dropped");
+                        }
+                        maybe_container = diag_stream.next(), if !stream_ended => {
+                            match maybe_container {
+                                Some(Ok(container)) => {
+                                    consecutive_reopens = 0;
+                                    diag_health_lock.write().await.containers_read += 1;
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    diag_task.process_container(qmdl_store.deref_mut(), container).await
+                                },
+                                Some(Err(err)) => break err,
+                                // re-opening a replay would play it again,
+                                // so the recording's just stopped
+                                None => {
+                                    info!("diag device has nothing more to read, stopping recording");
+                                    stream_ended = true;
+                                    let mut qmdl_store = qmdl_store_lock.write().await;
+                                    diag_task.stop(qmdl_store.deref_mut()).await;
+                                },
                             }
-                        },
+                        }
                     }
                 }
-                maybe_container = diag_stream.next() => {
-                    match maybe_container.unwrap() {
-                        Ok(container) => {
-                            let mut qmdl_store = qmdl_store_lock.write().await;
-                            diag_task.process_container(qmdl_store.deref_mut(), container).await
+            };
+
+            match read_error {
+                // those messages are lost, but the device is fine
+                DiagDeviceError::ParseMessagesContainerError(e) => {
+                    warn!("skipping diag messages that couldn't be parsed: {e}");
+                    diag_health_lock.write().await.parse_errors += 1;
+                }
+                // the current recording stays open, and just misses what the
+                // modem logged while the device was being re-opened
+                DiagDeviceError::DeviceReadFailed(_)
+                    if consecutive_reopens < MAX_CONSECUTIVE_REOPENS
+                        && !matches!(transport_config, DiagTransportConfig::Replay { .. }) =>
+                {
+                    warn!("error reading diag device, re-opening it: {read_error}");
+                    diag_health_lock.write().await.record_read_error(&read_error);
+                    consecutive_reopens += 1;
+                    let reopened = reopen_diag_device(
+                        &transport_config,
+                        &device,
+                        &diag_task.enabled_log_codes,
+                        &diag_task.modem_info_handle,
+                    ).await;
+                    match reopened {
+                        Ok(reopened) => {
+                            info!("re-opened diag device");
+                            dev = reopened;
+                            diag_task.log_code_handle = dev.log_code_handle();
+                            diag_health_lock.write().await.reopens += 1;
                         },
                         Err(err) => {
-                            error!("error reading diag device: {err}");
+                            let mut qmdl_store = qmdl_store_lock.write().await;
+                            diag_task.fail_diag(qmdl_store.deref_mut(), &err).await;
                             return Err(err);
                         }
                     }
                 }
+                err => {
+                    diag_health_lock.write().await.record_read_error(&err);
+                    let mut qmdl_store = qmdl_store_lock.write().await;
+                    diag_task.fail_diag(qmdl_store.deref_mut(), &err).await;
+                    return Err(err);
+                }
             }
         }
     });
@@ -350,6 +750,43 @@ pub async fn stop_recording(
     Ok((StatusCode::ACCEPTED, "ok".to_string()))
 }
 
+#[derive(Debug, Serialize)]
+pub struct LogCodesResponse {
+    pub selection: LogCodeSelection,
+    /// The log codes the selection enables
+    pub log_codes: Vec<u32>,
+}
+
+pub async fn get_log_codes(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<LogCodesResponse>, (StatusCode, String)> {
+    let selection = state.log_code_selection_lock.read().await.clone();
+    let log_codes = selection.log_codes();
+    Ok(Json(LogCodesResponse {
+        selection,
+        log_codes,
+    }))
+}
+
+/// Changes the log codes to enable, starting with the next recording
+pub async fn set_log_codes(
+    State(state): State<Arc<ServerState>>,
+    Json(selection): Json<LogCodeSelection>,
+) -> Result<(StatusCode, Json<LogCodesResponse>), (StatusCode, String)> {
+    selection
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let log_codes = selection.log_codes();
+    *state.log_code_selection_lock.write().await = selection.clone();
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(LogCodesResponse {
+            selection,
+            log_codes,
+        }),
+    ))
+}
+
 pub async fn delete_recording(
     State(state): State<Arc<ServerState>>,
     Path(qmdl_name): Path<String>,
@@ -425,7 +862,7 @@ pub async fn get_analysis_report(
     Path(qmdl_name): Path<String>,
 ) -> Result<Response, (StatusCode, String)> {
     let qmdl_store = state.qmdl_store_lock.read().await;
-    let (entry_index, _) = if qmdl_name == "live" {
+    let (entry_index, entry) = if qmdl_name == "live" {
         qmdl_store.get_current_entry().ok_or((
             StatusCode::SERVICE_UNAVAILABLE,
             "No QMDL data's being recorded to analyze, try starting a new recording!".to_string(),
@@ -436,6 +873,8 @@ pub async fn get_analysis_report(
             format!("Couldn't find QMDL entry with name \"{qmdl_name}\""),
         ))?
     };
+    ensure_not_encrypted(entry)?;
+    let annotations = entry.annotations.clone();
     let analysis_file = qmdl_store
         .open_entry_analysis(entry_index)
         .await
@@ -445,7 +884,7 @@ pub async fn get_analysis_report(
     let reader = BufReader::new(analysis_file);
     let lines_stream = LinesStream::new(reader.lines());
 
-    let mut normalizer = AnalysisLineNormalizer::new();
+    let mut normalizer = AnalysisLineNormalizer::new().with_annotations(annotations);
     let normalized_stream = lines_stream
         .try_filter(|line| future::ready(!line.is_empty()))
         .map_ok(move |line| normalizer.normalize_line(line));
@@ -454,3 +893,159 @@ pub async fn get_analysis_report(
     let body = Body::from_stream(normalized_stream);
     Ok((headers, body).into_response())
 }
+
+/// Serves a recording's signal samples as newline-delimited JSON
+pub async fn get_signal_samples(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let qmdl_store = state.qmdl_store_lock.read().await;
+    let (entry_index, entry) = if qmdl_name == "live" {
+        qmdl_store.get_current_entry().ok_or((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No QMDL data's being recorded, try starting a new recording!".to_string(),
+        ))?
+    } else {
+        qmdl_store.entry_for_name(&qmdl_name).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Couldn't find QMDL entry with name \"{qmdl_name}\""),
+        ))?
+    };
+    ensure_not_encrypted(entry)?;
+    // recordings made before signal samples were kept don't have any
+    let signal_file = qmdl_store.open_entry_signal(entry_index).await.map_err(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("No signal samples for \"{qmdl_name}\", try re-analyzing it"),
+        )
+    })?;
+
+    let headers = [(CONTENT_TYPE, "application/x-ndjson")];
+    let body = Body::from_stream(ReaderStream::new(signal_file));
+    Ok((headers, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayhunter::diag::{LogBody, Message, Nas4GMessageDirection, Timestamp};
+    use rayhunter::diag_transport::ReplayPacing;
+    use rayhunter::gps::{GpsTrack, LocationOptions};
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+
+    // Writes a QMDL file with the given number of NAS messages
+    async fn write_qmdl(path: &std::path::Path, count: usize) {
+        let time = DateTime::parse_from_rfc3339("2024-03-01T12:00:00.000Z").unwrap();
+        let mut writer = QmdlWriter::new(Vec::new());
+        for _ in 0..count {
+            let message = Message::new_log(
+                0xb0ec,
+                Timestamp::from_datetime(&time),
+                LogBody::Nas4GMessage {
+                    direction: Nas4GMessageDirection::Downlink,
+                    ext_header_version: 1,
+                    rrc_rel: 0,
+                    rrc_version_minor: 0,
+                    rrc_version_major: 0,
+                    msg: vec![0x07, 0x42],
+                },
+            )
+            .unwrap();
+            let container =
+                MessagesContainer::from_messages(DataType::UserSpace, &[message]).unwrap();
+            writer.write_container(&container).await.unwrap();
+        }
+        tokio::fs::write(path, writer.get_ref()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_recording() {
+        let replay_dir = TempDir::new().unwrap();
+        let replay_path = replay_dir.path().join("replay.qmdl");
+        write_qmdl(&replay_path, 2).await;
+        let transport_config = DiagTransportConfig::Replay {
+            path: replay_path.to_str().unwrap().to_string(),
+            pacing: ReplayPacing::AsFastAsPossible,
+        };
+        let mut dev = DiagDevice::new(&transport_config, &Device::Pinephone)
+            .await
+            .unwrap();
+        let log_code_selection = LogCodeSelection::default();
+        dev.config_logs(&log_code_selection.log_codes())
+            .await
+            .unwrap();
+
+        let store_dir = TempDir::new().unwrap();
+        let store = RecordingStore::create(store_dir.path()).await.unwrap();
+        let qmdl_store_lock = Arc::new(RwLock::new(store));
+        let diag_health_lock = Arc::new(RwLock::new(DiagHealth::default()));
+        let (diag_tx, diag_rx) = mpsc::channel(1);
+        let (ui_update_tx, mut ui_update_rx) = mpsc::channel(16);
+        let (analysis_tx, mut analysis_rx) = mpsc::channel(16);
+        let (notification_tx, _notification_rx) = mpsc::channel(16);
+        let gps_locator = TrackLocator::new(
+            Arc::new(std::sync::RwLock::new(GpsTrack::new())),
+            LocationOptions::default(),
+        );
+        let task_tracker = TaskTracker::new();
+        run_diag_read_thread(
+            &task_tracker,
+            dev,
+            transport_config,
+            Device::Pinephone,
+            diag_rx,
+            diag_tx.clone(),
+            ui_update_tx,
+            qmdl_store_lock.clone(),
+            analysis_tx,
+            AnalyzerConfig::default(),
+            notification_tx,
+            gps_locator,
+            None,
+            Arc::new(RwLock::new(log_code_selection)),
+            ModemInfoHandle::default(),
+            diag_health_lock.clone(),
+        );
+
+        // the recording starts, and stops once the whole file's been played
+        // back
+        let timeout = Duration::from_secs(5);
+        for expected in [
+            display::DisplayState::Recording,
+            display::DisplayState::Paused,
+        ] {
+            let state = tokio::time::timeout(timeout, ui_update_rx.recv()).await;
+            assert!(matches!(state, Ok(Some(state)) if state == expected));
+        }
+
+        let entry_name = {
+            let qmdl_store = qmdl_store_lock.read().await;
+            assert!(qmdl_store.current_entry.is_none());
+            assert_eq!(qmdl_store.manifest.entries.len(), 1);
+            let entry = &qmdl_store.manifest.entries[0];
+            assert!(entry.qmdl_size_bytes > 0);
+            entry.name.clone()
+        };
+        assert!(matches!(
+            analysis_rx.recv().await,
+            Some(AnalysisCtrlMessage::RecordingFinished(name)) if name == entry_name
+        ));
+        {
+            // the end of the file isn't a read error, so the file isn't
+            // played back again
+            let diag_health = diag_health_lock.read().await;
+            assert_eq!(diag_health.containers_read, 2);
+            assert_eq!(diag_health.read_errors, 0);
+            assert_eq!(diag_health.reopens, 0);
+            assert!(diag_health.recording_error.is_none());
+        }
+
+        // control messages are still handled once the replay's over
+        diag_tx.send(DiagDeviceCtrlMessage::Exit).await.unwrap();
+        task_tracker.close();
+        tokio::time::timeout(timeout, task_tracker.wait())
+            .await
+            .unwrap();
+    }
+}
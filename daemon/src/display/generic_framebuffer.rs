@@ -63,6 +63,7 @@ impl Color {
 fn display_style_from_state(state: DisplayState, colorblind_mode: bool) -> (Color, LinePattern) {
     match state {
         DisplayState::Paused => (Color::White, LinePattern::Solid),
+        DisplayState::RecordingFailed => (Color::White, LinePattern::Dashed),
         DisplayState::Recording => {
             if colorblind_mode {
                 (Color::Blue, LinePattern::Solid)
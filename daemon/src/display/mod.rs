@@ -23,4 +23,7 @@ pub enum DisplayState {
     /// Note that EventType::Informational is never sent through this. If it is, it's the same as
     /// Recording
     WarningDetected { event_type: EventType },
+    /// Recording stopped because of an error, such as storage being full or
+    /// the modem not responding anymore.
+    RecordingFailed,
 }
@@ -2,6 +2,7 @@
 /// DisplayState::Recording => Signal LED slowly blinks blue.
 /// DisplayState::Paused => WiFi LED blinks white.
 /// DisplayState::WarningDetected { .. } => Signal LED slowly blinks red.
+/// DisplayState::RecordingFailed => WiFi LED blinks white, signal LED blinks red.
 use log::{error, info};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
@@ -73,6 +74,11 @@ pub fn update_ui(
                     stop_blinking(led!("signal_blue")).await;
                     start_blinking(led!("signal_red")).await;
                 }
+                DisplayState::RecordingFailed => {
+                    stop_blinking(led!("signal_blue")).await;
+                    start_blinking(led!("wlan_white")).await;
+                    start_blinking(led!("signal_red")).await;
+                }
             }
             last_state = state;
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -2,6 +2,7 @@
 /// DisplayState::Recording => Green LED is solid.
 /// DisplayState::Paused => Signal LED is solid blue (wifi LED).
 /// DisplayState::WarningDetected => Signal LED is solid red.
+/// DisplayState::RecordingFailed => Signal LEDs are solid blue and red.
 use log::{error, info};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
@@ -78,6 +79,11 @@ pub fn update_ui(
                         led_off(led!("wifi")).await;
                         led_on(led!("red")).await;
                     }
+                    DisplayState::RecordingFailed => {
+                        led_off(led!("green")).await;
+                        led_on(led!("wifi")).await;
+                        led_on(led!("red")).await;
+                    }
                 }
                 last_state = state;
                 last_update = now;
@@ -1,4 +1,5 @@
-use rayhunter::diag_device::DiagDeviceError;
+use rayhunter::diag_device::{DiagDeviceError, LogCodeSelectionError};
+use rayhunter::encryption::EncryptionError;
 use thiserror::Error;
 
 use crate::qmdl_store::RecordingStoreError;
@@ -15,4 +16,25 @@ pub enum RayhunterError {
     QmdlStoreError(#[from] RecordingStoreError),
     #[error("No QMDL store found at path {0}, but can't create a new one due to debug mode")]
     NoStoreDebugMode(String),
+    #[error("Invalid encryption_public_key: {0}")]
+    InvalidEncryptionKey(EncryptionError),
+    #[error("Invalid diag_logs: {0}")]
+    InvalidLogCodeSelection(LogCodeSelectionError),
+}
+
+/// Why a recording had to be stopped
+#[derive(Error, Debug)]
+pub enum RecordingError {
+    #[error("failed to create recording: {0}")]
+    CreateEntryError(RecordingStoreError),
+    #[error("failed to create analysis file: {0}")]
+    CreateAnalysisFileError(std::io::Error),
+    #[error("failed to write to QMDL file: {0}")]
+    WriteQmdlError(std::io::Error),
+    #[error("failed to update QMDL file size: {0}")]
+    UpdateQmdlSizeError(RecordingStoreError),
+    #[error("failed to update QMDL checksums: {0}")]
+    UpdateChecksumsError(RecordingStoreError),
+    #[error("failed to write analysis results: {0}")]
+    WriteAnalysisError(std::io::Error),
 }
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+use rayhunter::analysis::analyzer::{AnalysisRow, ReportMetadata};
+use rayhunter::diag::DataType;
+use rayhunter::geo_export::GeoExport;
+use rayhunter::qmdl::QmdlReader;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::server::{ServerState, ensure_not_encrypted};
+
+/// Combines a recording's GPS track, the warnings from its analysis report
+/// and the cells seen in its QMDL into one map export.
+async fn build_geo_export(
+    state: &ServerState,
+    name: &str,
+) -> Result<GeoExport, (StatusCode, String)> {
+    let (qmdl_file, qmdl_size_bytes, analysis_file) = {
+        let qmdl_store = state.qmdl_store_lock.read().await;
+        let (entry_index, entry) = qmdl_store.entry_for_name(name).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("couldn't find manifest entry with name {name}"),
+        ))?;
+        ensure_not_encrypted(entry)?;
+        let qmdl_size_bytes = entry.qmdl_size_bytes;
+        let qmdl_file = qmdl_store
+            .open_entry_qmdl(entry_index)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:?}")))?;
+        // a recording that was never analyzed still has a track and cells
+        let analysis_file = qmdl_store.open_entry_analysis(entry_index).await.ok();
+        (qmdl_file, qmdl_size_bytes, analysis_file)
+    };
+
+    let track = state.gps_logger.entry_track(name).await;
+    if track.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("no GPS data was logged for {name}"),
+        ));
+    }
+    let mut export = GeoExport::new(name, track, state.gps_logger.location_options());
+
+    if let Some(analysis_file) = analysis_file {
+        let read_error = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:?}"));
+        let mut lines = BufReader::new(analysis_file).lines();
+        // the first line of a report is its metadata, which names the
+        // analyzers each row's events belong to
+        let metadata: ReportMetadata = match lines.next_line().await.map_err(read_error)? {
+            Some(line) => serde_json::from_str(&line).unwrap_or_default(),
+            None => ReportMetadata::default(),
+        };
+        while let Some(line) = lines.next_line().await.map_err(read_error)? {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AnalysisRow>(&line) {
+                Ok(row) => export.add_analysis_row(&row, &metadata.analyzers),
+                Err(e) => warn!("skipping unparseable analysis row for {name}: {e}"),
+            }
+        }
+    }
+
+    let mut qmdl_reader = QmdlReader::new(qmdl_file, Some(qmdl_size_bytes));
+    while let Some(container) = qmdl_reader
+        .get_next_messages_container()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:?}")))?
+    {
+        if container.data_type == DataType::UserSpace {
+            export.add_qmdl_messages(container);
+        }
+    }
+
+    Ok(export)
+}
+
+pub async fn get_geojson(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let name = name.trim_end_matches(".geojson");
+    let export = build_geo_export(&state, name).await?;
+    let headers = [(CONTENT_TYPE, "application/geo+json")];
+    Ok((headers, export.to_geojson().to_string()).into_response())
+}
+
+pub async fn get_kml(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let name = name.trim_end_matches(".kml");
+    let export = build_geo_export(&state, name).await?;
+    let headers = [(CONTENT_TYPE, "application/vnd.google-earth.kml+xml")];
+    Ok((headers, export.to_kml()).into_response())
+}
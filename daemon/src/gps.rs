@@ -4,6 +4,7 @@
 //! and save them to per-scan GPS files for tracking location data.
 
 use chrono::{DateTime, Utc};
+use rayhunter::gps::GpsFix;
 use serde::{Deserialize, Serialize};
 
 /// GPS coordinate data structure
@@ -27,3 +28,15 @@ pub struct GpsCoordinate {
     #[serde(default)]
     pub request_id: Option<String>,
 }
+
+impl GpsCoordinate {
+    pub fn to_fix(&self) -> GpsFix {
+        GpsFix {
+            timestamp: self.timestamp,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            accuracy: self.accuracy,
+            altitude: self.altitude,
+        }
+    }
+}
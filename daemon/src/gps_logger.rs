@@ -4,11 +4,17 @@
 //! It integrates with the existing recording system to ensure GPS logs are created
 //! at the same time as QMDL and NDJSON logs with the same timestamp filenames.
 
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashSet};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
 use tokio::sync::RwLock;
-use log::debug;
+use log::{debug, warn};
+use rayhunter::encryption::{self, EncryptingWriter, RecordingPublicKey};
+use rayhunter::gps::{GpsTrack, LocationOptions, SharedGpsTrack, TrackLocator};
+use rayhunter::gps_log::{GpsLogHeader, GpsLogReader};
 use serde::Serialize;
 
 use crate::gps::GpsCoordinate;
@@ -26,12 +32,45 @@ pub enum GpsLoggerError {
     NoCurrentEntry,
     #[error("GPS logging is disabled")]
     LoggingDisabled,
+    #[error("Recording is encrypted, but no encryption key is configured")]
+    NoEncryptionKey,
 }
 
+/// Outcome of a [`GpsLogger::backfill_gps_coordinates`] call
+#[derive(Debug, Default, Serialize)]
+pub struct GpsBackfillSummary {
+    /// Number of fixes written, across all recordings
+    pub written: usize,
+    /// Fixes skipped because their timestamp was already logged
+    pub duplicates: usize,
+    /// Fixes that don't fall inside any recording
+    pub unmatched: usize,
+    /// Fixes skipped because their recording's GPS log is encrypted and was
+    /// started before the daemon was, so it can't be checked for duplicates
+    pub encrypted: usize,
+    /// Fixes written per recording name
+    pub recordings: BTreeMap<String, usize>,
+}
+
+/// Timestamps (to the second) of the fixes in each recording's GPS log
+type FixTimes = BTreeMap<String, HashSet<i64>>;
+
+/// How much history the live track keeps, relative to its newest fix
+const LIVE_TRACK_WINDOW: chrono::Duration = chrono::Duration::hours(1);
+
 pub struct GpsLogger {
     qmdl_store: Arc<RwLock<RecordingStore>>,
     logging_enabled: bool,
     log_format: crate::config::GpsLogFormat,
+    // Recorded in the header of structured logs
+    log_source: String,
+    // Recent fixes, used to locate warnings while recording
+    live_track: SharedGpsTrack,
+    location_options: LocationOptions,
+    // Timestamps of the fixes in each encrypted GPS log, which can't be read
+    // back to find duplicates. Only logs started since the daemon was are
+    // tracked.
+    encrypted_fix_times: Mutex<FixTimes>,
 }
 
 impl GpsLogger {
@@ -39,11 +78,87 @@ impl GpsLogger {
         qmdl_store: Arc<RwLock<RecordingStore>>,
         gps_logging_enabled: bool,
         gps_log_format: crate::config::GpsLogFormat,
+        location_options: LocationOptions,
     ) -> Self {
         Self {
             qmdl_store,
             logging_enabled: gps_logging_enabled,
             log_format: gps_log_format,
+            log_source: "api".to_string(),
+            live_track: Arc::new(std::sync::RwLock::new(GpsTrack::new())),
+            location_options,
+            encrypted_fix_times: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with_log_source(mut self, log_source: &str) -> Self {
+        self.log_source = log_source.to_string();
+        self
+    }
+
+    /// Locator over the fixes received while the daemon is running, for
+    /// analysis of the current recording
+    pub fn live_locator(&self) -> TrackLocator {
+        TrackLocator::new(self.live_track.clone(), self.location_options)
+    }
+
+    /// Locator over a recording's `.gps` file, for re-analysis
+    pub async fn entry_locator(&self, entry_name: &str) -> TrackLocator {
+        let track = self.entry_track(entry_name).await;
+        TrackLocator::new(Arc::new(std::sync::RwLock::new(track)), self.location_options)
+    }
+
+    /// All fixes logged for a recording
+    pub async fn entry_track(&self, entry_name: &str) -> GpsTrack {
+        let gps_file_path = self.qmdl_store.read().await.path.join(format!("{}.gps", entry_name));
+        read_gps_track(&gps_file_path).await
+    }
+
+    pub fn location_options(&self) -> LocationOptions {
+        self.location_options
+    }
+
+    // Starts tracking an encrypted GPS log's fixes if it doesn't have any yet,
+    // returning whether all of its fixes are known
+    async fn track_encrypted_log(&self, entry_name: &str, gps_file_path: &Path) -> bool {
+        if self.lock_encrypted_fix_times().contains_key(entry_name) {
+            return true;
+        }
+        let is_empty = match tokio::fs::metadata(gps_file_path).await {
+            Ok(metadata) => metadata.len() == 0,
+            Err(e) => e.kind() == ErrorKind::NotFound,
+        };
+        if is_empty {
+            self.lock_encrypted_fix_times()
+                .insert(entry_name.to_string(), HashSet::new());
+        }
+        is_empty
+    }
+
+    // Remembers fixes written to a tracked encrypted GPS log
+    fn add_encrypted_fix_times(&self, entry_name: &str, fixes: &[GpsCoordinate]) {
+        if let Some(times) = self.lock_encrypted_fix_times().get_mut(entry_name) {
+            times.extend(fixes.iter().map(|fix| fix.timestamp.timestamp()));
+        }
+    }
+
+    fn lock_encrypted_fix_times(&self) -> MutexGuard<'_, FixTimes> {
+        // the timestamps are only ever extended, so they're fine to use even
+        // if another thread panicked
+        self.encrypted_fix_times
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn add_to_live_track(&self, fixes: &[GpsCoordinate]) {
+        let Ok(mut track) = self.live_track.write() else {
+            return;
+        };
+        for fix in fixes {
+            track.push(fix.to_fix());
+        }
+        if let Some(newest) = track.fixes().last().map(|fix| fix.timestamp) {
+            track.prune_before(newest - LIVE_TRACK_WINDOW);
         }
     }
 
@@ -57,11 +172,14 @@ impl GpsLogger {
         }
 
         // Get the current recording entry to determine the filename
-        let (current_entry_name, qmdl_directory) = {
+        let (current_entry_name, qmdl_directory, encryption_key) = {
             let qmdl_store = self.qmdl_store.read().await;
             if let Some(current_idx) = qmdl_store.current_entry {
                 if let Some(entry) = qmdl_store.manifest.entries.get(current_idx) {
-                    (entry.name.clone(), qmdl_store.path.clone())
+                    let encryption_key = qmdl_store
+                        .entry_encryption_key(current_idx)
+                        .map_err(|_| GpsLoggerError::NoEncryptionKey)?;
+                    (entry.name.clone(), qmdl_store.path.clone(), encryption_key)
                 } else {
                     return Err(GpsLoggerError::NoCurrentEntry);
                 }
@@ -72,30 +190,164 @@ impl GpsLogger {
 
         // Create the GPS log file path in the QMDL directory with the same timestamp filename
         let gps_file_path = qmdl_directory.join(format!("{}.gps", current_entry_name));
+        let tracked = encryption_key.is_some()
+            && self
+                .track_encrypted_log(&current_entry_name, &gps_file_path)
+                .await;
+        self.append_coordinates(
+            &gps_file_path,
+            encryption_key.as_ref(),
+            std::slice::from_ref(coordinates),
+        )
+        .await?;
+        if tracked {
+            self.add_encrypted_fix_times(&current_entry_name, std::slice::from_ref(coordinates));
+        }
+        self.add_to_live_track(std::slice::from_ref(coordinates));
+
+        debug!("GPS coordinates logged to {}: ({}, {})", 
+            gps_file_path.display(), coordinates.latitude, coordinates.longitude);
+
+        Ok(())
+    }
+
+    /// Write a batch of fixes into the `.gps` files of whichever recordings were
+    /// active when they were taken, including recordings that have since ended.
+    ///
+    /// Fixes whose timestamp (to the second) is already present in the target
+    /// file, or repeated within the batch, are skipped. Encrypted files can't
+    /// be read back, so their fixes are only known if they were all logged
+    /// since the daemon started; fixes for any other encrypted recording are
+    /// skipped and counted in [`GpsBackfillSummary::encrypted`].
+    pub async fn backfill_gps_coordinates(
+        &self,
+        mut fixes: Vec<GpsCoordinate>,
+    ) -> Result<GpsBackfillSummary, GpsLoggerError> {
+        if !self.logging_enabled {
+            return Err(GpsLoggerError::LoggingDisabled);
+        }
 
+        let mut summary = GpsBackfillSummary::default();
+        fixes.sort_by_key(|fix| fix.timestamp);
+
+        // Group fixes by the recording they belong to
+        let mut by_entry: BTreeMap<String, Vec<GpsCoordinate>> = BTreeMap::new();
+        let mut encryption_keys: BTreeMap<String, Option<RecordingPublicKey>> = BTreeMap::new();
+        let qmdl_directory = {
+            let qmdl_store = self.qmdl_store.read().await;
+            for fix in fixes {
+                match qmdl_store.entry_for_timestamp(&fix.timestamp) {
+                    Some((entry_index, entry)) => {
+                        let encryption_key = qmdl_store
+                            .entry_encryption_key(entry_index)
+                            .map_err(|_| GpsLoggerError::NoEncryptionKey)?;
+                        encryption_keys.insert(entry.name.clone(), encryption_key);
+                        by_entry.entry(entry.name.clone()).or_default().push(fix)
+                    }
+                    None => summary.unmatched += 1,
+                }
+            }
+            // deleted recordings don't need their fixes tracked anymore
+            self.lock_encrypted_fix_times().retain(|name, _| {
+                qmdl_store
+                    .manifest
+                    .entries
+                    .iter()
+                    .any(|entry| &entry.name == name)
+            });
+            qmdl_store.path.clone()
+        };
+
+        for (entry_name, mut entry_fixes) in by_entry {
+            let encryption_key = encryption_keys.get(&entry_name).copied().flatten();
+            let gps_file_path = qmdl_directory.join(format!("{}.gps", entry_name));
+            let mut seen: HashSet<i64> = if encryption_key.is_some() {
+                if !self.track_encrypted_log(&entry_name, &gps_file_path).await {
+                    warn!(
+                        "not backfilling {} GPS fixes into {entry_name}: its GPS log is encrypted \
+                         and was started before the daemon, so duplicates can't be found",
+                        entry_fixes.len()
+                    );
+                    summary.encrypted += entry_fixes.len();
+                    continue;
+                }
+                let known = self.lock_encrypted_fix_times().get(&entry_name).cloned();
+                known.unwrap_or_default()
+            } else {
+                read_gps_track(&gps_file_path)
+                    .await
+                    .fixes()
+                    .iter()
+                    .map(|fix| fix.timestamp.timestamp())
+                    .collect()
+            };
+            let before = entry_fixes.len();
+            entry_fixes.retain(|fix| seen.insert(fix.timestamp.timestamp()));
+            summary.duplicates += before - entry_fixes.len();
+
+            if entry_fixes.is_empty() {
+                continue;
+            }
+            self.append_coordinates(&gps_file_path, encryption_key.as_ref(), &entry_fixes)
+                .await?;
+            if encryption_key.is_some() {
+                self.add_encrypted_fix_times(&entry_name, &entry_fixes);
+            }
+            self.add_to_live_track(&entry_fixes);
+            debug!("backfilled {} GPS fixes into {}", entry_fixes.len(), gps_file_path.display());
+            summary.written += entry_fixes.len();
+            summary.recordings.insert(entry_name, entry_fixes.len());
+        }
+
+        Ok(summary)
+    }
+
+    // Appends fixes to a GPS log. Encrypted logs get a new segment for every
+    // append, so nothing that's already there has to be read back.
+    async fn append_coordinates(
+        &self,
+        gps_file_path: &Path,
+        encryption_key: Option<&RecordingPublicKey>,
+        coordinates: &[GpsCoordinate],
+    ) -> Result<(), GpsLoggerError> {
         // Open or create the GPS log file
         let gps_file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(&gps_file_path)
+            .open(gps_file_path)
             .await
             .map_err(|e| GpsLoggerError::FileCreationError(e.to_string()))?;
+        let is_new_file = gps_file
+            .metadata()
+            .await
+            .map_err(|e| GpsLoggerError::FileCreationError(e.to_string()))?
+            .len()
+            == 0;
 
-        let mut writer = BufWriter::new(gps_file);
+        let mut writer = BufWriter::new(EncryptingWriter::new(gps_file, encryption_key));
+
+        if is_new_file && self.log_format == crate::config::GpsLogFormat::Structured {
+            let header = serde_json::to_string(&GpsLogHeader::new(&self.log_source))
+                .map_err(|e| GpsLoggerError::SerializationError(e.to_string()))?;
+            writer.write_all((header + "\n").as_bytes()).await
+                .map_err(|e| GpsLoggerError::WriteError(e.to_string()))?;
+        }
 
         // Write GPS data according to the configured format
-        match self.log_format {
-            crate::config::GpsLogFormat::Json => {
-                self.write_json_format(&mut writer, coordinates).await?;
-            }
-            crate::config::GpsLogFormat::Csv => {
-                self.write_csv_format(&mut writer, coordinates).await?;
-            }
-            crate::config::GpsLogFormat::Raw => {
-                self.write_raw_format(&mut writer, coordinates).await?;
-            }
-            crate::config::GpsLogFormat::Simple => {
-                self.write_simple_format(&mut writer, coordinates).await?;
+        for coordinates in coordinates {
+            match self.log_format {
+                crate::config::GpsLogFormat::Json | crate::config::GpsLogFormat::Structured => {
+                    self.write_json_format(&mut writer, coordinates).await?;
+                }
+                crate::config::GpsLogFormat::Csv => {
+                    self.write_csv_format(&mut writer, coordinates).await?;
+                }
+                crate::config::GpsLogFormat::Raw => {
+                    self.write_raw_format(&mut writer, coordinates).await?;
+                }
+                crate::config::GpsLogFormat::Simple => {
+                    self.write_simple_format(&mut writer, coordinates).await?;
+                }
             }
         }
 
@@ -103,9 +355,6 @@ impl GpsLogger {
         writer.flush().await
             .map_err(|e| GpsLoggerError::WriteError(e.to_string()))?;
 
-        debug!("GPS coordinates logged to {}: ({}, {})", 
-            gps_file_path.display(), coordinates.latitude, coordinates.longitude);
-
         Ok(())
     }
 
@@ -209,3 +458,163 @@ impl GpsLogger {
         Ok(())
     }
 }
+
+/// Read a recording's GPS log. A missing, unreadable or encrypted file is an
+/// empty track.
+async fn read_gps_track(gps_file_path: &Path) -> GpsTrack {
+    let Ok(mut gps_file) = tokio::fs::File::open(gps_file_path).await else {
+        return GpsTrack::new();
+    };
+    let mut magic = Vec::new();
+    let read_magic = (&mut gps_file)
+        .take(encryption::ENCRYPTION_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .await;
+    if read_magic.is_err() || encryption::is_encrypted(&magic) {
+        return GpsTrack::new();
+    }
+    if gps_file.rewind().await.is_err() {
+        return GpsTrack::new();
+    }
+    let mut reader = GpsLogReader::new(gps_file);
+    match reader.read_track().await {
+        Ok(track) => track,
+        Err(e) => {
+            warn!("failed to read GPS log {}: {e}", gps_file_path.display());
+            GpsTrack::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GpsLogFormat;
+    use chrono::{DateTime, Utc};
+    use rayhunter::encryption::RecordingSecretKey;
+    use tempfile::TempDir;
+
+    fn fix(timestamp: i64, latitude: f64) -> GpsCoordinate {
+        GpsCoordinate {
+            timestamp: DateTime::<Utc>::from_timestamp(timestamp, 0).unwrap(),
+            latitude,
+            longitude: -122.0,
+            accuracy: None,
+            altitude: None,
+            speed: None,
+            heading: None,
+            device_id: None,
+            app_version: None,
+            request_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backfill_into_finished_recording() {
+        let dir = TempDir::new().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        store.close_current_entry().await.unwrap();
+        let start = 1_700_000_000;
+        let entry = &mut store.manifest.entries[entry_index];
+        entry.start_time = DateTime::<Utc>::from_timestamp(start, 0).unwrap().into();
+        entry.last_message_time = Some(entry.start_time + chrono::Duration::minutes(10));
+        let entry = entry.clone();
+        let store = Arc::new(RwLock::new(store));
+
+        let logger = GpsLogger::new(store, true, GpsLogFormat::Simple, LocationOptions::default());
+        let summary = logger
+            .backfill_gps_coordinates(vec![
+                fix(start, 37.0),
+                fix(start, 37.5),
+                fix(start - 3600, 38.0),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(summary.written, 1);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.unmatched, 1);
+        assert_eq!(summary.recordings.get(&entry.name), Some(&1));
+
+        // resubmitting the same batch doesn't write anything twice
+        let summary = logger
+            .backfill_gps_coordinates(vec![fix(start, 37.0)])
+            .await
+            .unwrap();
+        assert_eq!(summary.written, 0);
+        assert_eq!(summary.duplicates, 1);
+
+        let contents = tokio::fs::read_to_string(dir.path().join(format!("{}.gps", entry.name)))
+            .await
+            .unwrap();
+        assert_eq!(contents, format!("{},37,-122\n", start));
+    }
+
+    #[tokio::test]
+    async fn test_structured_log_header() {
+        let dir = TempDir::new().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_name = store.manifest.entries[store.current_entry.unwrap()].name.clone();
+        let store = Arc::new(RwLock::new(store));
+
+        let logger = GpsLogger::new(store, true, GpsLogFormat::Structured, LocationOptions::default())
+            .with_log_source("gpsd");
+        let now = Utc::now().timestamp();
+        logger.log_gps_coordinates(&fix(now, 37.0)).await.unwrap();
+        logger.log_gps_coordinates(&fix(now + 1, 37.5)).await.unwrap();
+
+        let gps_path = dir.path().join(format!("{entry_name}.gps"));
+        let contents = tokio::fs::read_to_string(&gps_path).await.unwrap();
+        assert_eq!(contents.lines().count(), 3);
+
+        let mut reader = GpsLogReader::new(tokio::fs::File::open(&gps_path).await.unwrap());
+        let track = reader.read_track().await.unwrap();
+        assert_eq!(reader.header().unwrap().source, "gpsd");
+        assert_eq!(track.len(), 2);
+        assert_eq!(logger.entry_track(&entry_name).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_encrypted_recording() {
+        let dir = TempDir::new().unwrap();
+        let key = RecordingSecretKey::generate().public_key();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_encryption_key(Some(key));
+        let _ = store.new_entry().await.unwrap();
+        let entry_name = store.manifest.entries[store.current_entry.unwrap()].name.clone();
+        let store = Arc::new(RwLock::new(store));
+
+        let logger = GpsLogger::new(store.clone(), true, GpsLogFormat::Simple, LocationOptions::default());
+        // a second later, so it's after the entry's start to the subsecond
+        let now = Utc::now().timestamp() + 1;
+        logger.log_gps_coordinates(&fix(now, 37.0)).await.unwrap();
+        let gps_path = dir.path().join(format!("{entry_name}.gps"));
+        let logged_len = tokio::fs::metadata(&gps_path).await.unwrap().len();
+
+        // the logged fix isn't written twice, but a new one is appended
+        let summary = logger
+            .backfill_gps_coordinates(vec![fix(now, 37.0), fix(now + 1, 37.5)])
+            .await
+            .unwrap();
+        assert_eq!(summary.written, 1);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.encrypted, 0);
+        let contents = tokio::fs::read(&gps_path).await.unwrap();
+        assert!(contents.len() as u64 > logged_len);
+        assert!(encryption::is_encrypted(&contents));
+
+        // after a restart, the fixes already in the log aren't known
+        let logger = GpsLogger::new(store, true, GpsLogFormat::Simple, LocationOptions::default());
+        let summary = logger
+            .backfill_gps_coordinates(vec![fix(now, 37.0)])
+            .await
+            .unwrap();
+        assert_eq!(summary.written, 0);
+        assert_eq!(summary.encrypted, 1);
+        assert_eq!(tokio::fs::read(&gps_path).await.unwrap(), contents);
+    }
+}
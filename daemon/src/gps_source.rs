@@ -0,0 +1,529 @@
+//! Local GPS sources
+//!
+//! Reads positions from a GPS receiver attached to the device instead of
+//! waiting for a phone to push them over `/api/v2/gps`. Supported sources are
+//! NMEA 0183 sentences from a serial device (e.g. a USB GPS puck) or a TCP
+//! socket, and a gpsd daemon speaking its JSON protocol. Every fix is handed
+//! to [`GpsLogger::log_gps_coordinates`].
+
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_util::task::TaskTracker;
+
+use crate::gps::GpsCoordinate;
+use crate::gps_logger::{GpsLogger, GpsLoggerError};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const KNOTS_TO_METERS_PER_SECOND: f64 = 0.514444;
+
+fn default_baud_rate() -> u32 {
+    9600
+}
+
+fn default_gpsd_address() -> String {
+    "127.0.0.1:2947".to_string()
+}
+
+/// Where the daemon should read GPS fixes from
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GpsSourceConfig {
+    /// NMEA sentences from a serial device, e.g. `/dev/ttyUSB0`
+    NmeaSerial {
+        path: String,
+        #[serde(default = "default_baud_rate")]
+        baud_rate: u32,
+    },
+    /// NMEA sentences from a TCP server, e.g. a GPS forwarding app
+    NmeaTcp { address: String },
+    /// A gpsd instance, using its JSON protocol
+    Gpsd {
+        #[serde(default = "default_gpsd_address")]
+        address: String,
+    },
+}
+
+impl GpsSourceConfig {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GpsSourceConfig::NmeaSerial { .. } => "nmea_serial",
+            GpsSourceConfig::NmeaTcp { .. } => "nmea_tcp",
+            GpsSourceConfig::Gpsd { .. } => "gpsd",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GpsSourceError {
+    #[error("Failed to open GPS source {0}: {1}")]
+    OpenError(String, std::io::Error),
+    #[error("Unsupported baud rate: {0}")]
+    UnsupportedBaudRate(u32),
+    #[error("Failed to configure serial device: {0}")]
+    SerialConfigError(std::io::Error),
+    #[error("Failed to read from GPS source: {0}")]
+    ReadError(std::io::Error),
+    #[error("GPS source closed the connection")]
+    Disconnected,
+}
+
+/// Spawn the GPS source task if one is configured. The task reconnects on
+/// errors until `shutdown_rx` fires.
+pub fn run_gps_source_thread(
+    task_tracker: &TaskTracker,
+    source: Option<GpsSourceConfig>,
+    gps_logger: Arc<GpsLogger>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) {
+    let Some(source) = source else {
+        return;
+    };
+
+    task_tracker.spawn(async move {
+        info!("Starting GPS source: {source:?}");
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => {
+                    info!("received GPS source shutdown");
+                    return;
+                }
+                result = read_source(&source, &gps_logger) => {
+                    match result {
+                        Ok(()) | Err(GpsSourceError::Disconnected) => {
+                            warn!("GPS source disconnected, reconnecting in {RECONNECT_DELAY:?}");
+                        }
+                        Err(e) => error!("GPS source failed, retrying in {RECONNECT_DELAY:?}: {e}"),
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = &mut shutdown_rx => {
+                    info!("received GPS source shutdown");
+                    return;
+                }
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            }
+        }
+    });
+}
+
+async fn read_source(
+    source: &GpsSourceConfig,
+    gps_logger: &GpsLogger,
+) -> Result<(), GpsSourceError> {
+    match source {
+        GpsSourceConfig::NmeaSerial { path, baud_rate } => {
+            let file = open_serial_device(path, *baud_rate)?;
+            let reader = BufReader::new(tokio::fs::File::from_std(file));
+            read_fixes(reader, NmeaParser::default(), gps_logger).await
+        }
+        GpsSourceConfig::NmeaTcp { address } => {
+            let stream = TcpStream::connect(address)
+                .await
+                .map_err(|e| GpsSourceError::OpenError(address.clone(), e))?;
+            read_fixes(BufReader::new(stream), NmeaParser::default(), gps_logger).await
+        }
+        GpsSourceConfig::Gpsd { address } => {
+            let mut stream = TcpStream::connect(address)
+                .await
+                .map_err(|e| GpsSourceError::OpenError(address.clone(), e))?;
+            stream
+                .write_all(b"?WATCH={\"enable\":true,\"json\":true};\n")
+                .await
+                .map_err(GpsSourceError::ReadError)?;
+            read_fixes(BufReader::new(stream), GpsdParser, gps_logger).await
+        }
+    }
+}
+
+/// Turns lines from a GPS source into fixes
+trait FixParser {
+    fn parse_line(&mut self, line: &str) -> Option<GpsCoordinate>;
+}
+
+/// Log every fix read from `reader`. Receivers commonly report several
+/// times per second, but GPS logs have one second resolution, so only the
+/// first fix of each second is kept.
+async fn read_fixes<R: AsyncBufRead + Unpin, P: FixParser>(
+    mut reader: R,
+    mut parser: P,
+    gps_logger: &GpsLogger,
+) -> Result<(), GpsSourceError> {
+    let mut buf = Vec::new();
+    let mut last_logged: Option<i64> = None;
+    loop {
+        buf.clear();
+        let read = reader
+            .read_until(b'\n', &mut buf)
+            .await
+            .map_err(GpsSourceError::ReadError)?;
+        if read == 0 {
+            break;
+        }
+        // a noisy serial line can garble bytes, which mustn't end the stream
+        let line = String::from_utf8_lossy(&buf);
+        let Some(fix) = parser.parse_line(line.trim_end_matches(['\r', '\n'])) else {
+            continue;
+        };
+        if last_logged == Some(fix.timestamp.timestamp()) {
+            continue;
+        }
+        last_logged = Some(fix.timestamp.timestamp());
+        match gps_logger.log_gps_coordinates(&fix).await {
+            Ok(()) => {}
+            Err(GpsLoggerError::NoCurrentEntry | GpsLoggerError::LoggingDisabled) => {
+                debug!("not recording, dropping GPS fix ({}, {})", fix.latitude, fix.longitude);
+            }
+            Err(e) => warn!("failed to log GPS fix: {e}"),
+        }
+    }
+    Err(GpsSourceError::Disconnected)
+}
+
+/// Open a serial device in raw mode at the given baud rate
+fn open_serial_device(path: &str, baud_rate: u32) -> Result<std::fs::File, GpsSourceError> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let speed = match baud_rate {
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        other => return Err(GpsSourceError::UnsupportedBaudRate(other)),
+    };
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)
+        .map_err(|e| GpsSourceError::OpenError(path.to_string(), e))?;
+
+    let fd = file.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(GpsSourceError::SerialConfigError(std::io::Error::last_os_error()));
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        if libc::cfsetispeed(&mut termios, speed) != 0
+            || libc::cfsetospeed(&mut termios, speed) != 0
+            || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0
+        {
+            return Err(GpsSourceError::SerialConfigError(std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(file)
+}
+
+/// Position from the most recent GGA sentence, merged into the RMC sentence
+/// of the same epoch
+#[derive(Debug, Clone)]
+struct GgaFix {
+    time: NaiveTime,
+    latitude: f64,
+    longitude: f64,
+    altitude: Option<f64>,
+}
+
+/// Parser for NMEA 0183 GGA and RMC sentences from any talker (GP, GN, GL,
+/// ...).
+///
+/// RMC carries the date, speed and course, so fixes are emitted on RMC with
+/// the altitude of a matching GGA attached. Receivers that only send GGA get
+/// fixes dated with the system's current UTC date.
+#[derive(Debug, Default)]
+pub struct NmeaParser {
+    seen_rmc: bool,
+    last_gga: Option<GgaFix>,
+}
+
+impl FixParser for NmeaParser {
+    fn parse_line(&mut self, line: &str) -> Option<GpsCoordinate> {
+        let fields = split_nmea_sentence(line)?;
+        let sentence_type = fields[0].get(2..)?;
+        match sentence_type {
+            "GGA" => self.parse_gga(&fields),
+            "RMC" => self.parse_rmc(&fields),
+            _ => None,
+        }
+    }
+}
+
+impl NmeaParser {
+    // $GPGGA,time,lat,N,lon,E,quality,satellites,hdop,altitude,M,...
+    fn parse_gga(&mut self, fields: &[&str]) -> Option<GpsCoordinate> {
+        let quality: u8 = fields.get(6)?.parse().ok()?;
+        if quality == 0 {
+            return None;
+        }
+        let gga = GgaFix {
+            time: parse_nmea_time(fields.get(1)?)?,
+            latitude: parse_nmea_coordinate(fields.get(2)?, fields.get(3)?)?,
+            longitude: parse_nmea_coordinate(fields.get(4)?, fields.get(5)?)?,
+            altitude: fields.get(9).and_then(|altitude| altitude.parse().ok()),
+        };
+        self.last_gga = Some(gga.clone());
+        if self.seen_rmc {
+            return None;
+        }
+
+        let timestamp = Utc::now().date_naive().and_time(gga.time).and_utc();
+        Some(GpsCoordinate {
+            timestamp,
+            latitude: gga.latitude,
+            longitude: gga.longitude,
+            accuracy: None,
+            altitude: gga.altitude,
+            speed: None,
+            heading: None,
+            device_id: None,
+            app_version: None,
+            request_id: None,
+        })
+    }
+
+    // $GPRMC,time,status,lat,N,lon,E,speed_knots,course,date,...
+    fn parse_rmc(&mut self, fields: &[&str]) -> Option<GpsCoordinate> {
+        self.seen_rmc = true;
+        if *fields.get(2)? != "A" {
+            return None;
+        }
+        let time = parse_nmea_time(fields.get(1)?)?;
+        let date = NaiveDate::parse_from_str(fields.get(9)?, "%d%m%y").ok()?;
+        let altitude = self
+            .last_gga
+            .as_ref()
+            .filter(|gga| gga.time == time)
+            .and_then(|gga| gga.altitude);
+
+        Some(GpsCoordinate {
+            timestamp: date.and_time(time).and_utc(),
+            latitude: parse_nmea_coordinate(fields.get(3)?, fields.get(4)?)?,
+            longitude: parse_nmea_coordinate(fields.get(5)?, fields.get(6)?)?,
+            accuracy: None,
+            altitude,
+            speed: fields
+                .get(7)
+                .and_then(|speed| speed.parse::<f64>().ok())
+                .map(|knots| knots * KNOTS_TO_METERS_PER_SECOND),
+            heading: fields.get(8).and_then(|course| course.parse().ok()),
+            device_id: None,
+            app_version: None,
+            request_id: None,
+        })
+    }
+}
+
+/// Verify an NMEA sentence's checksum (when present) and split it into
+/// comma-separated fields, starting with the talker and sentence type
+fn split_nmea_sentence(line: &str) -> Option<Vec<&str>> {
+    let body = line.trim().strip_prefix('$')?;
+    let body = match body.split_once('*') {
+        Some((body, checksum)) => {
+            let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+            let actual = body.bytes().fold(0, |acc, byte| acc ^ byte);
+            if actual != expected {
+                return None;
+            }
+            body
+        }
+        None => body,
+    };
+    let fields: Vec<&str> = body.split(',').collect();
+    if fields[0].len() != 5 {
+        return None;
+    }
+    Some(fields)
+}
+
+/// Parse `hhmmss.sss`
+fn parse_nmea_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H%M%S%.f")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H%M%S"))
+        .ok()
+}
+
+/// Parse a `ddmm.mmmm` / `dddmm.mmmm` coordinate with its hemisphere
+fn parse_nmea_coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    let dot = value.find('.').unwrap_or(value.len());
+    // garbage from a serial port might not split on a char boundary
+    let (degrees, minutes) = value.split_at_checked(dot.checked_sub(2)?)?;
+    let degrees: f64 = degrees.parse().ok()?;
+    let minutes: f64 = minutes.parse().ok()?;
+    let coordinate = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(coordinate),
+        "S" | "W" => Some(-coordinate),
+        _ => None,
+    }
+}
+
+/// Parser for gpsd's JSON protocol. Only TPV reports with a 2D or 3D fix
+/// produce coordinates.
+pub struct GpsdParser;
+
+#[derive(Debug, Deserialize)]
+struct GpsdTpv {
+    class: String,
+    #[serde(default)]
+    mode: u8,
+    time: Option<DateTime<Utc>>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    #[serde(default)]
+    alt: Option<f64>,
+    #[serde(default, rename = "altMSL")]
+    alt_msl: Option<f64>,
+    #[serde(default)]
+    speed: Option<f64>,
+    #[serde(default)]
+    track: Option<f64>,
+    #[serde(default)]
+    eph: Option<f64>,
+}
+
+impl FixParser for GpsdParser {
+    fn parse_line(&mut self, line: &str) -> Option<GpsCoordinate> {
+        let report: GpsdTpv = serde_json::from_str(line).ok()?;
+        if report.class != "TPV" || report.mode < 2 {
+            return None;
+        }
+        Some(GpsCoordinate {
+            timestamp: report.time?,
+            latitude: report.lat?,
+            longitude: report.lon?,
+            accuracy: report.eph,
+            altitude: report.alt_msl.or(report.alt),
+            speed: report.speed,
+            heading: report.track,
+            device_id: None,
+            app_version: None,
+            request_id: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GpsLogFormat;
+    use crate::qmdl_store::RecordingStore;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+
+    #[test]
+    fn test_nmea_rmc_with_gga_altitude() {
+        let mut parser = NmeaParser::default();
+        assert!(parser.parse_line(RMC).is_some());
+        // GGA alone doesn't produce a fix once the receiver is known to send RMC
+        assert!(parser.parse_line(GGA).is_none());
+
+        let fix = parser.parse_line(RMC).unwrap();
+        assert_eq!(fix.timestamp.to_rfc3339(), "1994-03-23T12:35:19+00:00");
+        assert!((fix.latitude - 48.1173).abs() < 1e-6);
+        assert!((fix.longitude - 11.516_666).abs() < 1e-6);
+        assert_eq!(fix.altitude, Some(545.4));
+        assert_eq!(fix.heading, Some(84.4));
+        assert!((fix.speed.unwrap() - 11.523_546).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_nmea_rejects_bad_checksum_and_void_fix() {
+        let mut parser = NmeaParser::default();
+        let corrupted = RMC.replace("4807.038", "4807.039");
+        assert!(parser.parse_line(&corrupted).is_none());
+        let void = "$GPRMC,123519,V,,,,,,,230394,,*";
+        assert!(parser.parse_line(void).is_none());
+        let garbled = "$GPRMC,123519,A,4é07.038,N,01131.000,E,022.4,084.4,230394,003.1,W";
+        assert!(parser.parse_line(garbled).is_none());
+    }
+
+    #[test]
+    fn test_gpsd_tpv() {
+        let mut parser = GpsdParser;
+        assert!(parser.parse_line(r#"{"class":"VERSION","release":"3.25"}"#).is_none());
+        assert!(parser.parse_line(r#"{"class":"TPV","mode":1}"#).is_none());
+        let fix = parser
+            .parse_line(r#"{"class":"TPV","mode":3,"time":"2024-05-01T10:00:00.000Z","lat":52.5,"lon":13.4,"altMSL":34.5,"speed":1.5,"track":270.0,"eph":4.2}"#)
+            .unwrap();
+        assert_eq!(fix.latitude, 52.5);
+        assert_eq!(fix.longitude, 13.4);
+        assert_eq!(fix.altitude, Some(34.5));
+        assert_eq!(fix.accuracy, Some(4.2));
+    }
+
+    #[tokio::test]
+    async fn test_read_fixes_skips_invalid_utf8() {
+        let dir = TempDir::new().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_name = store.get_current_entry().unwrap().1.name.clone();
+        let store = Arc::new(RwLock::new(store));
+        let logger = GpsLogger::new(store, true, GpsLogFormat::Simple, Default::default());
+
+        let mut stream = b"$GPGGA,12\xff3519\r\n".to_vec();
+        stream.extend_from_slice(format!("{GGA}\r\n").as_bytes());
+        assert!(matches!(
+            read_fixes(stream.as_slice(), NmeaParser::default(), &logger).await,
+            Err(GpsSourceError::Disconnected)
+        ));
+
+        let contents = tokio::fs::read_to_string(dir.path().join(format!("{entry_name}.gps")))
+            .await
+            .unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.contains(",48.1173,"));
+    }
+
+    #[tokio::test]
+    async fn test_nmea_tcp_source_logs_fixes() {
+        let dir = TempDir::new().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_name = store.get_current_entry().unwrap().1.name.clone();
+        let store = Arc::new(RwLock::new(store));
+        let logger = GpsLogger::new(store, true, GpsLogFormat::Simple, Default::default());
+
+        // a fake receiver sending two epochs, the first one twice
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let stream = format!(
+                "{RMC}\r\n{GGA}\r\n{RMC}\r\n$GPRMC,123520,A,4807.040,N,01131.000,E,022.4,084.4,230394,003.1,W\r\n"
+            );
+            socket.write_all(stream.as_bytes()).await.unwrap();
+        });
+
+        let source = GpsSourceConfig::NmeaTcp { address };
+        assert!(matches!(
+            read_source(&source, &logger).await,
+            Err(GpsSourceError::Disconnected)
+        ));
+
+        let contents = tokio::fs::read_to_string(dir.path().join(format!("{entry_name}.gps")))
+            .await
+            .unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("764426119,48.1173,"));
+        assert!(lines[1].starts_with("764426120,"));
+    }
+}
@@ -4,23 +4,26 @@
 //! All GPS data comes from JWT claims to ensure data cannot be tampered with.
 
 use axum::{
+    body::Bytes,
     extract::State,
     http::{HeaderMap, StatusCode},
     response::{IntoResponse, Json},
 };
 use chrono::Utc;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::collections::HashSet;
-use tokio::sync::Mutex;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use tokio::sync::{Mutex, MutexGuard};
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
 use base64::{engine::general_purpose, Engine as _};
-use log::debug;
+use log::{debug, error};
 
 use crate::server::ServerState;
 use crate::gps::GpsCoordinate;
+use crate::gps_logger::GpsLoggerError;
 
 type HmacSha256 = Hmac<Sha256>;
 
@@ -55,9 +58,181 @@ struct JwtPayload {
     heading: Option<f64>,
 }
 
+impl JwtPayload {
+    /// Build a GPS coordinate from the claims, using `iat` as the fix time
+    fn to_coordinate(&self) -> GpsCoordinate {
+        let timestamp = chrono::DateTime::from_timestamp(self.iat.try_into().unwrap_or(0), 0).unwrap_or_else(|| Utc::now());
+        GpsCoordinate {
+            latitude: self.lat,
+            longitude: self.lon,
+            timestamp,
+            accuracy: self.accuracy,
+            altitude: self.altitude,
+            speed: self.speed,
+            heading: self.heading,
+            device_id: None, // Not included in simplified JWT
+            app_version: None, // Not included in simplified JWT
+            request_id: None, // Not included in simplified JWT
+        }
+    }
+}
+
+/// JWT payload carrying a whole track of fixes, e.g. everything a phone
+/// recorded while it couldn't reach the device. The registered claims
+/// (exp/iat/jti) are validated the same way as for a single fix.
+#[derive(Debug, Deserialize)]
+struct TrackJwtPayload {
+    track: Vec<TrackPoint>,
+}
+
+/// A single fix inside a track claim
+#[derive(Debug, Deserialize)]
+struct TrackPoint {
+    ts: u64, // unix timestamp the fix was taken at
+    lat: f64,
+    lon: f64,
+    #[serde(default)]
+    accuracy: Option<f64>,
+    #[serde(default)]
+    altitude: Option<f64>,
+    #[serde(default)]
+    speed: Option<f64>,
+    #[serde(default)]
+    heading: Option<f64>,
+}
+
+impl TrackPoint {
+    fn to_coordinate(&self) -> Option<GpsCoordinate> {
+        Some(GpsCoordinate {
+            latitude: self.lat,
+            longitude: self.lon,
+            timestamp: chrono::DateTime::from_timestamp(self.ts.try_into().ok()?, 0)?,
+            accuracy: self.accuracy,
+            altitude: self.altitude,
+            speed: self.speed,
+            heading: self.heading,
+            device_id: None,
+            app_version: None,
+            request_id: None,
+        })
+    }
+}
+
+/// Maximum number of fixes accepted in one batch request
+const MAX_BATCH_FIXES: usize = 10_000;
+
+/// File in the recordings directory keeping the newest `iat` of any used
+/// token, so replay protection survives a restart
+const JTI_HIGH_WATER_FILE_NAME: &str = "jwt_high_water";
+
 // JWT ID store for preventing replay attacks
 lazy_static::lazy_static! {
-    static ref JTI_STORE: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::<String>::new()));
+    static ref JTI_STORE: Mutex<JtiStore> = Mutex::new(JtiStore::default());
+}
+
+/// JWT IDs that have already been used. An ID is only kept until its token
+/// would be rejected as too old anyway, so the store doesn't grow without
+/// bound.
+#[derive(Default)]
+struct JtiStore {
+    /// Where the high-water `iat` is saved, `None` until it's been loaded
+    path: Option<PathBuf>,
+    used: HashSet<String>,
+    /// Used IDs by the unix time after which they can be forgotten
+    expiries: BTreeMap<u64, Vec<String>>,
+    /// Newest `iat` of any used token
+    high_water: u64,
+    saved_high_water: u64,
+    /// `high_water` when the daemon started. The IDs used before then are
+    /// gone, so any token issued up to it counts as used.
+    restart_high_water: u64,
+}
+
+impl JtiStore {
+    async fn load(path: PathBuf) -> Self {
+        let high_water = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents.trim().parse().unwrap_or_else(|e| {
+                error!(
+                    "discarding unreadable JWT high-water mark {}: {e}",
+                    path.display()
+                );
+                0
+            }),
+            Err(_) => 0,
+        };
+        Self {
+            path: Some(path),
+            high_water,
+            saved_high_water: high_water,
+            restart_high_water: high_water,
+            ..Default::default()
+        }
+    }
+
+    /// Mark the token's `jti` as used. Returns false if it already was.
+    fn record(
+        &mut self,
+        claims: &JwtRegisteredClaims,
+        policy: &JwtValidationPolicy,
+        now: u64,
+    ) -> bool {
+        while let Some((&expiry, _)) = self.expiries.first_key_value() {
+            if expiry >= now {
+                break;
+            }
+            for jti in self.expiries.remove(&expiry).unwrap_or_default() {
+                self.used.remove(&jti);
+            }
+        }
+        if claims.iat <= self.restart_high_water || !self.used.insert(claims.jti.clone()) {
+            return false;
+        }
+        let expiry = claims.iat.saturating_add(policy.max_token_age_secs);
+        self.expiries
+            .entry(expiry)
+            .or_default()
+            .push(claims.jti.clone());
+        self.high_water = self.high_water.max(claims.iat);
+        true
+    }
+
+    /// Mark IDs recorded by a request that failed as unused again
+    fn forget(&mut self, jtis: &[String]) {
+        for jti in jtis {
+            self.used.remove(jti);
+        }
+    }
+
+    /// Save the high-water `iat` if it has moved since it was last saved
+    async fn save(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if self.high_water == self.saved_high_water {
+            return;
+        }
+        // write to a temporary file first so a crash can't leave it truncated
+        let tmp_path = path.with_extension("tmp");
+        let result = async {
+            tokio::fs::write(&tmp_path, self.high_water.to_string()).await?;
+            tokio::fs::rename(&tmp_path, path).await
+        }
+        .await;
+        match result {
+            Ok(()) => self.saved_high_water = self.high_water,
+            Err(e) => error!("failed to save JWT high-water mark {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Lock the JWT ID store, loading its high-water mark on first use
+async fn lock_jti_store(config: &crate::config::Config) -> MutexGuard<'static, JtiStore> {
+    let mut jti_store = JTI_STORE.lock().await;
+    if jti_store.path.is_none() {
+        let path = PathBuf::from(&config.qmdl_store_path).join(JTI_HIGH_WATER_FILE_NAME);
+        *jti_store = JtiStore::load(path).await;
+    }
+    jti_store
 }
 
 /// GPS v2 API response
@@ -99,6 +274,38 @@ pub struct GpsV2Error {
     pub security_details: Option<String>,
 }
 
+/// Body of a batch GPS submission
+#[derive(Debug, Default, Deserialize)]
+pub struct GpsBatchRequest {
+    /// Individually signed fixes, each a compact JWT with the same claims as
+    /// `/api/v2/gps`
+    #[serde(default)]
+    pub fixes: Vec<String>,
+}
+
+/// GPS v2 batch API response
+#[derive(Debug, Serialize)]
+pub struct GpsBatchResponse {
+    pub status: String,
+    pub message: String,
+    pub received: usize,
+    pub written: usize,
+    pub duplicates: usize,
+    pub unmatched: usize,
+    pub encrypted: usize,
+    pub rejected: Vec<GpsBatchRejection>,
+    pub recordings: std::collections::BTreeMap<String, usize>,
+    pub processing_time_ms: u64,
+}
+
+/// A fix in a batch that failed validation
+#[derive(Debug, Serialize)]
+pub struct GpsBatchRejection {
+    pub index: usize,
+    pub error: String,
+    pub code: String,
+}
+
 /// Enhanced GPS v2 API endpoint handler with comprehensive JWT security
 /// 
 /// This endpoint provides:
@@ -211,10 +418,258 @@ pub async fn gps_api_v2(
     Ok((StatusCode::OK, Json(response)))
 }
 
+/// Batch GPS submission and offline backfill endpoint
+///
+/// Accepts fixes the phone buffered while it couldn't reach the device, in
+/// one of two forms:
+///
+/// - a JSON body `{"fixes": ["<jwt>", ...]}` where every fix is a JWT signed
+///   when it was taken, with the same claims as `/api/v2/gps`. Their `exp`
+///   isn't enforced, but `iat` must be within `gps.backfill_max_age_secs`.
+/// - no body and `Authorization: Bearer <JWT>` whose payload carries a track:
+/// {
+///   "exp": 1640995200, "iat": 1640995170, "jti": "unique_id",
+///   "track": [
+///     {"ts": 1640994000, "lat": 37.7749, "lon": -122.4194, "accuracy": 5.0},
+///     ...
+///   ]
+/// }
+///
+/// Fixes are written into the `.gps` file of the recording that was active at
+/// their timestamp, even if it has since ended. Fixes whose timestamp is
+/// already logged are skipped, so a batch can safely overlap with fixes that
+/// made it through `/api/v2/gps`. Invalid fixes are reported individually
+/// rather than failing the whole batch. Fixes for an encrypted recording whose
+/// GPS log was started before the daemon can't be checked for duplicates, so
+/// they're skipped and counted as `encrypted`, with a 207 status. A signed fix
+/// whose `jti` was already used, e.g. because the client retried the batch,
+/// counts as a duplicate.
+///
+/// POST /api/v2/gps/batch
+pub async fn gps_batch_api_v2(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let start_time = std::time::Instant::now();
+    let batch_error = |status: StatusCode, error: String, code: &str, security_details: Option<String>| {
+        (
+            status,
+            Json(GpsV2Error {
+                status: "error".to_string(),
+                error,
+                code: code.to_string(),
+                security_details,
+            }),
+        )
+    };
+
+    let request: GpsBatchRequest = if body.is_empty() {
+        GpsBatchRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| {
+            batch_error(StatusCode::BAD_REQUEST, format!("Invalid batch body: {}", e), "INVALID_BATCH_BODY", None)
+        })?
+    };
+
+    let secret_key = get_secret_key_from_file(&state.config).await
+        .map_err(|(status, error, details)| batch_error(status, error, "JWT_VALIDATION_FAILED", details))?;
+
+    let mut rejected = Vec::new();
+    let mut candidates = Vec::new();
+    let mut replayed = 0;
+    let mut recorded_jtis = Vec::new();
+    let received;
+    if !request.fixes.is_empty() {
+        received = request.fixes.len();
+        if received > MAX_BATCH_FIXES {
+            return Err(batch_error(StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Batch contains {} fixes, maximum is {}", received, MAX_BATCH_FIXES), "BATCH_TOO_LARGE", None));
+        }
+        let policy = JwtValidationPolicy::backfill(&state.config);
+        let current_time = unix_now()
+            .map_err(|(status, error, details)| batch_error(status, error, "INTERNAL_ERROR", details))?;
+        // a fix whose token was already used, e.g. because the client retried
+        // the batch, was already handled and counts as a duplicate
+        let mut jti_store = lock_jti_store(&state.config).await;
+        for (index, token) in request.fixes.iter().enumerate() {
+            match verify_jwt::<JwtPayload>(token, &secret_key, &policy) {
+                Ok((claims, payload)) => {
+                    if jti_store.record(&claims, &policy, current_time) {
+                        candidates.push((index, payload.to_coordinate()));
+                        recorded_jtis.push(claims.jti);
+                    } else {
+                        replayed += 1;
+                    }
+                }
+                Err((_, error, _)) => rejected.push(GpsBatchRejection {
+                    index,
+                    error,
+                    code: "JWT_VALIDATION_FAILED".to_string(),
+                }),
+            }
+        }
+    } else {
+        let token = extract_bearer_token(&headers)
+            .map_err(|(status, error, details)| batch_error(status, error, "JWT_VALIDATION_FAILED", details))?;
+        let payload: TrackJwtPayload = validate_jwt(token, &secret_key, &JwtValidationPolicy::LIVE, &state.config).await
+            .map_err(|(status, error, details)| batch_error(status, error, "JWT_VALIDATION_FAILED", details))?;
+        received = payload.track.len();
+        if received > MAX_BATCH_FIXES {
+            return Err(batch_error(StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Track contains {} fixes, maximum is {}", received, MAX_BATCH_FIXES), "BATCH_TOO_LARGE", None));
+        }
+        let current_time = unix_now()
+            .map_err(|(status, error, details)| batch_error(status, error, "INTERNAL_ERROR", details))?;
+        for (index, point) in payload.track.iter().enumerate() {
+            let rejection = if point.ts > current_time {
+                Some(("Fix timestamp is in the future".to_string(), "FIX_IN_FUTURE"))
+            } else if current_time - point.ts > state.config.gps.backfill_max_age_secs {
+                Some((format!("Fix is older than {} seconds", state.config.gps.backfill_max_age_secs), "FIX_TOO_OLD"))
+            } else {
+                None
+            };
+            match (rejection, point.to_coordinate()) {
+                (None, Some(coordinate)) => candidates.push((index, coordinate)),
+                (Some((error, code)), _) => rejected.push(GpsBatchRejection { index, error, code: code.to_string() }),
+                (None, None) => rejected.push(GpsBatchRejection {
+                    index,
+                    error: format!("Invalid fix timestamp: {}", point.ts),
+                    code: "INVALID_TIMESTAMP".to_string(),
+                }),
+            }
+        }
+    }
+
+    let mut fixes = Vec::with_capacity(candidates.len());
+    for (index, coordinate) in candidates {
+        if !is_valid_latitude(coordinate.latitude) {
+            rejected.push(GpsBatchRejection {
+                index,
+                error: format!("Invalid latitude: {}. Must be between -90.0 and 90.0", coordinate.latitude),
+                code: "INVALID_LATITUDE_CLAIM".to_string(),
+            });
+        } else if !is_valid_longitude(coordinate.longitude) {
+            rejected.push(GpsBatchRejection {
+                index,
+                error: format!("Invalid longitude: {}. Must be between -180.0 and 180.0", coordinate.longitude),
+                code: "INVALID_LONGITUDE_CLAIM".to_string(),
+            });
+        } else {
+            fixes.push(coordinate);
+        }
+    }
+    rejected.sort_by_key(|rejection| rejection.index);
+
+    let result = state.gps_logger.backfill_gps_coordinates(fixes).await;
+    // the fixes weren't handled if the batch failed, so they can be sent again
+    {
+        let mut jti_store = lock_jti_store(&state.config).await;
+        if result.is_ok() {
+            jti_store.save().await;
+        } else {
+            jti_store.forget(&recorded_jtis);
+        }
+    }
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(GpsLoggerError::LoggingDisabled) => {
+            return Err(batch_error(StatusCode::SERVICE_UNAVAILABLE, "GPS logging is disabled".to_string(), "GPS_LOGGING_DISABLED", None));
+        }
+        Err(e) => {
+            return Err(batch_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to write GPS fixes: {}", e), "GPS_LOG_WRITE_FAILED", None));
+        }
+    };
+
+    let processing_time = start_time.elapsed().as_millis() as u64;
+    let duplicates = summary.duplicates + replayed;
+    debug!("GPS v2 batch: received {}, wrote {}, {} duplicates, {} unmatched, {} encrypted, {} rejected, processing time: {}ms",
+        received, summary.written, duplicates, summary.unmatched, summary.encrypted, rejected.len(), processing_time);
+
+    // fixes that couldn't be checked against an encrypted log weren't
+    // written, and the client should keep them
+    let (status_code, status) = if summary.encrypted > 0 {
+        (StatusCode::MULTI_STATUS, "partial")
+    } else {
+        (StatusCode::OK, "success")
+    };
+    Ok((status_code, Json(GpsBatchResponse {
+        status: status.to_string(),
+        message: format!("{} of {} GPS fixes written", summary.written, received),
+        received,
+        written: summary.written,
+        duplicates,
+        unmatched: summary.unmatched,
+        encrypted: summary.encrypted,
+        rejected,
+        recordings: summary.recordings,
+        processing_time_ms: processing_time,
+    })))
+}
+
+/// Error returned by the JWT validation helpers: HTTP status, error message and security details
+type JwtValidationError = (StatusCode, String, Option<String>);
+
+/// Registered claims checked on every token, whatever GPS payload it carries
+#[derive(Debug, Deserialize)]
+struct JwtRegisteredClaims {
+    exp: u64,
+    iat: u64,
+    jti: String,
+}
+
+/// How strictly a token's lifetime is enforced
+struct JwtValidationPolicy {
+    /// Maximum number of seconds between `iat` and now
+    max_token_age_secs: u64,
+    /// Whether an `exp` in the past rejects the token
+    enforce_exp: bool,
+}
+
+impl JwtValidationPolicy {
+    /// Tokens submitted as soon as they are signed
+    const LIVE: Self = Self {
+        max_token_age_secs: 30,
+        enforce_exp: true,
+    };
+
+    /// Tokens signed when a fix was taken and buffered by the phone until it
+    /// could reach the device again. Their short `exp` has usually passed by
+    /// then, so only the configured backfill age limit applies.
+    fn backfill(config: &crate::config::Config) -> Self {
+        Self {
+            max_token_age_secs: config.gps.backfill_max_age_secs,
+            enforce_exp: false,
+        }
+    }
+}
+
 /// Extract and validate JWT from Authorization header with comprehensive security
 /// This function ensures INTEGRITY OF CLAIMS - all GPS data comes from JWT
-async fn extract_and_validate_jwt(headers: &HeaderMap, config: &crate::config::Config) -> Result<GpsCoordinate, (StatusCode, String, Option<String>)> {
+async fn extract_and_validate_jwt(headers: &HeaderMap, config: &crate::config::Config) -> Result<GpsCoordinate, JwtValidationError> {
     // 1. Extract Authorization header
+    let token = extract_bearer_token(headers)?;
+
+    // 2. Get secret key from file
+    let secret_key = get_secret_key_from_file(config).await?;
+
+    // 3. Verify the token and its registered claims
+    let policy = JwtValidationPolicy::LIVE;
+    let payload: JwtPayload = validate_jwt(token, &secret_key, &policy, config).await?;
+
+    // 4. Create GPS coordinate from JWT claims (INTEGRITY OF CLAIMS)
+    // All GPS data comes from the JWT payload, ensuring it cannot be tampered with
+    let gps_coordinate = payload.to_coordinate();
+
+    // Log successful JWT validation with security details
+    debug!("JWT validation successful: claims integrity verified, replay protection active, token lifetime: {} seconds, JWT ID: {}", 
+        policy.max_token_age_secs, payload.jti);
+
+    Ok(gps_coordinate)
+}
+
+/// Extract the Bearer token from the Authorization header
+fn extract_bearer_token(headers: &HeaderMap) -> Result<&str, JwtValidationError> {
     let auth_header = headers
         .get("Authorization")
         .ok_or((StatusCode::UNAUTHORIZED, "No Authorization header provided".to_string(), Some("Missing Authorization header".to_string())))?;
@@ -223,13 +678,43 @@ async fn extract_and_validate_jwt(headers: &HeaderMap, config: &crate::config::C
         .to_str()
         .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid Authorization header".to_string(), Some("Invalid Authorization header format".to_string())))?;
 
-    if !auth_str.starts_with("Bearer ") {
-        return Err((StatusCode::UNAUTHORIZED, "Invalid Authorization format, expected Bearer token".to_string(), Some("Invalid Bearer token format".to_string())));
+    auth_str
+        .strip_prefix("Bearer ")
+        .ok_or((StatusCode::UNAUTHORIZED, "Invalid Authorization format, expected Bearer token".to_string(), Some("Invalid Bearer token format".to_string())))
+}
+
+/// Verify a compact HS256 JWT and decode its payload, recording its `jti`
+/// so it can't be used again.
+async fn validate_jwt<P: DeserializeOwned>(
+    token: &str,
+    secret_key: &[u8],
+    policy: &JwtValidationPolicy,
+    config: &crate::config::Config,
+) -> Result<P, JwtValidationError> {
+    let (claims, payload) = verify_jwt(token, secret_key, policy)?;
+
+    // Prevent replay attacks using JWT ID (jti). Checking and storing under
+    // the same lock keeps two concurrent requests from both using it.
+    let mut jti_store = lock_jti_store(config).await;
+    if !jti_store.record(&claims, policy, unix_now()?) {
+        return Err((StatusCode::UNAUTHORIZED, "JWT token already used (replay attack detected)".to_string(), Some("JWT replay protection activated".to_string())));
     }
+    jti_store.save().await;
 
-    let token = &auth_str[7..]; // Skip "Bearer "
+    Ok(payload)
+}
 
-    // 2. Split token into parts
+/// Verify a compact HS256 JWT and decode its payload.
+///
+/// Checks the signature and the registered claims (`exp`, `iat`, `jti`)
+/// according to `policy`. Whether the `jti` was already used is up to the
+/// caller.
+fn verify_jwt<P: DeserializeOwned>(
+    token: &str,
+    secret_key: &[u8],
+    policy: &JwtValidationPolicy,
+) -> Result<(JwtRegisteredClaims, P), JwtValidationError> {
+    // 1. Split token into parts
     let parts: Vec<&str> = token.split('.').collect();
     
     if parts.len() != 3 {
@@ -240,7 +725,7 @@ async fn extract_and_validate_jwt(headers: &HeaderMap, config: &crate::config::C
     let base64_payload = parts[1];
     let base64_signature = parts[2];
 
-    // 3. Decode header and payload
+    // 2. Decode header and payload
     let header_bytes = base64url_decode(base64_header)
         .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Failed to decode JWT header: {}", e), Some("JWT header decoding failed".to_string())))?;
 
@@ -250,24 +735,24 @@ async fn extract_and_validate_jwt(headers: &HeaderMap, config: &crate::config::C
     let signature = base64url_decode(base64_signature)
         .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Failed to decode JWT signature: {}", e), Some("JWT signature decoding failed".to_string())))?;
 
-    // 4. Parse header and payload
+    // 3. Parse header and payload
     let header: JwtHeader = serde_json::from_slice(&header_bytes)
         .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid JWT header format".to_string(), Some("JWT header parsing failed".to_string())))?;
 
-    let payload: JwtPayload = serde_json::from_slice(&payload_bytes)
+    let claims: JwtRegisteredClaims = serde_json::from_slice(&payload_bytes)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid JWT payload format".to_string(), Some("JWT payload parsing failed".to_string())))?;
+
+    let payload: P = serde_json::from_slice(&payload_bytes)
         .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid JWT payload format".to_string(), Some("JWT payload parsing failed".to_string())))?;
 
-    // 5. Verify algorithm
+    // 4. Verify algorithm
     if header.alg != "HS256" || header.typ != "JWT" {
         return Err((StatusCode::UNAUTHORIZED, "Unsupported JWT algorithm - only HS256 supported".to_string(), Some("JWT algorithm validation failed".to_string())));
     }
 
-    // 6. Get secret key from file
-    let secret_key = get_secret_key_from_file(config).await?;
-
-    // 7. Verify signature to ensure INTEGRITY OF CLAIMS
+    // 5. Verify signature to ensure INTEGRITY OF CLAIMS
     let message = format!("{}.{}", base64_header, base64_payload);
-    let mut mac = HmacSha256::new_from_slice(&secret_key)
+    let mut mac = HmacSha256::new_from_slice(secret_key)
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to initialize HMAC".to_string(), Some("HMAC initialization failed".to_string())))?;
     
     mac.update(message.as_bytes());
@@ -278,73 +763,39 @@ async fn extract_and_validate_jwt(headers: &HeaderMap, config: &crate::config::C
         return Err((StatusCode::UNAUTHORIZED, "JWT signature verification failed".to_string(), Some("JWT claims integrity compromised - signature verification failed".to_string())));
     }
 
-    // 8. Verify expiration with configurable lifespan (default 30 seconds)
-    let current_time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "System time error".to_string(), Some("System time validation failed".to_string())))?
-        .as_secs();
+    // 6. Verify expiration and token age according to the policy
+    let current_time = unix_now()?;
 
     // Check if token is expired
-    if payload.exp < current_time {
+    if policy.enforce_exp && claims.exp < current_time {
         return Err((StatusCode::UNAUTHORIZED, "JWT token expired".to_string(), Some("JWT token lifetime exceeded".to_string())));
     }
 
-    // Enforce maximum token lifespan (configurable, default 30 seconds)
-    let max_token_lifespan = 30; // Default 30 seconds
-    let token_age = current_time.saturating_sub(payload.iat);
+    let token_age = current_time.saturating_sub(claims.iat);
     
-    if token_age > max_token_lifespan {
-        return Err((StatusCode::UNAUTHORIZED, format!("JWT token lifespan exceeds maximum allowed ({} seconds)", max_token_lifespan), Some("JWT token lifetime configuration exceeded".to_string())));
+    if token_age > policy.max_token_age_secs {
+        return Err((StatusCode::UNAUTHORIZED, format!("JWT token lifespan exceeds maximum allowed ({} seconds)", policy.max_token_age_secs), Some("JWT token lifetime configuration exceeded".to_string())));
     }
 
-    // 9. Verify issued at time (iat claim)
-    if payload.iat > current_time {
+    // 7. Verify issued at time (iat claim)
+    if claims.iat > current_time {
         return Err((StatusCode::UNAUTHORIZED, "JWT token issued in the future".to_string(), Some("JWT timestamp validation failed".to_string())));
     }
 
-    // 10. Validate JWT ID (jti) - must be a valid format
-    if !is_valid_jwt_id(&payload.jti) {
-        return Err((StatusCode::UNAUTHORIZED, format!("Invalid JWT ID: {}", payload.jti), Some("JWT ID validation failed".to_string())));
-    }
-
-    // 11. Prevent replay attacks using JWT ID (jti)
-    let is_jti_used = {
-        let jti_store = JTI_STORE.lock().await;
-        jti_store.contains(&payload.jti)
-    };
-
-    if is_jti_used {
-        return Err((StatusCode::UNAUTHORIZED, "JWT token already used (replay attack detected)".to_string(), Some("JWT replay protection activated".to_string())));
-    }
-
-    // Store the JWT ID to prevent reuse
-    {
-        let mut jti_store = JTI_STORE.lock().await;
-        jti_store.insert(payload.jti.clone());
+    // 8. Validate JWT ID (jti) - must be a valid format
+    if !is_valid_jwt_id(&claims.jti) {
+        return Err((StatusCode::UNAUTHORIZED, format!("Invalid JWT ID: {}", claims.jti), Some("JWT ID validation failed".to_string())));
     }
 
-    // 12. Create GPS coordinate from JWT claims (INTEGRITY OF CLAIMS)
-    // All GPS data comes from the JWT payload, ensuring it cannot be tampered with
-    let timestamp = chrono::DateTime::from_timestamp(payload.iat.try_into().unwrap_or(0), 0).unwrap_or_else(|| Utc::now()); // Use iat as timestamp
-
-    let gps_coordinate = GpsCoordinate {
-        latitude: payload.lat,
-        longitude: payload.lon,
-        timestamp,
-        accuracy: payload.accuracy,
-        altitude: payload.altitude,
-        speed: payload.speed,
-        heading: payload.heading,
-        device_id: None, // Not included in simplified JWT
-        app_version: None, // Not included in simplified JWT
-        request_id: None, // Not included in simplified JWT
-    };
-
-    // Log successful JWT validation with security details
-    debug!("JWT validation successful: claims integrity verified, replay protection active, token lifetime: {} seconds, JWT ID: {}", 
-        max_token_lifespan, payload.jti);
+    Ok((claims, payload))
+}
 
-    Ok(gps_coordinate)
+/// Current unix time in seconds
+fn unix_now() -> Result<u64, JwtValidationError> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "System time error".to_string(), Some("System time validation failed".to_string())))?
+        .as_secs())
 }
 
 /// Decode base64url to bytes
@@ -414,3 +865,51 @@ fn is_valid_longitude(lon: f64) -> bool {
 fn is_valid_jwt_id(jti: &str) -> bool {
     !jti.is_empty() && jti.len() <= 128 && jti.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn claims(jti: &str, iat: u64) -> JwtRegisteredClaims {
+        JwtRegisteredClaims {
+            exp: iat + 30,
+            iat,
+            jti: jti.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_jti_store_rejects_reuse_until_expired() {
+        let mut store = JtiStore::default();
+        let policy = JwtValidationPolicy::LIVE;
+        assert!(store.record(&claims("a", 1000), &policy, 1000));
+        assert!(!store.record(&claims("a", 1000), &policy, 1010));
+        assert!(store.record(&claims("b", 1040), &policy, 1040));
+        // "a" is too old to be accepted by now, so it's been forgotten
+        assert!(!store.used.contains("a"));
+        assert_eq!(store.expiries.len(), 1);
+
+        store.forget(&["b".to_string()]);
+        assert!(store.record(&claims("b", 1040), &policy, 1041));
+    }
+
+    #[tokio::test]
+    async fn test_jti_store_high_water_survives_restart() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(JTI_HIGH_WATER_FILE_NAME);
+        let policy = JwtValidationPolicy {
+            max_token_age_secs: 3600,
+            enforce_exp: false,
+        };
+        let mut store = JtiStore::load(path.clone()).await;
+        assert!(store.record(&claims("a", 1000), &policy, 1100));
+        assert!(store.record(&claims("b", 1050), &policy, 1100));
+        store.save().await;
+
+        let mut store = JtiStore::load(path).await;
+        assert!(!store.record(&claims("a", 1000), &policy, 1200));
+        assert!(!store.record(&claims("c", 1050), &policy, 1200));
+        assert!(store.record(&claims("d", 1051), &policy, 1200));
+    }
+}
@@ -0,0 +1,404 @@
+//! Importing QMDL and GSMTAP pcapng files made by other devices or tools, so
+//! they can be analyzed and browsed like recordings made here.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::Json;
+use axum::body::Body;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Local};
+use futures::TryStreamExt;
+use log::{info, warn};
+use rayhunter::diag::Message;
+use rayhunter::encryption::ENCRYPTION_MAGIC;
+use rayhunter::pcap::{GsmtapPcapError, PcapConversion, gsmtap_pcapng_to_qmdl};
+use rayhunter::qmdl::{COMPRESSED_QMDL_MAGIC, QmdlCompression, QmdlReader, QmdlWriter};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::analysis::{AnalysisCtrlMessage, analyze_entry, queue_qmdl};
+use crate::integrity::{ChecksumWriter, ChunkChecksums};
+use crate::qmdl_store::{ImportedRecording, RecordingStoreError};
+use crate::server::ServerState;
+
+/// Largest file that can be imported
+pub const MAX_IMPORT_SIZE: usize = 512 * 1024 * 1024;
+
+const MAX_SOURCE_NAME_LEN: usize = 256;
+
+const PCAPNG_MAGIC: &[u8; 4] = b"\x0a\x0d\x0d\x0a";
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("Couldn't receive file: {0}")]
+    ReceiveError(axum::Error),
+    #[error("File is larger than {MAX_IMPORT_SIZE} bytes")]
+    TooLarge,
+    #[error("File is empty")]
+    Empty,
+    #[error("File is encrypted, decrypt it with rayhunter-check before importing it")]
+    Encrypted,
+    #[error("Couldn't convert pcapng file: {0}")]
+    PcapError(GsmtapPcapError),
+    #[error("Couldn't read QMDL file: {0}")]
+    QmdlError(std::io::Error),
+    #[error("File doesn't contain any diag log messages")]
+    NoMessages,
+    #[error("Couldn't write file: {0}")]
+    WriteError(std::io::Error),
+    #[error("Couldn't add recording: {0}")]
+    StoreError(RecordingStoreError),
+}
+
+impl ImportError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ImportError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ImportError::ReceiveError(_)
+            | ImportError::Empty
+            | ImportError::Encrypted
+            | ImportError::PcapError(_)
+            | ImportError::QmdlError(_)
+            | ImportError::NoMessages => StatusCode::BAD_REQUEST,
+            ImportError::WriteError(_) | ImportError::StoreError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImportFormat {
+    Qmdl(QmdlCompression),
+    Pcapng,
+}
+
+fn detect_format(header: &[u8]) -> Result<ImportFormat, ImportError> {
+    if header.starts_with(ENCRYPTION_MAGIC) {
+        Err(ImportError::Encrypted)
+    } else if header.starts_with(PCAPNG_MAGIC) {
+        Ok(ImportFormat::Pcapng)
+    } else if header.starts_with(COMPRESSED_QMDL_MAGIC) {
+        Ok(ImportFormat::Qmdl(QmdlCompression::Lz4))
+    } else {
+        Ok(ImportFormat::Qmdl(QmdlCompression::None))
+    }
+}
+
+/// A received file, ready to be added to the store
+#[derive(Debug)]
+pub struct StagedImport {
+    pub qmdl_path: PathBuf,
+    pub recording: ImportedRecording,
+    /// What was made of a pcapng file, or None if a QMDL file was imported
+    pub conversion: Option<PcapConversion>,
+}
+
+/// Writes the request body to `path`, returning its checksums
+async fn receive_file(body: Body, path: &Path) -> Result<ChunkChecksums, ImportError> {
+    let mut file = File::create(path).await.map_err(ImportError::WriteError)?;
+    let mut checksums = ChunkChecksums::default();
+    let mut size = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(data) = stream.try_next().await.map_err(ImportError::ReceiveError)? {
+        size += data.len();
+        if size > MAX_IMPORT_SIZE {
+            return Err(ImportError::TooLarge);
+        }
+        checksums.update(&data);
+        file.write_all(&data)
+            .await
+            .map_err(ImportError::WriteError)?;
+    }
+    file.flush().await.map_err(ImportError::WriteError)?;
+    if size == 0 {
+        return Err(ImportError::Empty);
+    }
+    Ok(checksums)
+}
+
+/// Returns the times of the first and last diag log messages in a QMDL file
+pub async fn qmdl_time_range(
+    qmdl_path: &Path,
+) -> Result<(DateTime<Local>, DateTime<Local>), ImportError> {
+    let file = File::open(qmdl_path)
+        .await
+        .map_err(ImportError::QmdlError)?;
+    let size = file.metadata().await.map_err(ImportError::QmdlError)?.len();
+    let mut reader = QmdlReader::new(file, Some(size as usize));
+    let mut range: Option<(DateTime<Local>, DateTime<Local>)> = None;
+    while let Some(container) = reader
+        .get_next_messages_container()
+        .await
+        .map_err(ImportError::QmdlError)?
+    {
+        for message in container.into_messages().into_iter().flatten() {
+            if let Message::Log { timestamp, .. } = message {
+                let time = timestamp.to_datetime().with_timezone(&Local);
+                range = Some(match range {
+                    Some((first, last)) => (first.min(time), last.max(time)),
+                    None => (time, time),
+                });
+            }
+        }
+    }
+    range.ok_or(ImportError::NoMessages)
+}
+
+/// Receives a QMDL or GSMTAP pcapng file into `staging_path`, converting it to
+/// QMDL if needed. pcapng files are converted using `compression`, while QMDL
+/// files are kept as they are.
+pub async fn stage_import(
+    body: Body,
+    source: String,
+    staging_path: &Path,
+    compression: QmdlCompression,
+) -> Result<StagedImport, ImportError> {
+    let received_path = staging_path.with_extension("received");
+    let result = stage_received_file(body, source, &received_path, staging_path, compression).await;
+    // the received file is either renamed or converted, so it's left over
+    // either way. if staging failed, so is anything that was converted.
+    let _ = fs::remove_file(&received_path).await;
+    if result.is_err() {
+        let _ = fs::remove_file(staging_path).await;
+    }
+    result
+}
+
+async fn stage_received_file(
+    body: Body,
+    source: String,
+    received_path: &Path,
+    staging_path: &Path,
+    compression: QmdlCompression,
+) -> Result<StagedImport, ImportError> {
+    let mut checksums = receive_file(body, received_path).await?;
+    let mut header = Vec::new();
+    File::open(received_path)
+        .await
+        .map_err(ImportError::WriteError)?
+        .take(COMPRESSED_QMDL_MAGIC.len() as u64)
+        .read_to_end(&mut header)
+        .await
+        .map_err(ImportError::WriteError)?;
+
+    let (compression, conversion) = match detect_format(&header)? {
+        ImportFormat::Qmdl(compression) => {
+            fs::rename(received_path, staging_path)
+                .await
+                .map_err(ImportError::WriteError)?;
+            (compression, None)
+        }
+        ImportFormat::Pcapng => {
+            let pcap_file = File::open(received_path)
+                .await
+                .map_err(ImportError::WriteError)?;
+            let qmdl_file = File::create(staging_path)
+                .await
+                .map_err(ImportError::WriteError)?;
+            let mut writer =
+                QmdlWriter::new(ChecksumWriter::new(qmdl_file)).with_compression(compression);
+            let conversion = gsmtap_pcapng_to_qmdl(pcap_file, &mut writer)
+                .await
+                .map_err(ImportError::PcapError)?;
+            writer.flush().await.map_err(ImportError::WriteError)?;
+            checksums = writer.get_ref().checksums().clone();
+            (compression, Some(conversion))
+        }
+    };
+
+    let (start_time, last_message_time) = qmdl_time_range(staging_path).await?;
+    Ok(StagedImport {
+        qmdl_path: staging_path.to_path_buf(),
+        recording: ImportedRecording {
+            start_time,
+            last_message_time,
+            compression,
+            source,
+            chunk_crcs: checksums.all(),
+        },
+        conversion,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportResponse {
+    pub name: String,
+    pub start_time: DateTime<Local>,
+    pub last_message_time: DateTime<Local>,
+    /// For pcapng files, how many packets were converted
+    pub converted_packets: Option<usize>,
+    /// For pcapng files, how many packets were left out
+    pub skipped_packets: Option<usize>,
+}
+
+pub async fn import_recording(
+    State(state): State<Arc<ServerState>>,
+    UrlPath(file_name): UrlPath<String>,
+    body: Body,
+) -> Result<(StatusCode, Json<ImportResponse>), (StatusCode, String)> {
+    let source: String = file_name.chars().take(MAX_SOURCE_NAME_LEN).collect();
+    let (staging_path, compression) = {
+        let store = state.qmdl_store_lock.read().await;
+        let staging_name = format!(".import-{}.tmp", hex::encode(rand::random::<[u8; 8]>()));
+        (store.path.join(staging_name), store.qmdl_compression)
+    };
+    let staged = stage_import(body, source.clone(), &staging_path, compression)
+        .await
+        .map_err(|e| (e.status_code(), e.to_string()))?;
+
+    let (name, start_time, last_message_time, encryption_key) = {
+        let mut store = state.qmdl_store_lock.write().await;
+        let result = store
+            .import_entry(&staged.qmdl_path, staged.recording)
+            .await;
+        let entry_index = match result {
+            Ok(entry_index) => entry_index,
+            Err(e) => {
+                let _ = fs::remove_file(&staged.qmdl_path).await;
+                let e = ImportError::StoreError(e);
+                return Err((e.status_code(), e.to_string()));
+            }
+        };
+        let entry = &store.manifest.entries[entry_index];
+        (
+            entry.name.clone(),
+            entry.start_time,
+            entry.last_message_time.unwrap_or(entry.start_time),
+            store.entry_encryption_key(entry_index).ok().flatten(),
+        )
+    };
+    info!("imported {source} as recording {name}");
+
+    // only the encrypted copy was stored, so it's analyzed now, while the
+    // plain text is still around
+    if let Some(encryption_key) = encryption_key {
+        let result = match File::open(&staged.qmdl_path).await {
+            Ok(qmdl_file) => {
+                analyze_entry(
+                    &name,
+                    qmdl_file,
+                    Some(&encryption_key),
+                    state.qmdl_store_lock.clone(),
+                    &state.config.analyzers,
+                    &state.gps_logger,
+                    state.baseline.as_ref(),
+                )
+                .await
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        let _ = fs::remove_file(&staged.qmdl_path).await;
+        match result {
+            Ok(()) => {
+                let _ = state
+                    .analysis_sender
+                    .send(AnalysisCtrlMessage::RecordingFinished(name.clone()))
+                    .await;
+            }
+            // the recording was still imported, but can't be analyzed on the
+            // device anymore
+            Err(e) => warn!("failed to analyze imported recording {name}: {e}"),
+        }
+    } else if queue_qmdl(&name, &mut state.analysis_status_lock.write().await) {
+        if let Err(e) = state
+            .analysis_sender
+            .send(AnalysisCtrlMessage::NewFilesQueued)
+            .await
+        {
+            // the recording was still imported, and can be analyzed later
+            warn!("failed to queue imported recording {name} for analysis: {e:?}");
+        }
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ImportResponse {
+            name,
+            start_time,
+            last_message_time,
+            converted_packets: staged.conversion.as_ref().map(|c| c.converted),
+            skipped_packets: staged.conversion.as_ref().map(|c| c.skipped),
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rayhunter::diag::{DataType, LogBody, MessagesContainer, Nas4GMessageDirection, Timestamp};
+    use tempfile::TempDir;
+
+    fn nas_message(time: DateTime<Local>) -> Message {
+        Message::new_log(
+            0xb0ec,
+            Timestamp::from_datetime(&time),
+            LogBody::Nas4GMessage {
+                direction: Nas4GMessageDirection::Downlink,
+                ext_header_version: 1,
+                rrc_rel: 0,
+                rrc_version_minor: 0,
+                rrc_version_major: 0,
+                msg: vec![0x07, 0x42],
+            },
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stage_qmdl_import() {
+        let dir = TempDir::new().unwrap();
+        let first = Local.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let last = Local.with_ymd_and_hms(2024, 3, 1, 12, 5, 30).unwrap();
+        let container = MessagesContainer::from_messages(
+            DataType::UserSpace,
+            &[nas_message(first), nas_message(last)],
+        )
+        .unwrap();
+        let mut writer = QmdlWriter::new(Vec::new());
+        writer.write_container(&container).await.unwrap();
+        let qmdl = writer.get_ref().clone();
+
+        let staging_path = dir.path().join(".import.tmp");
+        let staged = stage_import(
+            Body::from(qmdl.clone()),
+            "other.qmdl".to_string(),
+            &staging_path,
+            QmdlCompression::None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(staged.recording.start_time, first);
+        assert_eq!(staged.recording.last_message_time, last);
+        assert_eq!(staged.recording.compression, QmdlCompression::None);
+        assert!(staged.conversion.is_none());
+        assert_eq!(fs::read(&staging_path).await.unwrap(), qmdl);
+        let mut checksums = ChunkChecksums::default();
+        checksums.update(&qmdl);
+        assert_eq!(staged.recording.chunk_crcs, checksums.all());
+
+        let encrypted = [ENCRYPTION_MAGIC.as_slice(), &qmdl].concat();
+        let staging_path = dir.path().join(".import2.tmp");
+        assert!(matches!(
+            stage_import(
+                Body::from(encrypted),
+                "other.qmdl".to_string(),
+                &staging_path,
+                QmdlCompression::None,
+            )
+            .await,
+            Err(ImportError::Encrypted)
+        ));
+        assert!(!fs::try_exists(&staging_path).await.unwrap());
+        assert!(
+            !fs::try_exists(staging_path.with_extension("received"))
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! Integrity checks for recordings. While recording, a CRC32 is kept of every
+//! [`CHECKSUM_CHUNK_SIZE`] chunk of the QMDL file. These are saved in the
+//! recording's sidecar metadata file, so a store can later be checked for
+//! truncated or corrupt recordings.
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use crc::{CRC_32_ISO_HDLC, Crc, Digest};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWrite};
+
+use crate::qmdl_store::ManifestEntry;
+use crate::server::ServerState;
+
+/// Size of the QMDL byte ranges that are checksummed
+pub const CHECKSUM_CHUNK_SIZE: usize = 1024 * 1024;
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Running CRC32s of consecutive chunks of a file
+#[derive(Default, Clone)]
+pub struct ChunkChecksums {
+    complete: Vec<u32>,
+    partial: Option<Digest<'static, u32>>,
+    partial_len: usize,
+}
+
+impl ChunkChecksums {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let len = bytes.len().min(CHECKSUM_CHUNK_SIZE - self.partial_len);
+            self.partial
+                .get_or_insert_with(|| CRC32.digest())
+                .update(&bytes[..len]);
+            self.partial_len += len;
+            bytes = &bytes[len..];
+            if self.partial_len == CHECKSUM_CHUNK_SIZE {
+                let digest = self.partial.take().unwrap();
+                self.complete.push(digest.finalize());
+                self.partial_len = 0;
+            }
+        }
+    }
+
+    /// Checksums of the chunks that are complete
+    pub fn complete(&self) -> &[u32] {
+        &self.complete
+    }
+
+    /// Checksums of every chunk, including the last, incomplete one
+    pub fn all(&self) -> Vec<u32> {
+        let mut checksums = self.complete.clone();
+        if let Some(partial) = &self.partial {
+            checksums.push(partial.clone().finalize());
+        }
+        checksums
+    }
+}
+
+/// Wraps a writer, checksumming everything that's written to it
+pub struct ChecksumWriter<W> {
+    inner: W,
+    checksums: ChunkChecksums,
+    bytes_written: usize,
+}
+
+impl<W> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            checksums: ChunkChecksums::default(),
+            bytes_written: 0,
+        }
+    }
+
+    pub fn checksums(&self) -> &ChunkChecksums {
+        &self.checksums
+    }
+
+    /// Number of bytes the inner writer has accepted
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ChecksumWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.checksums.update(&buf[..written]);
+            this.bytes_written += written;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The contents of a recording's `.meta.toml` sidecar file. This duplicates
+/// its manifest entry, so the entry can be recovered if the manifest is lost.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct EntryMetadata {
+    /// CRC32 of each [`CHECKSUM_CHUNK_SIZE`] chunk of the QMDL file. The last
+    /// chunk may be shorter.
+    #[serde(default)]
+    pub chunk_crcs: Vec<u32>,
+    pub entry: ManifestEntry,
+}
+
+impl EntryMetadata {
+    pub async fn read(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("couldn't read {}: {e}", path.display()))?;
+        toml::from_str(&contents).map_err(|e| format!("couldn't parse {}: {e}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EntryIntegrity {
+    /// Every checksummed chunk matched
+    Ok { checked_bytes: usize },
+    /// There are no checksums to compare against, for example because the
+    /// recording predates them
+    Unverified,
+    Missing,
+    Truncated {
+        expected_bytes: usize,
+        actual_bytes: usize,
+    },
+    Corrupt { offset: usize },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryVerification {
+    pub name: String,
+    #[serde(flatten)]
+    pub integrity: EntryIntegrity,
+}
+
+/// Checks the QMDL file of an entry in the store at `path` against its size
+/// in the manifest and the checksums in its sidecar file
+pub async fn verify_entry(path: &Path, entry: &ManifestEntry) -> std::io::Result<EntryIntegrity> {
+    let mut qmdl_file = match File::open(entry.get_qmdl_filepath(path)).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(EntryIntegrity::Missing),
+        Err(e) => return Err(e),
+    };
+    let actual_bytes = qmdl_file.metadata().await?.len() as usize;
+    if actual_bytes < entry.qmdl_size_bytes {
+        return Ok(EntryIntegrity::Truncated {
+            expected_bytes: entry.qmdl_size_bytes,
+            actual_bytes,
+        });
+    }
+
+    let metadata = match EntryMetadata::read(&entry.get_metadata_filepath(path)).await {
+        Ok(metadata) if !metadata.chunk_crcs.is_empty() => metadata,
+        _ => return Ok(EntryIntegrity::Unverified),
+    };
+    // the checksums were saved along with the size of the file at the time.
+    // While recording, only complete chunks are checksummed; once finished,
+    // the last checksum covers whatever is left.
+    let covered_bytes = metadata
+        .entry
+        .qmdl_size_bytes
+        .min(metadata.chunk_crcs.len() * CHECKSUM_CHUNK_SIZE);
+    if actual_bytes < covered_bytes {
+        return Ok(EntryIntegrity::Truncated {
+            expected_bytes: covered_bytes,
+            actual_bytes,
+        });
+    }
+
+    let mut checked_bytes = 0;
+    let mut chunk = vec![0; CHECKSUM_CHUNK_SIZE];
+    for expected_crc in metadata.chunk_crcs {
+        let len = CHECKSUM_CHUNK_SIZE.min(covered_bytes - checked_bytes);
+        if len == 0 {
+            break;
+        }
+        qmdl_file.read_exact(&mut chunk[..len]).await?;
+        if CRC32.checksum(&chunk[..len]) != expected_crc {
+            return Ok(EntryIntegrity::Corrupt {
+                offset: checked_bytes,
+            });
+        }
+        checked_bytes += len;
+    }
+    Ok(EntryIntegrity::Ok { checked_bytes })
+}
+
+async fn verify_entries(
+    state: &ServerState,
+    name: Option<&str>,
+) -> Result<Vec<EntryVerification>, (StatusCode, String)> {
+    // copy what's needed so recording isn't blocked while files are read
+    let (path, entries) = {
+        let qmdl_store = state.qmdl_store_lock.read().await;
+        let entries: Vec<ManifestEntry> = qmdl_store
+            .manifest
+            .entries
+            .iter()
+            .filter(|entry| name.is_none_or(|name| entry.name == name))
+            .cloned()
+            .collect();
+        (qmdl_store.path.clone(), entries)
+    };
+    if let Some(name) = name {
+        if entries.is_empty() {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("couldn't find entry with name {name}"),
+            ));
+        }
+    }
+
+    let mut results = Vec::new();
+    for entry in entries {
+        let integrity = verify_entry(&path, &entry).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("error verifying {}: {e}", entry.name),
+            )
+        })?;
+        results.push(EntryVerification {
+            name: entry.name,
+            integrity,
+        });
+    }
+    Ok(results)
+}
+
+pub async fn verify_recordings(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<Vec<EntryVerification>>, (StatusCode, String)> {
+    verify_entries(&state, None).await.map(Json)
+}
+
+pub async fn verify_recording(
+    State(state): State<Arc<ServerState>>,
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<EntryVerification>, (StatusCode, String)> {
+    let mut results = verify_entries(&state, Some(&name)).await?;
+    Ok(Json(results.remove(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmdl_store::RecordingStore;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_chunk_checksums() {
+        let data: Vec<u8> = (0..CHECKSUM_CHUNK_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut checksums = ChunkChecksums::default();
+        // feed it in pieces that don't line up with chunk boundaries
+        for piece in data.chunks(4093) {
+            checksums.update(piece);
+        }
+        let expected: Vec<u32> = data
+            .chunks(CHECKSUM_CHUNK_SIZE)
+            .map(|chunk| CRC32.checksum(chunk))
+            .collect();
+        assert_eq!(checksums.complete(), &expected[..2]);
+        assert_eq!(checksums.all(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_verify_entry() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let (qmdl_file, _) = store.new_entry().await.unwrap();
+        let index = store.current_entry.unwrap();
+
+        let mut writer = ChecksumWriter::new(qmdl_file);
+        let data = vec![0x7e; CHECKSUM_CHUNK_SIZE + 10];
+        writer.write_all(&data).await.unwrap();
+        writer.flush().await.unwrap();
+        store.update_entry_qmdl_size(index, data.len()).await.unwrap();
+        store
+            .update_entry_checksums(index, writer.checksums().complete())
+            .await
+            .unwrap();
+        let entry = store.manifest.entries[index].clone();
+        // only the first chunk is checksummed until the recording is finished
+        assert_eq!(
+            verify_entry(dir.path(), &entry).await.unwrap(),
+            EntryIntegrity::Ok {
+                checked_bytes: CHECKSUM_CHUNK_SIZE
+            }
+        );
+
+        store
+            .update_entry_checksums(index, &writer.checksums().all())
+            .await
+            .unwrap();
+        store.close_current_entry().await.unwrap();
+        assert_eq!(
+            verify_entry(dir.path(), &entry).await.unwrap(),
+            EntryIntegrity::Ok {
+                checked_bytes: data.len()
+            }
+        );
+
+        // flip a byte in the second chunk
+        let qmdl_path = entry.get_qmdl_filepath(dir.path());
+        let mut corrupted = data.clone();
+        corrupted[CHECKSUM_CHUNK_SIZE + 1] = 0;
+        fs::write(&qmdl_path, &corrupted).await.unwrap();
+        assert_eq!(
+            verify_entry(dir.path(), &entry).await.unwrap(),
+            EntryIntegrity::Corrupt {
+                offset: CHECKSUM_CHUNK_SIZE
+            }
+        );
+
+        fs::write(&qmdl_path, &data[..100]).await.unwrap();
+        assert_eq!(
+            verify_entry(dir.path(), &entry).await.unwrap(),
+            EntryIntegrity::Truncated {
+                expected_bytes: data.len(),
+                actual_bytes: 100
+            }
+        );
+
+        // the sidecar is enough to recover the entry's metadata
+        fs::remove_file(dir.path().join("manifest.toml")).await.unwrap();
+        fs::write(&qmdl_path, &data).await.unwrap();
+        let recovered = RecordingStore::recover(dir.path()).await.unwrap();
+        assert_eq!(recovered.manifest.entries[0].rayhunter_version, entry.rayhunter_version);
+        assert_eq!(recovered.manifest.entries[0].last_message_time, entry.last_message_time);
+    }
+}
@@ -1,32 +1,43 @@
 mod analysis;
+mod annotations;
+mod baseline;
 mod config;
 mod diag;
 mod display;
 mod error;
+mod geo;
+mod integrity;
 mod key_input;
+mod modem_info;
 mod notifications;
 mod pcap;
 mod qmdl_store;
 mod server;
 mod gps;
 mod gps_logger;
+mod gps_source;
 mod stats;
+mod upload;
 mod gps_v2;
+mod import;
 
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::config::{parse_args, parse_config};
 use crate::diag::run_diag_read_thread;
 use crate::error::RayhunterError;
-use crate::notifications::{NotificationService, run_notification_worker};
+use crate::notifications::{
+    NotificationService, OUTBOX_FILE_NAME, Outbox, run_notification_worker,
+};
 use crate::pcap::get_pcap;
 use crate::qmdl_store::RecordingStore;
 use crate::server::{
     get_config, get_gps, get_qmdl, get_zip, set_config, debug_set_display_state, ServerState, serve_static,
 };
-use crate::stats::{get_qmdl_manifest, get_system_stats};
+use crate::stats::{get_qmdl_manifest, get_system_stats, run_disk_monitor_thread};
 
 use analysis::{
     AnalysisCtrlMessage, AnalysisStatus, get_analysis_status, run_analysis_thread, start_analysis,
@@ -35,13 +46,14 @@ use axum::Router;
 use axum::response::Redirect;
 use axum::routing::{get, post};
 use diag::{
-    DiagDeviceCtrlMessage, delete_all_recordings, delete_recording, get_analysis_report,
-    start_recording, stop_recording,
+    DiagDeviceCtrlMessage, DiagHealth, delete_all_recordings, delete_recording, get_analysis_report,
+    get_log_codes, get_signal_samples, set_log_codes, start_recording, stop_recording,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use qmdl_store::RecordingStoreError;
 use rayhunter::Device;
-use rayhunter::diag_device::DiagDevice;
+use rayhunter::baseline::Baseline;
+use rayhunter::diag_device::{DiagDevice, ModemInfoHandle};
 use tokio::net::TcpListener;
 use tokio::select;
 use tokio::sync::mpsc::{self, Sender};
@@ -54,19 +66,39 @@ type AppRouter = Router<Arc<ServerState>>;
 fn get_router() -> AppRouter {
     let router = Router::new()
         .route("/api/v2/gps", post(gps_v2::gps_api_v2))
+        .route("/api/v2/gps/batch", post(gps_v2::gps_batch_api_v2))
         .route("/api/pcap/{name}", get(get_pcap))
         .route("/api/qmdl/{name}", get(get_qmdl))
         .route("/api/gps/{name}", get(get_gps))
         .route("/api/zip/{name}", get(get_zip))
+        .route("/api/geojson/{name}", get(geo::get_geojson))
+        .route("/api/kml/{name}", get(geo::get_kml))
         .route("/api/system-stats", get(get_system_stats))
         .route("/api/qmdl-manifest", get(get_qmdl_manifest))
+        .route("/api/verify", get(integrity::verify_recordings))
+        .route("/api/verify/{name}", get(integrity::verify_recording))
+        .route("/api/annotations/{name}", get(annotations::get_annotations))
+        .route("/api/annotations/{name}", post(annotations::set_annotations))
         .route("/api/start-recording", post(start_recording))
         .route("/api/stop-recording", post(stop_recording))
+        .route("/api/log-codes", get(get_log_codes))
+        .route("/api/log-codes", post(set_log_codes))
         .route("/api/delete-recording/{name}", post(delete_recording))
         .route("/api/delete-all-recordings", post(delete_all_recordings))
         .route("/api/analysis-report/{name}", get(get_analysis_report))
+        .route("/api/signal/{name}", get(get_signal_samples))
         .route("/api/analysis", get(get_analysis_status))
         .route("/api/analysis/{name}", post(start_analysis))
+        .route("/api/import/{file_name}", post(import::import_recording))
+        .route("/api/upload", get(upload::get_uploads))
+        .route("/api/upload/{name}", post(upload::queue_upload))
+        .route("/api/notifications", get(notifications::get_notifications))
+        .route("/api/notifications/retry", post(notifications::retry_notifications))
+        .route("/api/notifications/clear", post(notifications::clear_notifications))
+        .route("/api/notifications/clear/{id}", post(notifications::clear_notification))
+        .route("/api/baseline", get(baseline::get_baseline))
+        .route("/api/baseline/reset", post(baseline::reset_baseline))
+        .route("/api/modem-info", get(modem_info::get_modem_info))
         .route("/api/config", get(get_config))
         .route("/api/config", post(set_config))
         .route("/api/debug/display-state", post(debug_set_display_state))
@@ -143,6 +175,9 @@ fn run_shutdown_thread(
     server_shutdown_tx: oneshot::Sender<()>,
     maybe_ui_shutdown_tx: Option<oneshot::Sender<()>>,
     maybe_key_input_shutdown_tx: Option<oneshot::Sender<()>>,
+    gps_source_shutdown_tx: oneshot::Sender<()>,
+    disk_monitor_shutdown_tx: oneshot::Sender<()>,
+    upload_shutdown_tx: oneshot::Sender<()>,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     analysis_tx: Sender<AnalysisCtrlMessage>,
 ) -> JoinHandle<Result<(), RayhunterError>> {
@@ -182,6 +217,9 @@ fn run_shutdown_thread(
         if let Some(key_input_shutdown_tx) = maybe_key_input_shutdown_tx {
             let _ = key_input_shutdown_tx.send(());
         }
+        let _ = gps_source_shutdown_tx.send(());
+        let _ = disk_monitor_shutdown_tx.send(());
+        let _ = upload_shutdown_tx.send(());
         diag_device_sender
             .send(DiagDeviceCtrlMessage::Exit)
             .await
@@ -221,25 +259,71 @@ async fn run_with_config(
     let task_tracker = TaskTracker::new();
     println!("R A Y H U N T E R 🐳");
 
-    let store = init_qmdl_store(&config).await?;
+    let store = init_qmdl_store(&config)
+        .await?
+        .with_policy(config.recordings.clone())
+        .with_qmdl_compression(config.qmdl_compression)
+        .with_encryption_key(config.encryption_key()?)
+        .with_uploads(config.upload.is_some());
     let analysis_status = AnalysisStatus::new(&store);
     let qmdl_store_lock = Arc::new(RwLock::new(store));
     let (diag_tx, diag_rx) = mpsc::channel::<DiagDeviceCtrlMessage>(1);
     let (ui_update_tx, ui_update_rx) = mpsc::channel::<display::DisplayState>(1);
     let (analysis_tx, analysis_rx) = mpsc::channel::<AnalysisCtrlMessage>(5);
+    let log_code_selection_lock = Arc::new(RwLock::new(config.diag_logs.clone()));
     let mut maybe_ui_shutdown_tx = None;
     let mut maybe_key_input_shutdown_tx = None;
 
-    let notification_service = NotificationService::new(config.ntfy_url.clone());
+    let notification_outbox =
+        Outbox::load(Path::new(&config.qmdl_store_path).join(OUTBOX_FILE_NAME)).await;
+    let notification_service =
+        NotificationService::new(config.notification_sinks(), notification_outbox);
+    let notification_outbox = notification_service.outbox();
+    let gps_logger = Arc::new(crate::gps_logger::GpsLogger::new(
+        qmdl_store_lock.clone(),
+        config.gps.gps_logging_enabled,
+        config.gps.gps_log_format,
+        config.gps.location_options(),
+    )
+    .with_log_source(config.gps.log_source()));
+
+    let diag_health_lock = Arc::new(RwLock::new(DiagHealth::default()));
+    let baseline = if config.baseline_enabled {
+        let baseline = match qmdl_store_lock.read().await.load_baseline().await {
+            Ok(baseline) => baseline,
+            Err(e) => {
+                warn!("couldn't load baseline, starting from scratch: {e}");
+                Baseline::default()
+            }
+        };
+        info!("loaded a baseline of {} cells", baseline.summary().cells);
+        Some(Arc::new(std::sync::RwLock::new(baseline)))
+    } else {
+        None
+    };
 
+    let mut modem_info = None;
     if !config.debug_mode {
         let (ui_shutdown_tx, ui_shutdown_rx) = oneshot::channel();
         maybe_ui_shutdown_tx = Some(ui_shutdown_tx);
         info!("Using configuration for device: {0:?}", config.device);
-        let mut dev = DiagDevice::new(&config.device)
+        let mut dev = DiagDevice::new(&config.diag_transport, &config.device)
             .await
             .map_err(RayhunterError::DiagInitError)?;
-        dev.config_logs()
+        // asked before logging is enabled, so the answers aren't buried in logs
+        let modem_info_handle = ModemInfoHandle::default();
+        match dev.modem_info().await {
+            Ok(info) => {
+                info!(
+                    "modem firmware build: {:?}, baseband version: {:?}",
+                    info.firmware_build, info.baseband_version
+                );
+                modem_info_handle.update(info);
+            }
+            Err(e) => warn!("couldn't query modem info: {e}"),
+        }
+        modem_info = Some(modem_info_handle.clone());
+        dev.config_logs(&config.diag_logs.log_codes())
             .await
             .map_err(RayhunterError::DiagInitError)?;
 
@@ -247,6 +331,8 @@ async fn run_with_config(
         run_diag_read_thread(
             &task_tracker,
             dev,
+            config.diag_transport.clone(),
+            config.device.clone(),
             diag_rx,
             diag_tx.clone(),
             ui_update_tx.clone(),
@@ -254,6 +340,11 @@ async fn run_with_config(
             analysis_tx.clone(),
             config.analyzers.clone(),
             notification_service.new_handler(),
+            gps_logger.live_locator(),
+            baseline.clone(),
+            log_code_selection_lock.clone(),
+            modem_info_handle,
+            diag_health_lock.clone(),
         );
         info!("Starting UI");
 
@@ -287,9 +378,39 @@ async fn run_with_config(
         qmdl_store_lock.clone(),
         analysis_status_lock.clone(),
         config.analyzers.clone(),
+        gps_logger.clone(),
+        baseline.clone(),
     );
     let should_restart_flag = Arc::new(AtomicBool::new(false));
 
+    let (gps_source_shutdown_tx, gps_source_shutdown_rx) = oneshot::channel();
+    gps_source::run_gps_source_thread(
+        &task_tracker,
+        config.gps.source.clone(),
+        gps_logger.clone(),
+        gps_source_shutdown_rx,
+    );
+
+    let (disk_monitor_shutdown_tx, disk_monitor_shutdown_rx) = oneshot::channel();
+    run_disk_monitor_thread(
+        &task_tracker,
+        config.qmdl_store_path.clone(),
+        config.disk_warning_percent,
+        notification_service.new_handler(),
+        disk_monitor_shutdown_rx,
+    );
+
+    let (upload_shutdown_tx, upload_shutdown_rx) = oneshot::channel();
+    if let Some(upload_config) = config.upload.clone() {
+        info!("Starting upload worker");
+        upload::run_upload_worker(
+            &task_tracker,
+            upload_config,
+            qmdl_store_lock.clone(),
+            upload_shutdown_rx,
+        );
+    }
+
     run_shutdown_thread(
         &task_tracker,
         diag_tx.clone(),
@@ -298,6 +419,9 @@ async fn run_with_config(
         server_shutdown_tx,
         maybe_ui_shutdown_tx,
         maybe_key_input_shutdown_tx,
+        gps_source_shutdown_tx,
+        disk_monitor_shutdown_tx,
+        upload_shutdown_tx,
         qmdl_store_lock.clone(),
         analysis_tx.clone(),
     );
@@ -311,11 +435,12 @@ async fn run_with_config(
         analysis_sender: analysis_tx,
         daemon_restart_tx: Arc::new(RwLock::new(Some(daemon_restart_tx))),
         ui_update_sender: Some(ui_update_tx),
-        gps_logger: Arc::new(crate::gps_logger::GpsLogger::new(
-            qmdl_store_lock.clone(),
-            config.gps.gps_logging_enabled,
-            config.gps.gps_log_format,
-        )),
+        gps_logger,
+        notification_outbox,
+        log_code_selection_lock,
+        baseline,
+        diag_health_lock,
+        modem_info,
     });
     run_server(&task_tracker, state, server_shutdown_rx).await;
 
@@ -0,0 +1,65 @@
+//! What the modem reports about itself over diag, along with the LTE cell it
+//! was last measured on while recording.
+
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use rayhunter::diag_device::ModemInfo;
+use rayhunter::signal::ServingCell;
+use serde::Serialize;
+
+use crate::server::ServerState;
+
+/// How many trailing digits of the IMEI and ESN are left unmasked, enough to
+/// tell devices apart without giving the identifiers away
+const UNMASKED_DIGITS: usize = 4;
+
+#[derive(Serialize)]
+pub struct ModemInfoResponse {
+    /// None until the modem has been queried
+    pub info: Option<ModemInfo>,
+    pub serving_cell: Option<ServingCell>,
+}
+
+pub async fn get_modem_info(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<ModemInfoResponse>, (StatusCode, String)> {
+    let handle = state
+        .modem_info
+        .as_ref()
+        .ok_or((StatusCode::FORBIDDEN, "server is in debug mode".to_string()))?;
+    let info = handle.latest().map(|mut info| {
+        info.imei = info.imei.as_deref().map(redact);
+        info.esn = info.esn.as_deref().map(redact);
+        info
+    });
+    Ok(Json(ModemInfoResponse {
+        info,
+        serving_cell: handle.serving_cell(),
+    }))
+}
+
+// Masks all but the last few characters, since anything on the network can
+// ask for these
+fn redact(identifier: &str) -> String {
+    let len = identifier.chars().count();
+    identifier
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if i + UNMASKED_DIGITS < len { '*' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("312345678901234"), "***********1234");
+        assert_eq!(redact("1234"), "1234");
+        assert_eq!(redact(""), "");
+    }
+}
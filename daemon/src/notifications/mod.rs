@@ -0,0 +1,605 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use rayhunter::analysis::analyzer::EventType;
+use rayhunter::gps::EventLocation;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio_util::task::TaskTracker;
+
+mod mqtt;
+mod ntfy;
+mod outbox;
+mod smtp;
+mod syslog;
+mod webhook;
+
+pub use outbox::{
+    OUTBOX_FILE_NAME, Outbox, SharedOutbox, clear_notification, clear_notifications,
+    get_notifications, retry_notifications,
+};
+
+fn default_min_severity() -> EventType {
+    EventType::Low
+}
+
+fn default_smtp_address() -> String {
+    "127.0.0.1:25".to_string()
+}
+
+/// A destination for notifications, along with which of them it wants and how
+/// they should be worded.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct NotificationSinkConfig {
+    #[serde(flatten)]
+    pub kind: NotificationSinkKind,
+    /// Notifications for events below this severity aren't sent to this sink.
+    /// Notifications that don't carry a severity are always sent.
+    #[serde(default = "default_min_severity")]
+    pub min_severity: EventType,
+    /// Message text, with placeholders such as `{message}`, `{severity}`,
+    /// `{recording}` and `{location}`. See [`render_template`]. If unset,
+    /// [`default_text`] is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+impl NotificationSinkConfig {
+    /// Identifies this sink in the outbox. It's derived from the sink's
+    /// settings, so it stays the same across restarts and reordering, but
+    /// queued notifications aren't sent to a sink whose settings changed.
+    pub fn id(&self) -> String {
+        let settings = serde_json::to_vec(&self.kind).unwrap_or_default();
+        let digest = Sha256::digest(&settings);
+        format!("{}-{}", self.kind.name(), hex::encode(&digest[..4]))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationSinkKind {
+    /// POST the message as plain text to an ntfy topic
+    Ntfy { url: String },
+    /// POST a JSON document to any URL, optionally signed with HMAC-SHA256
+    Webhook {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret: Option<String>,
+    },
+    /// Publish a JSON document to an MQTT 3.1.1 broker
+    Mqtt {
+        address: String,
+        topic: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        #[serde(default)]
+        retain: bool,
+    },
+    /// Log to a syslog server over UDP, or to the local syslog socket if no
+    /// address is given
+    Syslog {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        address: Option<String>,
+    },
+    /// Send an email through an SMTP relay that doesn't need authentication
+    Smtp {
+        #[serde(default = "default_smtp_address")]
+        address: String,
+        from: String,
+        to: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subject: Option<String>,
+    },
+}
+
+impl NotificationSinkKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotificationSinkKind::Ntfy { .. } => "ntfy",
+            NotificationSinkKind::Webhook { .. } => "webhook",
+            NotificationSinkKind::Mqtt { .. } => "mqtt",
+            NotificationSinkKind::Syslog { .. } => "syslog",
+            NotificationSinkKind::Smtp { .. } => "smtp",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NotificationError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Server responded with status {0}")]
+    HttpStatus(reqwest::StatusCode),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to serialize notification: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Unexpected response from server: {0}")]
+    Protocol(String),
+}
+
+/// Everything a sink might want to include in a notification.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotificationContent {
+    pub message_type: String,
+    pub message: String,
+    pub severity: Option<EventType>,
+    /// Name of the analyzer that raised the warning, if this is one
+    pub analyzer: Option<String>,
+    /// Name of the recording that was active, if any
+    pub recording: Option<String>,
+    /// Where the device was when the notification was raised
+    pub location: Option<EventLocation>,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub struct Notification {
+    content: NotificationContent,
+    debounce: Option<Duration>,
+}
+
+impl Notification {
+    pub fn new(message_type: String, message: String, debounce: Option<Duration>) -> Self {
+        Notification {
+            content: NotificationContent {
+                message_type,
+                message,
+                severity: None,
+                analyzer: None,
+                recording: None,
+                location: None,
+                timestamp: Utc::now(),
+            },
+            debounce,
+        }
+    }
+
+    pub fn with_severity(mut self, severity: EventType) -> Self {
+        self.content.severity = Some(severity);
+        self
+    }
+
+    pub fn with_analyzer(mut self, analyzer: String) -> Self {
+        self.content.analyzer = Some(analyzer);
+        self
+    }
+
+    pub fn with_recording(mut self, recording: Option<String>) -> Self {
+        self.content.recording = recording;
+        self
+    }
+
+    pub fn with_location(mut self, location: Option<EventLocation>) -> Self {
+        self.content.location = location;
+        self
+    }
+
+    pub fn has_location(&self) -> bool {
+        self.content.location.is_some()
+    }
+}
+
+/// Fill in a sink's message template. Unknown placeholders are left as is.
+///
+/// Available placeholders are `{message}`, `{type}`, `{severity}`,
+/// `{analyzer}`, `{recording}`, `{latitude}`, `{longitude}`, `{location}` and
+/// `{time}`.
+pub fn render_template(template: &str, content: &NotificationContent) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[1..end];
+        if name.contains('{') {
+            rendered.push('{');
+            rest = &rest[1..];
+            continue;
+        }
+        match placeholder_value(name, content) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn placeholder_value(name: &str, content: &NotificationContent) -> Option<String> {
+    let location = content.location.as_ref();
+    Some(match name {
+        "message" => content.message.clone(),
+        "type" => content.message_type.clone(),
+        "severity" => content
+            .severity
+            .map(|severity| format!("{severity:?}"))
+            .unwrap_or_default(),
+        "analyzer" => content.analyzer.clone().unwrap_or_default(),
+        "recording" => content.recording.clone().unwrap_or_else(|| "none".to_string()),
+        "latitude" => location
+            .map(|l| format!("{:.6}", l.latitude))
+            .unwrap_or_default(),
+        "longitude" => location
+            .map(|l| format!("{:.6}", l.longitude))
+            .unwrap_or_default(),
+        "location" => location
+            .map(|l| format!("{:.6},{:.6}", l.latitude, l.longitude))
+            .unwrap_or_else(|| "unknown location".to_string()),
+        "time" => content.timestamp.to_rfc3339(),
+        _ => return None,
+    })
+}
+
+/// The message sent by sinks without a template: the message, followed by
+/// whichever of the analyzer, recording and location are known.
+pub fn default_text(content: &NotificationContent) -> String {
+    let mut text = match (content.severity, &content.analyzer) {
+        (Some(severity), Some(analyzer)) => {
+            format!("Rayhunter {severity:?} severity warning from {analyzer}: {}", content.message)
+        }
+        _ => content.message.clone(),
+    };
+    if let Some(recording) = &content.recording {
+        text.push_str(&format!("\nRecording: {recording}"));
+    }
+    if let Some(location) = &content.location {
+        text.push_str(&format!(
+            "\nLocation: {:.6},{:.6}",
+            location.latitude, location.longitude
+        ));
+        if location.fix_offset_secs.abs() > 60 {
+            text.push_str(&format!(" ({}s from fix)", location.fix_offset_secs.abs()));
+        }
+    }
+    text
+}
+
+/// The JSON document sent by sinks that carry structured data: the rendered
+/// `text` alongside every field of the notification.
+fn json_body(content: &NotificationContent, text: &str) -> Result<Vec<u8>, serde_json::Error> {
+    #[derive(Serialize)]
+    struct Body<'a> {
+        text: &'a str,
+        #[serde(flatten)]
+        content: &'a NotificationContent,
+    }
+    serde_json::to_vec(&Body { text, content })
+}
+
+#[async_trait]
+pub trait NotificationSink: Send + Sync {
+    /// Identifies the sink in logs
+    fn name(&self) -> &'static str;
+
+    /// Deliver one notification. `text` is the sink's rendered template.
+    async fn send(&self, content: &NotificationContent, text: &str)
+    -> Result<(), NotificationError>;
+}
+
+fn build_sink(kind: &NotificationSinkKind, http_client: &reqwest::Client) -> Box<dyn NotificationSink> {
+    match kind.clone() {
+        NotificationSinkKind::Ntfy { url } => Box::new(ntfy::NtfySink::new(url, http_client.clone())),
+        NotificationSinkKind::Webhook { url, secret } => {
+            Box::new(webhook::WebhookSink::new(url, secret, http_client.clone()))
+        }
+        NotificationSinkKind::Mqtt {
+            address,
+            topic,
+            client_id,
+            username,
+            password,
+            retain,
+        } => Box::new(mqtt::MqttSink {
+            address,
+            topic,
+            client_id: client_id.unwrap_or_else(|| "rayhunter".to_string()),
+            username,
+            password,
+            retain,
+        }),
+        NotificationSinkKind::Syslog { address } => Box::new(syslog::SyslogSink { address }),
+        NotificationSinkKind::Smtp {
+            address,
+            from,
+            to,
+            subject,
+        } => Box::new(smtp::SmtpSink {
+            address,
+            from,
+            to,
+            subject: subject.unwrap_or_else(|| smtp::DEFAULT_SUBJECT.to_string()),
+        }),
+    }
+}
+
+struct ConfiguredSink {
+    id: String,
+    sink: Box<dyn NotificationSink>,
+    min_severity: EventType,
+    template: Option<String>,
+}
+
+impl ConfiguredSink {
+    fn accepts(&self, content: &NotificationContent) -> bool {
+        content
+            .severity
+            .is_none_or(|severity| severity >= self.min_severity)
+    }
+
+    async fn send(&self, content: &NotificationContent) -> Result<(), NotificationError> {
+        let text = match &self.template {
+            Some(template) => render_template(template, content),
+            None => default_text(content),
+        };
+        self.sink.send(content, &text).await
+    }
+}
+
+pub struct NotificationService {
+    sinks: Vec<NotificationSinkConfig>,
+    outbox: SharedOutbox,
+    tx: mpsc::Sender<Notification>,
+    rx: mpsc::Receiver<Notification>,
+}
+
+impl NotificationService {
+    pub fn new(sinks: Vec<NotificationSinkConfig>, outbox: Outbox) -> Self {
+        let (tx, rx) = mpsc::channel(10);
+        Self {
+            sinks,
+            outbox: Arc::new(RwLock::new(outbox)),
+            tx,
+            rx,
+        }
+    }
+
+    pub fn new_handler(&self) -> mpsc::Sender<Notification> {
+        self.tx.clone()
+    }
+
+    pub fn outbox(&self) -> SharedOutbox {
+        self.outbox.clone()
+    }
+}
+
+pub fn run_notification_worker(
+    task_tracker: &TaskTracker,
+    mut notification_service: NotificationService,
+) {
+    task_tracker.spawn(async move {
+        let http_client = reqwest::Client::new();
+        let sinks: Vec<ConfiguredSink> = notification_service
+            .sinks
+            .iter()
+            .map(|config| ConfiguredSink {
+                id: config.id(),
+                sink: build_sink(&config.kind, &http_client),
+                min_severity: config.min_severity,
+                template: config.template.clone(),
+            })
+            .collect();
+
+        let outbox = notification_service.outbox;
+        {
+            let sink_ids: Vec<String> = sinks.iter().map(|sink| sink.id.clone()).collect();
+            let mut outbox = outbox.write().await;
+            outbox.retain_sinks(&sink_ids);
+            outbox.save().await;
+        }
+
+        // If there's nowhere to send to we'll just discard the notifications
+        if sinks.is_empty() {
+            while notification_service.rx.recv().await.is_some() {}
+            return;
+        }
+
+        // When each (sink, message type) pair was last delivered, for debouncing
+        let mut last_sent: HashMap<(String, String), Instant> = HashMap::new();
+
+        loop {
+            // Queue any notifications since the last time we checked
+            let mut received = Vec::new();
+            loop {
+                match notification_service.rx.try_recv() {
+                    Ok(notification) => received.push(notification),
+                    Err(TryRecvError::Empty) => {
+                        break;
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        return;
+                    }
+                }
+            }
+            if !received.is_empty() {
+                let mut outbox = outbox.write().await;
+                for notification in received {
+                    // Delivery is tracked separately for each sink, so one
+                    // that's down doesn't hold up or duplicate sends to the others
+                    for sink in sinks.iter() {
+                        if !sink.accepts(&notification.content) {
+                            continue;
+                        }
+                        // Ignore if we're in the debounce period
+                        let key = (sink.id.clone(), notification.content.message_type.clone());
+                        if let (Some(debounce), Some(sent_at)) =
+                            (notification.debounce, last_sent.get(&key))
+                        {
+                            if sent_at.elapsed() < debounce {
+                                continue;
+                            }
+                        }
+                        outbox.push(&sink.id, notification.content.clone());
+                    }
+                }
+                outbox.save().await;
+            }
+
+            // Attempt to send pending notifications. The outbox isn't locked
+            // while sending, so the API stays responsive if a sink is slow.
+            let due = outbox.read().await.due(Utc::now());
+            if due.is_empty() {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                continue;
+            }
+            let mut results = Vec::with_capacity(due.len());
+            for entry in due {
+                let Some(sink) = sinks.iter().find(|sink| sink.id == entry.sink) else {
+                    continue;
+                };
+                let result = sink.send(&entry.content).await;
+                match &result {
+                    Ok(()) => {
+                        last_sent.insert((sink.id.clone(), entry.content.message_type.clone()), Instant::now());
+                    }
+                    Err(e) if entry.failed_attempts == 0 => {
+                        error!("Failed to send notification to {}: {e}", sink.sink.name());
+                    }
+                    Err(e) => {
+                        warn!("Failed to send notification to {}: {e}", sink.sink.name());
+                    }
+                }
+                results.push((entry.id, result));
+            }
+            {
+                let mut outbox = outbox.write().await;
+                let now = Utc::now();
+                for (id, result) in results {
+                    match result {
+                        Ok(()) => outbox.mark_sent(id),
+                        Err(e) => outbox.mark_failed(id, e.to_string(), now),
+                    }
+                }
+                outbox.save().await;
+            }
+
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NullSink;
+
+    #[async_trait]
+    impl NotificationSink for NullSink {
+        fn name(&self) -> &'static str {
+            "null"
+        }
+
+        async fn send(&self, _: &NotificationContent, _: &str) -> Result<(), NotificationError> {
+            Ok(())
+        }
+    }
+
+    fn content() -> NotificationContent {
+        NotificationContent {
+            message_type: "heuristic-warning".to_string(),
+            message: "IMSI requested {recording}".to_string(),
+            severity: Some(EventType::Medium),
+            analyzer: Some("IMSI Requested".to_string()),
+            recording: Some("1720000000".to_string()),
+            location: Some(EventLocation {
+                latitude: 52.52,
+                longitude: 13.405,
+                accuracy: None,
+                interpolated: false,
+                fix_offset_secs: 0,
+            }),
+            timestamp: DateTime::from_timestamp(1_720_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        let rendered = render_template(
+            "[{severity}] {message} in {recording} at {location} {unknown} {{type}} {",
+            &content(),
+        );
+        // placeholders inside substituted values are not expanded again
+        assert_eq!(
+            rendered,
+            "[Medium] IMSI requested {recording} in 1720000000 at 52.520000,13.405000 {unknown} {heuristic-warning} {"
+        );
+
+        let mut content = content();
+        content.location = None;
+        content.recording = None;
+        assert_eq!(
+            render_template("{recording} {location}", &content),
+            "none unknown location"
+        );
+    }
+
+    #[test]
+    fn test_default_text() {
+        let mut content = content();
+        content.message = "IMSI was requested".to_string();
+        assert_eq!(
+            default_text(&content),
+            "Rayhunter Medium severity warning from IMSI Requested: IMSI was requested\nRecording: 1720000000\nLocation: 52.520000,13.405000"
+        );
+
+        let mut content = NotificationContent {
+            severity: None,
+            analyzer: None,
+            recording: None,
+            location: None,
+            message: "Storage is 95% full".to_string(),
+            ..content
+        };
+        assert_eq!(default_text(&content), "Storage is 95% full");
+        content.analyzer = Some("ignored without a severity".to_string());
+        assert_eq!(default_text(&content), "Storage is 95% full");
+    }
+
+    #[test]
+    fn test_sink_config_and_min_severity() {
+        let config: NotificationSinkConfig = toml::from_str(
+            r#"
+            type = "webhook"
+            url = "https://example.com/hook"
+            secret = "hunter2"
+            min_severity = "High"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.kind,
+            NotificationSinkKind::Webhook {
+                url: "https://example.com/hook".to_string(),
+                secret: Some("hunter2".to_string()),
+            }
+        );
+
+        assert!(config.id().starts_with("webhook-"));
+        let sink = ConfiguredSink {
+            id: config.id(),
+            sink: Box::new(NullSink),
+            min_severity: config.min_severity,
+            template: None,
+        };
+        assert!(!sink.accepts(&content()));
+        let mut content = content();
+        content.severity = None;
+        assert!(sink.accepts(&content));
+    }
+}
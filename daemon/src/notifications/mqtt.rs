@@ -0,0 +1,181 @@
+//! Just enough of MQTT 3.1.1 to publish a single message: connect, publish at
+//! QoS 0 and disconnect.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{NotificationContent, NotificationError, NotificationSink, json_body};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const DISCONNECT: u8 = 0xe0;
+const KEEP_ALIVE_SECS: u16 = 30;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct MqttSink {
+    pub address: String,
+    pub topic: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub retain: bool,
+}
+
+fn encode_remaining_length(mut len: usize, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_string(value: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    encode_remaining_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+impl MqttSink {
+    fn connect_packet(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        encode_string(b"MQTT", &mut body);
+        body.push(4); // protocol level 3.1.1
+        let mut flags = 0x02; // clean session
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+        encode_string(self.client_id.as_bytes(), &mut body);
+        if let Some(username) = &self.username {
+            encode_string(username.as_bytes(), &mut body);
+        }
+        if let Some(password) = &self.password {
+            encode_string(password.as_bytes(), &mut body);
+        }
+        packet(CONNECT, &body)
+    }
+
+    fn publish_packet(&self, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        encode_string(self.topic.as_bytes(), &mut body);
+        body.extend_from_slice(payload);
+        packet(PUBLISH | self.retain as u8, &body)
+    }
+
+    async fn publish(&self, payload: &[u8]) -> Result<(), NotificationError> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(&self.connect_packet()).await?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack).await?;
+        if connack[0] != CONNACK || connack[1] != 2 {
+            return Err(NotificationError::Protocol(format!(
+                "expected CONNACK, got {connack:02x?}"
+            )));
+        }
+        if connack[3] != 0 {
+            return Err(NotificationError::Protocol(format!(
+                "broker refused connection with return code {}",
+                connack[3]
+            )));
+        }
+
+        stream.write_all(&self.publish_packet(payload)).await?;
+        stream.write_all(&[DISCONNECT, 0]).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NotificationSink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn send(&self, content: &NotificationContent, text: &str) -> Result<(), NotificationError> {
+        let payload = json_body(content, text)?;
+        tokio::time::timeout(TIMEOUT, self.publish(&payload))
+            .await
+            .map_err(|_| NotificationError::Protocol("timed out talking to broker".to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn sink(address: String) -> MqttSink {
+        MqttSink {
+            address,
+            topic: "rayhunter/alerts".to_string(),
+            client_id: "rayhunter".to_string(),
+            username: Some("user".to_string()),
+            password: None,
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn test_remaining_length() {
+        for (len, expected) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xff, 0x7f]),
+            (2_097_152, vec![0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let mut out = Vec::new();
+            encode_remaining_length(len, &mut out);
+            assert_eq!(out, expected, "length {len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = sink(listener.local_addr().unwrap().to_string());
+        let expected_connect = sink.connect_packet();
+
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut connect = vec![0; expected_connect.len()];
+            stream.read_exact(&mut connect).await.unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).await.unwrap();
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            (connect, rest)
+        });
+
+        sink.publish(b"hello").await.unwrap();
+        let (connect, rest) = broker.await.unwrap();
+        assert_eq!(connect, sink.connect_packet());
+        // retained PUBLISH of "hello" to the topic, then DISCONNECT
+        let mut expected = vec![PUBLISH | 1, 23, 0, 16];
+        expected.extend_from_slice(b"rayhunter/alerts");
+        expected.extend_from_slice(b"hello");
+        expected.extend_from_slice(&[DISCONNECT, 0]);
+        assert_eq!(rest, expected);
+    }
+}
@@ -0,0 +1,34 @@
+use async_trait::async_trait;
+
+use super::{NotificationContent, NotificationError, NotificationSink};
+
+pub struct NtfySink {
+    url: String,
+    http_client: reqwest::Client,
+}
+
+impl NtfySink {
+    pub fn new(url: String, http_client: reqwest::Client) -> Self {
+        Self { url, http_client }
+    }
+}
+
+#[async_trait]
+impl NotificationSink for NtfySink {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    async fn send(&self, _: &NotificationContent, text: &str) -> Result<(), NotificationError> {
+        let response = self
+            .http_client
+            .post(&self.url)
+            .body(text.to_string())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(NotificationError::HttpStatus(response.status()));
+        }
+        Ok(())
+    }
+}
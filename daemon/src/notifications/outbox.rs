@@ -0,0 +1,269 @@
+//! Notifications waiting to be delivered, persisted so that they survive a
+//! reboot. A device that detects something while out of coverage keeps
+//! retrying once it's back online.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::NotificationContent;
+use crate::server::ServerState;
+
+pub const OUTBOX_FILE_NAME: &str = "notification_outbox.json";
+
+/// A notification that hasn't been delivered to one of the sinks yet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: u64,
+    /// Which sink this is queued for, see [`super::NotificationSinkConfig::id`]
+    pub sink: String,
+    pub content: NotificationContent,
+    pub failed_attempts: u32,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    /// Whether the backoff since the last failed attempt has elapsed. Retries
+    /// back off exponentially, up to a maximum of 256 seconds.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        let Some(last_attempt) = self.last_attempt else {
+            return true;
+        };
+        let min_wait_time = chrono::Duration::seconds(2i64.pow(self.failed_attempts.min(8)));
+        now - last_attempt >= min_wait_time
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OutboxFile {
+    next_id: u64,
+    entries: Vec<OutboxEntry>,
+}
+
+/// The default outbox only lives in memory
+#[derive(Default)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    next_id: u64,
+    entries: Vec<OutboxEntry>,
+}
+
+impl Outbox {
+    /// Load the outbox saved at `path`. A missing or unreadable file results
+    /// in an empty outbox.
+    pub async fn load(path: PathBuf) -> Self {
+        let file = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                error!("discarding unreadable notification outbox {}: {e}", path.display());
+                OutboxFile::default()
+            }),
+            Err(_) => OutboxFile::default(),
+        };
+        Self {
+            path: Some(path),
+            next_id: file.next_id,
+            entries: file.entries,
+        }
+    }
+
+    pub async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let file = OutboxFile {
+            next_id: self.next_id,
+            entries: self.entries.clone(),
+        };
+        let contents = match serde_json::to_string(&file) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("failed to serialize notification outbox: {e}");
+                return;
+            }
+        };
+        // write to a temporary file first so a crash can't leave a truncated outbox
+        let tmp_path = path.with_extension("json.tmp");
+        let result = async {
+            tokio::fs::write(&tmp_path, contents).await?;
+            tokio::fs::rename(&tmp_path, path).await
+        }
+        .await;
+        if let Err(e) = result {
+            error!("failed to save notification outbox {}: {e}", path.display());
+        }
+    }
+
+    pub fn entries(&self) -> &[OutboxEntry] {
+        &self.entries
+    }
+
+    /// Queue `content` for `sink`. A notification of the same type that's still
+    /// waiting for that sink is replaced, so only the latest one is delivered.
+    pub fn push(&mut self, sink: &str, content: NotificationContent) {
+        self.entries
+            .retain(|entry| entry.sink != sink || entry.content.message_type != content.message_type);
+        self.entries.push(OutboxEntry {
+            id: self.next_id,
+            sink: sink.to_string(),
+            content,
+            failed_attempts: 0,
+            last_attempt: None,
+            last_error: None,
+        });
+        self.next_id += 1;
+    }
+
+    /// Drop entries for sinks that are no longer configured
+    pub fn retain_sinks(&mut self, sinks: &[String]) {
+        let before = self.entries.len();
+        self.entries.retain(|entry| sinks.contains(&entry.sink));
+        if self.entries.len() < before {
+            warn!(
+                "dropped {} queued notifications for sinks that are no longer configured",
+                before - self.entries.len()
+            );
+        }
+    }
+
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<OutboxEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_due(now))
+            .cloned()
+            .collect()
+    }
+
+    pub fn mark_sent(&mut self, id: u64) {
+        self.entries.retain(|entry| entry.id != id);
+    }
+
+    pub fn mark_failed(&mut self, id: u64, error: String, now: DateTime<Utc>) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.failed_attempts += 1;
+            entry.last_attempt = Some(now);
+            entry.last_error = Some(error);
+        }
+    }
+
+    /// Make every entry due immediately. Returns how many there are.
+    pub fn retry_all(&mut self) -> usize {
+        for entry in self.entries.iter_mut() {
+            entry.failed_attempts = 0;
+            entry.last_attempt = None;
+        }
+        self.entries.len()
+    }
+
+    /// Remove one entry, or all of them if `id` is `None`. Returns how many
+    /// were removed.
+    pub fn clear(&mut self, id: Option<u64>) -> usize {
+        let before = self.entries.len();
+        match id {
+            Some(id) => self.entries.retain(|entry| entry.id != id),
+            None => self.entries.clear(),
+        }
+        before - self.entries.len()
+    }
+}
+
+pub type SharedOutbox = Arc<RwLock<Outbox>>;
+
+pub async fn get_notifications(State(state): State<Arc<ServerState>>) -> Json<Vec<OutboxEntry>> {
+    Json(state.notification_outbox.read().await.entries().to_vec())
+}
+
+pub async fn retry_notifications(
+    State(state): State<Arc<ServerState>>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let mut outbox = state.notification_outbox.write().await;
+    let count = outbox.retry_all();
+    outbox.save().await;
+    Ok((StatusCode::ACCEPTED, format!("retrying {count} notifications")))
+}
+
+pub async fn clear_notifications(
+    State(state): State<Arc<ServerState>>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let mut outbox = state.notification_outbox.write().await;
+    let count = outbox.clear(None);
+    outbox.save().await;
+    Ok((StatusCode::ACCEPTED, format!("cleared {count} notifications")))
+}
+
+pub async fn clear_notification(
+    State(state): State<Arc<ServerState>>,
+    Path(id): Path<u64>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let mut outbox = state.notification_outbox.write().await;
+    if outbox.clear(Some(id)) == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("no queued notification with id {id}"),
+        ));
+    }
+    outbox.save().await;
+    Ok((StatusCode::ACCEPTED, format!("cleared notification {id}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn content(message_type: &str, message: &str) -> NotificationContent {
+        NotificationContent {
+            message_type: message_type.to_string(),
+            message: message.to_string(),
+            severity: None,
+            analyzer: None,
+            recording: None,
+            location: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_survives_reload() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(OUTBOX_FILE_NAME);
+        let now = Utc::now();
+
+        let mut outbox = Outbox::load(path.clone()).await;
+        outbox.push("ntfy-1", content("heuristic-warning", "first"));
+        outbox.push("ntfy-1", content("heuristic-warning", "second"));
+        outbox.push("webhook-2", content("heuristic-warning", "first"));
+        assert_eq!(outbox.entries().len(), 2);
+        let id = outbox.entries()[0].id;
+        outbox.mark_failed(id, "offline".to_string(), now);
+        outbox.save().await;
+
+        let mut outbox = Outbox::load(path).await;
+        assert_eq!(outbox.entries().len(), 2);
+        let entry = &outbox.entries()[0];
+        assert_eq!(entry.content.message, "second");
+        assert_eq!(entry.failed_attempts, 1);
+        assert!(!entry.is_due(now));
+        assert!(entry.is_due(now + chrono::Duration::seconds(2)));
+        assert_eq!(outbox.due(now).len(), 1);
+
+        // ids keep increasing across reloads
+        outbox.push("ntfy-1", content("disk-full", "third"));
+        assert_eq!(outbox.entries()[2].id, 3);
+
+        outbox.retain_sinks(&["ntfy-1".to_string()]);
+        assert_eq!(outbox.entries().len(), 2);
+        assert_eq!(outbox.retry_all(), 2);
+        assert_eq!(outbox.due(now).len(), 2);
+        assert_eq!(outbox.clear(Some(3)), 1);
+        assert_eq!(outbox.clear(Some(3)), 0);
+        assert_eq!(outbox.clear(None), 1);
+    }
+}
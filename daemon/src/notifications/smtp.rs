@@ -0,0 +1,188 @@
+//! A minimal SMTP client for handing mail to a local relay. There's no TLS or
+//! authentication; the relay is expected to take care of onward delivery.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use super::{NotificationContent, NotificationError, NotificationSink, render_template};
+
+pub const DEFAULT_SUBJECT: &str = "Rayhunter: {severity} {type}";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct SmtpSink {
+    pub address: String,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Template for the subject line, using the same placeholders as the body
+    pub subject: String,
+}
+
+struct SmtpConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl SmtpConnection {
+    /// Read a (possibly multiline) reply and check its status code
+    async fn expect(&mut self, code: &str) -> Result<(), NotificationError> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(NotificationError::Protocol(
+                    "connection closed by SMTP server".to_string(),
+                ));
+            }
+            if !line.starts_with(code) {
+                return Err(NotificationError::Protocol(format!(
+                    "expected {code}, got {}",
+                    line.trim_end()
+                )));
+            }
+            // the last line of a reply has a space after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str, code: &str) -> Result<(), NotificationError> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await?;
+        self.expect(code).await
+    }
+}
+
+/// Build the message, with CRLF line endings and dot-stuffing applied
+fn format_message(from: &str, to: &[String], subject: &str, content: &NotificationContent, text: &str) -> String {
+    let mut message = format!(
+        "From: {from}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        to.join(", "),
+        subject.replace(['\r', '\n'], " "),
+        content.timestamp.to_rfc2822(),
+    );
+    for line in text.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    message
+}
+
+impl SmtpSink {
+    async fn deliver(&self, content: &NotificationContent, text: &str) -> Result<(), NotificationError> {
+        let mut connection = SmtpConnection {
+            stream: BufReader::new(TcpStream::connect(&self.address).await?),
+        };
+        connection.expect("220").await?;
+        connection.command("EHLO rayhunter", "250").await?;
+        connection
+            .command(&format!("MAIL FROM:<{}>", self.from), "250")
+            .await?;
+        for recipient in &self.to {
+            connection
+                .command(&format!("RCPT TO:<{recipient}>"), "25")
+                .await?;
+        }
+        connection.command("DATA", "354").await?;
+        let subject = render_template(&self.subject, content);
+        let message = format_message(&self.from, &self.to, &subject, content, text);
+        connection.stream.get_mut().write_all(message.as_bytes()).await?;
+        connection.expect("250").await?;
+        connection.command("QUIT", "221").await
+    }
+}
+
+#[async_trait]
+impl NotificationSink for SmtpSink {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    async fn send(&self, content: &NotificationContent, text: &str) -> Result<(), NotificationError> {
+        tokio::time::timeout(TIMEOUT, self.deliver(content, text))
+            .await
+            .map_err(|_| NotificationError::Protocol("timed out talking to SMTP server".to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use rayhunter::analysis::analyzer::EventType;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_send_to_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = SmtpSink {
+            address: listener.local_addr().unwrap().to_string(),
+            from: "rayhunter@example.com".to_string(),
+            to: vec!["alerts@example.com".to_string()],
+            subject: DEFAULT_SUBJECT.to_string(),
+        };
+
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut transcript = Vec::new();
+            stream.get_mut().write_all(b"220 relay ready\r\n").await.unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        transcript.push(line);
+                        continue;
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-relay\r\n250 8BITMIME\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    transcript.push(line);
+                    stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                transcript.push(line);
+                stream.get_mut().write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+
+        let content = NotificationContent {
+            message_type: "heuristic-warning".to_string(),
+            message: "unused".to_string(),
+            severity: Some(EventType::High),
+            analyzer: None,
+            recording: None,
+            location: None,
+            timestamp: DateTime::from_timestamp(1_720_000_000, 0).unwrap(),
+        };
+        sink.send(&content, "first line\n.second line").await.unwrap();
+
+        let transcript = relay.await.unwrap();
+        assert_eq!(transcript[1], "MAIL FROM:<rayhunter@example.com>");
+        assert_eq!(transcript[2], "RCPT TO:<alerts@example.com>");
+        assert!(transcript.contains(&"Subject: Rayhunter: High heuristic-warning".to_string()));
+        assert!(transcript.contains(&"..second line".to_string()));
+        assert_eq!(transcript.last().unwrap(), "QUIT");
+    }
+}
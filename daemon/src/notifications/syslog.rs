@@ -0,0 +1,92 @@
+use async_trait::async_trait;
+use rayhunter::analysis::analyzer::EventType;
+use tokio::net::{UdpSocket, UnixDatagram};
+
+use super::{NotificationContent, NotificationError, NotificationSink};
+
+const LOCAL_SOCKET: &str = "/dev/log";
+const FACILITY_DAEMON: u8 = 3;
+const APP_NAME: &str = "rayhunter";
+
+pub struct SyslogSink {
+    /// `host:port` of a remote syslog server. Uses the local socket if unset.
+    pub address: Option<String>,
+}
+
+fn priority(severity: Option<EventType>) -> u8 {
+    let level = match severity {
+        Some(EventType::High) => 2,          // critical
+        Some(EventType::Medium) => 3,        // error
+        Some(EventType::Low) => 4,           // warning
+        Some(EventType::Informational) => 6, // informational
+        None => 5,                           // notice
+    };
+    FACILITY_DAEMON * 8 + level
+}
+
+/// RFC 5424 message, for remote servers
+fn format_remote(content: &NotificationContent, text: &str) -> String {
+    format!(
+        "<{}>1 {} - {APP_NAME} - {} - {text}",
+        priority(content.severity),
+        content.timestamp.to_rfc3339(),
+        content.message_type,
+    )
+}
+
+/// BSD-style message, which every local syslog daemon understands
+fn format_local(content: &NotificationContent, text: &str) -> String {
+    format!("<{}>{APP_NAME}: {text}", priority(content.severity))
+}
+
+#[async_trait]
+impl NotificationSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    async fn send(&self, content: &NotificationContent, text: &str) -> Result<(), NotificationError> {
+        match &self.address {
+            Some(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket
+                    .send_to(format_remote(content, text).as_bytes(), address)
+                    .await?;
+            }
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket
+                    .send_to(format_local(content, text).as_bytes(), LOCAL_SOCKET)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_message_format() {
+        let content = NotificationContent {
+            message_type: "heuristic-warning".to_string(),
+            message: "something happened".to_string(),
+            severity: Some(EventType::High),
+            analyzer: None,
+            recording: None,
+            location: None,
+            timestamp: DateTime::from_timestamp(1_720_000_000, 0).unwrap(),
+        };
+        assert_eq!(
+            format_remote(&content, "something happened"),
+            "<26>1 2024-07-03T09:46:40+00:00 - rayhunter - heuristic-warning - something happened"
+        );
+        assert_eq!(
+            format_local(&content, "something happened"),
+            "<26>rayhunter: something happened"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use sha2::Sha256;
+
+use super::{NotificationContent, NotificationError, NotificationSink, json_body};
+
+/// Header carrying `sha256=<hex HMAC of the body>` when a secret is configured
+pub const SIGNATURE_HEADER: &str = "X-Rayhunter-Signature";
+
+pub struct WebhookSink {
+    url: String,
+    secret: Option<String>,
+    http_client: reqwest::Client,
+}
+
+impl WebhookSink {
+    pub fn new(url: String, secret: Option<String>, http_client: reqwest::Client) -> Self {
+        Self {
+            url,
+            secret,
+            http_client,
+        }
+    }
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[async_trait]
+impl NotificationSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, content: &NotificationContent, text: &str) -> Result<(), NotificationError> {
+        let body = json_body(content, text)?;
+        let mut request = self
+            .http_client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, &body));
+        }
+        let response = request.body(body).send().await?;
+        if !response.status().is_success() {
+            return Err(NotificationError::HttpStatus(response.status()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::ServerState;
+use crate::server::ensure_not_encrypted;
 
 use anyhow::Error;
 use axum::body::Body;
@@ -30,6 +31,7 @@ pub async fn get_pcap(
         StatusCode::NOT_FOUND,
         format!("couldn't find manifest entry with name {qmdl_name}"),
     ))?;
+    ensure_not_encrypted(entry)?;
     if entry.qmdl_size_bytes == 0 {
         return Err((
             StatusCode::SERVICE_UNAVAILABLE,
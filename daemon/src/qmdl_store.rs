@@ -2,16 +2,23 @@ use std::io::{self, ErrorKind};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, TimeDelta};
 use log::{info, warn};
-use rayhunter::util::RuntimeMetadata;
+use rayhunter::analysis::analyzer::ModemFirmware;
+use rayhunter::baseline::Baseline;
+use rayhunter::encryption::{ENCRYPTION_MAGIC, EncryptingWriter, RecordingPublicKey};
+use rayhunter::qmdl::{COMPRESSED_QMDL_MAGIC, QmdlCompression};
+use rayhunter::util::{RecordingAnnotations, RuntimeMetadata};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
     fs::{self, File, OpenOptions, try_exists},
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
 };
 
+use crate::integrity::{ChecksumWriter, EntryMetadata};
+use crate::upload::{UploadState, UploadStatus};
+
 #[derive(Debug, Error)]
 pub enum RecordingStoreError {
     #[error("Can't close an entry when there's no current entry")]
@@ -32,12 +39,84 @@ pub enum RecordingStoreError {
     WriteManifestError(tokio::io::Error),
     #[error("Couldn't parse QMDL store manifest file: {0}")]
     ParseManifestError(toml::de::Error),
+    #[error("Couldn't write entry metadata file: {0}")]
+    WriteMetadataError(tokio::io::Error),
+    #[error("Entry is encrypted, but no encryption key is configured")]
+    NoEncryptionKey,
+    #[error("Couldn't read baseline file: {0}")]
+    ReadBaselineError(tokio::io::Error),
+    #[error("Couldn't parse baseline file: {0}")]
+    ParseBaselineError(serde_json::Error),
+    #[error("Couldn't write baseline file: {0}")]
+    WriteBaselineError(tokio::io::Error),
+}
+
+/// The cells, PLMNs and neighbor relations usually seen, kept in the store
+/// directory when `baseline_enabled` is set
+pub const BASELINE_FILE_NAME: &str = "baseline.json";
+
+/// A QMDL file made elsewhere, to be added with
+/// [`RecordingStore::import_entry`]
+#[derive(Debug, Clone)]
+pub struct ImportedRecording {
+    pub start_time: DateTime<Local>,
+    pub last_message_time: DateTime<Local>,
+    pub compression: QmdlCompression,
+    /// Name of the file it was imported from
+    pub source: String,
+    /// Checksums of the QMDL file, as computed by `ChunkChecksums`
+    pub chunk_crcs: Vec<u32>,
+}
+
+/// When to start a new recording, and which old ones to delete. Every limit is
+/// optional. Recordings with warnings and the current recording are never
+/// deleted.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct RecordingPolicy {
+    /// Start a new recording once the current QMDL file reaches this size
+    pub max_recording_size_mb: Option<u64>,
+    /// Start a new recording once the current one is this old
+    pub max_recording_duration_mins: Option<u64>,
+    /// Delete the oldest recordings once all of them together exceed this size
+    pub max_total_size_mb: Option<u64>,
+    /// Delete recordings whose last message is older than this
+    pub max_age_days: Option<u64>,
+    /// Keep at most this many recordings
+    pub max_recordings: Option<usize>,
+    /// Delete the oldest recordings while less than this percentage of the
+    /// disk is free
+    pub min_free_disk_percent: Option<u8>,
+}
+
+impl RecordingPolicy {
+    fn should_rotate(&self, entry: &ManifestEntry, now: DateTime<Local>) -> bool {
+        let too_big = self
+            .max_recording_size_mb
+            .is_some_and(|max| entry.qmdl_size_bytes as u64 >= max * 1024 * 1024);
+        let too_long = self
+            .max_recording_duration_mins
+            .and_then(|max| TimeDelta::try_minutes(max.try_into().ok()?))
+            .is_some_and(|max| now - entry.start_time >= max);
+        too_big || too_long
+    }
 }
 
 pub struct RecordingStore {
     pub path: PathBuf,
     pub manifest: Manifest,
     pub current_entry: Option<usize>, // index into manifest
+    pub policy: RecordingPolicy,
+    /// Compression used for new recordings
+    pub qmdl_compression: QmdlCompression,
+    /// Key new recordings are encrypted to, if any
+    pub encryption_key: Option<RecordingPublicKey>,
+    /// Whether finished recordings are queued to be uploaded
+    pub uploads_enabled: bool,
+    /// The modem firmware new recordings are made with
+    pub modem_firmware: ModemFirmware,
+    // checksums of the current entry's QMDL file, as of the last update
+    current_chunk_crcs: Vec<u32>,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
@@ -54,6 +133,34 @@ pub struct ManifestEntry {
     pub rayhunter_version: Option<String>,
     pub system_os: Option<String>,
     pub arch: Option<String>,
+    /// Whether analysis found any warnings in this recording. These are never
+    /// deleted automatically, and neither are recordings from before this was
+    /// kept track of, which are None until they're analyzed again.
+    #[serde(default)]
+    pub has_warnings: Option<bool>,
+    #[serde(default)]
+    pub compression: QmdlCompression,
+    /// Whether the entry's files are encrypted at rest, in which case only the
+    /// holder of the secret key can read them
+    #[serde(default)]
+    pub encrypted: bool,
+    #[serde(default)]
+    pub annotations: RecordingAnnotations,
+    /// Name of the file this recording was imported from, if it was made by
+    /// another device or tool
+    #[serde(default)]
+    pub imported_from: Option<String>,
+    /// When it was imported. Retention counts its age from then, rather than
+    /// from when it was recorded.
+    #[serde(default)]
+    pub imported_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub upload: UploadState,
+    /// The modem firmware the recording was made with, if the modem said
+    #[serde(default)]
+    pub firmware_build: Option<String>,
+    #[serde(default)]
+    pub baseband_version: Option<String>,
 }
 
 impl ManifestEntry {
@@ -68,9 +175,39 @@ impl ManifestEntry {
             rayhunter_version: Some(metadata.rayhunter_version),
             system_os: Some(metadata.system_os),
             arch: Some(metadata.arch),
+            has_warnings: Some(false),
+            compression: QmdlCompression::None,
+            encrypted: false,
+            annotations: RecordingAnnotations::default(),
+            imported_from: None,
+            imported_at: None,
+            upload: UploadState::default(),
+            firmware_build: None,
+            baseband_version: None,
         }
     }
 
+    pub fn modem_firmware(&self) -> ModemFirmware {
+        ModemFirmware {
+            firmware_build: self.firmware_build.clone(),
+            baseband_version: self.baseband_version.clone(),
+        }
+    }
+
+    // When the entry was added to the store, which is when it started unless
+    // it was imported
+    fn stored_at(&self) -> DateTime<Local> {
+        self.imported_at.unwrap_or(self.start_time)
+    }
+
+    // What retention counts the entry's age from
+    fn last_activity(&self) -> DateTime<Local> {
+        let last_message_time = self.last_message_time.unwrap_or(self.start_time);
+        self.imported_at.map_or(last_message_time, |imported_at| {
+            imported_at.max(last_message_time)
+        })
+    }
+
     pub fn get_qmdl_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         let mut filepath = path.as_ref().join(&self.name);
         filepath.set_extension("qmdl");
@@ -82,6 +219,33 @@ impl ManifestEntry {
         filepath.set_extension("ndjson");
         filepath
     }
+
+    // The signal strength time series, see rayhunter::signal
+    pub fn get_signal_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut filepath = path.as_ref().join(&self.name);
+        filepath.set_extension("signal.ndjson");
+        filepath
+    }
+
+    // The GPS fixes logged while recording, see crate::gps_logger
+    pub fn get_gps_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut filepath = path.as_ref().join(&self.name);
+        filepath.set_extension("gps");
+        filepath
+    }
+
+    pub fn get_metadata_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut filepath = path.as_ref().join(&self.name);
+        filepath.set_extension("meta.toml");
+        filepath
+    }
+
+    // The ZIP bundle that's kept while the entry is waiting to be uploaded
+    pub fn get_upload_bundle_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut filepath = path.as_ref().join(&self.name);
+        filepath.set_extension("zip");
+        filepath
+    }
 }
 
 impl RecordingStore {
@@ -113,6 +277,12 @@ impl RecordingStore {
             path,
             manifest,
             current_entry: None,
+            policy: RecordingPolicy::default(),
+            qmdl_compression: QmdlCompression::None,
+            encryption_key: None,
+            uploads_enabled: false,
+            modem_firmware: ModemFirmware::default(),
+            current_chunk_crcs: Vec::new(),
         })
     }
 
@@ -132,6 +302,12 @@ impl RecordingStore {
                 entries: Vec::new(),
             },
             current_entry: None,
+            policy: RecordingPolicy::default(),
+            qmdl_compression: QmdlCompression::None,
+            encryption_key: None,
+            uploads_enabled: false,
+            modem_firmware: ModemFirmware::default(),
+            current_chunk_crcs: Vec::new(),
         };
 
         store.write_manifest().await?;
@@ -188,8 +364,15 @@ impl RecordingStore {
                 continue;
             };
 
-            info!("successfully recovered QMDL entry {os_filename:?}!");
-            manifest_entries.push(ManifestEntry {
+            let (compression, encrypted) = match read_qmdl_format(&entry.path()).await {
+                Ok(format) => format,
+                Err(err) => {
+                    warn!("failed to read QMDL file {os_filename:?}: {err:?}, skipping");
+                    continue;
+                }
+            };
+
+            let mut manifest_entry = ManifestEntry {
                 name: stem.to_string(),
                 start_time: start_time.into(),
                 last_message_time: Some(last_message_time.into()),
@@ -197,7 +380,43 @@ impl RecordingStore {
                 rayhunter_version: None,
                 system_os: None,
                 arch: None,
-            });
+                has_warnings: None,
+                compression,
+                encrypted,
+                annotations: RecordingAnnotations::default(),
+                imported_from: None,
+                imported_at: None,
+                upload: UploadState::default(),
+                firmware_build: None,
+                baseband_version: None,
+            };
+            // the sidecar file has everything the filename doesn't. The QMDL
+            // file itself is more up to date about its size, though.
+            let metadata_path = manifest_entry.get_metadata_filepath(path.as_ref());
+            match EntryMetadata::read(&metadata_path).await {
+                Ok(sidecar) if sidecar.entry.name == manifest_entry.name => {
+                    if sidecar.entry.qmdl_size_bytes == manifest_entry.qmdl_size_bytes {
+                        manifest_entry.last_message_time = sidecar.entry.last_message_time;
+                    }
+                    manifest_entry.start_time = sidecar.entry.start_time;
+                    manifest_entry.rayhunter_version = sidecar.entry.rayhunter_version;
+                    manifest_entry.system_os = sidecar.entry.system_os;
+                    manifest_entry.arch = sidecar.entry.arch;
+                    manifest_entry.has_warnings = sidecar.entry.has_warnings;
+                    manifest_entry.annotations = sidecar.entry.annotations;
+                    manifest_entry.imported_from = sidecar.entry.imported_from;
+                    manifest_entry.imported_at = sidecar.entry.imported_at;
+                    // an encrypted file doesn't reveal its compression
+                    if encrypted {
+                        manifest_entry.compression = sidecar.entry.compression;
+                    }
+                }
+                Ok(_) => warn!("metadata file for {os_filename:?} is for a different entry, ignoring"),
+                Err(err) => info!("no usable metadata for {os_filename:?}: {err}"),
+            }
+
+            info!("successfully recovered QMDL entry {os_filename:?}!");
+            manifest_entries.push(manifest_entry);
         }
 
         // sort chronologically
@@ -209,12 +428,52 @@ impl RecordingStore {
                 entries: manifest_entries,
             },
             current_entry: None,
+            policy: RecordingPolicy::default(),
+            qmdl_compression: QmdlCompression::None,
+            encryption_key: None,
+            uploads_enabled: false,
+            modem_firmware: ModemFirmware::default(),
+            current_chunk_crcs: Vec::new(),
         };
         store.write_manifest().await?;
 
         Ok(store)
     }
 
+    pub fn with_policy(mut self, policy: RecordingPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_qmdl_compression(mut self, compression: QmdlCompression) -> Self {
+        self.qmdl_compression = compression;
+        self
+    }
+
+    pub fn with_encryption_key(mut self, key: Option<RecordingPublicKey>) -> Self {
+        self.encryption_key = key;
+        self
+    }
+
+    pub fn with_uploads(mut self, enabled: bool) -> Self {
+        self.uploads_enabled = enabled;
+        self
+    }
+
+    // Returns the key to encrypt the given entry's files to, or None if
+    // they're stored in plain text
+    pub fn entry_encryption_key(
+        &self,
+        entry_index: usize,
+    ) -> Result<Option<RecordingPublicKey>, RecordingStoreError> {
+        if !self.manifest.entries[entry_index].encrypted {
+            return Ok(None);
+        }
+        self.encryption_key
+            .map(Some)
+            .ok_or(RecordingStoreError::NoEncryptionKey)
+    }
+
     async fn read_manifest<P>(path: P) -> Result<Manifest, RecordingStoreError>
     where
         P: AsRef<Path>,
@@ -234,7 +493,11 @@ impl RecordingStore {
         if self.current_entry.is_some() {
             self.close_current_entry().await?;
         }
-        let new_entry = ManifestEntry::new();
+        let mut new_entry = ManifestEntry::new();
+        new_entry.compression = self.qmdl_compression;
+        new_entry.encrypted = self.encryption_key.is_some();
+        new_entry.firmware_build = self.modem_firmware.firmware_build.clone();
+        new_entry.baseband_version = self.modem_firmware.baseband_version.clone();
         let qmdl_filepath = new_entry.get_qmdl_filepath(&self.path);
         let qmdl_file = File::create(&qmdl_filepath)
             .await
@@ -245,10 +508,93 @@ impl RecordingStore {
             .map_err(RecordingStoreError::CreateFileError)?;
         self.manifest.entries.push(new_entry);
         self.current_entry = Some(self.manifest.entries.len() - 1);
+        self.current_chunk_crcs.clear();
         self.write_manifest().await?;
+        self.write_entry_metadata(self.manifest.entries.len() - 1)
+            .await?;
         Ok((qmdl_file, analysis_file))
     }
 
+    // Adds a finished entry for a QMDL file made elsewhere, moving the file
+    // into the store. If new entries are encrypted, an encrypted copy is
+    // stored instead, and the plain text file is left for the caller to
+    // analyze and delete. Like entries recorded here, it's named after its
+    // start time. Returns the new entry's index.
+    pub async fn import_entry(
+        &mut self,
+        qmdl_path: &Path,
+        recording: ImportedRecording,
+    ) -> Result<usize, RecordingStoreError> {
+        let mut timestamp = recording.start_time.timestamp();
+        let name = loop {
+            let name = timestamp.to_string();
+            let taken = self.entry_for_name(&name).is_some()
+                || tokio::fs::try_exists(self.path.join(format!("{name}.qmdl")))
+                    .await
+                    .unwrap_or(true);
+            if !taken {
+                break name;
+            }
+            timestamp += 1;
+        };
+        let qmdl_size_bytes = tokio::fs::metadata(qmdl_path)
+            .await
+            .map_err(RecordingStoreError::ReadFileError)?
+            .len() as usize;
+        let mut entry = ManifestEntry {
+            name,
+            start_time: recording.start_time,
+            last_message_time: Some(recording.last_message_time),
+            qmdl_size_bytes,
+            // there's no telling what it was recorded with
+            rayhunter_version: None,
+            system_os: None,
+            arch: None,
+            compression: recording.compression,
+            imported_from: Some(recording.source),
+            imported_at: Some(Local::now()),
+            ..ManifestEntry::new()
+        };
+
+        let chunk_crcs = match self.encryption_key {
+            Some(key) => {
+                let stored_path = entry.get_qmdl_filepath(&self.path);
+                let result = write_encrypted_copy(qmdl_path, &stored_path, &key).await;
+                let (size, chunk_crcs) = match result {
+                    Ok(copied) => copied,
+                    Err(e) => {
+                        let _ = fs::remove_file(&stored_path).await;
+                        return Err(RecordingStoreError::CreateFileError(e));
+                    }
+                };
+                entry.qmdl_size_bytes = size;
+                entry.encrypted = true;
+                chunk_crcs
+            }
+            None => {
+                tokio::fs::rename(qmdl_path, entry.get_qmdl_filepath(&self.path))
+                    .await
+                    .map_err(RecordingStoreError::CreateFileError)?;
+                recording.chunk_crcs
+            }
+        };
+        File::create(entry.get_analysis_filepath(&self.path))
+            .await
+            .map_err(RecordingStoreError::CreateFileError)?;
+        let metadata = EntryMetadata {
+            chunk_crcs,
+            entry: entry.clone(),
+        };
+        let contents = toml::to_string(&metadata).expect("failed to serialize entry metadata");
+        write_atomically(&entry.get_metadata_filepath(&self.path), contents.as_bytes(), true)
+            .await
+            .map_err(RecordingStoreError::WriteMetadataError)?;
+
+        self.manifest.entries.push(entry);
+        self.write_manifest().await?;
+        Ok(self.manifest.entries.len() - 1)
+    }
+
     // Returns the corresponding QMDL file for a given entry
     pub async fn open_entry_qmdl(&self, entry_index: usize) -> Result<File, RecordingStoreError> {
         let entry = &self.manifest.entries[entry_index];
@@ -282,17 +628,80 @@ impl RecordingStore {
         Ok(file)
     }
 
-    // Unsets the current entry
+    // Returns the corresponding signal samples file for a given entry
+    pub async fn open_entry_signal(&self, entry_index: usize) -> Result<File, RecordingStoreError> {
+        let entry = &self.manifest.entries[entry_index];
+        File::open(entry.get_signal_filepath(&self.path))
+            .await
+            .map_err(RecordingStoreError::ReadFileError)
+    }
+
+    // Creates or truncates the signal samples file for a given entry, which
+    // entries made before there were signal samples don't have
+    pub async fn clear_and_open_entry_signal(
+        &self,
+        entry_index: usize,
+    ) -> Result<File, RecordingStoreError> {
+        let entry = &self.manifest.entries[entry_index];
+        File::create(entry.get_signal_filepath(&self.path))
+            .await
+            .map_err(RecordingStoreError::CreateFileError)
+    }
+
+    // Unsets the current entry, saving its final metadata and queueing it to
+    // be uploaded if uploads are enabled
     pub async fn close_current_entry(&mut self) -> Result<(), RecordingStoreError> {
         match self.current_entry {
-            Some(_) => {
+            Some(entry_index) => {
+                let result = self.write_entry_metadata(entry_index).await;
                 self.current_entry = None;
-                Ok(())
+                self.current_chunk_crcs.clear();
+                if self.uploads_enabled {
+                    self.manifest.entries[entry_index].upload.status = UploadStatus::Pending;
+                    self.write_manifest().await?;
+                }
+                result
             }
             None => Err(RecordingStoreError::NoCurrentEntry),
         }
     }
 
+    // Records the checksums of the current entry's QMDL file, updating its
+    // metadata file if there are new ones
+    pub async fn update_entry_checksums(
+        &mut self,
+        entry_index: usize,
+        chunk_crcs: &[u32],
+    ) -> Result<(), RecordingStoreError> {
+        if self.current_entry != Some(entry_index) || self.current_chunk_crcs == chunk_crcs {
+            return Ok(());
+        }
+        self.current_chunk_crcs = chunk_crcs.to_vec();
+        self.write_entry_metadata(entry_index).await
+    }
+
+    async fn write_entry_metadata(&self, entry_index: usize) -> Result<(), RecordingStoreError> {
+        let entry = &self.manifest.entries[entry_index];
+        let metadata_path = entry.get_metadata_filepath(&self.path);
+        // the checksums of a finished entry are only in its metadata file
+        let chunk_crcs = if self.current_entry == Some(entry_index) {
+            self.current_chunk_crcs.clone()
+        } else {
+            EntryMetadata::read(&metadata_path)
+                .await
+                .map(|metadata| metadata.chunk_crcs)
+                .unwrap_or_default()
+        };
+        let metadata = EntryMetadata {
+            chunk_crcs,
+            entry: entry.clone(),
+        };
+        let contents = toml::to_string(&metadata).expect("failed to serialize entry metadata");
+        write_atomically(&metadata_path, contents.as_bytes(), true)
+            .await
+            .map_err(RecordingStoreError::WriteMetadataError)
+    }
+
     // Sets the given entry's size and updates the last_message_time to now, updating the manifest
     pub async fn update_entry_qmdl_size(
         &mut self,
@@ -301,29 +710,174 @@ impl RecordingStore {
     ) -> Result<(), RecordingStoreError> {
         self.manifest.entries[entry_index].qmdl_size_bytes = size_bytes;
         self.manifest.entries[entry_index].last_message_time = Some(Local::now());
+        // this happens for every container written, which is too often to sync
+        // to flash storage. If it's lost, recovery gets the size from the QMDL
+        // file itself.
+        self.write_manifest_synced(false).await
+    }
+
+    // Records whether analysis found warnings in the given entry, updating the
+    // manifest if needed. Entries with warnings are exempt from retention, and
+    // stay that way even if analyzing them again finds none.
+    pub async fn set_entry_has_warnings(
+        &mut self,
+        entry_index: usize,
+        has_warnings: bool,
+    ) -> Result<(), RecordingStoreError> {
+        let entry = &mut self.manifest.entries[entry_index];
+        let updated = Some(has_warnings || entry.has_warnings.unwrap_or(false));
+        if entry.has_warnings == updated {
+            return Ok(());
+        }
+        entry.has_warnings = updated;
+        self.write_manifest().await
+    }
+
+    // Replaces the given entry's annotations, updating the manifest and its
+    // metadata file
+    pub async fn set_entry_annotations(
+        &mut self,
+        entry_index: usize,
+        annotations: RecordingAnnotations,
+    ) -> Result<(), RecordingStoreError> {
+        self.manifest.entries[entry_index].annotations = annotations;
+        self.write_manifest().await?;
+        self.write_entry_metadata(entry_index).await
+    }
+
+    // Replaces the given entry's upload state, updating the manifest
+    pub async fn set_entry_upload(
+        &mut self,
+        entry_index: usize,
+        upload: UploadState,
+    ) -> Result<(), RecordingStoreError> {
+        self.manifest.entries[entry_index].upload = upload;
         self.write_manifest().await
     }
 
+    // Whether the current entry has reached the maximum size or duration and a
+    // new one should be started
+    pub fn should_rotate(&self) -> bool {
+        match self.get_current_entry() {
+            Some((_, entry)) => self.policy.should_rotate(entry, Local::now()),
+            None => false,
+        }
+    }
+
+    // Deletes old entries according to the retention policy, returning the
+    // names of the deleted entries
+    pub async fn enforce_retention(&mut self) -> Result<Vec<String>, RecordingStoreError> {
+        self.enforce_retention_at(Local::now(), crate::stats::disk_used_percent)
+            .await
+    }
+
+    async fn enforce_retention_at<F>(
+        &mut self,
+        now: DateTime<Local>,
+        disk_used_percent: F,
+    ) -> Result<Vec<String>, RecordingStoreError>
+    where
+        F: Fn(&str) -> Result<u8, String>,
+    {
+        let mut deleted = Vec::new();
+        let mut total_size = 0;
+        for entry in &self.manifest.entries {
+            total_size += self.entry_disk_usage(entry).await;
+        }
+        loop {
+            let Some(name) = self.next_entry_to_delete(now, total_size, &disk_used_percent) else {
+                return Ok(deleted);
+            };
+            if let Some((_, entry)) = self.entry_for_name(&name) {
+                total_size = total_size.saturating_sub(self.entry_disk_usage(entry).await);
+            }
+            info!("deleting recording {name} to satisfy the retention policy");
+            self.delete_entry(&name).await?;
+            deleted.push(name);
+        }
+    }
+
+    // How much space all of the entry's files take up. The QMDL file's size
+    // comes from the manifest if it can't be checked.
+    async fn entry_disk_usage(&self, entry: &ManifestEntry) -> u64 {
+        let mut total = file_size(&entry.get_qmdl_filepath(&self.path))
+            .await
+            .unwrap_or(entry.qmdl_size_bytes as u64);
+        for path in [
+            entry.get_analysis_filepath(&self.path),
+            entry.get_signal_filepath(&self.path),
+            entry.get_gps_filepath(&self.path),
+            entry.get_metadata_filepath(&self.path),
+            entry.get_upload_bundle_filepath(&self.path),
+        ] {
+            total += file_size(&path).await.unwrap_or(0);
+        }
+        total
+    }
+
+    // The oldest entry that may be deleted, if any limit is being exceeded.
+    // `total_size` is how much space all of the entries take up.
+    fn next_entry_to_delete<F>(
+        &self,
+        now: DateTime<Local>,
+        total_size: u64,
+        disk_used_percent: F,
+    ) -> Option<String>
+    where
+        F: Fn(&str) -> Result<u8, String>,
+    {
+        let policy = &self.policy;
+        let low_disk = policy.min_free_disk_percent.is_some_and(|min_free| {
+            match disk_used_percent(self.path.to_str().unwrap_or_default()) {
+                Ok(used) => 100u8.saturating_sub(used) < min_free,
+                Err(e) => {
+                    warn!("couldn't check free disk space: {e}");
+                    false
+                }
+            }
+        });
+        // recordings waiting to be uploaded are kept for the upload worker,
+        // unless there isn't one or they're in the way of freeing up the disk
+        let keep_unfinished_uploads = self.uploads_enabled && !low_disk;
+        let oldest = self
+            .manifest
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(idx, entry)| {
+                self.current_entry != Some(*idx)
+                    && entry.has_warnings == Some(false)
+                    && !(keep_unfinished_uploads && entry.upload.is_unfinished())
+            })
+            .map(|(_, entry)| entry)
+            .min_by_key(|entry| entry.stored_at())?;
+
+        let too_many = policy
+            .max_recordings
+            .is_some_and(|max| self.manifest.entries.len() > max);
+        let too_big = policy
+            .max_total_size_mb
+            .is_some_and(|max| total_size > max * 1024 * 1024);
+        let too_old = policy
+            .max_age_days
+            .and_then(|max| TimeDelta::try_days(max.try_into().ok()?))
+            .is_some_and(|max| now - oldest.last_activity() > max);
+
+        (too_many || too_big || too_old || low_disk).then(|| oldest.name.clone())
+    }
+
     async fn write_manifest(&mut self) -> Result<(), RecordingStoreError> {
+        self.write_manifest_synced(true).await
+    }
+
+    async fn write_manifest_synced(&mut self, sync: bool) -> Result<(), RecordingStoreError> {
         // we don't technically need a mutable reference to `self` here, but it
         // does prevent multiple concurrent writes across different threads
-        let tmp_path = self.path.join("manifest.toml.new");
-        let mut manifest_tmp_file = File::create(&tmp_path)
-            .await
-            .map_err(RecordingStoreError::WriteManifestError)?;
-
         let manifest_contents =
             toml::to_string_pretty(&self.manifest).expect("failed to serialize manifest");
-        manifest_tmp_file
-            .write_all(manifest_contents.as_bytes())
-            .await
-            .map_err(RecordingStoreError::WriteManifestError)?;
-
-        fs::rename(tmp_path, self.path.join("manifest.toml"))
+        write_atomically(&self.path.join("manifest.toml"), manifest_contents.as_bytes(), sync)
             .await
-            .map_err(RecordingStoreError::WriteManifestError)?;
-
-        Ok(())
+            .map_err(RecordingStoreError::WriteManifestError)
     }
 
     // Finds an entry by filename
@@ -336,6 +890,33 @@ impl RecordingStore {
         Some((entry_index, &self.manifest.entries[entry_index]))
     }
 
+    /// Finds the entry that was recording at the given time. The current entry
+    /// matches anything after its start; finished entries match up to their last
+    /// received message.
+    pub fn entry_for_timestamp<Tz: chrono::TimeZone>(
+        &self,
+        timestamp: &DateTime<Tz>,
+    ) -> Option<(usize, &ManifestEntry)> {
+        let timestamp = timestamp.with_timezone(&Local);
+        self.manifest
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(idx, entry)| {
+                if entry.start_time > timestamp {
+                    return false;
+                }
+                if self.current_entry == Some(*idx) {
+                    return true;
+                }
+                match entry.last_message_time {
+                    Some(last_message_time) => timestamp <= last_message_time,
+                    None => false,
+                }
+            })
+            .max_by_key(|(_, entry)| entry.start_time)
+    }
+
     pub fn get_current_entry(&self) -> Option<(usize, &ManifestEntry)> {
         let entry_index = self.current_entry?;
         Some((entry_index, &self.manifest.entries[entry_index]))
@@ -362,21 +943,37 @@ impl RecordingStore {
             Some(current_entry) if current_entry == entry_to_delete_idx => {
                 self.close_current_entry().await?;
             }
-            Some(current_entry) => {
+            Some(current_entry) if current_entry > entry_to_delete_idx => {
                 self.current_entry = Some(current_entry - 1);
             }
-            None => {}
+            _ => {}
         };
         let entry_to_delete = self.manifest.entries.remove(entry_to_delete_idx);
         self.write_manifest().await?;
         let qmdl_filepath = entry_to_delete.get_qmdl_filepath(&self.path);
         let analysis_filepath = entry_to_delete.get_analysis_filepath(&self.path);
+        let metadata_filepath = entry_to_delete.get_metadata_filepath(&self.path);
+        let bundle_filepath = entry_to_delete.get_upload_bundle_filepath(&self.path);
+        let signal_filepath = entry_to_delete.get_signal_filepath(&self.path);
+        let gps_filepath = entry_to_delete.get_gps_filepath(&self.path);
         remove_file_if_exists(&qmdl_filepath)
             .await
             .map_err(RecordingStoreError::DeleteFileError)?;
         remove_file_if_exists(&analysis_filepath)
             .await
             .map_err(RecordingStoreError::DeleteFileError)?;
+        remove_file_if_exists(&metadata_filepath)
+            .await
+            .map_err(RecordingStoreError::DeleteFileError)?;
+        remove_file_if_exists(&bundle_filepath)
+            .await
+            .map_err(RecordingStoreError::DeleteFileError)?;
+        remove_file_if_exists(&signal_filepath)
+            .await
+            .map_err(RecordingStoreError::DeleteFileError)?;
+        remove_file_if_exists(&gps_filepath)
+            .await
+            .map_err(RecordingStoreError::DeleteFileError)?;
         Ok(())
     }
 
@@ -403,6 +1000,26 @@ impl RecordingStore {
                 continue;
             }
 
+            let metadata_filepath = entry.get_metadata_filepath(&self.path);
+            if let Err(e) = remove_file_if_exists(&metadata_filepath).await {
+                log::warn!("failed to remove {metadata_filepath:?}: {e:?}");
+            }
+
+            let bundle_filepath = entry.get_upload_bundle_filepath(&self.path);
+            if let Err(e) = remove_file_if_exists(&bundle_filepath).await {
+                log::warn!("failed to remove {bundle_filepath:?}: {e:?}");
+            }
+
+            let signal_filepath = entry.get_signal_filepath(&self.path);
+            if let Err(e) = remove_file_if_exists(&signal_filepath).await {
+                log::warn!("failed to remove {signal_filepath:?}: {e:?}");
+            }
+
+            let gps_filepath = entry.get_gps_filepath(&self.path);
+            if let Err(e) = remove_file_if_exists(&gps_filepath).await {
+                log::warn!("failed to remove {gps_filepath:?}: {e:?}");
+            }
+
             keep.push(false);
         }
 
@@ -411,6 +1028,101 @@ impl RecordingStore {
         self.write_manifest().await?;
         Ok(())
     }
+
+    /// Loads the baseline saved by [`RecordingStore::save_baseline`], or an
+    /// empty one if there isn't any
+    pub async fn load_baseline(&self) -> Result<Baseline, RecordingStoreError> {
+        let contents = match fs::read(self.path.join(BASELINE_FILE_NAME)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Baseline::default()),
+            Err(e) => return Err(RecordingStoreError::ReadBaselineError(e)),
+        };
+        serde_json::from_slice(&contents).map_err(RecordingStoreError::ParseBaselineError)
+    }
+
+    pub async fn save_baseline(&self, baseline: &Baseline) -> Result<(), RecordingStoreError> {
+        let contents = serde_json::to_vec(baseline).expect("failed to serialize baseline");
+        write_atomically(&self.path.join(BASELINE_FILE_NAME), &contents, true)
+            .await
+            .map_err(RecordingStoreError::WriteBaselineError)
+    }
+
+    pub async fn delete_baseline(&self) -> Result<(), RecordingStoreError> {
+        match fs::remove_file(self.path.join(BASELINE_FILE_NAME)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(RecordingStoreError::WriteBaselineError(e)),
+        }
+    }
+}
+
+// Writes a copy of a plain text file to `path`, encrypted to `key`, returning
+// the size and checksums of the encrypted copy
+async fn write_encrypted_copy(
+    plain_path: &Path,
+    path: &Path,
+    key: &RecordingPublicKey,
+) -> io::Result<(usize, Vec<u32>)> {
+    let mut reader = BufReader::with_capacity(64 * 1024, File::open(plain_path).await?);
+    let mut writer =
+        EncryptingWriter::new(ChecksumWriter::new(File::create(path).await?), Some(key));
+    tokio::io::copy_buf(&mut reader, &mut writer).await?;
+    writer.flush().await?;
+    let checksum_writer = writer.get_ref();
+    Ok((
+        checksum_writer.bytes_written(),
+        checksum_writer.checksums().all(),
+    ))
+}
+
+// Replaces the file at `path` with `contents` by writing a temporary file and
+// renaming it over `path`. With `sync`, this survives a power loss: the
+// temporary file is synced before the rename, and the directory after it so
+// the rename itself is persisted.
+async fn write_atomically(path: &Path, contents: &[u8], sync: bool) -> Result<(), io::Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".new");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut tmp_file = File::create(&tmp_path).await?;
+    tmp_file.write_all(contents).await?;
+    if sync {
+        tmp_file.sync_all().await?;
+    } else {
+        tmp_file.flush().await?;
+    }
+    drop(tmp_file);
+
+    fs::rename(&tmp_path, path).await?;
+    if sync {
+        if let Some(dir) = path.parent() {
+            File::open(dir).await?.sync_all().await?;
+        }
+    }
+    Ok(())
+}
+
+// Checks a QMDL file's magic bytes to see whether it's compressed or
+// encrypted. The compression of an encrypted file can't be known without
+// decrypting it.
+async fn read_qmdl_format(path: &Path) -> Result<(QmdlCompression, bool), io::Error> {
+    let mut magic = Vec::with_capacity(COMPRESSED_QMDL_MAGIC.len());
+    File::open(path)
+        .await?
+        .take(COMPRESSED_QMDL_MAGIC.len().max(ENCRYPTION_MAGIC.len()) as u64)
+        .read_to_end(&mut magic)
+        .await?;
+    if magic.starts_with(ENCRYPTION_MAGIC) {
+        Ok((QmdlCompression::None, true))
+    } else if magic.starts_with(COMPRESSED_QMDL_MAGIC) {
+        Ok((QmdlCompression::Lz4, false))
+    } else {
+        Ok((QmdlCompression::None, false))
+    }
+}
+
+async fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path).await.ok().map(|metadata| metadata.len())
 }
 
 async fn remove_file_if_exists(path: &Path) -> Result<(), io::Error> {
@@ -423,6 +1135,9 @@ async fn remove_file_if_exists(path: &Path) -> Result<(), io::Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::integrity::{EntryIntegrity, verify_entry};
+    use chrono::TimeZone;
+    use rayhunter::encryption::{RecordingSecretKey, decrypt};
     use tempfile::{Builder, TempDir};
 
     fn make_temp_dir() -> TempDir {
@@ -502,6 +1217,23 @@ mod tests {
         assert_eq!(store.manifest.entries.len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_entries_record_modem_firmware() {
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        store.modem_firmware = ModemFirmware {
+            firmware_build: Some("MPSS.JO.2.0.2.c1-00064".to_string()),
+            baseband_version: None,
+        };
+        let _ = store.new_entry().await.unwrap();
+        let (_, entry) = store.get_current_entry().unwrap();
+        assert_eq!(entry.modem_firmware(), store.modem_firmware);
+        assert_eq!(
+            RecordingStore::read_manifest(dir.path()).await.unwrap(),
+            store.manifest
+        );
+    }
+
     #[tokio::test]
     async fn test_delete_all_entries() {
         let dir = make_temp_dir();
@@ -517,4 +1249,316 @@ mod tests {
         store.delete_all_entries().await.unwrap();
         assert!(store.current_entry.is_none());
     }
+
+    #[tokio::test]
+    async fn test_entry_for_timestamp() {
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let first_index = store.current_entry.unwrap();
+        store.update_entry_qmdl_size(first_index, 10).await.unwrap();
+        let first = store.manifest.entries[first_index].clone();
+        store.close_current_entry().await.unwrap();
+
+        let inside = first.start_time;
+        let (idx, _) = store.entry_for_timestamp(&inside).unwrap();
+        assert_eq!(idx, first_index);
+        let before = first.start_time - chrono::Duration::seconds(1);
+        assert!(store.entry_for_timestamp(&before).is_none());
+        let after = first.last_message_time.unwrap() + chrono::Duration::seconds(60);
+        assert!(store.entry_for_timestamp(&after).is_none());
+
+        // the current entry accepts any fix after it started
+        let _ = store.new_entry().await.unwrap();
+        let second_index = store.current_entry.unwrap();
+        let later = store.manifest.entries[second_index].start_time + chrono::Duration::hours(1);
+        let (idx, _) = store.entry_for_timestamp(&later).unwrap();
+        assert_eq!(idx, second_index);
+    }
+
+    fn entry(name: &str, start_time: DateTime<Local>, size_mb: usize) -> ManifestEntry {
+        ManifestEntry {
+            name: name.to_string(),
+            start_time,
+            last_message_time: Some(start_time),
+            qmdl_size_bytes: size_mb * 1024 * 1024,
+            ..ManifestEntry::new()
+        }
+    }
+
+    #[test]
+    fn test_should_rotate() {
+        let now = Local::now();
+        let policy = RecordingPolicy {
+            max_recording_size_mb: Some(2),
+            max_recording_duration_mins: Some(60),
+            ..Default::default()
+        };
+        assert!(!policy.should_rotate(&entry("a", now - TimeDelta::minutes(59), 1), now));
+        assert!(policy.should_rotate(&entry("a", now - TimeDelta::minutes(60), 1), now));
+        assert!(policy.should_rotate(&entry("a", now, 2), now));
+        assert!(!RecordingPolicy::default().should_rotate(&entry("a", now - TimeDelta::days(365), 1000), now));
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let dir = make_temp_dir();
+        let now = Local::now();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_policy(RecordingPolicy {
+                max_total_size_mb: Some(3),
+                max_age_days: Some(7),
+                max_recordings: Some(4),
+                ..Default::default()
+            });
+        let mut flagged = entry("flagged", now - TimeDelta::days(9), 1);
+        flagged.has_warnings = Some(true);
+        store.manifest.entries = vec![
+            entry("old", now - TimeDelta::days(10), 0),
+            flagged,
+            entry("a", now - TimeDelta::days(3), 1),
+            entry("b", now - TimeDelta::days(2), 1),
+            entry("c", now - TimeDelta::days(1), 1),
+            entry("current", now, 1),
+        ];
+        store.current_entry = Some(5);
+
+        // "old" is too old, then "a" goes to get down to 4 recordings and "b"
+        // to get down to 3MB. Recordings with warnings are kept regardless.
+        let deleted = store.enforce_retention_at(now, |_| Ok(0)).await.unwrap();
+        assert_eq!(deleted, vec!["old", "a", "b"]);
+        let (_, current) = store.get_current_entry().unwrap();
+        assert_eq!(current.name, "current");
+        assert_eq!(
+            RecordingStore::read_manifest(dir.path()).await.unwrap(),
+            store.manifest
+        );
+
+        // a nearly full disk deletes everything that isn't protected
+        store.policy.min_free_disk_percent = Some(10);
+        let deleted = store.enforce_retention_at(now, |_| Ok(95)).await.unwrap();
+        assert_eq!(deleted, vec!["c"]);
+        let names: Vec<_> = store.manifest.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["flagged", "current"]);
+
+        // recordings waiting to be uploaded are kept while uploads are
+        // enabled, but not when the disk is nearly full
+        let mut queued = entry("queued", now - TimeDelta::days(30), 1);
+        queued.upload.status = UploadStatus::Pending;
+        store.manifest.entries.insert(0, queued.clone());
+        store.uploads_enabled = true;
+        store.policy.min_free_disk_percent = None;
+        let deleted = store.enforce_retention_at(now, |_| Ok(95)).await.unwrap();
+        assert!(deleted.is_empty());
+        store.policy.min_free_disk_percent = Some(10);
+        let deleted = store.enforce_retention_at(now, |_| Ok(95)).await.unwrap();
+        assert_eq!(deleted, vec!["queued"]);
+
+        // and without uploads, nothing's going to upload them
+        store.manifest.entries.insert(0, queued);
+        store.uploads_enabled = false;
+        let deleted = store.enforce_retention_at(now, |_| Ok(0)).await.unwrap();
+        assert_eq!(deleted, vec!["queued"]);
+    }
+
+    #[tokio::test]
+    async fn test_retention_counts_every_file() {
+        let dir = make_temp_dir();
+        let now = Local::now();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_policy(RecordingPolicy {
+                max_total_size_mb: Some(1),
+                ..Default::default()
+            });
+        let mut legacy = entry("legacy", now - TimeDelta::days(3), 0);
+        legacy.has_warnings = None;
+        store.manifest.entries = vec![
+            legacy,
+            entry("a", now - TimeDelta::days(2), 0),
+            entry("b", now - TimeDelta::days(1), 0),
+        ];
+        let bundle_path = store.manifest.entries[1].get_upload_bundle_filepath(dir.path());
+        let gps_path = store.manifest.entries[1].get_gps_filepath(dir.path());
+        fs::write(&bundle_path, vec![0; 1024 * 1024]).await.unwrap();
+        fs::write(&gps_path, b"1700000000, 1.0, 2.0\n")
+            .await
+            .unwrap();
+
+        // the upload bundle alone puts "a" over the limit, and the recording
+        // from before warnings were kept track of isn't touched
+        let deleted = store.enforce_retention_at(now, |_| Ok(0)).await.unwrap();
+        assert_eq!(deleted, vec!["a"]);
+        assert!(!try_exists(&bundle_path).await.unwrap());
+        assert!(!try_exists(&gps_path).await.unwrap());
+        let names: Vec<_> = store
+            .manifest
+            .entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["legacy", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_entry_keeps_current_entry() {
+        let dir = make_temp_dir();
+        let now = Local::now();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        store.manifest.entries = vec![
+            entry("a", now - TimeDelta::days(2), 1),
+            entry("current", now - TimeDelta::days(1), 1),
+            entry("imported", now - TimeDelta::days(30), 1),
+        ];
+        store.current_entry = Some(1);
+
+        // deleting an entry after the current one leaves its index alone
+        store.delete_entry("imported").await.unwrap();
+        assert_eq!(store.get_current_entry().unwrap().1.name, "current");
+        store.delete_entry("a").await.unwrap();
+        assert_eq!(store.get_current_entry().unwrap().1.name, "current");
+    }
+
+    #[tokio::test]
+    async fn test_retention_of_imported_entries() {
+        let dir = make_temp_dir();
+        let now = Local::now();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_policy(RecordingPolicy {
+                max_age_days: Some(7),
+                max_recordings: Some(2),
+                ..Default::default()
+            });
+        let mut imported = entry("imported", now - TimeDelta::days(365), 1);
+        imported.imported_at = Some(now - TimeDelta::hours(1));
+        store.manifest.entries = vec![
+            entry("a", now - TimeDelta::days(2), 1),
+            entry("b", now - TimeDelta::days(1), 1),
+            imported,
+        ];
+
+        // a recording imported just now isn't too old, and counts as newer
+        // than the ones recorded here
+        let deleted = store.enforce_retention_at(now, |_| Ok(0)).await.unwrap();
+        assert_eq!(deleted, vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_entries() {
+        let dir = make_temp_dir();
+        let key = RecordingSecretKey::generate().public_key();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_encryption_key(Some(key));
+        let (mut qmdl_file, _) = store.new_entry().await.unwrap();
+        let index = store.current_entry.unwrap();
+        assert!(store.manifest.entries[index].encrypted);
+        assert_eq!(store.entry_encryption_key(index).unwrap(), Some(key));
+
+        let mut writer = EncryptingWriter::new(&mut qmdl_file, Some(&key));
+        writer.write_all(b"\x7e\x00\x7e").await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+        store.close_current_entry().await.unwrap();
+
+        // without the key, new entries are stored in plain text and the
+        // encrypted one can't be appended to
+        let mut store = RecordingStore::recover(dir.path()).await.unwrap();
+        assert!(store.manifest.entries[index].encrypted);
+        assert!(matches!(
+            store.entry_encryption_key(index),
+            Err(RecordingStoreError::NoEncryptionKey)
+        ));
+        let _ = store.new_entry().await.unwrap();
+        let plain_index = store.current_entry.unwrap();
+        assert!(!store.manifest.entries[plain_index].encrypted);
+        assert_eq!(store.entry_encryption_key(plain_index).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_import_entry() {
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let start_time = Local.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let recording = ImportedRecording {
+            start_time,
+            last_message_time: start_time + TimeDelta::minutes(5),
+            compression: QmdlCompression::None,
+            source: "other.qmdl".to_string(),
+            chunk_crcs: vec![1234],
+        };
+        let staged = dir.path().join(".import.tmp");
+        tokio::fs::write(&staged, b"\x7e\x00\x7e").await.unwrap();
+        let index = store.import_entry(&staged, recording.clone()).await.unwrap();
+        assert!(!tokio::fs::try_exists(&staged).await.unwrap());
+
+        // importing a recording with the same start time picks the next free
+        // name
+        tokio::fs::write(&staged, b"\x7e\x00\x7e").await.unwrap();
+        let second_index = store.import_entry(&staged, recording).await.unwrap();
+        let entry = &store.manifest.entries[index];
+        assert_eq!(entry.name, start_time.timestamp().to_string());
+        assert_eq!(entry.qmdl_size_bytes, 3);
+        assert_eq!(entry.imported_from.as_deref(), Some("other.qmdl"));
+        assert!(entry.imported_at.is_some());
+        assert_eq!(
+            store.manifest.entries[second_index].name,
+            (start_time.timestamp() + 1).to_string()
+        );
+        assert_eq!(store.current_entry, None);
+
+        let recovered = RecordingStore::recover(dir.path()).await.unwrap();
+        let (_, entry) = recovered.entry_for_name(&entry.name).unwrap();
+        assert_eq!(entry.imported_from.as_deref(), Some("other.qmdl"));
+    }
+
+    #[tokio::test]
+    async fn test_import_encrypted_entry() {
+        let dir = make_temp_dir();
+        let secret_key = RecordingSecretKey::generate();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_encryption_key(Some(secret_key.public_key()));
+        let start_time = Local.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let recording = ImportedRecording {
+            start_time,
+            last_message_time: start_time + TimeDelta::minutes(5),
+            compression: QmdlCompression::None,
+            source: "other.qmdl".to_string(),
+            chunk_crcs: vec![1234],
+        };
+        let staged = dir.path().join(".import.tmp");
+        tokio::fs::write(&staged, b"\x7e\x00\x7e").await.unwrap();
+        let index = store.import_entry(&staged, recording).await.unwrap();
+        // the plain text is left to be analyzed
+        assert!(tokio::fs::try_exists(&staged).await.unwrap());
+
+        let entry = &store.manifest.entries[index];
+        assert!(entry.encrypted);
+        let stored = tokio::fs::read(entry.get_qmdl_filepath(dir.path()))
+            .await
+            .unwrap();
+        assert!(stored.starts_with(ENCRYPTION_MAGIC));
+        assert_eq!(entry.qmdl_size_bytes, stored.len());
+        let mut decrypted = Vec::new();
+        decrypt(stored.as_slice(), &mut decrypted, &secret_key)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, b"\x7e\x00\x7e");
+
+        // the checksums are of the encrypted file
+        assert_eq!(
+            verify_entry(dir.path(), entry).await.unwrap(),
+            EntryIntegrity::Ok {
+                checked_bytes: stored.len()
+            }
+        );
+    }
 }
@@ -9,10 +9,15 @@ use axum::extract::State;
 use axum::http::header::{self, CONTENT_LENGTH, CONTENT_TYPE};
 use axum::http::{HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use futures::TryStreamExt;
 use log::{error, warn};
+use rayhunter::baseline::SharedBaseline;
+use rayhunter::diag_device::{LogCodeSelection, ModemInfoHandle};
+use rayhunter::qmdl::{QmdlCompression, QmdlReader};
+use std::pin::pin;
 use std::sync::Arc;
 use tokio::fs::write;
-use tokio::io::{AsyncReadExt, copy, duplex};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, copy, duplex};
 use tokio::sync::mpsc::Sender;
 use tokio::sync::{RwLock, oneshot};
 use tokio_util::compat::FuturesAsyncWriteCompatExt;
@@ -21,10 +26,12 @@ use tokio_util::io::ReaderStream;
 use crate::DiagDeviceCtrlMessage;
 use crate::analysis::{AnalysisCtrlMessage, AnalysisStatus};
 use crate::config::Config;
+use crate::diag::DiagHealth;
 use crate::display::DisplayState;
 use crate::gps_logger::GpsLogger;
+use crate::notifications::SharedOutbox;
 use crate::pcap::generate_pcap_data;
-use crate::qmdl_store::RecordingStore;
+use crate::qmdl_store::{ManifestEntry, RecordingStore};
 
 pub struct ServerState {
     pub config_path: String,
@@ -36,6 +43,29 @@ pub struct ServerState {
     pub daemon_restart_tx: Arc<RwLock<Option<oneshot::Sender<()>>>>,
     pub ui_update_sender: Option<Sender<DisplayState>>,
     pub gps_logger: Arc<GpsLogger>,
+    pub notification_outbox: SharedOutbox,
+    /// Log codes to enable when the next recording starts
+    pub log_code_selection_lock: Arc<RwLock<LogCodeSelection>>,
+    /// The cells usually seen, if `baseline_enabled` is set
+    pub baseline: Option<SharedBaseline>,
+    pub diag_health_lock: Arc<RwLock<DiagHealth>>,
+    /// What the modem reports about itself, except in debug mode
+    pub modem_info: Option<ModemInfoHandle>,
+}
+
+// Encrypted recordings can only be downloaded as they are, since the device
+// doesn't have the key to read them
+pub fn ensure_not_encrypted(entry: &ManifestEntry) -> Result<(), (StatusCode, String)> {
+    if entry.encrypted {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "recording {} is encrypted, download it and decrypt it with rayhunter-check",
+                entry.name
+            ),
+        ));
+    }
+    Ok(())
 }
 
 pub async fn get_qmdl(
@@ -58,6 +88,14 @@ pub async fn get_qmdl(
             )
         })?;
     let limited_qmdl_file = qmdl_file.take(entry.qmdl_size_bytes as u64);
+    // encrypted files are sent as they are, compressed or not
+    if entry.compression != QmdlCompression::None && !entry.encrypted {
+        // the uncompressed size isn't known up front, so this is sent chunked
+        let reader = QmdlReader::new(limited_qmdl_file, Some(entry.qmdl_size_bytes));
+        let headers = [(CONTENT_TYPE, "application/octet-stream")];
+        let body = Body::from_stream(reader.into_raw_stream());
+        return Ok((headers, body).into_response());
+    }
     let qmdl_stream = ReaderStream::new(limited_qmdl_file);
 
     let headers = [
@@ -80,7 +118,7 @@ pub async fn get_gps(
     ))?;
     
     // Check if GPS file exists
-    let gps_file_path = qmdl_store.path.join(format!("{}.gps", entry.name));
+    let gps_file_path = entry.get_gps_filepath(&qmdl_store.path);
     let gps_file = tokio::fs::File::open(&gps_file_path).await.map_err(|err| {
         (
             StatusCode::NOT_FOUND,
@@ -159,6 +197,11 @@ pub async fn set_config(
     State(state): State<Arc<ServerState>>,
     Json(config): Json<Config>,
 ) -> Result<(StatusCode, String), (StatusCode, String)> {
+    // the daemon wouldn't start with it
+    config
+        .diag_logs
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid diag_logs: {e}")))?;
     let config_str = toml::to_string_pretty(&config).map_err(|err| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -199,9 +242,9 @@ pub async fn get_zip(
     Path(entry_name): Path<String>,
 ) -> Result<Response, (StatusCode, String)> {
     let qmdl_idx = entry_name.trim_end_matches(".zip").to_owned();
-    let (entry_index, qmdl_size_bytes) = {
+    {
         let qmdl_store = state.qmdl_store_lock.read().await;
-        let (entry_index, entry) = qmdl_store.entry_for_name(&qmdl_idx).ok_or((
+        let (_, entry) = qmdl_store.entry_for_name(&qmdl_idx).ok_or((
             StatusCode::NOT_FOUND,
             format!("couldn't find entry with name {qmdl_idx}"),
         ))?;
@@ -212,96 +255,133 @@ pub async fn get_zip(
                 "QMDL file is empty, try again in a bit!".to_string(),
             ));
         }
-
-        (entry_index, entry.qmdl_size_bytes)
-    };
+    }
 
     let qmdl_store_lock = state.qmdl_store_lock.clone();
 
     let (reader, writer) = duplex(8192);
 
     tokio::spawn(async move {
-        let result: Result<(), Error> = async {
-            let mut zip = ZipFileWriter::with_tokio(writer);
-
-            // Add QMDL file
-            {
-                let entry =
-                    ZipEntryBuilder::new(format!("{qmdl_idx}.qmdl").into(), Compression::Stored);
-                // FuturesAsyncWriteCompatExt::compat_write because async-zip's entrystream does
-                // not impl tokio's AsyncWrite, but only future's AsyncWrite. This can be removed
-                // once https://github.com/Majored/rs-async-zip/pull/160 is released.
-                let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
-
-                let mut qmdl_file = {
-                    let qmdl_store = qmdl_store_lock.read().await;
-                    qmdl_store
-                        .open_entry_qmdl(entry_index)
-                        .await?
-                        .take(qmdl_size_bytes as u64)
-                };
-
-                copy(&mut qmdl_file, &mut entry_writer).await?;
-                entry_writer.into_inner().close().await?;
-            }
+        if let Err(e) = write_recording_zip(&qmdl_store_lock, &qmdl_idx, writer).await {
+            error!("Error generating ZIP file: {e:?}");
+        }
+    });
 
-            // Add PCAP file
-            {
-                let entry =
-                    ZipEntryBuilder::new(format!("{qmdl_idx}.pcapng").into(), Compression::Stored);
-                let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
-
-                let qmdl_file_for_pcap = {
-                    let qmdl_store = qmdl_store_lock.read().await;
-                    qmdl_store
-                        .open_entry_qmdl(entry_index)
-                        .await?
-                        .take(qmdl_size_bytes as u64)
-                };
-
-                if let Err(e) =
-                    generate_pcap_data(&mut entry_writer, qmdl_file_for_pcap, qmdl_size_bytes).await
-                {
-                    // if we fail to generate the PCAP file, we should still continue and give the
-                    // user the QMDL.
-                    error!("Failed to generate PCAP: {e:?}");
-                }
-
-                entry_writer.into_inner().close().await?;
-            }
+    let headers = [(CONTENT_TYPE, "application/zip")];
+    let body = Body::from_stream(ReaderStream::new(reader));
+    Ok((headers, body).into_response())
+}
 
-            // Add GPS file if it exists
-            {
-                let gps_file_path = {
-                    let qmdl_store = qmdl_store_lock.read().await;
-                    qmdl_store.path.join(format!("{qmdl_idx}.gps"))
-                };
-                
-                if let Ok(mut gps_file) = tokio::fs::File::open(&gps_file_path).await {
-                    let entry = ZipEntryBuilder::new(format!("{qmdl_idx}.gps").into(), Compression::Stored);
-                    let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
-                    
-                    if let Err(e) = copy(&mut gps_file, &mut entry_writer).await {
-                        error!("Failed to add GPS file to ZIP: {e:?}");
-                    }
-                    
-                    entry_writer.into_inner().close().await?;
-                }
+/// Writes a ZIP of everything belonging to a recording: its QMDL, a PCAP
+/// generated from it, its GPS log and its annotations
+pub async fn write_recording_zip<W>(
+    qmdl_store_lock: &RwLock<RecordingStore>,
+    qmdl_idx: &str,
+    writer: W,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let (entry_index, qmdl_size_bytes, compression, encrypted, annotations) = {
+        let qmdl_store = qmdl_store_lock.read().await;
+        let (entry_index, entry) = qmdl_store
+            .entry_for_name(qmdl_idx)
+            .ok_or_else(|| anyhow::anyhow!("couldn't find entry with name {qmdl_idx}"))?;
+        (
+            entry_index,
+            entry.qmdl_size_bytes,
+            entry.compression,
+            entry.encrypted,
+            entry.annotations.clone(),
+        )
+    };
+
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    // Add QMDL file
+    {
+        let entry = ZipEntryBuilder::new(format!("{qmdl_idx}.qmdl").into(), Compression::Stored);
+        // FuturesAsyncWriteCompatExt::compat_write because async-zip's entrystream does
+        // not impl tokio's AsyncWrite, but only future's AsyncWrite. This can be removed
+        // once https://github.com/Majored/rs-async-zip/pull/160 is released.
+        let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+
+        let mut qmdl_file = {
+            let qmdl_store = qmdl_store_lock.read().await;
+            qmdl_store
+                .open_entry_qmdl(entry_index)
+                .await?
+                .take(qmdl_size_bytes as u64)
+        };
+
+        if compression == QmdlCompression::None || encrypted {
+            copy(&mut qmdl_file, &mut entry_writer).await?;
+        } else {
+            let reader = QmdlReader::new(qmdl_file, Some(qmdl_size_bytes));
+            let mut raw_stream = pin!(reader.into_raw_stream());
+            while let Some(chunk) = raw_stream.try_next().await? {
+                entry_writer.write_all(&chunk).await?;
             }
+        }
+        entry_writer.into_inner().close().await?;
+    }
 
-            zip.close().await?;
-            Ok(())
+    // Add PCAP file, which can't be generated from an encrypted QMDL
+    if !encrypted {
+        let entry = ZipEntryBuilder::new(format!("{qmdl_idx}.pcapng").into(), Compression::Stored);
+        let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+
+        let qmdl_file_for_pcap = {
+            let qmdl_store = qmdl_store_lock.read().await;
+            qmdl_store
+                .open_entry_qmdl(entry_index)
+                .await?
+                .take(qmdl_size_bytes as u64)
+        };
+
+        if let Err(e) =
+            generate_pcap_data(&mut entry_writer, qmdl_file_for_pcap, qmdl_size_bytes).await
+        {
+            // if we fail to generate the PCAP file, we should still continue and give the
+            // user the QMDL.
+            error!("Failed to generate PCAP: {e:?}");
         }
-        .await;
 
-        if let Err(e) = result {
-            error!("Error generating ZIP file: {e:?}");
+        entry_writer.into_inner().close().await?;
+    }
+
+    // Add GPS file if it exists
+    {
+        let gps_file_path = {
+            let qmdl_store = qmdl_store_lock.read().await;
+            qmdl_store.path.join(format!("{qmdl_idx}.gps"))
+        };
+
+        if let Ok(mut gps_file) = tokio::fs::File::open(&gps_file_path).await {
+            let entry = ZipEntryBuilder::new(format!("{qmdl_idx}.gps").into(), Compression::Stored);
+            let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+
+            if let Err(e) = copy(&mut gps_file, &mut entry_writer).await {
+                error!("Failed to add GPS file to ZIP: {e:?}");
+            }
+
+            entry_writer.into_inner().close().await?;
         }
-    });
+    }
 
-    let headers = [(CONTENT_TYPE, "application/zip")];
-    let body = Body::from_stream(ReaderStream::new(reader));
-    Ok((headers, body).into_response())
+    // Add annotations, so they stay with the recording once it's been
+    // downloaded
+    if !annotations.is_empty() {
+        let entry = ZipEntryBuilder::new(
+            format!("{qmdl_idx}.annotations.json").into(),
+            Compression::Stored,
+        );
+        zip.write_entry_whole(entry, &serde_json::to_vec_pretty(&annotations)?)
+            .await?;
+    }
+
+    zip.close().await?;
+    Ok(())
 }
 
 pub async fn debug_set_display_state(
@@ -387,7 +467,7 @@ mod tests {
         Arc::new(ServerState {
             config_path: "/tmp/test_config.toml".to_string(),
             config: Config::default(),
-            qmdl_store_lock: store_lock,
+            qmdl_store_lock: store_lock.clone(),
             diag_device_ctrl_sender: tx,
             analysis_status_lock: Arc::new(RwLock::new(analysis_status)),
             analysis_sender: analysis_tx,
@@ -397,7 +477,13 @@ mod tests {
                 store_lock.clone(),
                 true,
                 crate::config::GpsLogFormat::Simple,
+                Default::default(),
             )),
+            notification_outbox: Default::default(),
+            log_code_selection_lock: Default::default(),
+            baseline: None,
+            diag_health_lock: Default::default(),
+            modem_info: None,
         })
     }
 
@@ -433,4 +519,37 @@ mod tests {
             vec![format!("{entry_name}.qmdl"), format!("{entry_name}.pcapng"), format!("{entry_name}.gps"),]
         );
     }
+
+    #[tokio::test]
+    async fn test_get_zip_with_annotations() {
+        let (_temp_dir, store_lock) = create_test_qmdl_store().await;
+        let test_qmdl_data = vec![0x7E, 0x00, 0x00, 0x00, 0x10, 0x00, 0x7E];
+        let entry_name = create_test_entry_with_data(&store_lock, &test_qmdl_data).await;
+        {
+            let mut store = store_lock.write().await;
+            let (entry_index, _) = store.entry_for_name(&entry_name).unwrap();
+            let annotations = rayhunter::util::RecordingAnnotations {
+                tags: vec!["control".to_string()],
+                ..Default::default()
+            };
+            store
+                .set_entry_annotations(entry_index, annotations)
+                .await
+                .unwrap();
+        }
+        let state = create_test_server_state(store_lock);
+
+        let response = get_zip(State(state), Path(entry_name.clone())).await.unwrap();
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let zip_reader = ZipFileReader::new(body_bytes.to_vec()).await.unwrap();
+        let filenames = zip_reader
+            .file()
+            .entries()
+            .iter()
+            .map(|entry| entry.filename().as_str().unwrap().to_owned())
+            .collect::<Vec<String>>();
+        assert!(filenames.contains(&format!("{entry_name}.annotations.json")));
+    }
 }
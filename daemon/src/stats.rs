@@ -1,29 +1,45 @@
+use std::ffi::CString;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::diag::DiagHealth;
+use crate::notifications::Notification;
 use crate::qmdl_store::ManifestEntry;
 use crate::server::ServerState;
 
 use axum::Json;
 use axum::extract::State;
 use axum::http::StatusCode;
-use log::error;
+use log::{error, info, warn};
 use rayhunter::{Device, util::RuntimeMetadata};
 use serde::Serialize;
 use tokio::process::Command;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio_util::task::TaskTracker;
+
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DISK_WARNING_DEBOUNCE: Duration = Duration::from_secs(6 * 60 * 60);
 
 #[derive(Debug, Serialize)]
 pub struct SystemStats {
     pub disk_stats: DiskStats,
     pub memory_stats: MemoryStats,
     pub runtime_metadata: RuntimeMetadata,
+    pub diag_health: DiagHealth,
 }
 
 impl SystemStats {
-    pub async fn new(qmdl_path: &str, device: &Device) -> Result<Self, String> {
+    pub async fn new(
+        qmdl_path: &str,
+        device: &Device,
+        diag_health: DiagHealth,
+    ) -> Result<Self, String> {
         Ok(Self {
             disk_stats: DiskStats::new(qmdl_path, device).await?,
             memory_stats: MemoryStats::new(device).await?,
             runtime_metadata: RuntimeMetadata::new(),
+            diag_health,
         })
     }
 }
@@ -67,6 +83,70 @@ impl DiskStats {
     }
 }
 
+// how full the partition containing `path` is, in percent, rounded up the same
+// way df does
+pub fn disk_used_percent(path: &str) -> Result<u8, String> {
+    let c_path = CString::new(path).map_err(|e| format!("invalid path {path}: {e}"))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "statvfs {path} failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // the block counts are u32 on some targets
+    let used = (stat.f_blocks - stat.f_bfree) as u64;
+    let usable = used + stat.f_bavail as u64;
+    if usable == 0 {
+        return Ok(0);
+    }
+    Ok(used.saturating_mul(100).div_ceil(usable).min(100) as u8)
+}
+
+// Periodically checks how full the disk holding recordings is, and sends a
+// notification once it's above `threshold` percent
+pub fn run_disk_monitor_thread(
+    task_tracker: &TaskTracker,
+    qmdl_path: String,
+    threshold: u8,
+    notification_channel: Sender<Notification>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) {
+    if threshold == 0 {
+        return;
+    }
+
+    task_tracker.spawn(async move {
+        loop {
+            match disk_used_percent(&qmdl_path) {
+                Ok(used_percent) if used_percent >= threshold => {
+                    warn!("disk containing {qmdl_path} is {used_percent}% full");
+                    let notification = Notification::new(
+                        "disk-almost-full".to_string(),
+                        format!(
+                            "The disk Rayhunter records to is {used_percent}% full. Delete old recordings to keep recording."
+                        ),
+                        Some(DISK_WARNING_DEBOUNCE),
+                    );
+                    if let Err(e) = notification_channel.send(notification).await {
+                        warn!("couldn't send notification: {e}");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("error checking disk usage: {e}"),
+            }
+
+            tokio::select! {
+                _ = &mut shutdown_rx => {
+                    info!("received disk monitor shutdown");
+                    return;
+                }
+                _ = tokio::time::sleep(DISK_CHECK_INTERVAL) => {}
+            }
+        }
+    });
+}
+
 #[derive(Debug, Serialize)]
 pub struct MemoryStats {
     total: String,
@@ -127,7 +207,14 @@ pub async fn get_system_stats(
     State(state): State<Arc<ServerState>>,
 ) -> Result<Json<SystemStats>, (StatusCode, String)> {
     let qmdl_store = state.qmdl_store_lock.read().await;
-    match SystemStats::new(qmdl_store.path.to_str().unwrap(), &state.config.device).await {
+    let diag_health = state.diag_health_lock.read().await.clone();
+    match SystemStats::new(
+        qmdl_store.path.to_str().unwrap(),
+        &state.config.device,
+        diag_health,
+    )
+    .await
+    {
         Ok(stats) => Ok(Json(stats)),
         Err(err) => {
             error!("error getting system stats: {err}");
@@ -156,3 +243,16 @@ pub async fn get_qmdl_manifest(
         current_entry,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_used_percent() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let used_percent = disk_used_percent(dir.path().to_str().unwrap()).unwrap();
+        assert!(used_percent <= 100);
+        assert!(disk_used_percent("/nonexistent/rayhunter").is_err());
+    }
+}
@@ -0,0 +1,662 @@
+//! Uploads finished recordings to a server, so they don't have to be
+//! downloaded from the device by hand.
+//!
+//! Each recording is bundled into the same ZIP file `/api/zip/{name}` serves,
+//! which is kept next to the recording until it's been uploaded. Bundles are
+//! uploaded in chunks, and how far each upload got is saved in the manifest,
+//! so an upload that's interrupted (by the network or a restart) carries on
+//! where it left off.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio::sync::{RwLock, oneshot};
+use tokio_util::task::TaskTracker;
+
+use crate::qmdl_store::{ManifestEntry, RecordingStore};
+use crate::server::{ServerState, write_recording_zip};
+
+mod s3;
+mod tus;
+mod webdav;
+
+/// How often to look for recordings to upload
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest wait before retrying a failed upload
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// How long to wait for a connection to the upload server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest any one request may take, including sending its chunk
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+fn default_chunk_size_kb() -> usize {
+    1024
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UploadConfig {
+    #[serde(flatten)]
+    pub target: UploadTargetConfig,
+    /// Size of each uploaded chunk. S3 uploads use at least 5 MiB.
+    #[serde(default = "default_chunk_size_kb")]
+    pub chunk_size_kb: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UploadTargetConfig {
+    /// A server implementing the tus resumable upload protocol
+    /// (<https://tus.io/protocols/resumable-upload>)
+    Https {
+        url: String,
+        /// Sent as a bearer token, if set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// An S3-compatible bucket, using multipart uploads
+    S3 {
+        /// e.g. `https://s3.us-east-1.amazonaws.com`. Buckets are addressed by
+        /// path, not by subdomain.
+        endpoint: String,
+        bucket: String,
+        #[serde(default = "default_s3_region")]
+        region: String,
+        access_key_id: String,
+        secret_access_key: String,
+        /// Prepended to each object's key, e.g. `rayhunter/device-1/`
+        #[serde(default)]
+        prefix: String,
+    },
+    /// A directory on a WebDAV server
+    Webdav {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadStatus {
+    /// The recording hasn't been queued for upload
+    #[default]
+    NotQueued,
+    /// Waiting for its turn, or to retry after a failure
+    Pending,
+    Uploading,
+    Uploaded,
+}
+
+/// How far along a recording's upload is. This is kept in its manifest entry.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct UploadState {
+    pub status: UploadStatus,
+    #[serde(flatten)]
+    pub progress: UploadProgress,
+    /// Size of the ZIP bundle being uploaded
+    pub bundle_size_bytes: u64,
+    /// Failed attempts since the last successful chunk
+    pub failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<DateTime<Local>>,
+}
+
+impl UploadState {
+    /// Whether the recording is waiting to be uploaded, which keeps it from
+    /// being deleted by the retention policy while uploads are enabled,
+    /// unless the disk is nearly full
+    pub fn is_unfinished(&self) -> bool {
+        matches!(self.status, UploadStatus::Pending | UploadStatus::Uploading)
+    }
+}
+
+/// What a target needs to resume an upload
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct UploadProgress {
+    /// Identifies the upload to the server: a tus upload URL, S3 upload ID or
+    /// the URL of a partial WebDAV file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Bytes the server has confirmed receiving
+    pub uploaded_bytes: u64,
+    /// ETags of the S3 parts uploaded so far
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Server responded with status {0}")]
+    HttpStatus(reqwest::StatusCode),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unexpected response from server: {0}")]
+    Protocol(String),
+    /// The server no longer knows about the upload, so it has to start over
+    #[error("The server doesn't know about this upload anymore")]
+    SessionLost,
+}
+
+#[async_trait]
+pub trait UploadTarget: Send + Sync {
+    /// Identifies the target in logs
+    fn name(&self) -> &'static str;
+
+    /// Smallest chunk the target accepts, other than the last one
+    fn min_chunk_size(&self) -> usize {
+        0
+    }
+
+    /// Start uploading a bundle of `size` bytes as `file_name`, or if
+    /// `progress` has a session, find out how much of it the server has
+    async fn begin(
+        &self,
+        file_name: &str,
+        size: u64,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError>;
+
+    /// Upload the chunk starting at `progress.uploaded_bytes`
+    async fn upload_chunk(
+        &self,
+        file_name: &str,
+        size: u64,
+        chunk: Vec<u8>,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError>;
+
+    /// Complete the upload once every chunk has been uploaded
+    async fn finish(
+        &self,
+        file_name: &str,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError>;
+}
+
+fn build_target(
+    config: &UploadTargetConfig,
+    http_client: reqwest::Client,
+) -> Box<dyn UploadTarget> {
+    match config.clone() {
+        UploadTargetConfig::Https { url, token } => {
+            Box::new(tus::TusTarget::new(url, token, http_client))
+        }
+        UploadTargetConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key_id,
+            secret_access_key,
+            prefix,
+        } => Box::new(s3::S3Target::new(
+            endpoint,
+            bucket,
+            region,
+            access_key_id,
+            secret_access_key,
+            prefix,
+            http_client,
+        )),
+        UploadTargetConfig::Webdav {
+            url,
+            username,
+            password,
+        } => Box::new(webdav::WebdavTarget::new(
+            url,
+            username,
+            password,
+            http_client,
+        )),
+    }
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    Duration::from_secs(30)
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+// Finds the oldest finished recording that's waiting to be uploaded, and isn't
+// waiting to retry
+fn next_upload(store: &RecordingStore, retry_at: &HashMap<String, Instant>) -> Option<String> {
+    store
+        .manifest
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(idx, entry)| store.current_entry != Some(*idx) && entry.upload.is_unfinished())
+        .map(|(_, entry)| entry)
+        .filter(|entry| {
+            retry_at
+                .get(&entry.name)
+                .is_none_or(|retry_at| *retry_at <= Instant::now())
+        })
+        .min_by_key(|entry| entry.start_time)
+        .map(|entry| entry.name.clone())
+}
+
+async fn save_state(
+    qmdl_store_lock: &RwLock<RecordingStore>,
+    name: &str,
+    state: &UploadState,
+) -> Result<(), UploadError> {
+    let mut qmdl_store = qmdl_store_lock.write().await;
+    // the recording may have been deleted while it was being uploaded
+    let Some((entry_index, _)) = qmdl_store.entry_for_name(name) else {
+        return Err(UploadError::Io(std::io::ErrorKind::NotFound.into()));
+    };
+    qmdl_store
+        .set_entry_upload(entry_index, state.clone())
+        .await
+        .map_err(|e| UploadError::Io(std::io::Error::other(e.to_string())))
+}
+
+/// Uploads one recording, building its bundle first if needed. Progress is
+/// saved after every chunk, and the bundle is deleted once it's uploaded.
+/// Returns false if the upload was interrupted by a shutdown.
+pub async fn upload_recording(
+    qmdl_store_lock: &RwLock<RecordingStore>,
+    target: &dyn UploadTarget,
+    chunk_size: usize,
+    name: &str,
+    shutdown: &mut oneshot::Receiver<()>,
+) -> Result<bool, UploadError> {
+    let (mut state, bundle_path) = {
+        let qmdl_store = qmdl_store_lock.read().await;
+        let (_, entry) = qmdl_store
+            .entry_for_name(name)
+            .ok_or(UploadError::Io(std::io::ErrorKind::NotFound.into()))?;
+        (
+            entry.upload.clone(),
+            entry.get_upload_bundle_filepath(&qmdl_store.path),
+        )
+    };
+
+    // a bundle has to stay the same until it's uploaded, so it's only built
+    // once. Without it, any upload that was in progress has to start over.
+    let mut bundle = match File::open(&bundle_path).await {
+        Ok(bundle) => bundle,
+        Err(_) => {
+            let mut tmp_path = bundle_path.as_os_str().to_owned();
+            tmp_path.push(".new");
+            let tmp_file = File::create(&tmp_path).await?;
+            if let Err(e) = write_recording_zip(qmdl_store_lock, name, tmp_file).await {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(UploadError::Io(std::io::Error::other(e.to_string())));
+            }
+            tokio::fs::rename(&tmp_path, &bundle_path).await?;
+            state.progress = UploadProgress::default();
+            File::open(&bundle_path).await?
+        }
+    };
+    let size = bundle.metadata().await?.len();
+    state.bundle_size_bytes = size;
+    state.status = UploadStatus::Uploading;
+
+    let file_name = format!("{name}.zip");
+    let chunk_size = chunk_size.max(target.min_chunk_size()).max(1);
+    if let Err(e) = target.begin(&file_name, size, &mut state.progress).await {
+        if !matches!(e, UploadError::SessionLost) {
+            return Err(e);
+        }
+        warn!("{} lost the upload of {name}, starting over", target.name());
+        state.progress = UploadProgress::default();
+        target.begin(&file_name, size, &mut state.progress).await?;
+    }
+    save_state(qmdl_store_lock, name, &state).await?;
+
+    while state.progress.uploaded_bytes < size {
+        if shutdown.try_recv().is_ok() {
+            return Ok(false);
+        }
+        let offset = state.progress.uploaded_bytes;
+        let len = chunk_size.min((size - offset) as usize);
+        let mut chunk = vec![0; len];
+        bundle.seek(SeekFrom::Start(offset)).await?;
+        bundle.read_exact(&mut chunk).await?;
+        // a chunk can take a while on a slow connection, so don't hold up a
+        // shutdown until it's sent. It's sent again when the upload resumes.
+        let result = tokio::select! {
+            result = target.upload_chunk(&file_name, size, chunk, &mut state.progress) => result,
+            _ = &mut *shutdown => return Ok(false),
+        };
+        if let Err(UploadError::SessionLost) = result {
+            // start over the next time round
+            state.progress = UploadProgress::default();
+            save_state(qmdl_store_lock, name, &state).await?;
+        }
+        result?;
+        state.failures = 0;
+        save_state(qmdl_store_lock, name, &state).await?;
+    }
+
+    target.finish(&file_name, &mut state.progress).await?;
+    state = UploadState {
+        status: UploadStatus::Uploaded,
+        bundle_size_bytes: size,
+        uploaded_at: Some(Local::now()),
+        ..Default::default()
+    };
+    save_state(qmdl_store_lock, name, &state).await?;
+    if let Err(e) = tokio::fs::remove_file(&bundle_path).await {
+        warn!("couldn't delete upload bundle {bundle_path:?}: {e}");
+    }
+    Ok(true)
+}
+
+/// An HTTP client whose requests give up on a dead connection instead of
+/// hanging
+pub fn build_http_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+}
+
+pub fn run_upload_worker(
+    task_tracker: &TaskTracker,
+    config: UploadConfig,
+    qmdl_store_lock: Arc<RwLock<RecordingStore>>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) {
+    task_tracker.spawn(async move {
+        let client = match build_http_client() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("couldn't create upload client: {e}");
+                return;
+            }
+        };
+        let target = build_target(&config.target, client);
+        let chunk_size = config.chunk_size_kb.saturating_mul(1024);
+        // when each failed upload can be retried
+        let mut retry_at: HashMap<String, Instant> = HashMap::new();
+
+        loop {
+            let next = next_upload(&*qmdl_store_lock.read().await, &retry_at);
+            let Some(name) = next else {
+                tokio::select! {
+                    _ = &mut shutdown_rx => return,
+                    _ = tokio::time::sleep(POLL_INTERVAL) => continue,
+                }
+            };
+
+            info!("uploading {name} to {}", target.name());
+            let result = upload_recording(
+                &qmdl_store_lock,
+                &*target,
+                chunk_size,
+                &name,
+                &mut shutdown_rx,
+            )
+            .await;
+            match result {
+                Ok(true) => {
+                    info!("finished uploading {name}");
+                    retry_at.remove(&name);
+                }
+                Ok(false) => return,
+                Err(e) => {
+                    let mut qmdl_store = qmdl_store_lock.write().await;
+                    let Some((entry_index, entry)) = qmdl_store.entry_for_name(&name) else {
+                        continue;
+                    };
+                    let mut state = entry.upload.clone();
+                    state.status = UploadStatus::Pending;
+                    state.failures += 1;
+                    state.last_error = Some(e.to_string());
+                    let delay = retry_delay(state.failures);
+                    error!("failed to upload {name}, retrying in {delay:?}: {e}");
+                    retry_at.insert(name, Instant::now() + delay);
+                    if let Err(e) = qmdl_store.set_entry_upload(entry_index, state).await {
+                        error!("couldn't save upload state: {e}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryUpload {
+    pub name: String,
+    #[serde(flatten)]
+    pub upload: UploadState,
+}
+
+impl From<&ManifestEntry> for EntryUpload {
+    fn from(entry: &ManifestEntry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            upload: entry.upload.clone(),
+        }
+    }
+}
+
+pub async fn get_uploads(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<Vec<EntryUpload>>, (StatusCode, String)> {
+    let qmdl_store = state.qmdl_store_lock.read().await;
+    Ok(Json(
+        qmdl_store
+            .manifest
+            .entries
+            .iter()
+            .map(EntryUpload::from)
+            .collect(),
+    ))
+}
+
+/// Queues a recording to be uploaded, or uploaded again
+pub async fn queue_upload(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<EntryUpload>), (StatusCode, String)> {
+    if state.config.upload.is_none() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "uploads aren't configured".to_string(),
+        ));
+    }
+    let mut qmdl_store = state.qmdl_store_lock.write().await;
+    let (entry_index, entry) = qmdl_store.entry_for_name(&name).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("couldn't find entry with name {name}"),
+    ))?;
+    if !entry.upload.is_unfinished() {
+        qmdl_store
+            .set_entry_upload(
+                entry_index,
+                UploadState {
+                    status: UploadStatus::Pending,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("couldn't queue upload: {e}"),
+                )
+            })?;
+    }
+    let entry = &qmdl_store.manifest.entries[entry_index];
+    Ok((StatusCode::ACCEPTED, Json(EntryUpload::from(entry))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::http::HeaderMap;
+    use axum::routing::{head, post};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(3), Duration::from_secs(120));
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[derive(Default)]
+    struct StandIn {
+        length: u64,
+        data: Vec<u8>,
+        patches: usize,
+        // fails the PATCH request after this many have succeeded
+        fail_after: Option<usize>,
+    }
+
+    type SharedStandIn = Arc<Mutex<StandIn>>;
+
+    async fn create_upload(
+        State(stand_in): State<SharedStandIn>,
+        headers: HeaderMap,
+    ) -> (StatusCode, [(&'static str, &'static str); 1]) {
+        let length = headers["upload-length"].to_str().unwrap().parse().unwrap();
+        stand_in.lock().unwrap().length = length;
+        (StatusCode::CREATED, [("location", "/files/1")])
+    }
+
+    async fn upload_offset(
+        State(stand_in): State<SharedStandIn>,
+    ) -> (StatusCode, [(&'static str, String); 1]) {
+        let offset = stand_in.lock().unwrap().data.len().to_string();
+        (StatusCode::OK, [("upload-offset", offset)])
+    }
+
+    async fn patch_upload(
+        State(stand_in): State<SharedStandIn>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<(StatusCode, [(&'static str, String); 1]), StatusCode> {
+        let mut stand_in = stand_in.lock().unwrap();
+        if stand_in.fail_after == Some(stand_in.patches) {
+            stand_in.fail_after = None;
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let offset: usize = headers["upload-offset"].to_str().unwrap().parse().unwrap();
+        if offset != stand_in.data.len() {
+            return Err(StatusCode::CONFLICT);
+        }
+        stand_in.data.extend_from_slice(&body);
+        stand_in.patches += 1;
+        let offset = stand_in.data.len().to_string();
+        Ok((StatusCode::NO_CONTENT, [("upload-offset", offset)]))
+    }
+
+    // Just enough of a tus server to upload to
+    async fn spawn_tus_server(stand_in: SharedStandIn) -> String {
+        let router = Router::new()
+            .route("/files", post(create_upload))
+            .route("/files/1", head(upload_offset).patch(patch_upload))
+            .with_state(stand_in);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{address}/files")
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        let _ = rustls_rustcrypto::provider().install_default();
+        let stand_in = SharedStandIn::default();
+        stand_in.lock().unwrap().fail_after = Some(2);
+        let url = spawn_tus_server(stand_in.clone()).await;
+        let target = build_target(
+            &UploadTargetConfig::Https { url, token: None },
+            build_http_client().unwrap(),
+        );
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = RecordingStore::create(dir.path())
+            .await
+            .unwrap()
+            .with_uploads(true);
+        let (mut qmdl_file, _) = store.new_entry().await.unwrap();
+        let data = [0x7E, 0x00, 0x00, 0x00, 0x10, 0x00, 0x7E];
+        qmdl_file.write_all(&data).await.unwrap();
+        qmdl_file.flush().await.unwrap();
+        let index = store.current_entry.unwrap();
+        store
+            .update_entry_qmdl_size(index, data.len())
+            .await
+            .unwrap();
+        store.close_current_entry().await.unwrap();
+        let entry = store.manifest.entries[index].clone();
+        assert_eq!(entry.upload.status, UploadStatus::Pending);
+        let bundle_path = entry.get_upload_bundle_filepath(dir.path());
+        let qmdl_store_lock = RwLock::new(store);
+        let (_shutdown_tx, mut shutdown_rx) = oneshot::channel();
+
+        // the third chunk fails, but the first two are kept
+        let result = upload_recording(
+            &qmdl_store_lock,
+            &*target,
+            64,
+            &entry.name,
+            &mut shutdown_rx,
+        )
+        .await;
+        assert!(matches!(result, Err(UploadError::HttpStatus(_))));
+        let state = qmdl_store_lock.read().await.manifest.entries[index]
+            .upload
+            .clone();
+        assert_eq!(state.status, UploadStatus::Uploading);
+        assert_eq!(state.progress.uploaded_bytes, 128);
+        assert!(bundle_path.exists());
+
+        // and the upload picks up where it left off
+        let finished = upload_recording(
+            &qmdl_store_lock,
+            &*target,
+            64,
+            &entry.name,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+        assert!(finished);
+        let state = qmdl_store_lock.read().await.manifest.entries[index]
+            .upload
+            .clone();
+        assert_eq!(state.status, UploadStatus::Uploaded);
+        assert!(state.uploaded_at.is_some());
+        assert!(!bundle_path.exists());
+
+        let stand_in = stand_in.lock().unwrap();
+        assert_eq!(stand_in.length, state.bundle_size_bytes);
+        assert_eq!(stand_in.data.len() as u64, state.bundle_size_bytes);
+        assert_eq!(
+            stand_in.patches as u64,
+            state.bundle_size_bytes.div_ceil(64)
+        );
+        assert!(stand_in.data.starts_with(b"PK\x03\x04"));
+    }
+}
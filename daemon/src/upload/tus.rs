@@ -0,0 +1,130 @@
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::{RequestBuilder, StatusCode, Url};
+
+use super::{UploadError, UploadProgress, UploadTarget};
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Uploads to a tus server, which creates an upload URL for each bundle and
+/// accepts its chunks with PATCH requests
+pub struct TusTarget {
+    url: String,
+    token: Option<String>,
+    http_client: reqwest::Client,
+}
+
+impl TusTarget {
+    pub fn new(url: String, token: Option<String>, http_client: reqwest::Client) -> Self {
+        Self {
+            url,
+            token,
+            http_client,
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header("Tus-Resumable", TUS_VERSION);
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn upload_offset(response: &reqwest::Response) -> Result<u64, UploadError> {
+    response
+        .headers()
+        .get("Upload-Offset")
+        .and_then(|offset| offset.to_str().ok())
+        .and_then(|offset| offset.parse().ok())
+        .ok_or(UploadError::Protocol("missing Upload-Offset".to_string()))
+}
+
+fn check_session(status: StatusCode) -> Result<(), UploadError> {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Err(UploadError::SessionLost),
+        status if !status.is_success() => Err(UploadError::HttpStatus(status)),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl UploadTarget for TusTarget {
+    fn name(&self) -> &'static str {
+        "tus server"
+    }
+
+    async fn begin(
+        &self,
+        file_name: &str,
+        size: u64,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError> {
+        if let Some(session) = &progress.session {
+            let response = self
+                .authorize(self.http_client.head(session))
+                .send()
+                .await?;
+            check_session(response.status())?;
+            progress.uploaded_bytes = upload_offset(&response)?;
+            return Ok(());
+        }
+
+        let response = self
+            .authorize(self.http_client.post(&self.url))
+            .header("Upload-Length", size)
+            .header(
+                "Upload-Metadata",
+                format!("filename {}", STANDARD.encode(file_name)),
+            )
+            .send()
+            .await?;
+        if response.status() != StatusCode::CREATED {
+            return Err(UploadError::HttpStatus(response.status()));
+        }
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or(UploadError::Protocol("missing Location".to_string()))?;
+        // the location may be relative to the creation URL
+        let session = Url::parse(&self.url)
+            .and_then(|url| url.join(location))
+            .map_err(|e| UploadError::Protocol(format!("invalid Location: {e}")))?;
+        progress.session = Some(session.to_string());
+        progress.uploaded_bytes = 0;
+        Ok(())
+    }
+
+    async fn upload_chunk(
+        &self,
+        _file_name: &str,
+        _size: u64,
+        chunk: Vec<u8>,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError> {
+        let session = progress.session.as_ref().ok_or(UploadError::SessionLost)?;
+        let response = self
+            .authorize(self.http_client.patch(session))
+            .header(CONTENT_TYPE, "application/offset+octet-stream")
+            .header("Upload-Offset", progress.uploaded_bytes)
+            .body(chunk)
+            .send()
+            .await?;
+        check_session(response.status())?;
+        progress.uploaded_bytes = upload_offset(&response)?;
+        Ok(())
+    }
+
+    async fn finish(
+        &self,
+        _file_name: &str,
+        _progress: &mut UploadProgress,
+    ) -> Result<(), UploadError> {
+        // the upload is complete as soon as the server has every byte
+        Ok(())
+    }
+}
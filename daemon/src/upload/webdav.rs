@@ -0,0 +1,125 @@
+use async_trait::async_trait;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE};
+use reqwest::{Method, RequestBuilder, StatusCode};
+
+use super::{UploadError, UploadProgress, UploadTarget};
+
+/// Uploads to a directory on a WebDAV server. Each bundle is written to a
+/// `.part` file, appending chunks with ranged PUT requests, and renamed once
+/// it's complete.
+pub struct WebdavTarget {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    http_client: reqwest::Client,
+}
+
+impl WebdavTarget {
+    pub fn new(
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+        http_client: reqwest::Client,
+    ) -> Self {
+        Self {
+            url,
+            username,
+            password,
+            http_client,
+        }
+    }
+
+    fn file_url(&self, file_name: &str) -> String {
+        format!("{}/{file_name}", self.url.trim_end_matches('/'))
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.http_client.request(method, url);
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl UploadTarget for WebdavTarget {
+    fn name(&self) -> &'static str {
+        "WebDAV server"
+    }
+
+    async fn begin(
+        &self,
+        file_name: &str,
+        _size: u64,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError> {
+        let Some(session) = &progress.session else {
+            progress.session = Some(self.file_url(&format!("{file_name}.part")));
+            progress.uploaded_bytes = 0;
+            return Ok(());
+        };
+
+        // the partial file's size is how much of it was uploaded
+        let response = self.request(Method::HEAD, session).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(UploadError::SessionLost);
+        }
+        if !response.status().is_success() {
+            return Err(UploadError::HttpStatus(response.status()));
+        }
+        progress.uploaded_bytes = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok())
+            .ok_or(UploadError::Protocol("missing Content-Length".to_string()))?;
+        Ok(())
+    }
+
+    async fn upload_chunk(
+        &self,
+        _file_name: &str,
+        size: u64,
+        chunk: Vec<u8>,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError> {
+        let session = progress.session.as_ref().ok_or(UploadError::SessionLost)?;
+        let start = progress.uploaded_bytes;
+        let len = chunk.len() as u64;
+        let mut request = self.request(Method::PUT, session);
+        // the first chunk creates the file, the rest are written after it
+        if start > 0 {
+            request = request.header(
+                CONTENT_RANGE,
+                format!("bytes {start}-{}/{size}", start + len - 1),
+            );
+        }
+        let response = request.body(chunk).send().await?;
+        if !response.status().is_success() {
+            return Err(UploadError::HttpStatus(response.status()));
+        }
+        progress.uploaded_bytes = start + len;
+        Ok(())
+    }
+
+    async fn finish(
+        &self,
+        file_name: &str,
+        progress: &mut UploadProgress,
+    ) -> Result<(), UploadError> {
+        let session = progress.session.as_ref().ok_or(UploadError::SessionLost)?;
+        let method = Method::from_bytes(b"MOVE").expect("MOVE is a valid method");
+        let response = self
+            .request(method, session)
+            .header("Destination", self.file_url(file_name))
+            .header("Overwrite", "T")
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(UploadError::SessionLost),
+            status if !status.is_success() => Err(UploadError::HttpStatus(status)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -17,11 +17,15 @@ export class ReportMetadata {
     public analyzers: AnalyzerMetadata[];
     public rayhunter: RayhunterMetadata;
     public report_version: number;
+    public firmware_build?: string;
+    public baseband_version?: string;
 
     constructor(ndjson: any) {
         this.analyzers = ndjson.analyzers;
         this.rayhunter = ndjson.rayhunter;
         this.report_version = ndjson.report_version || 2; // Default to v2
+        this.firmware_build = ndjson.firmware_build;
+        this.baseband_version = ndjson.baseband_version;
     }
 }
 
@@ -52,6 +56,15 @@ export type PacketAnalysis = {
     type: AnalysisRowType.Analysis;
     packet_timestamp: Date;
     events: Event[];
+    location?: EventLocation;
+};
+
+export type EventLocation = {
+    latitude: number;
+    longitude: number;
+    accuracy: number | null;
+    interpolated: boolean;
+    fix_offset_secs: number;
 };
 
 export type EventType = 'Informational' | 'Low' | 'Medium' | 'High';
@@ -89,6 +102,7 @@ function get_rows(row_jsons: any[]): AnalysisRow[] {
                 type: AnalysisRowType.Analysis,
                 packet_timestamp: new Date(row_json.packet_timestamp),
                 events,
+                location: row_json.location ?? undefined,
             });
         }
     }
@@ -2,6 +2,18 @@ export interface SystemStats {
     disk_stats: DiskStats;
     memory_stats: MemoryStats;
     runtime_metadata: RuntimeMetadata;
+    diag_health: DiagHealth;
+}
+
+export interface DiagHealth {
+    containers_read: number;
+    read_errors: number;
+    parse_errors: number;
+    reopens: number;
+    storage_errors: number;
+    last_read_error: string | null;
+    last_read_error_time: string | null;
+    recording_error: string | null;
 }
 
 export interface RuntimeMetadata {
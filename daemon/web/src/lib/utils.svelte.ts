@@ -9,6 +9,10 @@ export interface AnalyzerConfig {
     null_cipher: boolean;
     nas_null_cipher: boolean;
     incomplete_sib: boolean;
+    strong_new_cell: boolean;
+    neighbor_list: boolean;
+    cell_parameters: boolean;
+    unfamiliar_cell: boolean;
     test_analyzer: boolean;
 }
 
@@ -3,16 +3,22 @@ use pcap_file_tokio::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
+use crate::baseline::SharedBaseline;
+use crate::diag::{LogBody, Message, MessagesContainer};
+use crate::gps::{EventLocation, TrackLocator};
 use crate::gsmtap::{GsmtapHeader, GsmtapMessage, GsmtapType};
-use crate::util::RuntimeMetadata;
-use crate::{diag::MessagesContainer, gsmtap_parser};
+use crate::gsmtap_parser;
+use crate::signal::{ServingCell, SignalSample, SignalTracker};
+use crate::util::{RecordingAnnotations, RuntimeMetadata};
 
 use super::{
+    cell_parameters::CellParameterAnalyzer,
     connection_redirect_downgrade::ConnectionRedirect2GDowngradeAnalyzer,
     imsi_requested::ImsiRequestedAnalyzer, incomplete_sib::IncompleteSibAnalyzer,
     information_element::InformationElement, nas_null_cipher::NasNullCipherAnalyzer,
-    null_cipher::NullCipherAnalyzer, priority_2g_downgrade::LteSib6And7DowngradeAnalyzer,
-    test_analyzer::TestAnalyzer,
+    neighbor_list::NeighborListAnalyzer, null_cipher::NullCipherAnalyzer,
+    priority_2g_downgrade::LteSib6And7DowngradeAnalyzer, strong_new_cell::StrongNewCellAnalyzer,
+    test_analyzer::TestAnalyzer, unfamiliar_cell::UnfamiliarCellAnalyzer,
 };
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -24,6 +30,10 @@ pub struct AnalyzerConfig {
     pub null_cipher: bool,
     pub nas_null_cipher: bool,
     pub incomplete_sib: bool,
+    pub strong_new_cell: bool,
+    pub neighbor_list: bool,
+    pub cell_parameters: bool,
+    pub unfamiliar_cell: bool,
     pub test_analyzer: bool,
 }
 
@@ -36,6 +46,10 @@ impl Default for AnalyzerConfig {
             null_cipher: true,
             nas_null_cipher: true,
             incomplete_sib: true,
+            strong_new_cell: true,
+            neighbor_list: true,
+            cell_parameters: true,
+            unfamiliar_cell: true,
             test_analyzer: false,
         }
     }
@@ -105,6 +119,39 @@ pub struct Event {
     pub message: String,
 }
 
+/// What's known about the packet an [InformationElement] was decoded from,
+/// beyond its contents. QMDL LTE RRC messages record which cell they were sent
+/// or received on, while packets read from a PCAP only carry a timestamp and
+/// the GSMTAP header's EARFCN.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PacketContext {
+    pub timestamp: Option<DateTime<FixedOffset>>,
+    pub earfcn: Option<u32>,
+    pub pci: Option<u16>,
+    /// Where the device was at the time, if the [Harness] has a GPS track
+    pub location: Option<EventLocation>,
+}
+
+impl PacketContext {
+    pub fn from_message(message: &Message) -> Self {
+        let Message::Log {
+            timestamp, body, ..
+        } = message
+        else {
+            return Self::default();
+        };
+        let mut context = PacketContext {
+            timestamp: Some(timestamp.to_datetime()),
+            ..Default::default()
+        };
+        if let LogBody::LteRrcOtaMessage { packet, .. } = body {
+            context.earfcn = Some(packet.get_earfcn());
+            context.pci = Some(packet.get_phy_cell_id());
+        }
+        context
+    }
+}
+
 /// An [Analyzer] represents one type of heuristic for detecting an IMSI Catcher
 /// (IC). While maintaining some amount of state is useful, be mindful of how
 /// much memory your [Analyzer] uses at runtime, since rayhunter may run for
@@ -126,6 +173,32 @@ pub trait Analyzer {
     /// thousands of them alongside many other [Analyzers](Analyzer).
     fn analyze_information_element(&mut self, ie: &InformationElement) -> Option<Event>;
 
+    /// Analyze a single [InformationElement] along with the [PacketContext]
+    /// it came in, for heuristics that need to know which cell sent it. The
+    /// default implementation ignores the context.
+    fn analyze_information_element_with_context(
+        &mut self,
+        ie: &InformationElement,
+        _context: &PacketContext,
+    ) -> Option<Event> {
+        self.analyze_information_element(ie)
+    }
+
+    /// Analyze a [SignalSample] of the serving and neighbor cells'
+    /// measurements. These are taken about once a second, so most
+    /// [Analyzers](Analyzer), which only look at signaling, can rely on the
+    /// default implementation, which ignores them.
+    fn analyze_signal_sample(&mut self, _sample: &SignalSample) -> Option<Event> {
+        None
+    }
+
+    /// Gives the [Analyzer] the [Baseline](crate::baseline::Baseline) of what
+    /// the device usually sees, to tell new or unusual things apart from
+    /// familiar ones. It's only available when the [Harness] was given one,
+    /// and is updated with each message after every [Analyzer] has looked at
+    /// it. The default implementation ignores it.
+    fn set_baseline(&mut self, _baseline: SharedBaseline) {}
+
     /// Returns a version number for this Analyzer. This should only ever
     /// increase in value, and do so whenever substantial changes are made to
     /// the Analyzer's heuristic.
@@ -139,6 +212,16 @@ pub struct AnalyzerMetadata {
     pub version: u32,
 }
 
+/// The modem firmware a recording was made with, as far as the modem
+/// reported it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ModemFirmware {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_build: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseband_version: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
 #[derive(Default)]
@@ -152,6 +235,14 @@ pub struct ReportMetadata {
     // clearly differentiate some known false-positive-results from the pre-versioned era from v1
     // heuristics
     pub report_version: u32,
+
+    /// The recording's annotations, as of when the report was served
+    #[serde(skip_serializing_if = "RecordingAnnotations::is_empty")]
+    pub annotations: RecordingAnnotations,
+
+    /// Helps tell apart false positives that only happen with some firmware
+    #[serde(flatten)]
+    pub modem_firmware: ModemFirmware,
 }
 
 impl ReportMetadata {
@@ -166,6 +257,7 @@ impl ReportMetadata {
 /// are expected to be AnalysisRow entries.
 pub struct AnalysisLineNormalizer {
     is_first: bool,
+    annotations: Option<RecordingAnnotations>,
 }
 
 impl Default for AnalysisLineNormalizer {
@@ -176,7 +268,17 @@ impl Default for AnalysisLineNormalizer {
 
 impl AnalysisLineNormalizer {
     pub fn new() -> Self {
-        Self { is_first: true }
+        Self {
+            is_first: true,
+            annotations: None,
+        }
+    }
+
+    /// Replace the annotations in the report metadata, which are only as
+    /// current as when the report was written
+    pub fn with_annotations(mut self, annotations: RecordingAnnotations) -> Self {
+        self.annotations = Some(annotations);
+        self
     }
 
     /// Normalize a single line from an analysis report.
@@ -188,6 +290,9 @@ impl AnalysisLineNormalizer {
             // latest, because the output of the remaining lines will follow latest versions
             if let Ok(mut metadata) = serde_json::from_str::<ReportMetadata>(&line) {
                 metadata.normalize();
+                if let Some(annotations) = &self.annotations {
+                    metadata.annotations = annotations.clone();
+                }
                 serde_json::to_string(&metadata).unwrap_or(line) + "\n"
             } else {
                 line + "\n"
@@ -208,6 +313,10 @@ pub struct AnalysisRow {
     pub packet_timestamp: Option<DateTime<FixedOffset>>,
     pub skipped_message_reason: Option<String>,
     pub events: Vec<Option<Event>>,
+    /// Where the device was when this packet was received. Only set on rows
+    /// containing warnings, and only if GPS data was available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<EventLocation>,
 }
 
 impl AnalysisRow {
@@ -254,6 +363,8 @@ impl<'de> Deserialize<'de> for AnalysisRow {
             packet_timestamp: Option<DateTime<FixedOffset>>,
             skipped_message_reason: Option<String>,
             events: Vec<Option<Event>>,
+            #[serde(default)]
+            location: Option<EventLocation>,
         }
 
         #[derive(Deserialize)]
@@ -272,12 +383,14 @@ impl<'de> Deserialize<'de> for AnalysisRow {
                         packet_timestamp: Some(first_analysis.timestamp),
                         skipped_message_reason: None,
                         events: first_analysis.events.clone(),
+                        location: None,
                     })
                 } else if let Some(first_reason) = v1.skipped_message_reasons.first() {
                     Ok(AnalysisRow {
                         packet_timestamp: Some(v1.timestamp),
                         skipped_message_reason: Some(first_reason.clone()),
                         events: Vec::new(),
+                        location: None,
                     })
                 } else {
                     Err(D::Error::custom(
@@ -289,6 +402,7 @@ impl<'de> Deserialize<'de> for AnalysisRow {
                 packet_timestamp: v2.packet_timestamp,
                 skipped_message_reason: v2.skipped_message_reason,
                 events: v2.events,
+                location: v2.location,
             }),
         }
     }
@@ -296,6 +410,12 @@ impl<'de> Deserialize<'de> for AnalysisRow {
 
 pub struct Harness {
     analyzers: Vec<Box<dyn Analyzer + Send>>,
+    locator: Option<TrackLocator>,
+    signal_tracker: SignalTracker,
+    // only kept when they're collected, see set_collect_signal_samples
+    signal_samples: Option<Vec<SignalSample>>,
+    baseline: Option<SharedBaseline>,
+    modem_firmware: ModemFirmware,
 }
 
 impl Default for Harness {
@@ -308,6 +428,11 @@ impl Harness {
     pub fn new() -> Self {
         Self {
             analyzers: Vec::new(),
+            locator: None,
+            signal_tracker: SignalTracker::new(),
+            signal_samples: None,
+            baseline: None,
+            modem_firmware: ModemFirmware::default(),
         }
     }
 
@@ -335,6 +460,22 @@ impl Harness {
             harness.add_analyzer(Box::new(IncompleteSibAnalyzer::new()))
         }
 
+        if analyzer_config.strong_new_cell {
+            harness.add_analyzer(Box::new(StrongNewCellAnalyzer::new()))
+        }
+
+        if analyzer_config.neighbor_list {
+            harness.add_analyzer(Box::new(NeighborListAnalyzer::new()))
+        }
+
+        if analyzer_config.cell_parameters {
+            harness.add_analyzer(Box::new(CellParameterAnalyzer::new()))
+        }
+
+        if analyzer_config.unfamiliar_cell {
+            harness.add_analyzer(Box::new(UnfamiliarCellAnalyzer::new()))
+        }
+
         if analyzer_config.test_analyzer {
             harness.add_analyzer(Box::new(TestAnalyzer::new()))
         }
@@ -342,16 +483,74 @@ impl Harness {
         harness
     }
 
-    pub fn add_analyzer(&mut self, analyzer: Box<dyn Analyzer + Send>) {
+    pub fn add_analyzer(&mut self, mut analyzer: Box<dyn Analyzer + Send>) {
+        if let Some(baseline) = self.baseline.as_ref() {
+            analyzer.set_baseline(baseline.clone());
+        }
         self.analyzers.push(analyzer);
     }
 
+    /// Share the given baseline with the analyzers, and teach it everything
+    /// that's analyzed from now on
+    pub fn set_baseline(&mut self, baseline: SharedBaseline) {
+        for analyzer in self.analyzers.iter_mut() {
+            analyzer.set_baseline(baseline.clone());
+        }
+        self.baseline = Some(baseline);
+    }
+
+    /// Attach locations from the given GPS track to rows containing warnings.
+    pub fn set_locator(&mut self, locator: TrackLocator) {
+        self.locator = Some(locator);
+    }
+
+    /// Keep the signal samples taken while analyzing QMDL messages, to be
+    /// retrieved with [`Harness::take_signal_samples`]
+    pub fn set_collect_signal_samples(&mut self, collect: bool) {
+        self.signal_samples = collect.then(Vec::new);
+    }
+
+    /// Record the modem firmware the analyzed messages came from in the
+    /// report metadata
+    pub fn set_modem_firmware(&mut self, modem_firmware: ModemFirmware) {
+        self.modem_firmware = modem_firmware;
+    }
+
+    /// The LTE cell the modem was last measured on, in the analyzed messages
+    pub fn serving_cell(&self) -> Option<ServingCell> {
+        self.signal_tracker.serving_cell()
+    }
+
+    /// Returns the signal samples collected since the last call
+    pub fn take_signal_samples(&mut self) -> Vec<SignalSample> {
+        self.signal_samples
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn locate_context(&self, context: &mut PacketContext) {
+        if let (Some(locator), Some(timestamp)) = (&self.locator, context.timestamp) {
+            context.location = locator.locate(&timestamp);
+        }
+    }
+
+    fn locate_warnings(&self, row: &mut AnalysisRow) {
+        if !row.contains_warnings() {
+            return;
+        }
+        if let (Some(locator), Some(timestamp)) = (&self.locator, row.packet_timestamp) {
+            row.location = locator.locate(&timestamp);
+        }
+    }
+
     pub fn analyze_pcap_packet(&mut self, packet: EnhancedPacketBlock) -> AnalysisRow {
         let epoch = DateTime::parse_from_rfc3339("1980-01-06T00:00:00-00:00").unwrap();
         let mut row = AnalysisRow {
             packet_timestamp: Some(epoch + packet.timestamp),
             skipped_message_reason: None,
             events: Vec::new(),
+            location: None,
         };
         let gsmtap_offset = 20 + 8;
         let gsmtap_data = &packet.data[gsmtap_offset..];
@@ -369,14 +568,23 @@ impl Harness {
             header: gsmtap_header,
             payload: packet_data.to_vec(),
         };
+        // the ARFCN is the low 14 bits of the GSMTAP header's fifth and sixth bytes
+        let arfcn = u16::from_be_bytes([gsmtap_data[4], gsmtap_data[5]]) & 0x3fff;
+        let mut context = PacketContext {
+            timestamp: row.packet_timestamp,
+            earfcn: (arfcn != 0).then_some(arfcn as u32),
+            ..Default::default()
+        };
+        self.locate_context(&mut context);
         row.events = match InformationElement::try_from(&gsmtap_message) {
-            Ok(element) => self.analyze_information_element(&element),
+            Ok(element) => self.analyze_information_element_with_context(&element, &context),
             Err(err) => {
                 row.skipped_message_reason =
                     Some(format!("failed to convert gsmtap message to IE: {err:?}"));
                 return row;
             }
         };
+        self.locate_warnings(&mut row);
         row
     }
 
@@ -387,6 +595,7 @@ impl Harness {
                 packet_timestamp: None,
                 skipped_message_reason: None,
                 events: Vec::new(),
+                location: None,
             });
             // unwrap is safe here since we just pushed a value
             let row = rows.last_mut().unwrap();
@@ -398,6 +607,18 @@ impl Harness {
                 }
             };
 
+            if let Some(sample) = self.signal_tracker.process_message(&qmdl_message) {
+                row.packet_timestamp = Some(sample.timestamp);
+                row.events = self.analyze_signal_sample(&sample);
+                if let Some(samples) = self.signal_samples.as_mut() {
+                    samples.push(sample);
+                }
+                continue;
+            }
+
+            // parsing consumes the message, so note where it came from first
+            let mut context = PacketContext::from_message(&qmdl_message);
+            self.locate_context(&mut context);
             let gsmtap_message = match gsmtap_parser::parse(qmdl_message) {
                 Ok(msg) => msg,
                 Err(err) => {
@@ -419,15 +640,40 @@ impl Harness {
                 }
             };
 
-            row.events = self.analyze_information_element(&element);
+            row.events = self.analyze_information_element_with_context(&element, &context);
+        }
+        for row in rows.iter_mut() {
+            self.locate_warnings(row);
         }
         rows
     }
 
     pub fn analyze_information_element(&mut self, ie: &InformationElement) -> Vec<Option<Event>> {
+        self.analyze_information_element_with_context(ie, &PacketContext::default())
+    }
+
+    pub fn analyze_information_element_with_context(
+        &mut self,
+        ie: &InformationElement,
+        context: &PacketContext,
+    ) -> Vec<Option<Event>> {
+        let events = self
+            .analyzers
+            .iter_mut()
+            .map(|analyzer| analyzer.analyze_information_element_with_context(ie, context))
+            .collect();
+        if let Some(baseline) = self.baseline.as_ref() {
+            if let Ok(mut baseline) = baseline.write() {
+                baseline.learn(ie, context);
+            }
+        }
+        events
+    }
+
+    pub fn analyze_signal_sample(&mut self, sample: &SignalSample) -> Vec<Option<Event>> {
         self.analyzers
             .iter_mut()
-            .map(|analyzer| analyzer.analyze_information_element(ie))
+            .map(|analyzer| analyzer.analyze_signal_sample(sample))
             .collect()
     }
 
@@ -447,6 +693,8 @@ impl Harness {
             analyzers,
             rayhunter,
             report_version: REPORT_VERSION,
+            annotations: RecordingAnnotations::default(),
+            modem_firmware: self.modem_firmware.clone(),
         }
     }
 }
@@ -503,4 +751,56 @@ mod tests {
         );
         assert!(row.events[2].is_none());
     }
+
+    #[test]
+    fn test_analysis_row_location_roundtrip() {
+        let row: AnalysisRow = serde_json::from_value(json!({
+            "packet_timestamp": "2023-01-01T00:00:00+00:00",
+            "skipped_message_reason": null,
+            "events": [{ "event_type": "High", "message": "Test warning" }],
+            "location": {
+                "latitude": 52.5,
+                "longitude": 13.4,
+                "accuracy": null,
+                "interpolated": true,
+                "fix_offset_secs": -3
+            }
+        }))
+        .unwrap();
+        let location = row.location.as_ref().unwrap();
+        assert_eq!(location.fix_offset_secs, -3);
+        assert!(serde_json::to_string(&row).unwrap().contains("\"interpolated\":true"));
+
+        // rows without a location don't grow a null field
+        let row: AnalysisRow = serde_json::from_value(json!({
+            "packet_timestamp": null,
+            "skipped_message_reason": "skipped",
+            "events": []
+        }))
+        .unwrap();
+        assert!(row.location.is_none());
+        assert!(!serde_json::to_string(&row).unwrap().contains("location"));
+    }
+
+    #[test]
+    fn test_report_metadata_modem_firmware() {
+        let mut harness = Harness::new();
+        let metadata = serde_json::to_value(harness.get_metadata()).unwrap();
+        assert!(metadata.get("firmware_build").is_none());
+
+        harness.set_modem_firmware(ModemFirmware {
+            firmware_build: Some("MPSS.JO.2.0.2.c1-00064".to_string()),
+            baseband_version: Some("M9607A".to_string()),
+        });
+        let line = serde_json::to_string(&harness.get_metadata()).unwrap();
+        let metadata: ReportMetadata = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            metadata.modem_firmware.firmware_build.as_deref(),
+            Some("MPSS.JO.2.0.2.c1-00064")
+        );
+        assert_eq!(
+            metadata.modem_firmware.baseband_version.as_deref(),
+            Some("M9607A")
+        );
+    }
 }
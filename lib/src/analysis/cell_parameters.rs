@@ -0,0 +1,368 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use super::analyzer::{Analyzer, Event, EventType, PacketContext};
+use super::information_element::InformationElement;
+use crate::baseline::{CellObservation, SharedBaseline};
+use crate::gps::EventLocation;
+
+/// The number of cells to remember. Past this, the one seen longest ago is
+/// forgotten.
+const MAX_KNOWN_CELLS: usize = 4096;
+
+/// A TAC change is seen as happening in place if the device moved less than
+/// this far since the cell was last seen...
+const STATIONARY_MAX_METERS: f64 = 200.0;
+
+/// ...and no more than this long ago
+const STATIONARY_MAX_SECS: i64 = 60 * 60;
+
+/// The parameters an LTE cell was last seen with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellRecord {
+    /// MCC-MNC of the first PLMN in the cell's SIB1, e.g. `310-260`
+    pub plmn: String,
+    pub cell_identity: u32,
+    pub tracking_area_code: u32,
+    pub pci: Option<u16>,
+    pub earfcn: Option<u32>,
+    pub last_seen: Option<DateTime<FixedOffset>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<EventLocation>,
+}
+
+impl CellRecord {
+    /// The parameters of the observed cell, as seen with the given context
+    pub fn new(observation: &CellObservation, context: &PacketContext) -> Self {
+        CellRecord {
+            plmn: observation.plmn.clone(),
+            cell_identity: observation.cell_identity,
+            tracking_area_code: observation.tracking_area_code,
+            pci: observation.pci,
+            earfcn: observation.earfcn,
+            last_seen: context.timestamp,
+            location: context.location.clone(),
+        }
+    }
+
+    fn key(&self) -> (String, u32) {
+        (self.plmn.clone(), self.cell_identity)
+    }
+
+    fn same_parameters(&self, other: &CellRecord) -> bool {
+        self.tracking_area_code == other.tracking_area_code
+            && !differs(self.pci, other.pci)
+            && !differs(self.earfcn, other.earfcn)
+    }
+
+    // whether the device stayed put between the two sightings, going by GPS
+    fn stationary_since(&self, earlier: &CellRecord) -> bool {
+        let (Some(then), Some(now)) = (earlier.last_seen, self.last_seen) else {
+            return false;
+        };
+        let (Some(there), Some(here)) = (&earlier.location, &self.location) else {
+            return false;
+        };
+        (now - then).num_seconds() <= STATIONARY_MAX_SECS
+            && here.distance_meters(there) <= STATIONARY_MAX_METERS
+    }
+}
+
+// PCAPs don't say which PCI a packet came from, which isn't a change
+fn differs<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+    matches!((a, b), (Some(a), Some(b)) if a != b)
+}
+
+/// Every LTE cell seen so far, by PLMN and cell identity. It's serialized as
+/// a list of [CellRecords](CellRecord), so the
+/// [Baseline](crate::baseline::Baseline) can keep it across recordings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<CellRecord>", into = "Vec<CellRecord>")]
+pub struct CellHistory {
+    cells: HashMap<(String, u32), CellRecord>,
+}
+
+impl From<Vec<CellRecord>> for CellHistory {
+    fn from(records: Vec<CellRecord>) -> Self {
+        let mut history = CellHistory::default();
+        for record in records {
+            history.insert(record);
+        }
+        history
+    }
+}
+
+impl From<CellHistory> for Vec<CellRecord> {
+    fn from(history: CellHistory) -> Self {
+        let mut records: Vec<CellRecord> = history.cells.into_values().collect();
+        records.sort_by(|a, b| (&a.plmn, a.cell_identity).cmp(&(&b.plmn, b.cell_identity)));
+        records
+    }
+}
+
+impl CellHistory {
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn get(&self, plmn: &str, cell_identity: u32) -> Option<&CellRecord> {
+        self.cells.get(&(plmn.to_string(), cell_identity))
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &CellRecord> {
+        self.cells.values()
+    }
+
+    pub fn insert(&mut self, record: CellRecord) {
+        let key = record.key();
+        if self.cells.len() >= MAX_KNOWN_CELLS && !self.cells.contains_key(&key) {
+            let oldest = self
+                .cells
+                .iter()
+                .min_by_key(|(_, record)| record.last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.cells.remove(&oldest);
+            }
+        }
+        self.cells.insert(key, record);
+    }
+
+    /// Records a sighting of a cell. A sighting without a PCI or EARFCN keeps
+    /// the one the cell was last seen with.
+    pub fn record(&mut self, mut record: CellRecord) {
+        if let Some(previous) = self.get(&record.plmn, record.cell_identity) {
+            record.pci = record.pci.or(previous.pci);
+            record.earfcn = record.earfcn.or(previous.earfcn);
+        }
+        self.insert(record);
+    }
+}
+
+/// A fake base station often copies the identity of a real cell nearby, but
+/// broadcasts it with its own PCI and frequency, and a different TAC to make
+/// phones register with it.
+///
+/// Cells are compared against the [Baseline](crate::baseline::Baseline) if
+/// there is one, so changes between recordings are caught too. Otherwise each
+/// analyzer keeps its own history, starting from scratch.
+pub struct CellParameterAnalyzer {
+    history: CellHistory,
+    baseline: Option<SharedBaseline>,
+}
+
+impl Default for CellParameterAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CellParameterAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            history: CellHistory::default(),
+            baseline: None,
+        }
+    }
+
+    /// Returns an event if the cell was last seen with different parameters,
+    /// recording the sighting unless the baseline does that
+    fn observe(&mut self, current: CellRecord) -> Option<Event> {
+        if let Some(baseline) = self.baseline.as_ref() {
+            // the harness teaches the baseline once every analyzer has seen
+            // the message
+            let baseline = baseline.read().ok()?;
+            let previous = baseline
+                .cell_history()
+                .get(&current.plmn, current.cell_identity)?;
+            return Self::compare(previous, &current);
+        }
+        let event = self
+            .history
+            .get(&current.plmn, current.cell_identity)
+            .and_then(|previous| Self::compare(previous, &current));
+        self.history.record(current);
+        event
+    }
+
+    fn compare(previous: &CellRecord, current: &CellRecord) -> Option<Event> {
+        if previous.same_parameters(current) {
+            return None;
+        }
+        Some(Self::parameter_change(previous, current))
+    }
+
+    fn parameter_change(previous: &CellRecord, current: &CellRecord) -> Event {
+        let mut changes = Vec::new();
+        if previous.tracking_area_code != current.tracking_area_code {
+            changes.push(format!(
+                "TAC {} to {}",
+                previous.tracking_area_code, current.tracking_area_code
+            ));
+        }
+        if let (Some(before), Some(after)) = (previous.pci, current.pci) {
+            if before != after {
+                changes.push(format!("PCI {before} to {after}"));
+            }
+        }
+        if let (Some(before), Some(after)) = (previous.earfcn, current.earfcn) {
+            if before != after {
+                changes.push(format!("EARFCN {before} to {after}"));
+            }
+        }
+        let tac_changed = previous.tracking_area_code != current.tracking_area_code;
+        let stationary = current.stationary_since(previous);
+        let event_type = match (tac_changed, stationary) {
+            (true, true) => EventType::High,
+            (true, false) => EventType::Medium,
+            (false, _) => EventType::Low,
+        };
+        let mut message = format!(
+            "Cell {} of PLMN {} changed its {}",
+            current.cell_identity,
+            current.plmn,
+            changes.join(", ")
+        );
+        if tac_changed && stationary {
+            message.push_str(" without the device moving");
+        }
+        Event {
+            event_type,
+            message,
+        }
+    }
+}
+
+impl Analyzer for CellParameterAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Cell Parameter Change")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(
+            "Tests whether a cell identity seen before shows up with a different TAC, PCI or \
+            EARFCN. A TAC change while GPS shows the device hasn't moved is the strongest sign. \
+            Networks occasionally re-plan their cells, which triggers this once per cell.",
+        )
+    }
+
+    fn get_version(&self) -> u32 {
+        2
+    }
+
+    fn set_baseline(&mut self, baseline: SharedBaseline) {
+        self.baseline = Some(baseline);
+    }
+
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_information_element_with_context(
+        &mut self,
+        ie: &InformationElement,
+        context: &PacketContext,
+    ) -> Option<Event> {
+        let observation = CellObservation::from_information_element(ie, context)?;
+        self.observe(CellRecord::new(&observation, context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::baseline::Baseline;
+
+    fn record(tac: u32, pci: u16, earfcn: u32, secs: i64, latitude: f64) -> CellRecord {
+        CellRecord {
+            plmn: "310-260".to_string(),
+            cell_identity: 20001,
+            tracking_area_code: tac,
+            pci: Some(pci),
+            earfcn: Some(earfcn),
+            last_seen: DateTime::from_timestamp(1_714_564_800 + secs, 0)
+                .map(|time| time.fixed_offset()),
+            location: Some(EventLocation {
+                latitude,
+                longitude: 13.0,
+                accuracy: None,
+                interpolated: false,
+                fix_offset_secs: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_parameter_changes() {
+        let mut analyzer = CellParameterAnalyzer::new();
+        assert!(analyzer.observe(record(100, 301, 5230, 0, 52.0)).is_none());
+        assert!(analyzer.observe(record(100, 301, 5230, 1, 52.0)).is_none());
+
+        let event = analyzer.observe(record(100, 12, 5230, 2, 52.0)).unwrap();
+        assert_eq!(event.event_type, EventType::Low);
+        assert!(event.message.contains("PCI 301 to 12"));
+
+        // about 1.1km north
+        let event = analyzer.observe(record(200, 12, 5230, 3, 52.01)).unwrap();
+        assert_eq!(event.event_type, EventType::Medium);
+        assert!(event.message.contains("TAC 100 to 200"));
+
+        let event = analyzer.observe(record(300, 12, 5230, 4, 52.01)).unwrap();
+        assert_eq!(event.event_type, EventType::High);
+        assert!(event.message.ends_with("without the device moving"));
+
+        // too long ago to say whether the device moved in between
+        let event = analyzer
+            .observe(record(400, 12, 5230, 4 + STATIONARY_MAX_SECS + 1, 52.01))
+            .unwrap();
+        assert_eq!(event.event_type, EventType::Medium);
+    }
+
+    #[test]
+    fn test_history_keeps_pci_and_earfcn() {
+        let mut history = CellHistory::default();
+        history.record(record(100, 301, 5230, 0, 52.0));
+
+        // a PCAP doesn't have the PCI
+        let mut without_pci = record(100, 301, 2050, 1, 52.0);
+        without_pci.pci = None;
+        history.record(without_pci);
+        let recorded = history.get("310-260", 20001).unwrap();
+        assert_eq!(recorded.pci, Some(301));
+        assert_eq!(recorded.earfcn, Some(2050));
+    }
+
+    #[test]
+    fn test_baseline_history() {
+        let baseline = SharedBaseline::default();
+        baseline
+            .write()
+            .unwrap()
+            .learn_cell_parameters(record(100, 301, 5230, 0, 52.0));
+
+        let json = serde_json::to_string(&*baseline.read().unwrap()).unwrap();
+        let restored: Baseline = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            restored.cell_history().get("310-260", 20001),
+            Some(&record(100, 301, 5230, 0, 52.0))
+        );
+
+        // a new analyzer picks up where the baseline left off, and leaves
+        // recording the cell to whoever teaches the baseline
+        let mut analyzer = CellParameterAnalyzer::new();
+        analyzer.set_baseline(Arc::new(RwLock::new(restored)));
+        assert!(analyzer.observe(record(100, 301, 5230, 10, 52.0)).is_none());
+        assert!(analyzer.observe(record(100, 301, 2050, 11, 52.0)).is_some());
+        assert!(analyzer.observe(record(100, 301, 2050, 12, 52.0)).is_some());
+        assert!(analyzer.history.is_empty());
+    }
+}
@@ -268,7 +268,6 @@ impl CellularNetworkAnalyzer {
                             rssi: Some(avg_signal),
                             rsrp: None,
                             rsrq: None,
-                            sinr: None,
                             cqi: None,
                             bandwidth: None,
                         });
@@ -1,11 +1,15 @@
 pub mod analyzer;
+pub mod cell_parameters;
 pub mod connection_redirect_downgrade;
 pub mod imsi_requested;
 pub mod incomplete_sib;
 pub mod information_element;
 pub mod nas_null_cipher;
+pub mod neighbor_list;
 pub mod null_cipher;
 pub mod priority_2g_downgrade;
+pub mod strong_new_cell;
 pub mod test_analyzer;
+pub mod unfamiliar_cell;
 pub mod util;
 pub mod cellular_network;
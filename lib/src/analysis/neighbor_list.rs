@@ -0,0 +1,483 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use super::analyzer::{Analyzer, Event, EventType, PacketContext};
+use super::information_element::{InformationElement, LteInformationElement};
+use crate::baseline::SharedBaseline;
+use telcom_parser::lte_rrc::{
+    BCCH_DL_SCH_MessageType, BCCH_DL_SCH_MessageType_c1, DL_DCCH_MessageType,
+    DL_DCCH_MessageType_c1, MeasConfig, MeasObjectToAddModMeasObject,
+    RRCConnectionReconfigurationCriticalExtensions,
+    RRCConnectionReconfigurationCriticalExtensions_c1,
+    SystemInformation_r8_IEsSib_TypeAndInfo_Entry, SystemInformationCriticalExtensions,
+};
+
+/// The number of cells to remember before starting over, to bound memory use
+const MAX_KNOWN_CELLS: usize = 1024;
+
+/// A cell, by EARFCN and PCI
+type Cell = (u32, u16);
+
+/// The neighbors a cell has told us about, either in its SIB4 and SIB5
+/// broadcasts or in the measurement configs it sent us
+#[derive(Default)]
+struct NeighborList {
+    // carrier frequencies to measure, even if no cells on them are listed
+    frequencies: HashSet<u32>,
+    cells: HashSet<Cell>,
+    saw_system_information: bool,
+    reported_empty: bool,
+}
+
+impl NeighborList {
+    fn is_empty(&self) -> bool {
+        self.frequencies.is_empty() && self.cells.is_empty()
+    }
+
+    /// Whether this list, sent by `own`, points the phone to the given cell.
+    /// Where the cells on a frequency are listed one by one, only those
+    /// count. Otherwise a listed frequency, or the cell's own frequency once
+    /// it's advertised anything, leaves finding its cells to the phone.
+    fn lists(&self, own: Cell, (earfcn, pci): Cell) -> bool {
+        if self.cells.contains(&(earfcn, pci)) {
+            return true;
+        }
+        let measured = self.frequencies.contains(&earfcn) || (earfcn == own.0 && !self.is_empty());
+        measured && !self.cells.iter().any(|&(listed, _)| listed == earfcn)
+    }
+}
+
+/// The neighbors advertised in a single message
+#[derive(Debug, Default, PartialEq)]
+pub struct AdvertisedNeighbors {
+    /// Carrier frequencies to measure, other than the cell's own
+    pub frequencies: Vec<u32>,
+    /// Neighbor cells, by EARFCN and PCI
+    pub cells: Vec<(u32, u16)>,
+}
+
+impl AdvertisedNeighbors {
+    fn add_frequency(&mut self, cell: Cell, earfcn: u32) {
+        if earfcn != cell.0 {
+            self.frequencies.push(earfcn);
+        }
+    }
+
+    fn add_cell(&mut self, cell: Cell, neighbor: Cell) {
+        // the serving cell shows up in some measurement configs
+        if neighbor != cell {
+            self.cells.push(neighbor);
+        }
+    }
+
+    fn add_meas_config(&mut self, cell: Cell, meas_config: &MeasConfig) {
+        let Some(meas_objects) = meas_config.meas_object_to_add_mod_list.as_ref() else {
+            return;
+        };
+        for meas_object in &meas_objects.0 {
+            let MeasObjectToAddModMeasObject::MeasObjectEUTRA(eutra) = &meas_object.meas_object
+            else {
+                continue;
+            };
+            let earfcn = eutra.carrier_freq.0 as u32;
+            self.add_frequency(cell, earfcn);
+            if let Some(cells) = eutra.cells_to_add_mod_list.as_ref() {
+                for neighbor in &cells.0 {
+                    self.add_cell(cell, (earfcn, neighbor.phys_cell_id.0));
+                }
+            }
+        }
+    }
+}
+
+/// Returns the neighbors the given cell advertised in its SIB4 and SIB5
+/// broadcasts, or in a measurement config, if the message is one of those
+pub fn advertised_neighbors(
+    cell: (u32, u16),
+    ie: &InformationElement,
+) -> Option<AdvertisedNeighbors> {
+    let InformationElement::LTE(lte_ie) = ie else {
+        return None;
+    };
+    let mut advertised = AdvertisedNeighbors::default();
+    match &**lte_ie {
+        LteInformationElement::BcchDlSch(bcch_dl_sch_message) => {
+            let BCCH_DL_SCH_MessageType::C1(BCCH_DL_SCH_MessageType_c1::SystemInformation(
+                system_information,
+            )) = &bcch_dl_sch_message.message
+            else {
+                return None;
+            };
+            let SystemInformationCriticalExtensions::SystemInformation_r8(sib) =
+                &system_information.critical_extensions
+            else {
+                return None;
+            };
+            for entry in &sib.sib_type_and_info.0 {
+                match entry {
+                    SystemInformation_r8_IEsSib_TypeAndInfo_Entry::Sib4(sib4) => {
+                        if let Some(neighbors) = sib4.intra_freq_neigh_cell_list.as_ref() {
+                            for neighbor in &neighbors.0 {
+                                advertised.add_cell(cell, (cell.0, neighbor.phys_cell_id.0));
+                            }
+                        }
+                    }
+                    SystemInformation_r8_IEsSib_TypeAndInfo_Entry::Sib5(sib5) => {
+                        for carrier in &sib5.inter_freq_carrier_freq_list.0 {
+                            let earfcn = carrier.dl_carrier_freq.0 as u32;
+                            advertised.add_frequency(cell, earfcn);
+                            if let Some(neighbors) = carrier.inter_freq_neigh_cell_list.as_ref() {
+                                for neighbor in &neighbors.0 {
+                                    advertised.add_cell(cell, (earfcn, neighbor.phys_cell_id.0));
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        LteInformationElement::DlDcch(dcch_msg) => {
+            let DL_DCCH_MessageType::C1(DL_DCCH_MessageType_c1::RrcConnectionReconfiguration(
+                reconfiguration,
+            )) = &dcch_msg.message
+            else {
+                return None;
+            };
+            let RRCConnectionReconfigurationCriticalExtensions::C1(
+                RRCConnectionReconfigurationCriticalExtensions_c1::RrcConnectionReconfiguration_r8(
+                    r8,
+                ),
+            ) = &reconfiguration.critical_extensions
+            else {
+                return None;
+            };
+            advertised.add_meas_config(cell, r8.meas_config.as_ref()?);
+        }
+        _ => return None,
+    }
+    Some(advertised)
+}
+
+/// Real networks are planned so that each cell tells phones which cells
+/// around it to measure and hand over to. A fake base station isn't part of
+/// that plan, so none of the real cells list it as a neighbor, and it usually
+/// doesn't bother to list any neighbors itself.
+pub struct NeighborListAnalyzer {
+    neighbor_lists: HashMap<Cell, NeighborList>,
+    serving_cell: Option<Cell>,
+    baseline: Option<SharedBaseline>,
+}
+
+impl Default for NeighborListAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NeighborListAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            neighbor_lists: HashMap::new(),
+            serving_cell: None,
+            baseline: None,
+        }
+    }
+
+    fn neighbor_list(&mut self, cell: Cell) -> &mut NeighborList {
+        if self.neighbor_lists.len() >= MAX_KNOWN_CELLS && !self.neighbor_lists.contains_key(&cell)
+        {
+            self.neighbor_lists.clear();
+        }
+        self.neighbor_lists.entry(cell).or_default()
+    }
+
+    fn add_frequency(&mut self, cell: Cell, earfcn: u32) {
+        self.neighbor_list(cell).frequencies.insert(earfcn);
+    }
+
+    fn add_neighbor(&mut self, cell: Cell, neighbor: Cell) {
+        self.neighbor_list(cell).cells.insert(neighbor);
+    }
+
+    /// Records the neighbors advertised in a message sent by the given cell
+    fn add_neighbors_from(&mut self, cell: Cell, ie: &InformationElement) {
+        if let InformationElement::LTE(lte_ie) = ie {
+            if let LteInformationElement::BcchDlSch(_) = &**lte_ie {
+                self.neighbor_list(cell).saw_system_information = true;
+            }
+        }
+        let Some(advertised) = advertised_neighbors(cell, ie) else {
+            return;
+        };
+        for earfcn in advertised.frequencies {
+            self.add_frequency(cell, earfcn);
+        }
+        for neighbor in advertised.cells {
+            self.add_neighbor(cell, neighbor);
+        }
+    }
+
+    /// Whether any cell we've seen so far would have pointed the phone to the
+    /// given one, or the baseline has seen it listed in earlier recordings
+    fn is_listed(&self, cell: Cell) -> bool {
+        let listed_now = self
+            .neighbor_lists
+            .iter()
+            .any(|(&other, neighbor_list)| other != cell && neighbor_list.lists(other, cell));
+        listed_now || self.listed_in_baseline(cell)
+    }
+
+    fn listed_in_baseline(&self, (earfcn, pci): Cell) -> bool {
+        let Some(baseline) = &self.baseline else {
+            return false;
+        };
+        baseline
+            .read()
+            .is_ok_and(|baseline| baseline.neighbor(earfcn, pci).is_some())
+    }
+
+    /// Updates the serving cell, returning an event if the new cell wasn't
+    /// listed by any of the cells before it, or the one we left never listed
+    /// any neighbors
+    fn change_serving_cell(&mut self, cell: Cell) -> Option<Event> {
+        let Some(previous) = self.serving_cell.replace(cell) else {
+            self.neighbor_list(cell);
+            return None;
+        };
+        if previous == cell {
+            return None;
+        }
+
+        let mut event = None;
+        if let Some(neighbor_list) = self.neighbor_lists.get_mut(&previous) {
+            if neighbor_list.saw_system_information
+                && neighbor_list.is_empty()
+                && !neighbor_list.reported_empty
+            {
+                neighbor_list.reported_empty = true;
+                event = Some(Event {
+                    event_type: EventType::Low,
+                    message: format!(
+                        "Cell {} on EARFCN {} didn't advertise any neighbor cells",
+                        previous.1, previous.0
+                    ),
+                });
+            }
+        }
+
+        // a cell we've been served by before has already been checked, and
+        // without any neighbor lists to go on there's nothing to check against
+        let is_new = !self.neighbor_lists.contains_key(&cell);
+        let have_neighbor_lists = self.neighbor_lists.values().any(|list| !list.is_empty());
+        if is_new && have_neighbor_lists && !self.is_listed(cell) {
+            // the unlisted cell is more interesting than an empty list
+            event = Some(Event {
+                event_type: EventType::Medium,
+                message: format!(
+                    "Cell {} on EARFCN {} isn't listed as a neighbor by any of the cells seen before it",
+                    cell.1, cell.0
+                ),
+            });
+        }
+        self.neighbor_list(cell);
+        event
+    }
+}
+
+impl Analyzer for NeighborListAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Neighbor List Consistency")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(
+            "Tests whether the phone moved to a cell that none of the previously seen cells \
+            advertised as a neighbor in their SIB4/SIB5 broadcasts or measurement configs, \
+            and whether a cell advertised no neighbors at all. Driving into an area served \
+            by a different network, or networks that leave neighbor discovery to the phone, \
+            can trigger this too.",
+        )
+    }
+
+    fn get_version(&self) -> u32 {
+        2
+    }
+
+    fn set_baseline(&mut self, baseline: SharedBaseline) {
+        self.baseline = Some(baseline);
+    }
+
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_information_element_with_context(
+        &mut self,
+        ie: &InformationElement,
+        context: &PacketContext,
+    ) -> Option<Event> {
+        let (Some(earfcn), Some(pci)) = (context.earfcn, context.pci) else {
+            return None;
+        };
+        let cell = (earfcn, pci);
+        let event = self.change_serving_cell(cell);
+        self.add_neighbors_from(cell, ie);
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telcom_parser::decode;
+
+    #[test]
+    fn test_unlisted_cell() {
+        let mut analyzer = NeighborListAnalyzer::new();
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        analyzer.add_neighbor((5230, 301), (5230, 12));
+        analyzer.add_frequency((5230, 301), 2050);
+
+        // listed by PCI, or on a listed frequency without a cell list
+        assert!(analyzer.change_serving_cell((5230, 12)).is_none());
+        assert!(analyzer.change_serving_cell((2050, 160)).is_none());
+
+        // the cells on 5230 were listed one by one, so others on it weren't
+        let event = analyzer.change_serving_cell((5230, 13)).unwrap();
+        assert!(event.message.contains("Cell 13 on EARFCN 5230"));
+
+        let event = analyzer.change_serving_cell((700, 99)).unwrap();
+        assert_eq!(event.event_type, EventType::Medium);
+        assert!(event.message.contains("Cell 99 on EARFCN 700"));
+
+        // only the first visit is checked
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        assert!(analyzer.change_serving_cell((700, 99)).is_none());
+    }
+
+    #[test]
+    fn test_listed_in_baseline() {
+        let baseline = SharedBaseline::default();
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2024-05-01T08:00:00+00:00").unwrap();
+        baseline
+            .write()
+            .unwrap()
+            .learn_neighbor((5230, 301), (700, 99), timestamp);
+
+        let mut analyzer = NeighborListAnalyzer::new();
+        analyzer.set_baseline(baseline);
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        analyzer.add_neighbor((5230, 301), (5230, 12));
+
+        // listed by a cell in an earlier recording
+        assert!(analyzer.change_serving_cell((700, 99)).is_none());
+        assert!(analyzer.change_serving_cell((800, 7)).is_some());
+    }
+
+    #[test]
+    fn test_cell_without_neighbors() {
+        let mut analyzer = NeighborListAnalyzer::new();
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        analyzer.neighbor_list((5230, 301)).saw_system_information = true;
+
+        // nothing has listed any neighbors yet, so the new cell isn't flagged
+        let event = analyzer.change_serving_cell((5230, 12)).unwrap();
+        assert_eq!(event.event_type, EventType::Low);
+        assert!(event.message.contains("Cell 301 on EARFCN 5230"));
+
+        // and each cell is only reported once
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        assert!(analyzer.change_serving_cell((5230, 12)).is_none());
+    }
+
+    #[test]
+    fn test_lists_own_frequency() {
+        let mut analyzer = NeighborListAnalyzer::new();
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        analyzer.add_frequency((5230, 301), 2050);
+
+        // without a cell list, the phone finds cells on the serving frequency
+        // by itself
+        assert!(analyzer.change_serving_cell((5230, 12)).is_none());
+        // but a cell that hasn't advertised anything vouches for nothing
+        assert!(analyzer.change_serving_cell((700, 99)).is_some());
+    }
+
+    fn lte_ie(lte_ie: LteInformationElement) -> InformationElement {
+        InformationElement::LTE(Box::new(lte_ie))
+    }
+
+    #[test]
+    fn test_advertised_neighbors_sib4() {
+        // SIB4 listing intra-frequency neighbors 12 and 13
+        let sib4 = [0x00, 0x09, 0x04, 0x0c, 0x78, 0x1a, 0xf0];
+        let ie = lte_ie(LteInformationElement::BcchDlSch(decode(&sib4).unwrap()));
+        assert_eq!(
+            advertised_neighbors((5230, 301), &ie),
+            Some(AdvertisedNeighbors {
+                frequencies: vec![],
+                cells: vec![(5230, 12), (5230, 13)],
+            })
+        );
+    }
+
+    #[test]
+    fn test_advertised_neighbors_sib5() {
+        // SIB5 with carrier 2050 listing cell 160, and carrier 700 without
+        // a cell list
+        let sib5 = [
+            0x00, 0x0c, 0x45, 0x04, 0x01, 0x0c, 0x94, 0xa7, 0xa8, 0x28, 0x1e, 0x20, 0x0a, 0xf0,
+            0x64, 0xa5, 0x3d, 0x40,
+        ];
+        let ie = lte_ie(LteInformationElement::BcchDlSch(decode(&sib5).unwrap()));
+        assert_eq!(
+            advertised_neighbors((5230, 301), &ie),
+            Some(AdvertisedNeighbors {
+                frequencies: vec![2050, 700],
+                cells: vec![(2050, 160)],
+            })
+        );
+    }
+
+    #[test]
+    fn test_advertised_neighbors_meas_config() {
+        // RRCConnectionReconfiguration measuring carrier 2050, with cells 160
+        // and 161
+        let reconfiguration = [
+            0x20, 0x10, 0x10, 0x00, 0x00, 0x04, 0x04, 0x01, 0x3a, 0x10, 0x28, 0x1e, 0x15, 0x0b,
+            0xc0,
+        ];
+        let ie = lte_ie(LteInformationElement::DlDcch(Box::new(
+            decode(&reconfiguration).unwrap(),
+        )));
+        let advertised = advertised_neighbors((5230, 301), &ie).unwrap();
+        assert_eq!(
+            advertised,
+            AdvertisedNeighbors {
+                frequencies: vec![2050],
+                cells: vec![(2050, 160), (2050, 161)],
+            }
+        );
+
+        // and a cell on a frequency with a cell list has to be on it
+        let mut analyzer = NeighborListAnalyzer::new();
+        assert!(analyzer.change_serving_cell((5230, 301)).is_none());
+        analyzer.add_neighbors_from((5230, 301), &ie);
+        assert!(analyzer.change_serving_cell((2050, 161)).is_none());
+        assert!(analyzer.change_serving_cell((2050, 162)).is_some());
+    }
+
+    #[test]
+    fn test_needs_cell_context() {
+        let mut analyzer = NeighborListAnalyzer::new();
+        let ie = InformationElement::GSM;
+        let context = PacketContext::default();
+        assert!(
+            analyzer
+                .analyze_information_element_with_context(&ie, &context)
+                .is_none()
+        );
+        assert!(analyzer.serving_cell.is_none());
+    }
+}
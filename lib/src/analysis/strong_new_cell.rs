@@ -0,0 +1,191 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use super::analyzer::{Analyzer, Event, EventType};
+use super::information_element::InformationElement;
+use crate::signal::SignalSample;
+
+/// Serving cells at least this strong are suspicious if nothing's heard of them
+const STRONG_RSRP_DBM: i16 = -75;
+
+/// How many samples a suspicious cell has to report a neighbor in
+const NEIGHBOR_GRACE_SAMPLES: usize = 5;
+
+/// The number of cells to remember before starting over, to bound memory use
+const MAX_KNOWN_CELLS: usize = 4096;
+
+/// A fake base station tends to show up as a suddenly very strong cell that
+/// none of the surrounding cells have been measured as a neighbor, and which
+/// can't hear any neighbors itself.
+pub struct StrongNewCellAnalyzer {
+    // cells seen as either serving or neighbor cells, by EARFCN and PCI
+    known_cells: HashSet<(u32, u16)>,
+    serving_cell: Option<(u32, u16)>,
+    suspect: Option<SuspectCell>,
+}
+
+struct SuspectCell {
+    rsrp: i16,
+    samples: usize,
+}
+
+impl Default for StrongNewCellAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StrongNewCellAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            known_cells: HashSet::new(),
+            serving_cell: None,
+            suspect: None,
+        }
+    }
+
+    fn remember(&mut self, cell: (u32, u16)) {
+        if self.known_cells.len() >= MAX_KNOWN_CELLS {
+            self.known_cells.clear();
+        }
+        self.known_cells.insert(cell);
+    }
+}
+
+impl Analyzer for StrongNewCellAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Strong New Cell Without Neighbors")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(
+            "Tests whether the phone moved to an unusually strong cell that was never measured \
+            as a neighbor of the previous cells, and that doesn't measure any neighbors of its \
+            own. Standing right next to a real small cell, or a cell with neighbor measurements \
+            turned off, can trigger this too.",
+        )
+    }
+
+    fn get_version(&self) -> u32 {
+        1
+    }
+
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_signal_sample(&mut self, sample: &SignalSample) -> Option<Event> {
+        let cell = (sample.earfcn, sample.pci);
+        if self.serving_cell != Some(cell) {
+            // the first cell we see has nothing to be compared against
+            let is_new = self.serving_cell.is_some() && !self.known_cells.contains(&cell);
+            self.suspect = match sample.signal.rsrp {
+                Some(rsrp) if is_new && rsrp >= STRONG_RSRP_DBM => {
+                    Some(SuspectCell { rsrp, samples: 0 })
+                }
+                _ => None,
+            };
+            self.serving_cell = Some(cell);
+        } else if !sample.neighbor_cells.is_empty() {
+            // neighbors in a new cell's first sample may have been measured by
+            // the previous cell, so they only count from the second one on
+            self.suspect = None;
+        } else if let Some(suspect) = self.suspect.as_mut() {
+            suspect.samples += 1;
+        }
+
+        self.remember(cell);
+        for neighbor in &sample.neighbor_cells {
+            if let (Some(earfcn), Some(pci)) = (neighbor.earfcn, neighbor.physical_cell_id) {
+                self.remember((earfcn, pci));
+            }
+        }
+
+        let suspect = self
+            .suspect
+            .take_if(|suspect| suspect.samples >= NEIGHBOR_GRACE_SAMPLES)?;
+        Some(Event {
+            event_type: EventType::Medium,
+            message: format!(
+                "Cell {} on EARFCN {} appeared at {} dBm without being measured as a neighbor \
+                first, and doesn't measure any neighbor cells",
+                sample.pci, sample.earfcn, suspect.rsrp
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cellular_info::{NeighborCellInfo, SignalInfo};
+    use chrono::DateTime;
+
+    fn sample(earfcn: u32, pci: u16, rsrp: i16, neighbors: &[(u32, u16)]) -> SignalSample {
+        SignalSample {
+            timestamp: DateTime::parse_from_rfc3339("2024-05-01T12:00:00+00:00").unwrap(),
+            earfcn,
+            pci,
+            signal: SignalInfo {
+                rsrp: Some(rsrp),
+                rsrq: None,
+                rssi: None,
+                cqi: None,
+                bandwidth: None,
+            },
+            neighbor_cells: neighbors
+                .iter()
+                .map(|&(earfcn, pci)| NeighborCellInfo {
+                    physical_cell_id: Some(pci),
+                    earfcn: Some(earfcn),
+                    rsrp: Some(-100),
+                    rsrq: None,
+                    plmn_info: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn run(analyzer: &mut StrongNewCellAnalyzer, samples: &[SignalSample]) -> Vec<Event> {
+        samples
+            .iter()
+            .filter_map(|sample| analyzer.analyze_signal_sample(sample))
+            .collect()
+    }
+
+    #[test]
+    fn test_strong_unknown_cell_without_neighbors() {
+        let mut analyzer = StrongNewCellAnalyzer::new();
+        let mut samples = vec![sample(5230, 301, -100, &[(5230, 12)])];
+        samples.extend((0..=NEIGHBOR_GRACE_SAMPLES).map(|_| sample(2050, 99, -60, &[])));
+        let events = run(&mut analyzer, &samples);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, EventType::Medium);
+        assert!(events[0].message.contains("Cell 99 on EARFCN 2050"));
+    }
+
+    #[test]
+    fn test_ignores_known_weak_or_connected_cells() {
+        // a cell that was measured as a neighbor first
+        let mut analyzer = StrongNewCellAnalyzer::new();
+        let mut samples = vec![sample(5230, 301, -100, &[(2050, 99)])];
+        samples.extend((0..=NEIGHBOR_GRACE_SAMPLES).map(|_| sample(2050, 99, -60, &[])));
+        assert!(run(&mut analyzer, &samples).is_empty());
+
+        // a weak cell
+        let mut analyzer = StrongNewCellAnalyzer::new();
+        let mut samples = vec![sample(5230, 301, -100, &[])];
+        samples.extend((0..=NEIGHBOR_GRACE_SAMPLES).map(|_| sample(2050, 99, -110, &[])));
+        assert!(run(&mut analyzer, &samples).is_empty());
+
+        // a cell that measures its neighbors
+        let mut analyzer = StrongNewCellAnalyzer::new();
+        let mut samples = vec![
+            sample(5230, 301, -100, &[]),
+            sample(2050, 99, -60, &[]),
+            sample(2050, 99, -60, &[(2050, 7)]),
+        ];
+        samples.extend((0..=NEIGHBOR_GRACE_SAMPLES).map(|_| sample(2050, 99, -60, &[])));
+        assert!(run(&mut analyzer, &samples).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+
+use super::analyzer::{Analyzer, Event, EventType, PacketContext};
+use super::information_element::InformationElement;
+use crate::baseline::{Baseline, CellObservation, SharedBaseline};
+
+/// Compares the cells the device sees against its baseline of the cells it
+/// usually sees. Without a baseline, this never triggers.
+pub struct UnfamiliarCellAnalyzer {
+    baseline: Option<SharedBaseline>,
+}
+
+impl Default for UnfamiliarCellAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UnfamiliarCellAnalyzer {
+    pub fn new() -> Self {
+        Self { baseline: None }
+    }
+
+    fn check(baseline: &Baseline, observation: &CellObservation) -> Option<Event> {
+        let cell = format!(
+            "Cell {} of PLMN {}",
+            observation.cell_identity, observation.plmn
+        );
+        if baseline.is_first_sighting(observation) {
+            // driving somewhere new turns up new cells all the time, so only
+            // new cells in places the device knows well are interesting
+            let familiar_area = observation
+                .area
+                .and_then(|area| baseline.area(&area))
+                .is_some_and(|sightings| sightings.is_usual());
+            if !familiar_area {
+                return None;
+            }
+            return Some(Event {
+                event_type: EventType::Low,
+                message: format!("{cell} was seen for the first time, in a familiar area"),
+            });
+        }
+        let deviations = baseline.deviations(observation);
+        if deviations.is_empty() {
+            return None;
+        }
+        let deviations: Vec<String> = deviations.iter().map(|d| d.to_string()).collect();
+        Some(Event {
+            event_type: EventType::Medium,
+            message: format!(
+                "{cell}, which is usually seen here, showed up with an unusual {}",
+                deviations.join(", ")
+            ),
+        })
+    }
+}
+
+impl Analyzer for UnfamiliarCellAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Unfamiliar Cell")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(
+            "Compares cells against the baseline of cells the device has seen over many \
+            recordings. Tests whether a cell is seen for the first time in an area the device \
+            knows well, and whether a cell it usually sees shows up with a different TAC, PCI, \
+            EARFCN, band or minimum receive level. New or re-planned cells trigger this once. \
+            Only active when the baseline is enabled.",
+        )
+    }
+
+    fn get_version(&self) -> u32 {
+        1
+    }
+
+    fn set_baseline(&mut self, baseline: SharedBaseline) {
+        self.baseline = Some(baseline);
+    }
+
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_information_element_with_context(
+        &mut self,
+        ie: &InformationElement,
+        context: &PacketContext,
+    ) -> Option<Event> {
+        let baseline = self.baseline.as_ref()?;
+        let observation = CellObservation::from_information_element(ie, context)?;
+        let baseline = baseline.read().ok()?;
+        Self::check(&baseline, &observation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baseline::Area;
+    use chrono::{DateTime, FixedOffset};
+
+    fn at(day: u32) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(&format!("2024-05-{day:02}T08:00:00+00:00")).unwrap()
+    }
+
+    fn observation(cell_identity: u32, tac: u32) -> CellObservation {
+        CellObservation {
+            plmn: "310-260".to_string(),
+            cell_identity,
+            tracking_area_code: tac,
+            pci: Some(301),
+            earfcn: Some(5230),
+            q_rx_lev_min: -128,
+            freq_band_indicator: 2,
+            area: Some(Area {
+                latitude: 5200,
+                longitude: 1300,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_unfamiliar_cells() {
+        let mut baseline = Baseline::default();
+        // nothing's familiar on the first day
+        baseline.learn_cell(&observation(20001, 100), at(1));
+        assert!(UnfamiliarCellAnalyzer::check(&baseline, &observation(20002, 100)).is_none());
+        assert!(UnfamiliarCellAnalyzer::check(&baseline, &observation(20001, 200)).is_none());
+
+        baseline.learn_cell(&observation(20001, 100), at(2));
+        baseline.learn_cell(&observation(20001, 100), at(3));
+        assert!(UnfamiliarCellAnalyzer::check(&baseline, &observation(20001, 100)).is_none());
+
+        let event = UnfamiliarCellAnalyzer::check(&baseline, &observation(20002, 100)).unwrap();
+        assert_eq!(event.event_type, EventType::Low);
+        assert!(event.message.starts_with("Cell 20002 of PLMN 310-260"));
+
+        let event = UnfamiliarCellAnalyzer::check(&baseline, &observation(20001, 200)).unwrap();
+        assert_eq!(event.event_type, EventType::Medium);
+        assert!(event.message.ends_with("an unusual TAC 200"));
+
+        // a new cell somewhere unfamiliar
+        let mut elsewhere = observation(20003, 100);
+        elsewhere.area = Some(Area {
+            latitude: 4000,
+            longitude: -7400,
+        });
+        assert!(UnfamiliarCellAnalyzer::check(&baseline, &elsewhere).is_none());
+    }
+}
@@ -1 +1,14 @@
+use telcom_parser::lte_rrc::{MCC, MCC_MNC_Digit, MNC, PLMN_Identity};
 
+/// Formats a PLMN as MCC-MNC, e.g. `310-260`, with a `?` for a missing MCC
+pub fn format_plmn(plmn: &PLMN_Identity) -> String {
+    let digits = |digits: &[MCC_MNC_Digit]| -> String {
+        digits.iter().map(|digit| digit.0.to_string()).collect()
+    };
+    let mcc = match &plmn.mcc {
+        Some(MCC(mcc)) => digits(mcc),
+        None => "?".to_string(),
+    };
+    let MNC(mnc) = &plmn.mnc;
+    format!("{}-{}", mcc, digits(mnc))
+}
@@ -0,0 +1,620 @@
+//! The "known environment" a device builds up over many recordings: which
+//! cells and PLMNs it usually sees, with which parameters and neighbors, and
+//! where, as well as the [CellHistory] of the parameters each cell was last
+//! seen with.
+//!
+//! [`Baseline`] learns from every LTE message the
+//! [`Harness`](crate::analysis::analyzer::Harness) analyzes, after its
+//! [Analyzers](crate::analysis::analyzer::Analyzer) have looked at it, so they
+//! can ask whether something is being seen for the first time, or deviates
+//! from what's usual. Something is usual once it's been seen on
+//! [`USUAL_MIN_DAYS`] different days, so a commute past the same cells every
+//! morning stops being interesting after a few days.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use deku::bitvec::*;
+use serde::{Deserialize, Serialize};
+use telcom_parser::lte_rrc::{BCCH_DL_SCH_MessageType, BCCH_DL_SCH_MessageType_c1};
+
+use crate::analysis::analyzer::PacketContext;
+use crate::analysis::cell_parameters::{CellHistory, CellRecord};
+use crate::analysis::information_element::{InformationElement, LteInformationElement};
+use crate::analysis::neighbor_list::advertised_neighbors;
+use crate::analysis::util::format_plmn;
+use crate::gps::EventLocation;
+
+/// How many different days something has to be seen on to be usual
+pub const USUAL_MIN_DAYS: u32 = 3;
+
+/// The most cells, and cells with known neighbors, to remember. Past this,
+/// the ones seen longest ago are forgotten.
+const MAX_CELLS: usize = 4096;
+
+/// The most areas to remember
+const MAX_AREAS: usize = 1024;
+
+/// Areas are squares of this many degrees of latitude and longitude, about a
+/// kilometer across at the equator
+const AREA_SIZE_DEGREES: f64 = 0.01;
+
+/// A square on the map, see [`Area::of`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Area {
+    pub latitude: i32,
+    pub longitude: i32,
+}
+
+impl Area {
+    /// The area containing the given location
+    pub fn of(location: &EventLocation) -> Self {
+        Area {
+            latitude: (location.latitude / AREA_SIZE_DEGREES).floor() as i32,
+            longitude: (location.longitude / AREA_SIZE_DEGREES).floor() as i32,
+        }
+    }
+}
+
+/// When something was seen, and on how many different days
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sightings {
+    pub first_seen: DateTime<FixedOffset>,
+    pub last_seen: DateTime<FixedOffset>,
+    pub days_seen: u32,
+}
+
+impl Sightings {
+    fn new(timestamp: DateTime<FixedOffset>) -> Self {
+        Sightings {
+            first_seen: timestamp,
+            last_seen: timestamp,
+            days_seen: 1,
+        }
+    }
+
+    fn record(&mut self, timestamp: DateTime<FixedOffset>) {
+        if day(&timestamp) > day(&self.last_seen) {
+            self.days_seen += 1;
+        }
+        self.last_seen = self.last_seen.max(timestamp);
+    }
+
+    /// Whether this has been seen on at least [`USUAL_MIN_DAYS`] days
+    pub fn is_usual(&self) -> bool {
+        self.days_seen >= USUAL_MIN_DAYS
+    }
+}
+
+fn day(timestamp: &DateTime<FixedOffset>) -> NaiveDate {
+    timestamp.date_naive()
+}
+
+/// What was seen of a cell in one SIB1
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellObservation {
+    /// MCC-MNC of the first PLMN in the SIB1
+    pub plmn: String,
+    pub cell_identity: u32,
+    pub tracking_area_code: u32,
+    pub pci: Option<u16>,
+    pub earfcn: Option<u32>,
+    /// Minimum required receive level, in dBm
+    pub q_rx_lev_min: i16,
+    pub freq_band_indicator: u8,
+    pub area: Option<Area>,
+}
+
+impl CellObservation {
+    /// Reads a cell's SIB1, if that's what the message is
+    pub fn from_information_element(
+        ie: &InformationElement,
+        context: &PacketContext,
+    ) -> Option<Self> {
+        let InformationElement::LTE(lte_ie) = ie else {
+            return None;
+        };
+        let LteInformationElement::BcchDlSch(sch_msg) = &**lte_ie else {
+            return None;
+        };
+        let BCCH_DL_SCH_MessageType::C1(BCCH_DL_SCH_MessageType_c1::SystemInformationBlockType1(
+            sib1,
+        )) = &sch_msg.message
+        else {
+            return None;
+        };
+        let access_info = &sib1.cell_access_related_info;
+        let plmn = access_info.plmn_identity_list.0.first()?;
+        Some(CellObservation {
+            plmn: format_plmn(&plmn.plmn_identity),
+            cell_identity: access_info.cell_identity.0.as_bitslice().load_be::<u32>(),
+            tracking_area_code: access_info
+                .tracking_area_code
+                .0
+                .as_bitslice()
+                .load_be::<u32>(),
+            pci: context.pci,
+            earfcn: context.earfcn,
+            q_rx_lev_min: sib1.cell_selection_info.q_rx_lev_min.0 as i16 * 2,
+            freq_band_indicator: sib1.freq_band_indicator.0,
+            area: context.location.as_ref().map(Area::of),
+        })
+    }
+}
+
+/// Everything seen of a cell, by PLMN and cell identity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellBaseline {
+    pub plmn: String,
+    pub cell_identity: u32,
+    pub tracking_area_codes: BTreeSet<u32>,
+    pub pcis: BTreeSet<u16>,
+    pub earfcns: BTreeSet<u32>,
+    pub q_rx_lev_mins: BTreeSet<i16>,
+    pub freq_band_indicators: BTreeSet<u8>,
+    pub areas: BTreeSet<Area>,
+    #[serde(flatten)]
+    pub sightings: Sightings,
+}
+
+/// How an observation differs from a usual cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deviation {
+    TrackingAreaCode(u32),
+    Pci(u16),
+    Earfcn(u32),
+    QRxLevMin(i16),
+    FreqBandIndicator(u8),
+}
+
+impl std::fmt::Display for Deviation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Deviation::TrackingAreaCode(tac) => write!(f, "TAC {tac}"),
+            Deviation::Pci(pci) => write!(f, "PCI {pci}"),
+            Deviation::Earfcn(earfcn) => write!(f, "EARFCN {earfcn}"),
+            Deviation::QRxLevMin(dbm) => write!(f, "minimum receive level {dbm} dBm"),
+            Deviation::FreqBandIndicator(band) => write!(f, "band {band}"),
+        }
+    }
+}
+
+impl CellBaseline {
+    fn new(observation: &CellObservation, timestamp: DateTime<FixedOffset>) -> Self {
+        let mut cell = CellBaseline {
+            plmn: observation.plmn.clone(),
+            cell_identity: observation.cell_identity,
+            tracking_area_codes: BTreeSet::new(),
+            pcis: BTreeSet::new(),
+            earfcns: BTreeSet::new(),
+            q_rx_lev_mins: BTreeSet::new(),
+            freq_band_indicators: BTreeSet::new(),
+            areas: BTreeSet::new(),
+            sightings: Sightings::new(timestamp),
+        };
+        cell.record(observation, timestamp);
+        cell
+    }
+
+    fn record(&mut self, observation: &CellObservation, timestamp: DateTime<FixedOffset>) {
+        self.tracking_area_codes
+            .insert(observation.tracking_area_code);
+        self.pcis.extend(observation.pci);
+        self.earfcns.extend(observation.earfcn);
+        self.q_rx_lev_mins.insert(observation.q_rx_lev_min);
+        self.freq_band_indicators
+            .insert(observation.freq_band_indicator);
+        self.areas.extend(observation.area);
+        self.sightings.record(timestamp);
+    }
+
+    /// The ways the observation differs from everything this cell was seen
+    /// with before
+    pub fn deviations(&self, observation: &CellObservation) -> Vec<Deviation> {
+        let mut deviations = Vec::new();
+        if !self
+            .tracking_area_codes
+            .contains(&observation.tracking_area_code)
+        {
+            deviations.push(Deviation::TrackingAreaCode(observation.tracking_area_code));
+        }
+        if let Some(pci) = observation.pci {
+            if !self.pcis.is_empty() && !self.pcis.contains(&pci) {
+                deviations.push(Deviation::Pci(pci));
+            }
+        }
+        if let Some(earfcn) = observation.earfcn {
+            if !self.earfcns.is_empty() && !self.earfcns.contains(&earfcn) {
+                deviations.push(Deviation::Earfcn(earfcn));
+            }
+        }
+        if !self.q_rx_lev_mins.contains(&observation.q_rx_lev_min) {
+            deviations.push(Deviation::QRxLevMin(observation.q_rx_lev_min));
+        }
+        if !self
+            .freq_band_indicators
+            .contains(&observation.freq_band_indicator)
+        {
+            deviations.push(Deviation::FreqBandIndicator(
+                observation.freq_band_indicator,
+            ));
+        }
+        deviations
+    }
+}
+
+/// The cells that advertised a cell as their neighbor
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NeighborBaseline {
+    pub earfcn: u32,
+    pub pci: u16,
+    /// The advertising cells, by EARFCN and PCI
+    pub advertised_by: BTreeSet<(u32, u16)>,
+    #[serde(flatten)]
+    pub sightings: Sightings,
+}
+
+/// How much is in a [`Baseline`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BaselineSummary {
+    pub cells: usize,
+    pub usual_cells: usize,
+    pub plmns: usize,
+    pub neighbors: usize,
+    pub areas: usize,
+    pub first_seen: Option<DateTime<FixedOffset>>,
+    pub last_seen: Option<DateTime<FixedOffset>>,
+}
+
+/// See the [module documentation](self)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "BaselineFile", into = "BaselineFile")]
+pub struct Baseline {
+    cells: HashMap<(String, u32), CellBaseline>,
+    plmns: HashMap<String, Sightings>,
+    neighbors: HashMap<(u32, u16), NeighborBaseline>,
+    areas: HashMap<Area, Sightings>,
+    cell_history: CellHistory,
+}
+
+/// A [Baseline] shared between the [Harness](crate::analysis::analyzer::Harness)
+/// that teaches it, the [Analyzers](crate::analysis::analyzer::Analyzer) that
+/// query it, and whoever persists it.
+pub type SharedBaseline = Arc<RwLock<Baseline>>;
+
+// maps with tuple keys don't serialize to JSON, so it's stored as lists
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct BaselineFile {
+    cells: Vec<CellBaseline>,
+    plmns: Vec<PlmnBaseline>,
+    neighbors: Vec<NeighborBaseline>,
+    areas: Vec<AreaBaseline>,
+    cell_history: CellHistory,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PlmnBaseline {
+    plmn: String,
+    #[serde(flatten)]
+    sightings: Sightings,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AreaBaseline {
+    #[serde(flatten)]
+    area: Area,
+    #[serde(flatten)]
+    sightings: Sightings,
+}
+
+impl From<BaselineFile> for Baseline {
+    fn from(file: BaselineFile) -> Self {
+        Baseline {
+            cells: file
+                .cells
+                .into_iter()
+                .map(|cell| ((cell.plmn.clone(), cell.cell_identity), cell))
+                .collect(),
+            plmns: file
+                .plmns
+                .into_iter()
+                .map(|plmn| (plmn.plmn, plmn.sightings))
+                .collect(),
+            neighbors: file
+                .neighbors
+                .into_iter()
+                .map(|neighbor| ((neighbor.earfcn, neighbor.pci), neighbor))
+                .collect(),
+            areas: file
+                .areas
+                .into_iter()
+                .map(|area| (area.area, area.sightings))
+                .collect(),
+            cell_history: file.cell_history,
+        }
+    }
+}
+
+impl From<Baseline> for BaselineFile {
+    fn from(baseline: Baseline) -> Self {
+        let mut file = BaselineFile {
+            cells: baseline.cells.into_values().collect(),
+            plmns: baseline
+                .plmns
+                .into_iter()
+                .map(|(plmn, sightings)| PlmnBaseline { plmn, sightings })
+                .collect(),
+            neighbors: baseline.neighbors.into_values().collect(),
+            areas: baseline
+                .areas
+                .into_iter()
+                .map(|(area, sightings)| AreaBaseline { area, sightings })
+                .collect(),
+            cell_history: baseline.cell_history,
+        };
+        file.cells
+            .sort_by(|a, b| (&a.plmn, a.cell_identity).cmp(&(&b.plmn, b.cell_identity)));
+        file.plmns.sort_by(|a, b| a.plmn.cmp(&b.plmn));
+        file.neighbors
+            .sort_by_key(|neighbor| (neighbor.earfcn, neighbor.pci));
+        file.areas.sort_by_key(|area| area.area);
+        file
+    }
+}
+
+// forgets the entry seen longest ago if the map is full and `key` isn't in it
+fn make_room<K, V>(
+    map: &mut HashMap<K, V>,
+    key: &K,
+    max: usize,
+    sightings: impl Fn(&V) -> &Sightings,
+) where
+    K: std::hash::Hash + Eq + Clone,
+{
+    if map.len() < max || map.contains_key(key) {
+        return;
+    }
+    let oldest = map
+        .iter()
+        .min_by_key(|(_, value)| sightings(value).last_seen)
+        .map(|(key, _)| key.clone());
+    if let Some(oldest) = oldest {
+        map.remove(&oldest);
+    }
+}
+
+impl Baseline {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+            && self.plmns.is_empty()
+            && self.neighbors.is_empty()
+            && self.areas.is_empty()
+            && self.cell_history.is_empty()
+    }
+
+    pub fn cell(&self, plmn: &str, cell_identity: u32) -> Option<&CellBaseline> {
+        self.cells.get(&(plmn.to_string(), cell_identity))
+    }
+
+    pub fn plmn(&self, plmn: &str) -> Option<&Sightings> {
+        self.plmns.get(plmn)
+    }
+
+    pub fn area(&self, area: &Area) -> Option<&Sightings> {
+        self.areas.get(area)
+    }
+
+    /// The parameters each cell was last seen with
+    pub fn cell_history(&self) -> &CellHistory {
+        &self.cell_history
+    }
+
+    /// The cells that have advertised the given cell as a neighbor
+    pub fn neighbor(&self, earfcn: u32, pci: u16) -> Option<&NeighborBaseline> {
+        self.neighbors.get(&(earfcn, pci))
+    }
+
+    /// Whether the observed cell has never been seen before
+    pub fn is_first_sighting(&self, observation: &CellObservation) -> bool {
+        self.cell(&observation.plmn, observation.cell_identity)
+            .is_none()
+    }
+
+    /// The ways the observation differs from a cell that's usually seen.
+    /// Empty if it doesn't, or if the cell isn't usual yet.
+    pub fn deviations(&self, observation: &CellObservation) -> Vec<Deviation> {
+        match self.cell(&observation.plmn, observation.cell_identity) {
+            Some(cell) if cell.sightings.is_usual() => cell.deviations(observation),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn summary(&self) -> BaselineSummary {
+        let sightings = self
+            .cells
+            .values()
+            .map(|cell| &cell.sightings)
+            .chain(self.plmns.values());
+        BaselineSummary {
+            cells: self.cells.len(),
+            usual_cells: self
+                .cells
+                .values()
+                .filter(|cell| cell.sightings.is_usual())
+                .count(),
+            plmns: self.plmns.len(),
+            neighbors: self.neighbors.len(),
+            areas: self.areas.len(),
+            first_seen: sightings.clone().map(|s| s.first_seen).min(),
+            last_seen: sightings.map(|s| s.last_seen).max(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Baseline::default();
+    }
+
+    /// Learns from a message, as analyzed with the given context
+    pub fn learn(&mut self, ie: &InformationElement, context: &PacketContext) {
+        let Some(timestamp) = context.timestamp else {
+            return;
+        };
+        if let Some(observation) = CellObservation::from_information_element(ie, context) {
+            self.learn_cell(&observation, timestamp);
+            self.learn_cell_parameters(CellRecord::new(&observation, context));
+        }
+        if let (Some(earfcn), Some(pci)) = (context.earfcn, context.pci) {
+            if let Some(advertised) = advertised_neighbors((earfcn, pci), ie) {
+                for neighbor in advertised.cells {
+                    self.learn_neighbor((earfcn, pci), neighbor, timestamp);
+                }
+            }
+        }
+    }
+
+    pub fn learn_cell(&mut self, observation: &CellObservation, timestamp: DateTime<FixedOffset>) {
+        let key = (observation.plmn.clone(), observation.cell_identity);
+        make_room(&mut self.cells, &key, MAX_CELLS, |cell| &cell.sightings);
+        match self.cells.get_mut(&key) {
+            Some(cell) => cell.record(observation, timestamp),
+            None => {
+                self.cells
+                    .insert(key, CellBaseline::new(observation, timestamp));
+            }
+        }
+
+        match self.plmns.get_mut(&observation.plmn) {
+            Some(sightings) => sightings.record(timestamp),
+            None => {
+                self.plmns
+                    .insert(observation.plmn.clone(), Sightings::new(timestamp));
+            }
+        }
+
+        if let Some(area) = observation.area {
+            make_room(&mut self.areas, &area, MAX_AREAS, |sightings| sightings);
+            self.areas
+                .entry(area)
+                .and_modify(|sightings| sightings.record(timestamp))
+                .or_insert_with(|| Sightings::new(timestamp));
+        }
+    }
+
+    pub fn learn_cell_parameters(&mut self, record: CellRecord) {
+        self.cell_history.record(record);
+    }
+
+    pub fn learn_neighbor(
+        &mut self,
+        cell: (u32, u16),
+        neighbor: (u32, u16),
+        timestamp: DateTime<FixedOffset>,
+    ) {
+        make_room(&mut self.neighbors, &neighbor, MAX_CELLS, |neighbor| {
+            &neighbor.sightings
+        });
+        let entry = self
+            .neighbors
+            .entry(neighbor)
+            .or_insert_with(|| NeighborBaseline {
+                earfcn: neighbor.0,
+                pci: neighbor.1,
+                advertised_by: BTreeSet::new(),
+                sightings: Sightings::new(timestamp),
+            });
+        entry.advertised_by.insert(cell);
+        entry.sightings.record(timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(&format!("2024-05-{day:02}T{hour:02}:00:00+00:00")).unwrap()
+    }
+
+    fn observation(tac: u32, pci: u16) -> CellObservation {
+        CellObservation {
+            plmn: "310-260".to_string(),
+            cell_identity: 20001,
+            tracking_area_code: tac,
+            pci: Some(pci),
+            earfcn: Some(5230),
+            q_rx_lev_min: -128,
+            freq_band_indicator: 2,
+            area: Some(Area {
+                latitude: 5200,
+                longitude: 1300,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_first_sighting_and_deviations() {
+        let mut baseline = Baseline::default();
+        assert!(baseline.is_first_sighting(&observation(100, 301)));
+        baseline.learn_cell(&observation(100, 301), at(1, 8));
+        assert!(!baseline.is_first_sighting(&observation(100, 301)));
+
+        // not usual yet, so nothing deviates from it
+        assert!(baseline.deviations(&observation(200, 12)).is_empty());
+
+        baseline.learn_cell(&observation(100, 301), at(1, 18));
+        baseline.learn_cell(&observation(100, 301), at(2, 8));
+        assert!(
+            !baseline
+                .cell("310-260", 20001)
+                .unwrap()
+                .sightings
+                .is_usual()
+        );
+        baseline.learn_cell(&observation(100, 301), at(3, 8));
+        assert!(
+            baseline
+                .cell("310-260", 20001)
+                .unwrap()
+                .sightings
+                .is_usual()
+        );
+
+        assert!(baseline.deviations(&observation(100, 301)).is_empty());
+        assert_eq!(
+            baseline.deviations(&observation(200, 12)),
+            vec![Deviation::TrackingAreaCode(200), Deviation::Pci(12)]
+        );
+        assert_eq!(baseline.plmn("310-260").unwrap().days_seen, 3);
+    }
+
+    #[test]
+    fn test_baseline_roundtrip() {
+        let mut baseline = Baseline::default();
+        baseline.learn_cell(&observation(100, 301), at(1, 8));
+        baseline.learn_neighbor((5230, 301), (5230, 12), at(1, 8));
+        baseline.learn_cell_parameters(CellRecord::new(
+            &observation(100, 301),
+            &PacketContext::default(),
+        ));
+        let summary = baseline.summary();
+        assert_eq!(summary.cells, 1);
+        assert_eq!(summary.neighbors, 1);
+        assert_eq!(summary.areas, 1);
+        assert_eq!(summary.first_seen, Some(at(1, 8)));
+
+        let json = serde_json::to_string(&baseline).unwrap();
+        let restored: Baseline = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.summary(), summary);
+        assert_eq!(
+            restored.neighbor(5230, 12).unwrap().advertised_by,
+            BTreeSet::from([(5230, 301)])
+        );
+        assert_eq!(restored.cell_history().len(), 1);
+
+        // resetting the baseline forgets the cell history too
+        baseline.clear();
+        assert!(baseline.is_empty());
+    }
+}
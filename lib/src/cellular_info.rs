@@ -7,6 +7,12 @@
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, FixedOffset};
+use deku::prelude::*;
+
+use crate::diag::{
+    GsmCellInfo, LogBody, LteMl1NeighborCellMeasPacket, LteMl1ServingCellMeasPacket, Message,
+    WcdmaCellId,
+};
 
 /// Comprehensive cellular network information
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -37,9 +43,24 @@ pub enum RadioAccessTechnology {
 pub struct PlmnInfo {
     pub mcc: Option<u16>,        // Mobile Country Code
     pub mnc: Option<u16>,        // Mobile Network Code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mnc_digits: Option<u8>,  // 2 or 3, since MNC 010 isn't MNC 10
     pub plmn_id: Option<String>, // Combined PLMN identifier
 }
 
+impl PlmnInfo {
+    /// A PLMN whose MNC has the given number of digits, which it keeps in
+    /// `plmn_id`
+    fn new(mcc: u16, mnc: u16, mnc_digits: u8) -> Self {
+        Self {
+            mcc: Some(mcc),
+            mnc: Some(mnc),
+            mnc_digits: Some(mnc_digits),
+            plmn_id: Some(format!("{mcc:03}{mnc:0width$}", width = mnc_digits as usize)),
+        }
+    }
+}
+
 /// Cell identification information
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct CellInfo {
@@ -62,21 +83,30 @@ pub struct LocationInfo {
 /// Signal quality and measurement information
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SignalInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rsrp: Option<i16>,              // Reference Signal Received Power (dBm)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rsrq: Option<i16>,              // Reference Signal Received Quality (dB)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rssi: Option<i16>,              // Received Signal Strength Indicator (dBm)
-    pub sinr: Option<i16>,              // Signal to Interference plus Noise Ratio (dB)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub cqi: Option<u8>,                // Channel Quality Indicator
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub bandwidth: Option<u8>,          // Channel bandwidth (MHz)
 }
 
 /// Neighbor cell information
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct NeighborCellInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub physical_cell_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub earfcn: Option<u32>,            // E-UTRA Absolute Radio Frequency Channel Number
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rsrp: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rsrq: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub plmn_info: Option<PlmnInfo>,
 }
 
@@ -136,11 +166,35 @@ impl CellularInfoExtractor {
     /// Extract cellular information from a QMDL message
     pub fn extract_from_message(
         &mut self,
-        message: &crate::diag::Message,
+        message: &Message,
     ) -> Option<CellularNetworkInfo> {
-        // For now, we don't actually extract from the message
-        // This is a placeholder for future implementation
-        None
+        match message {
+            Message::Log { timestamp, body, .. } => self.extract_from_log_body(body, timestamp.to_datetime()),
+            _ => None,
+        }
+    }
+
+    /// Extract cellular information from a parsed log body
+    pub fn extract_from_log_body(
+        &mut self,
+        body: &LogBody,
+        timestamp: DateTime<FixedOffset>,
+    ) -> Option<CellularNetworkInfo> {
+        match body {
+            LogBody::LteMl1ServingCellMeas { packet, .. } => self.extract_lte_serving_cell_info(packet, timestamp),
+            LogBody::LteMl1NeighborCellMeas { packet, .. } => self.extract_lte_neighbor_info(packet, timestamp),
+            LogBody::GsmCellInfo(cell) => self.extract_gsm_cell_id(cell, timestamp),
+            LogBody::WcdmaCellId(cell) => self.extract_wcdma_cell_id(cell, timestamp),
+            _ => None,
+        }
+    }
+
+    /// Parse the body of a log whose layout is described in [`LogBody`]
+    fn parse_log_body(log_type: u16, log_data: &[u8]) -> Option<LogBody> {
+        let hdr_len = u16::try_from(log_data.len()).ok()?;
+        let mut cursor = std::io::Cursor::new(log_data);
+        let mut reader = deku::reader::Reader::new(&mut cursor);
+        LogBody::from_reader_with_ctx(&mut reader, (log_type, hdr_len)).ok()
     }
     
     /// Extract cellular information from raw log data
@@ -154,18 +208,17 @@ impl CellularInfoExtractor {
             // LTE RRC messages - extract PLMN and cell info from SIBs
             0xb0c0 => self.extract_lte_rrc_info(log_data, timestamp),
             
-            // LTE ML1 serving cell info
-            0xb0e4 => self.extract_lte_serving_cell_info(log_data, timestamp),
-            
-            // LTE ML1 neighbor measurements
-            0xb0e1 => self.extract_lte_neighbor_info(log_data, timestamp),
-            
+            // LTE ML1 serving cell info and neighbor measurements, GSM cell
+            // information and WCDMA cell ID are parsed as LogBody variants
+            0xb0e4 | 0xb0e1 | 0x513a | 0x4127 => {
+                let body = Self::parse_log_body(log_type, log_data)?;
+                self.extract_from_log_body(&body, timestamp)
+            }
+
             // GSM cell information
-            0x513a => self.extract_gsm_cell_id(log_data, timestamp),
             0x513b => self.extract_gsm_cell_info(log_data, timestamp),
             
             // WCDMA cell information
-            0x4127 => self.extract_wcdma_cell_id(log_data, timestamp),
             0x412a => self.extract_wcdma_serving_cell_info(log_data, timestamp),
             
             // NAS messages - extract PLMN and location info
@@ -208,35 +261,13 @@ impl CellularInfoExtractor {
     }
 
     /// Extract LTE serving cell information
-    fn extract_lte_serving_cell_info(&mut self, data: &[u8], timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
-        // Parse LTE ML1 serving cell info structure
-        if data.len() < 16 {
-            return None;
-        }
-
-        // Extract EARFCN and PCI (positions may vary based on log version)
-        let _earfcn = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        let phy_cell_id = u16::from_le_bytes([data[4], data[5]]);
-        
-        // Extract signal measurements if available
-        let rsrp = if data.len() > 8 {
-            Some(i16::from_le_bytes([data[6], data[7]]))
-        } else {
-            None
-        };
-        
-        let rsrq = if data.len() > 10 {
-            Some(i16::from_le_bytes([data[8], data[9]]))
-        } else {
-            None
-        };
-
+    fn extract_lte_serving_cell_info(&mut self, packet: &LteMl1ServingCellMeasPacket, timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
         Some(CellularNetworkInfo {
             timestamp,
             rat: RadioAccessTechnology::LTE,
             plmn_info: None,
             cell_info: Some(CellInfo {
-                physical_cell_id: Some(phy_cell_id),
+                physical_cell_id: Some(packet.get_pci()),
                 global_cell_id: None,
                 cell_identity: None,
                 enodeb_id: None,
@@ -244,10 +275,9 @@ impl CellularInfoExtractor {
             }),
             location_info: None,
             signal_info: Some(SignalInfo {
-                rsrp,
-                rsrq,
-                rssi: None,
-                sinr: None,
+                rsrp: Some(packet.get_rsrp_dbm().round() as i16),
+                rsrq: Some(packet.get_rsrq_db().round() as i16),
+                rssi: Some(packet.get_rssi_dbm().round() as i16),
                 cqi: None,
                 bandwidth: None,
             }),
@@ -256,30 +286,18 @@ impl CellularInfoExtractor {
     }
 
     /// Extract LTE neighbor cell information
-    fn extract_lte_neighbor_info(&mut self, data: &[u8], timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
-        if data.len() < 8 {
-            return None;
-        }
-
-        let mut neighbor_cells = Vec::new();
-        
-        // Parse neighbor cell measurements (format varies by log version)
-        let mut offset = 4; // Skip header
-        while offset + 8 <= data.len() {
-            let phy_cell_id = u16::from_le_bytes([data[offset], data[offset + 1]]);
-            let earfcn = u32::from_le_bytes([data[offset + 2], data[offset + 3], data[offset + 4], data[offset + 5]]);
-            let rsrp = i16::from_le_bytes([data[offset + 6], data[offset + 7]]);
-            
-            neighbor_cells.push(NeighborCellInfo {
-                physical_cell_id: Some(phy_cell_id),
-                earfcn: Some(earfcn),
-                rsrp: Some(rsrp),
-                rsrq: None,
+    fn extract_lte_neighbor_info(&mut self, packet: &LteMl1NeighborCellMeasPacket, timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
+        let neighbor_cells = packet
+            .get_cells()
+            .iter()
+            .map(|cell| NeighborCellInfo {
+                physical_cell_id: Some(cell.get_pci()),
+                earfcn: Some(packet.get_earfcn()),
+                rsrp: Some(cell.get_rsrp_dbm().round() as i16),
+                rsrq: Some(cell.get_rsrq_db().round() as i16),
                 plmn_info: None,
-            });
-            
-            offset += 8;
-        }
+            })
+            .collect();
 
         Some(CellularNetworkInfo {
             timestamp,
@@ -293,41 +311,31 @@ impl CellularInfoExtractor {
     }
 
     /// Extract GSM cell ID information
-    fn extract_gsm_cell_id(&mut self, data: &[u8], timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
-        if data.len() < 6 {
-            return None;
-        }
-
-        let cell_id = u16::from_le_bytes([data[0], data[1]]);
-        let lac = u16::from_le_bytes([data[2], data[3]]);
-        let mcc_mnc = u16::from_le_bytes([data[4], data[5]]);
-        
-        // Decode MCC/MNC from combined value
-        let mcc = (mcc_mnc & 0x0fff) as u16;
-        let mnc = ((mcc_mnc >> 12) & 0x0fff) as u16;
-
+    fn extract_gsm_cell_id(&mut self, cell: &GsmCellInfo, timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
         Some(CellularNetworkInfo {
             timestamp,
             rat: RadioAccessTechnology::GSM,
-            plmn_info: Some(PlmnInfo {
-                mcc: Some(mcc),
-                mnc: Some(mnc),
-                plmn_id: Some(format!("{:03}{:03}", mcc, mnc)),
-            }),
+            plmn_info: self.decode_plmn_from_bytes(&cell.plmn),
             cell_info: Some(CellInfo {
                 physical_cell_id: None,
-                global_cell_id: Some(cell_id as u32),
-                cell_identity: Some(cell_id as u32),
+                global_cell_id: Some(cell.cell_id as u32),
+                cell_identity: Some(cell.cell_id as u32),
                 enodeb_id: None,
                 sector_id: None,
             }),
             location_info: Some(LocationInfo {
-                lac: Some(lac),
+                lac: Some(cell.lac),
                 rac: None,
                 tac: None,
                 tracking_area_id: None,
             }),
-            signal_info: None,
+            signal_info: Some(SignalInfo {
+                rsrp: None,
+                rsrq: None,
+                rssi: Some(cell.get_rssi_dbm()),
+                cqi: None,
+                bandwidth: None,
+            }),
             neighbor_cells: Vec::new(),
         })
     }
@@ -353,7 +361,6 @@ impl CellularInfoExtractor {
                 rsrp: None,
                 rsrq: None,
                 rssi: Some(rx_level),
-                sinr: None,
                 cqi: None,
                 bandwidth: None,
             }),
@@ -362,28 +369,26 @@ impl CellularInfoExtractor {
     }
 
     /// Extract WCDMA cell ID information
-    fn extract_wcdma_cell_id(&mut self, data: &[u8], timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
-        if data.len() < 8 {
-            return None;
-        }
+    fn extract_wcdma_cell_id(&mut self, cell: &WcdmaCellId, timestamp: DateTime<FixedOffset>) -> Option<CellularNetworkInfo> {
+        let plmn_info = match (cell.get_mcc(), cell.get_mnc()) {
+            (Some(mcc), Some(mnc)) => Some(PlmnInfo::new(mcc, mnc, cell.get_mnc_digits())),
+            _ => None,
+        };
 
-        let cell_id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        let lac = u16::from_le_bytes([data[4], data[5]]);
-        
         Some(CellularNetworkInfo {
             timestamp,
             rat: RadioAccessTechnology::UMTS,
-            plmn_info: None,
+            plmn_info,
             cell_info: Some(CellInfo {
-                physical_cell_id: None,
-                global_cell_id: Some(cell_id),
-                cell_identity: Some(cell_id),
+                physical_cell_id: Some(cell.psc),
+                global_cell_id: Some(cell.cell_id),
+                cell_identity: Some(cell.cell_id),
                 enodeb_id: None,
                 sector_id: None,
             }),
             location_info: Some(LocationInfo {
-                lac: Some(lac),
-                rac: None,
+                lac: Some(cell.lac as u16),
+                rac: Some(cell.rac as u8),
                 tac: None,
                 tracking_area_id: None,
             }),
@@ -419,7 +424,6 @@ impl CellularInfoExtractor {
                 rsrp: Some(rscp),
                 rsrq: Some(ecno),
                 rssi: None,
-                sinr: None,
                 cqi: None,
                 bandwidth: None,
             }),
@@ -481,21 +485,17 @@ impl CellularInfoExtractor {
             return None;
         }
         
-        let mcc = mcc_digit1 as u16 + (mcc_digit2 as u16) * 10 + (mcc_digit3 as u16) * 100;
+        let mcc = (mcc_digit1 as u16) * 100 + (mcc_digit2 as u16) * 10 + mcc_digit3 as u16;
         
-        let mnc = if mnc_digit3 == 0xf {
+        let (mnc, mnc_digits) = if mnc_digit3 == 0xf {
             // 2-digit MNC
-            mnc_digit1 as u16 + (mnc_digit2 as u16) * 10
+            ((mnc_digit1 as u16) * 10 + mnc_digit2 as u16, 2)
         } else {
             // 3-digit MNC
-            mnc_digit1 as u16 + (mnc_digit2 as u16) * 10 + (mnc_digit3 as u16) * 100
+            ((mnc_digit1 as u16) * 100 + (mnc_digit2 as u16) * 10 + mnc_digit3 as u16, 3)
         };
         
-        Some(PlmnInfo {
-            mcc: Some(mcc),
-            mnc: Some(mnc),
-            plmn_id: Some(format!("{:03}{:02}", mcc, mnc)),
-        })
+        Some(PlmnInfo::new(mcc, mnc, mnc_digits))
     }
 
     /// Get all collected cellular information
@@ -532,10 +532,105 @@ mod tests {
         let plmn_bytes = [0x13, 0x00, 0x14]; 
         let plmn_info = extractor.decode_plmn_from_bytes(&plmn_bytes);
         assert!(plmn_info.is_some());
+        assert_eq!(plmn_info.unwrap().plmn_id.as_deref(), Some("310410"));
         
         // Test 2-digit MNC (MCC=310, MNC=41)
         let plmn_bytes = [0x13, 0xf0, 0x14];
         let plmn_info = extractor.decode_plmn_from_bytes(&plmn_bytes);
         assert!(plmn_info.is_some());
+        assert_eq!(plmn_info.unwrap().plmn_id.as_deref(), Some("31041"));
+
+        // 3-digit MNC with a leading zero (MCC=310, MNC=010)
+        let plmn_info = extractor.decode_plmn_from_bytes(&[0x13, 0x00, 0x10]).unwrap();
+        assert_eq!((plmn_info.mnc, plmn_info.mnc_digits), (Some(10), Some(3)));
+        assert_eq!(plmn_info.plmn_id.as_deref(), Some("310010"));
+    }
+
+    // The ML1 and WCDMA logs below weren't captured from a modem. They're laid
+    // out field by field after the LogBody definitions in diag.rs, which
+    // follow the layouts open-source diag parsers use for these log versions.
+
+    // version 5, EARFCN 5230, PCI 301, RSRP -95 dBm, RSRQ -10 dB, RSSI -65 dBm
+    const LTE_ML1_SERVING_CELL_MEAS_V5: [u8; 36] = [
+        5, 1, 0, 0, 0x6e, 0x14, 0, 0, 0x2d, 0x07, 0, 0, 0x50, 0x05, 0, 0, 0x50, 0x05, 0, 0, 0, 0,
+        0x05, 0, 0, 0x40, 0x0b, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    // version 4, EARFCN 2050, PCI 160 at -100 dBm/-12 dB, PCI 7 at -110 dBm/-15 dB
+    const LTE_ML1_NEIGHBOR_CELL_MEAS_V4: [u8; 40] = [
+        4, 1, 0, 0, 0x02, 0x08, 2, 0, 0xa0, 0, 0, 0, 0, 0x05, 0, 0, 0, 0x80, 0x04, 0, 0, 0, 0, 0,
+        7, 0, 0, 0, 0x60, 0x04, 0, 0, 0, 0xc0, 0x03, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn test_lte_ml1_extraction() {
+        let mut extractor = CellularInfoExtractor::new();
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T12:34:56+00:00").unwrap();
+
+        let info = extractor
+            .extract_from_log_data(0xb0e4, &LTE_ML1_SERVING_CELL_MEAS_V5, timestamp)
+            .unwrap();
+        assert_eq!(info.rat, RadioAccessTechnology::LTE);
+        assert_eq!(info.cell_info.unwrap().physical_cell_id, Some(301));
+        let signal = info.signal_info.unwrap();
+        assert_eq!((signal.rsrp, signal.rsrq, signal.rssi), (Some(-95), Some(-10), Some(-65)));
+
+        let info = extractor
+            .extract_from_log_data(0xb0e1, &LTE_ML1_NEIGHBOR_CELL_MEAS_V4, timestamp)
+            .unwrap();
+        let neighbors: Vec<_> = info
+            .neighbor_cells
+            .iter()
+            .map(|cell| (cell.earfcn, cell.physical_cell_id, cell.rsrp, cell.rsrq))
+            .collect();
+        assert_eq!(
+            neighbors,
+            vec![
+                (Some(2050), Some(160), Some(-100), Some(-12)),
+                (Some(2050), Some(7), Some(-110), Some(-15)),
+            ]
+        );
+
+        // cut off in the middle of a neighbor cell
+        assert!(
+            extractor
+                .extract_from_log_data(0xb0e1, &LTE_ML1_NEIGHBOR_CELL_MEAS_V4[..20], timestamp)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_wcdma_cell_id_extraction() {
+        let mut extractor = CellularInfoExtractor::new();
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T12:34:56+00:00").unwrap();
+        // MCC 310, MNC 010
+        let data = [
+            0x8c, 0x25, 0, 0, 0x42, 0x29, 0, 0, 0x67, 0x45, 0x23, 0x01, 0, 0, 0, 0, 0x2d, 0x01, 3,
+            1, 0, 0, 1, 0, 0x2b, 0x1a, 0, 0, 5, 0, 0, 0,
+        ];
+        let info = extractor.extract_from_log_data(0x4127, &data, timestamp).unwrap();
+        assert_eq!(info.rat, RadioAccessTechnology::UMTS);
+        let plmn = info.plmn_info.unwrap();
+        assert_eq!((plmn.mcc, plmn.mnc, plmn.mnc_digits), (Some(310), Some(10), Some(3)));
+        assert_eq!(plmn.plmn_id.as_deref(), Some("310010"));
+        assert_eq!(info.cell_info.unwrap().physical_cell_id, Some(301));
+        assert_eq!(info.location_info.unwrap().lac, Some(0x1a2b));
+    }
+
+    #[test]
+    fn test_gsm_cell_info_extraction() {
+        let mut extractor = CellularInfoExtractor::new();
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T12:34:56+00:00").unwrap();
+        let data = [0x80, 0x20, 0x21, 0x21, 0x4e, 0x13, 0xf0, 0x62, 0x34, 0x12, 40];
+        let info = extractor.extract_from_log_data(0x513a, &data, timestamp).unwrap();
+        assert_eq!(info.rat, RadioAccessTechnology::GSM);
+        let plmn = info.plmn_info.unwrap();
+        assert_eq!((plmn.mcc, plmn.mnc), (Some(310), Some(26)));
+        assert_eq!(info.cell_info.unwrap().cell_identity, Some(20001));
+        assert_eq!(info.location_info.unwrap().lac, Some(0x1234));
+        assert_eq!(info.signal_info.unwrap().rssi, Some(-70));
+
+        // too short to be a cell info log
+        assert!(extractor.extract_from_log_data(0x513a, &data[..4], timestamp).is_none());
     }
 }
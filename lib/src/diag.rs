@@ -1,11 +1,12 @@
 //! Diag protocol serialization/deserialization
 
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeZone};
 use crc::{Algorithm, Crc};
 use deku::prelude::*;
 
-use crate::hdlc::{self, hdlc_decapsulate};
+use crate::hdlc::{self, hdlc_decapsulate, hdlc_encapsulate};
 use log::{error, warn};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 pub const MESSAGE_TERMINATOR: u8 = 0x7e;
@@ -24,11 +25,57 @@ pub struct RequestContainer {
     pub hdlc_encapsulated_request: Vec<u8>,
 }
 
+// NV items, see https://github.com/fgsect/scat and libqcdm's nv-items.h
+pub const NV_ITEM_ESN: u16 = 0;
+pub const NV_ITEM_IMEI: u16 = 550;
+
+/// NV read status for success, others mean the item couldn't be read or
+/// isn't set
+pub const NV_STATUS_DONE: u16 = 0;
+
+/// The call manager subsystem, and its command for the current state
+pub const SUBSYSTEM_CM: u8 = 15;
+pub const CM_STATE_INFO: u16 = 0;
+
 #[derive(Debug, Clone, PartialEq, DekuWrite)]
-#[deku(id_type = "u32")]
+#[deku(id_type = "u8")]
 pub enum Request {
+    #[deku(id = "0")]
+    VersionInfo,
+
+    #[deku(id = "38")]
+    NvRead(NvItem),
+
+    #[deku(id = "75")]
+    Subsystem {
+        subsystem_id: u8,
+        #[deku(endian = "little")]
+        subsystem_command: u16,
+    },
+
+    // the log config command code is followed by 3 reserved bytes
     #[deku(id = "115")]
-    LogConfig(LogConfigRequest),
+    LogConfig(#[deku(pad_bytes_before = "3")] LogConfigRequest),
+
+    #[deku(id = "124")]
+    ExtendedBuildId,
+}
+
+impl Request {
+    pub fn nv_read(item: u16) -> Self {
+        Request::NvRead(NvItem {
+            item,
+            data: [0; 128],
+            status: NV_STATUS_DONE,
+        })
+    }
+
+    pub fn cm_state_info() -> Self {
+        Request::Subsystem {
+            subsystem_id: SUBSYSTEM_CM,
+            subsystem_command: CM_STATE_INFO,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, DekuWrite)]
@@ -85,6 +132,25 @@ pub struct MessagesContainer {
 }
 
 impl MessagesContainer {
+    /// HDLC encapsulates each message into a new container
+    pub fn from_messages(data_type: DataType, messages: &[Message]) -> Result<Self, DekuError> {
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let data = hdlc_encapsulate(&message.to_bytes()?, &CRC_CCITT);
+                Ok(HdlcEncapsulatedMessage {
+                    len: data.len() as u32,
+                    data,
+                })
+            })
+            .collect::<Result<Vec<_>, DekuError>>()?;
+        Ok(MessagesContainer {
+            data_type,
+            num_messages: messages.len() as u32,
+            messages,
+        })
+    }
+
     pub fn into_messages(self) -> Vec<Result<Message, DiagParsingError>> {
         let mut result = Vec::new();
         for msg in self.messages {
@@ -135,6 +201,27 @@ pub enum Message {
         body: LogBody,
     },
 
+    #[deku(id = "0")]
+    VersionInfo(VersionInfoResponse),
+
+    #[deku(id = "38")]
+    NvRead(NvItem),
+
+    #[deku(id = "75")]
+    Subsystem(SubsystemResponse),
+
+    #[deku(id = "124")]
+    ExtendedBuildId(ExtendedBuildIdResponse),
+
+    // the modem didn't support the request (19), didn't like its parameters
+    // (20) or its length (21), and sends it back after the error code
+    #[deku(id_pat = "19..=21")]
+    Rejected {
+        code: u8,
+        #[deku(read_all)]
+        request: Vec<u8>,
+    },
+
     // kinda unpleasant deku hackery here. deku expects an enum's variant to be
     // right before its data, but in this case, a status value comes between the
     // variants and the data. so we need to use deku's context (ctx) feature to
@@ -149,6 +236,40 @@ pub enum Message {
     },
 }
 
+impl Message {
+    /// Whether this is a response to a request, rather than a log
+    pub fn is_response(&self) -> bool {
+        !matches!(self, Message::Log { .. })
+    }
+
+    /// Builds a log message, filling in its length fields
+    pub fn new_log(log_type: u16, timestamp: Timestamp, body: LogBody) -> Result<Self, DekuError> {
+        let mut message = Message::Log {
+            pending_msgs: 0,
+            outer_length: 0,
+            inner_length: 0,
+            log_type,
+            timestamp,
+            body,
+        };
+        // the lengths count everything from the inner length onwards
+        let len = u16::try_from(message.to_bytes()?.len())
+            .ok()
+            .and_then(|len| len.checked_sub(3))
+            .ok_or_else(|| DekuError::InvalidParam("log message too long".into()))?;
+        if let Message::Log {
+            outer_length,
+            inner_length,
+            ..
+        } = &mut message
+        {
+            *outer_length = len;
+            *inner_length = len;
+        }
+        Ok(message)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
 #[deku(ctx = "log_type: u16, hdr_len: u16", id = "log_type")]
 pub enum LogBody {
@@ -205,6 +326,22 @@ pub enum LogBody {
         #[deku(count = "hdr_len - 8")]
         msg: Vec<u8>,
     },
+    #[deku(id = "0xb0e1")]
+    LteMl1NeighborCellMeas {
+        version: u8,
+        #[deku(ctx = "*version, hdr_len.saturating_sub(1)")]
+        packet: LteMl1NeighborCellMeasPacket,
+    },
+    #[deku(id = "0xb0e4")]
+    LteMl1ServingCellMeas {
+        version: u8,
+        #[deku(ctx = "*version, hdr_len.saturating_sub(1)")]
+        packet: LteMl1ServingCellMeasPacket,
+    },
+    #[deku(id = "0x4127")]
+    WcdmaCellId(WcdmaCellId),
+    #[deku(id = "0x513a")]
+    GsmCellInfo(GsmCellInfo),
     #[deku(id = "0x713a")]
     UmtsNasOtaMessage {
         is_uplink: u8,
@@ -320,6 +457,15 @@ impl LteRrcOtaPacket {
         }
     }
 
+    pub fn get_phy_cell_id(&self) -> u16 {
+        match self {
+            LteRrcOtaPacket::V0 { phy_cell_id, .. } => *phy_cell_id,
+            LteRrcOtaPacket::V5 { phy_cell_id, .. } => *phy_cell_id,
+            LteRrcOtaPacket::V8 { phy_cell_id, .. } => *phy_cell_id,
+            LteRrcOtaPacket::V25 { phy_cell_id, .. } => *phy_cell_id,
+        }
+    }
+
     pub fn get_earfcn(&self) -> u32 {
         match self {
             LteRrcOtaPacket::V0 { earfcn, .. } => *earfcn as u32,
@@ -339,6 +485,261 @@ impl LteRrcOtaPacket {
     }
 }
 
+// ML1 measurements are fixed point in 1/16 dB steps. The offsets and bit
+// positions are the ones used by
+// https://github.com/fgsect/scat/blob/master/src/scat/parsers/qualcomm/diagltelogparser.py
+fn ml1_rsrp_dbm(raw: u32) -> f32 {
+    -180.0 + (raw & 0xfff) as f32 * 0.0625
+}
+
+fn ml1_rsrq_db(raw: u32) -> f32 {
+    -30.0 + ((raw >> 10) & 0x3ff) as f32 * 0.0625
+}
+
+fn ml1_rssi_dbm(raw: u32) -> f32 {
+    -110.0 + ((raw >> 10) & 0x7ff) as f32 * 0.0625
+}
+
+/// LTE ML1 serving cell measurements (0xb0e4). `len` is the length of the
+/// packet after the version byte, so that trailing fields end up in `extra`
+/// instead of being left over.
+///
+/// Versions 4 and 5 are laid out as in SCAT's `parse_lte_ml1_scell_meas`.
+/// Those are the only versions it knows, so others fail to parse rather than
+/// being read with a guessed layout.
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u8, len: u16", id = "version")]
+pub enum LteMl1ServingCellMeasPacket {
+    #[deku(id = "4")]
+    V4 {
+        rrc_rel: u8,
+        reserved: u16,
+        earfcn: u16,
+        // lower 9 bits are the PCI, upper 7 the serving layer priority
+        pci_layer_priority: u16,
+        meas_rsrp: u32,
+        avg_rsrp: u32,
+        rsrq: u32,
+        rssi: u32,
+        rxlev: u32,
+        s_search: u32,
+        #[deku(count = "len.saturating_sub(31)")]
+        extra: Vec<u8>,
+    },
+    #[deku(id = "5")]
+    V5 {
+        rrc_rel: u8,
+        reserved: u16,
+        earfcn: u32,
+        pci_layer_priority: u16,
+        reserved2: u16,
+        meas_rsrp: u32,
+        avg_rsrp: u32,
+        rsrq: u32,
+        rssi: u32,
+        rxlev: u32,
+        s_search: u32,
+        #[deku(count = "len.saturating_sub(35)")]
+        extra: Vec<u8>,
+    },
+}
+
+impl LteMl1ServingCellMeasPacket {
+    pub fn get_earfcn(&self) -> u32 {
+        match self {
+            LteMl1ServingCellMeasPacket::V4 { earfcn, .. } => *earfcn as u32,
+            LteMl1ServingCellMeasPacket::V5 { earfcn, .. } => *earfcn,
+        }
+    }
+
+    pub fn get_pci(&self) -> u16 {
+        match self {
+            LteMl1ServingCellMeasPacket::V4 {
+                pci_layer_priority, ..
+            } => *pci_layer_priority & 0x1ff,
+            LteMl1ServingCellMeasPacket::V5 {
+                pci_layer_priority, ..
+            } => *pci_layer_priority & 0x1ff,
+        }
+    }
+
+    fn get_raw_measurements(&self) -> (u32, u32, u32) {
+        match self {
+            LteMl1ServingCellMeasPacket::V4 {
+                meas_rsrp,
+                rsrq,
+                rssi,
+                ..
+            } => (*meas_rsrp, *rsrq, *rssi),
+            LteMl1ServingCellMeasPacket::V5 {
+                meas_rsrp,
+                rsrq,
+                rssi,
+                ..
+            } => (*meas_rsrp, *rsrq, *rssi),
+        }
+    }
+
+    pub fn get_rsrp_dbm(&self) -> f32 {
+        ml1_rsrp_dbm(self.get_raw_measurements().0)
+    }
+
+    pub fn get_rsrq_db(&self) -> f32 {
+        ml1_rsrq_db(self.get_raw_measurements().1)
+    }
+
+    pub fn get_rssi_dbm(&self) -> f32 {
+        ml1_rssi_dbm(self.get_raw_measurements().2)
+    }
+}
+
+/// LTE ML1 neighbor cell measurements (0xb0e1), one packet per measured
+/// frequency. `len` is the length of the packet after the version byte.
+///
+/// As with serving cell measurements, only versions 4 and 5 are parsed,
+/// laid out as in SCAT's `parse_lte_ml1_ncell_meas`.
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(ctx = "version: u8, len: u16", id = "version")]
+pub enum LteMl1NeighborCellMeasPacket {
+    #[deku(id = "4")]
+    V4 {
+        rrc_rel: u8,
+        reserved: u16,
+        earfcn: u16,
+        num_cells: u8,
+        reserved2: u8,
+        #[deku(count = "num_cells")]
+        cells: Vec<LteMl1NeighborCell>,
+        #[deku(count = "(len as usize).saturating_sub(7 + 16 * *num_cells as usize)")]
+        extra: Vec<u8>,
+    },
+    #[deku(id = "5")]
+    V5 {
+        rrc_rel: u8,
+        reserved: u16,
+        earfcn: u32,
+        num_cells: u8,
+        reserved2: [u8; 3],
+        #[deku(count = "num_cells")]
+        cells: Vec<LteMl1NeighborCell>,
+        #[deku(count = "(len as usize).saturating_sub(11 + 16 * *num_cells as usize)")]
+        extra: Vec<u8>,
+    },
+}
+
+impl LteMl1NeighborCellMeasPacket {
+    pub fn get_earfcn(&self) -> u32 {
+        match self {
+            LteMl1NeighborCellMeasPacket::V4 { earfcn, .. } => *earfcn as u32,
+            LteMl1NeighborCellMeasPacket::V5 { earfcn, .. } => *earfcn,
+        }
+    }
+
+    pub fn get_cells(&self) -> &[LteMl1NeighborCell] {
+        match self {
+            LteMl1NeighborCellMeasPacket::V4 { cells, .. } => cells,
+            LteMl1NeighborCellMeasPacket::V5 { cells, .. } => cells,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+pub struct LteMl1NeighborCell {
+    // lower 9 bits are the PCI
+    pub pci_ranking: u32,
+    pub meas_rsrp: u32,
+    pub rsrq: u32,
+    pub rssi: u32,
+}
+
+impl LteMl1NeighborCell {
+    pub fn get_pci(&self) -> u16 {
+        (self.pci_ranking & 0x1ff) as u16
+    }
+
+    pub fn get_rsrp_dbm(&self) -> f32 {
+        ml1_rsrp_dbm(self.meas_rsrp)
+    }
+
+    pub fn get_rsrq_db(&self) -> f32 {
+        ml1_rsrq_db(self.rsrq)
+    }
+
+    pub fn get_rssi_dbm(&self) -> f32 {
+        ml1_rssi_dbm(self.rssi)
+    }
+}
+
+/// WCDMA cell ID (0x4127). This log has no version field. The layout is
+/// the one SCAT's `parse_wcdma_cell_id` reads.
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+pub struct WcdmaCellId {
+    pub ul_uarfcn: u32,
+    pub dl_uarfcn: u32,
+    pub cell_id: u32,
+    pub ura_id: u16,
+    pub cell_access_flags: u8,
+    pub call_access: u8,
+    pub psc: u16,
+    // one digit per byte, with 0xff as the third MNC digit for 2-digit MNCs
+    pub mcc: [u8; 3],
+    pub mnc: [u8; 3],
+    pub lac: u32,
+    pub rac: u32,
+}
+
+impl WcdmaCellId {
+    fn digits_to_number(digits: &[u8]) -> Option<u16> {
+        let mut number = 0;
+        for &digit in digits.iter().take_while(|&&digit| digit != 0xff) {
+            if digit > 9 {
+                return None;
+            }
+            number = number * 10 + digit as u16;
+        }
+        Some(number)
+    }
+
+    pub fn get_mcc(&self) -> Option<u16> {
+        Self::digits_to_number(&self.mcc)
+    }
+
+    pub fn get_mnc(&self) -> Option<u16> {
+        Self::digits_to_number(&self.mnc)
+    }
+
+    /// How many digits the MNC has, which tells MNC 010 from MNC 10
+    pub fn get_mnc_digits(&self) -> u8 {
+        self.mnc.iter().take_while(|&&digit| digit != 0xff).count() as u8
+    }
+}
+
+/// GSM RR cell information (0x513a). This log has no version field. Unlike
+/// the other measurement logs, this layout hasn't been checked against a
+/// capture or another parser.
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+pub struct GsmCellInfo {
+    // lower 12 bits are the ARFCN, upper 4 the band
+    pub bcch_arfcn: u16,
+    pub bsic: u8,
+    pub cell_id: u16,
+    // BCD encoded as in 3GPP TS 24.008 10.5.1.3
+    pub plmn: [u8; 3],
+    pub lac: u16,
+    // received level from 0 (-110 dBm or less) to 63 (-47 dBm or more)
+    pub rxlev: u8,
+}
+
+impl GsmCellInfo {
+    pub fn get_arfcn(&self) -> u16 {
+        self.bcch_arfcn & 0xfff
+    }
+
+    pub fn get_rssi_dbm(&self) -> i16 {
+        self.rxlev as i16 - 110
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
 #[deku(endian = "little")]
 pub struct Timestamp {
@@ -346,12 +747,33 @@ pub struct Timestamp {
 }
 
 impl Timestamp {
+    fn epoch() -> DateTime<FixedOffset> {
+        chrono::DateTime::parse_from_rfc3339("1980-01-06T00:00:00-00:00").unwrap()
+    }
+
+    /// The inverse of [`Timestamp::to_datetime`], for times after 1980-01-06
+    pub fn from_datetime<Tz: TimeZone>(datetime: &DateTime<Tz>) -> Self {
+        let micros = datetime
+            .signed_duration_since(Self::epoch())
+            .num_microseconds()
+            .unwrap_or_default()
+            .max(0) as u64;
+        // a tick is 1/800s, or 1250µs, and to_datetime counts 40960 units of
+        // the lower 16 bits per millisecond. Rounding up keeps it, since it
+        // truncates to milliseconds, from landing a millisecond early.
+        let ticks = micros / 1250;
+        let remainder = ((micros % 1250) * 40960).div_ceil(1000);
+        Timestamp {
+            ts: (ticks << 16) | remainder,
+        }
+    }
+
     pub fn to_datetime(&self) -> DateTime<FixedOffset> {
         // Upper 48 bits: epoch at 1980-01-06 00:00:00, incremented by 1 for 1/800s
         // Lower 16 bits: time since last 1/800s tick in 1/32 chip units
         let ts_upper = self.ts >> 16;
         let ts_lower = self.ts & 0xffff;
-        let epoch = chrono::DateTime::parse_from_rfc3339("1980-01-06T00:00:00-00:00").unwrap();
+        let epoch = Self::epoch();
         let mut delta_seconds = ts_upper as f64 * 1.25;
         delta_seconds += ts_lower as f64 / 40960.0;
         let ts_delta = chrono::Duration::milliseconds(delta_seconds as i64);
@@ -376,6 +798,208 @@ pub enum LogConfigResponse {
     SetMask,
 }
 
+// Strings in diag responses are fixed-size and padded, or terminated, with
+// NULs
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// The modem's response to a version info request, as in libqcdm
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct VersionInfoResponse {
+    pub compile_date: [u8; 11],
+    pub compile_time: [u8; 8],
+    pub release_date: [u8; 11],
+    pub release_time: [u8; 8],
+    // the firmware's version directory, e.g. "MPSS.JO.2"
+    pub version_dir: [u8; 8],
+    pub station_class_mark: u8,
+    pub cai_revision: u8,
+    pub mobile_model: u8,
+    pub mobile_firmware_revision: u16,
+    pub slot_cycle_index: u8,
+    pub msm_version: u8,
+    pub reserved: u8,
+}
+
+impl VersionInfoResponse {
+    pub fn get_version_dir(&self) -> String {
+        c_string(&self.version_dir)
+    }
+
+    pub fn get_compiled(&self) -> String {
+        format!(
+            "{} {}",
+            c_string(&self.compile_date),
+            c_string(&self.compile_time)
+        )
+    }
+
+    pub fn get_released(&self) -> String {
+        format!(
+            "{} {}",
+            c_string(&self.release_date),
+            c_string(&self.release_time)
+        )
+    }
+}
+
+/// An NV item, both as requested and as read back
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct NvItem {
+    pub item: u16,
+    pub data: [u8; 128],
+    pub status: u16,
+}
+
+impl NvItem {
+    /// The IMEI, if this is a successfully read IMEI item. It's stored as in
+    /// 3GPP TS 24.008 10.5.1.4: a length, then BCD digits with the first
+    /// nibble giving the identity type.
+    pub fn get_imei(&self) -> Option<String> {
+        if self.item != NV_ITEM_IMEI || self.status != NV_STATUS_DONE {
+            return None;
+        }
+        let len = self.data[0] as usize;
+        if len != 8 || self.data[1] & 0x0f != 0x0a {
+            return None;
+        }
+        let mut digits = vec![self.data[1] >> 4];
+        for byte in &self.data[2..=len] {
+            digits.push(byte & 0x0f);
+            digits.push(byte >> 4);
+        }
+        if digits.iter().any(|&digit| digit > 9) || digits.iter().all(|&digit| digit == 0) {
+            return None;
+        }
+        Some(digits.iter().map(|digit| digit.to_string()).collect())
+    }
+
+    /// The ESN, in hex, if this is a successfully read, and set, ESN item
+    pub fn get_esn(&self) -> Option<String> {
+        if self.item != NV_ITEM_ESN || self.status != NV_STATUS_DONE {
+            return None;
+        }
+        let esn = u32::from_le_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]);
+        (esn != 0).then(|| format!("{esn:08X}"))
+    }
+}
+
+/// The modem's response to a subsystem command, whose payload depends on the
+/// subsystem and command
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+pub struct SubsystemResponse {
+    pub subsystem_id: u8,
+    #[deku(endian = "little")]
+    pub subsystem_command: u16,
+    #[deku(read_all)]
+    pub payload: Vec<u8>,
+}
+
+impl SubsystemResponse {
+    /// The call manager's state, if this is a response to a CM state info
+    /// request
+    pub fn get_cm_state_info(&self) -> Option<CmStateInfo> {
+        if self.subsystem_id != SUBSYSTEM_CM || self.subsystem_command != CM_STATE_INFO {
+            return None;
+        }
+        CmStateInfo::from_bytes((&self.payload, 0))
+            .ok()
+            .map(|(_, info)| info)
+    }
+}
+
+/// The call manager's current state, as in libqcdm
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct CmStateInfo {
+    pub call_state: u32,
+    pub operating_mode: u32,
+    pub system_mode: u32,
+    pub mode_preference: u32,
+    pub band_preference: u32,
+    pub roam_preference: u32,
+    pub service_domain_preference: u32,
+    pub acquisition_order_preference: u32,
+    pub hybrid_preference: u32,
+    pub network_selection_preference: u32,
+}
+
+impl CmStateInfo {
+    pub fn get_system_mode(&self) -> SystemMode {
+        SystemMode::from(self.system_mode)
+    }
+}
+
+/// The radio access technology the modem is currently using
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMode {
+    NoService,
+    Amps,
+    Cdma,
+    Gsm,
+    Hdr,
+    Wcdma,
+    Gps,
+    GsmWcdma,
+    Wlan,
+    Lte,
+    Unknown,
+}
+
+impl From<u32> for SystemMode {
+    fn from(system_mode: u32) -> Self {
+        match system_mode {
+            0 => SystemMode::NoService,
+            1 => SystemMode::Amps,
+            2 => SystemMode::Cdma,
+            3 => SystemMode::Gsm,
+            4 => SystemMode::Hdr,
+            5 => SystemMode::Wcdma,
+            6 => SystemMode::Gps,
+            7 => SystemMode::GsmWcdma,
+            8 => SystemMode::Wlan,
+            9 => SystemMode::Lte,
+            _ => SystemMode::Unknown,
+        }
+    }
+}
+
+/// The modem's response to an extended build ID request, which is followed
+/// by the NUL terminated build ID and model strings
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct ExtendedBuildIdResponse {
+    pub version: u8,
+    pub reserved: [u8; 2],
+    pub msm_hw_version: u32,
+    pub mobile_model_id: u32,
+    #[deku(read_all)]
+    pub strings: Vec<u8>,
+}
+
+impl ExtendedBuildIdResponse {
+    fn get_string(&self, index: usize) -> Option<String> {
+        self.strings
+            .split(|&b| b == 0)
+            .nth(index)
+            .map(c_string)
+            .filter(|s| !s.is_empty())
+    }
+
+    pub fn get_build_id(&self) -> Option<String> {
+        self.get_string(0)
+    }
+
+    pub fn get_model(&self) -> Option<String> {
+        self.get_string(1)
+    }
+}
+
 pub fn build_log_mask_request(
     log_type: u32,
     log_mask_bitsize: u32,
@@ -427,6 +1051,76 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_modem_info_request_serialization() {
+        assert_eq!(Request::VersionInfo.to_bytes().unwrap(), vec![0]);
+        assert_eq!(Request::ExtendedBuildId.to_bytes().unwrap(), vec![124]);
+        assert_eq!(
+            Request::cm_state_info().to_bytes().unwrap(),
+            vec![75, 15, 0, 0]
+        );
+        let bytes = Request::nv_read(NV_ITEM_IMEI).to_bytes().unwrap();
+        assert_eq!(bytes.len(), 133);
+        assert_eq!(bytes[..3], [38, 0x26, 0x02]);
+        assert!(bytes[3..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_modem_info_responses() {
+        let mut data = vec![
+            38, 0x26, 0x02, 8, 0x3a, 0x21, 0x43, 0x65, 0x87, 0x09, 0x21, 0x43,
+        ];
+        data.resize(133, 0);
+        let (_, message) = Message::from_bytes((&data, 0)).unwrap();
+        assert!(message.is_response());
+        let Message::NvRead(item) = message else {
+            panic!("not an NV item: {message:?}");
+        };
+        assert_eq!(item.get_imei(), Some("312345678901234".to_string()));
+        assert_eq!(item.get_esn(), None);
+
+        // an unset item
+        data[131] = 5;
+        let (_, message) = Message::from_bytes((&data, 0)).unwrap();
+        let Message::NvRead(item) = message else {
+            panic!("not an NV item: {message:?}");
+        };
+        assert_eq!(item.get_imei(), None);
+
+        let mut data = vec![124, 1, 0, 0, 0xe1, 0x00, 0x00, 0x00, 0, 0, 0, 0];
+        data.extend(b"MPSS.JO.2.0.2.c1-00064\0ORBIC\0");
+        let (_, message) = Message::from_bytes((&data, 0)).unwrap();
+        let Message::ExtendedBuildId(build_id) = message else {
+            panic!("not an extended build ID: {message:?}");
+        };
+        assert_eq!(build_id.msm_hw_version, 0xe1);
+        assert_eq!(
+            build_id.get_build_id(),
+            Some("MPSS.JO.2.0.2.c1-00064".to_string())
+        );
+        assert_eq!(build_id.get_model(), Some("ORBIC".to_string()));
+
+        let mut data = vec![75, 15, 0, 0];
+        for field in [0u32, 5, 9, 2, 0, 0, 0, 0, 0, 0] {
+            data.extend(field.to_le_bytes());
+        }
+        let (_, message) = Message::from_bytes((&data, 0)).unwrap();
+        let Message::Subsystem(response) = message else {
+            panic!("not a subsystem response: {message:?}");
+        };
+        let state = response.get_cm_state_info().unwrap();
+        assert_eq!(state.get_system_mode(), SystemMode::Lte);
+
+        let (_, message) = Message::from_bytes((&[19, 124][..], 0)).unwrap();
+        assert_eq!(
+            message,
+            Message::Rejected {
+                code: 19,
+                request: vec![124],
+            }
+        );
+    }
+
     #[test]
     fn test_build_log_mask_request() {
         let log_type = 11;
@@ -509,6 +1203,181 @@ mod test {
         );
     }
 
+    // wraps a log body in a message header, parses it, and checks that it
+    // serializes back to the same bytes. There are no captures with the
+    // measurement logs to take their bodies from, so the tests below build
+    // them by hand from the layouts above.
+    fn parse_log_body(log_type: u16, body: &[u8]) -> LogBody {
+        let len = (body.len() + 12) as u16;
+        let mut data = vec![16, 0];
+        data.extend(len.to_le_bytes());
+        data.extend(len.to_le_bytes());
+        data.extend(log_type.to_le_bytes());
+        data.extend(72659535985485082u64.to_le_bytes());
+        data.extend(body);
+        let ((leftover, _), msg) = Message::from_bytes((&data, 0)).unwrap();
+        assert!(leftover.is_empty());
+        assert_eq!(msg.to_bytes().unwrap(), data);
+        match msg {
+            Message::Log { body, .. } => body,
+            _ => panic!("not a log message: {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn test_lte_ml1_serving_cell_meas() {
+        let body = parse_log_body(
+            0xb0e4,
+            &[
+                5, 1, 0, 0, 0x6e, 0x14, 0, 0, 0x2d, 0x07, 0, 0, 0x50, 0x05, 0, 0, 0x50, 0x05, 0,
+                0, 0, 0, 0x05, 0, 0, 0x40, 0x0b, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xaa, 0xbb, 0xcc,
+                0xdd,
+            ],
+        );
+        let LogBody::LteMl1ServingCellMeas { version, packet } = body else {
+            panic!("wrong log body: {body:?}");
+        };
+        assert_eq!(version, 5);
+        assert!(matches!(
+            &packet,
+            LteMl1ServingCellMeasPacket::V5 { extra, .. } if extra == &[0xaa, 0xbb, 0xcc, 0xdd]
+        ));
+        assert_eq!(packet.get_earfcn(), 5230);
+        assert_eq!(packet.get_pci(), 301);
+        assert_eq!(packet.get_rsrp_dbm(), -95.0);
+        assert_eq!(packet.get_rsrq_db(), -10.0);
+        assert_eq!(packet.get_rssi_dbm(), -65.0);
+    }
+
+    #[test]
+    fn test_lte_ml1_unknown_version() {
+        // version 6 of the serving cell measurements, otherwise the same as
+        // version 5
+        let body = [
+            6, 1, 0, 0, 0x6e, 0x14, 0, 0, 0x2d, 0x07, 0, 0, 0x50, 0x05, 0, 0, 0x50, 0x05, 0, 0, 0,
+            0, 0x05, 0, 0, 0x40, 0x0b, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let len = (body.len() + 12) as u16;
+        let mut data = vec![16, 0];
+        data.extend(len.to_le_bytes());
+        data.extend(len.to_le_bytes());
+        data.extend(0xb0e4u16.to_le_bytes());
+        data.extend(72659535985485082u64.to_le_bytes());
+        data.extend(body);
+        assert!(Message::from_bytes((&data, 0)).is_err());
+    }
+
+    #[test]
+    fn test_lte_ml1_neighbor_cell_meas() {
+        let body = parse_log_body(
+            0xb0e1,
+            &[
+                4, 1, 0, 0, 0x02, 0x08, 2, 0, 0xa0, 0, 0, 0, 0, 0x05, 0, 0, 0, 0x80, 0x04, 0, 0,
+                0, 0, 0, 7, 0, 0, 0, 0x60, 0x04, 0, 0, 0, 0xc0, 0x03, 0, 0, 0, 0, 0,
+            ],
+        );
+        let LogBody::LteMl1NeighborCellMeas { version, packet } = body else {
+            panic!("wrong log body: {body:?}");
+        };
+        assert_eq!(version, 4);
+        assert_eq!(packet.get_earfcn(), 2050);
+        let cells: Vec<_> = packet
+            .get_cells()
+            .iter()
+            .map(|cell| (cell.get_pci(), cell.get_rsrp_dbm(), cell.get_rsrq_db()))
+            .collect();
+        assert_eq!(cells, vec![(160, -100.0, -12.0), (7, -110.0, -15.0)]);
+        assert_eq!(packet.get_cells()[0].get_rssi_dbm(), -110.0);
+    }
+
+    #[test]
+    fn test_wcdma_cell_id() {
+        let body = parse_log_body(
+            0x4127,
+            &[
+                0x8c, 0x25, 0, 0, 0x42, 0x29, 0, 0, 0x67, 0x45, 0x23, 0x01, 0, 0, 0, 0, 0x2d,
+                0x01, 3, 1, 0, 2, 6, 0xff, 0x2b, 0x1a, 0, 0, 5, 0, 0, 0,
+            ],
+        );
+        let LogBody::WcdmaCellId(cell) = body else {
+            panic!("wrong log body: {body:?}");
+        };
+        assert_eq!(cell.dl_uarfcn, 10562);
+        assert_eq!(cell.cell_id, 0x01234567);
+        assert_eq!(cell.psc, 301);
+        assert_eq!(cell.get_mcc(), Some(310));
+        assert_eq!(cell.get_mnc(), Some(26));
+        assert_eq!(cell.get_mnc_digits(), 2);
+        assert_eq!(cell.lac, 0x1a2b);
+        assert_eq!(cell.rac, 5);
+    }
+
+    #[test]
+    fn test_gsm_cell_info() {
+        let body = parse_log_body(
+            0x513a,
+            &[0x80, 0x20, 0x21, 0x21, 0x4e, 0x13, 0xf0, 0x62, 0x34, 0x12, 40],
+        );
+        assert_eq!(
+            body,
+            LogBody::GsmCellInfo(GsmCellInfo {
+                bcch_arfcn: 0x2080,
+                bsic: 0x21,
+                cell_id: 20001,
+                plmn: [0x13, 0xf0, 0x62],
+                lac: 0x1234,
+                rxlev: 40,
+            })
+        );
+        let LogBody::GsmCellInfo(cell) = body else {
+            unreachable!();
+        };
+        assert_eq!(cell.get_arfcn(), 128);
+        assert_eq!(cell.get_rssi_dbm(), -70);
+    }
+
+    #[test]
+    fn test_timestamp_from_datetime() {
+        for datetime in [
+            "2024-05-01T12:34:56.789+00:00",
+            "2024-05-01T12:34:56.000+00:00",
+            "2024-05-01T12:34:56.999-07:00",
+        ] {
+            let datetime = DateTime::parse_from_rfc3339(datetime).unwrap();
+            assert_eq!(Timestamp::from_datetime(&datetime).to_datetime(), datetime);
+        }
+    }
+
+    #[test]
+    fn test_new_log() {
+        let (encapsulated, message) = get_test_message(&[0x40, 0x1, 0xee]);
+        let Message::Log {
+            log_type,
+            timestamp,
+            body,
+            ..
+        } = message.clone()
+        else {
+            unreachable!();
+        };
+        let built = Message::new_log(log_type, timestamp, body).unwrap();
+        assert_eq!(built, message);
+        let container =
+            MessagesContainer::from_messages(DataType::UserSpace, &[built]).unwrap();
+        assert_eq!(container, make_container(DataType::UserSpace, encapsulated));
+
+        // a body too long for the length fields is refused
+        let too_long = LogBody::Nas4GMessage {
+            direction: Nas4GMessageDirection::Downlink,
+            ext_header_version: 1,
+            rrc_rel: 0,
+            rrc_version_minor: 0,
+            rrc_version_major: 0,
+            msg: vec![0; 0x10000],
+        };
+        assert!(Message::new_log(0xb0ec, Timestamp { ts: 0 }, too_long).is_err());
+    }
+
     fn make_container(data_type: DataType, message: HdlcEncapsulatedMessage) -> MessagesContainer {
         MessagesContainer {
             data_type,
@@ -1,15 +1,23 @@
+use crate::analysis::analyzer::ModemFirmware;
 use crate::diag::{
     CRC_CCITT, DataType, DiagParsingError, LogConfigRequest, LogConfigResponse, Message,
-    MessagesContainer, Request, RequestContainer, ResponsePayload, build_log_mask_request,
+    MessagesContainer, NV_ITEM_ESN, NV_ITEM_IMEI, Request, RequestContainer, ResponsePayload,
+    SystemMode, build_log_mask_request,
 };
+use crate::diag_transport::{DiagTransport, DiagTransportConfig, open_transport};
 use crate::hdlc::hdlc_encapsulate;
+use crate::signal::ServingCell;
 use crate::{Device, log_codes};
 
+use async_trait::async_trait;
 use deku::prelude::*;
 use futures::TryStream;
 use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::io::ErrorKind;
 use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use thiserror::Error;
 use tokio::fs::File;
@@ -20,7 +28,7 @@ pub type DiagResult<T> = Result<T, DiagDeviceError>;
 
 #[derive(Error, Debug)]
 pub enum DiagDeviceError {
-    #[error("Failed to initialize /dev/diag: {0}")]
+    #[error("Failed to initialize diag device: {0}")]
     InitializationFailed(String),
     #[error("Failed to read diag device: {0}")]
     DeviceReadFailed(std::io::Error),
@@ -38,8 +46,14 @@ pub enum DiagDeviceError {
     OpenDiagDeviceError(std::io::Error),
     #[error("Failed to parse MessagesContainer: {0}")]
     ParseMessagesContainerError(deku::DekuError),
+    #[error("Unsupported baud rate: {0}")]
+    UnsupportedBaudRate(u32),
+    #[error("No more messages to read")]
+    EndOfStream,
 }
 
+/// The log codes Rayhunter has always enabled: signaling and the user's own IP
+/// traffic
 pub const LOG_CODES_FOR_RAW_PACKET_LOGGING: [u32; 11] = [
     // Layer 2:
     log_codes::LOG_GPRS_MAC_SIGNALLING_MESSAGE_C, // 0x5226
@@ -58,6 +72,185 @@ pub const LOG_CODES_FOR_RAW_PACKET_LOGGING: [u32; 11] = [
     log_codes::LOG_DATA_PROTOCOL_LOGGING_C, // 0x11eb
 ];
 
+/// RRC and NAS signaling, which is what the analyzers look at
+pub const SIGNALING_LOG_CODES: [u32; 10] = [
+    log_codes::LOG_GPRS_MAC_SIGNALLING_MESSAGE_C, // 0x5226
+    log_codes::LOG_GSM_RR_SIGNALING_MESSAGE_C,    // 0x512f
+    log_codes::WCDMA_SIGNALLING_MESSAGE,          // 0x412f
+    log_codes::LOG_LTE_RRC_OTA_MSG_LOG_C,         // 0xb0c0
+    log_codes::LOG_NR_RRC_OTA_MSG_LOG_C,          // 0xb821
+    log_codes::LOG_UMTS_NAS_OTA_MESSAGE_LOG_PACKET_C, // 0x713a
+    log_codes::LOG_LTE_NAS_ESM_OTA_IN_MSG_LOG_C,  // 0xb0e2
+    log_codes::LOG_LTE_NAS_ESM_OTA_OUT_MSG_LOG_C, // 0xb0e3
+    log_codes::LOG_LTE_NAS_EMM_OTA_IN_MSG_LOG_C,  // 0xb0ec
+    log_codes::LOG_LTE_NAS_EMM_OTA_OUT_MSG_LOG_C, // 0xb0ed
+];
+
+/// Serving and neighbor cell measurements and cell identities
+pub const MEASUREMENT_LOG_CODES: [u32; 4] = [
+    log_codes::LOG_LTE_ML1_SERVING_CELL_MEAS_C,  // 0xb0e4
+    log_codes::LOG_LTE_ML1_NEIGHBOR_CELL_MEAS_C, // 0xb0e1
+    log_codes::LOG_GSM_RR_CELL_INFORMATION_C,    // 0x513a
+    log_codes::WCDMA_CELL_ID,                    // 0x4127
+];
+
+/// The user's own IP traffic
+pub const IP_TRAFFIC_LOG_CODES: [u32; 1] = [
+    log_codes::LOG_DATA_PROTOCOL_LOGGING_C, // 0x11eb
+];
+
+/// The most log codes that can be enabled on top of a preset
+pub const MAX_EXTRA_LOG_CODES: usize = 64;
+
+/// Named sets of log codes to enable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogCodePreset {
+    /// [`SIGNALING_LOG_CODES`] only
+    MinimalSignaling,
+    /// [`LOG_CODES_FOR_RAW_PACKET_LOGGING`]
+    #[default]
+    Standard,
+    /// [`SIGNALING_LOG_CODES`] and [`MEASUREMENT_LOG_CODES`]
+    SignalingAndMeasurements,
+    /// Everything, including [`IP_TRAFFIC_LOG_CODES`]
+    Full,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LogCodeSelectionError {
+    #[error("at most {MAX_EXTRA_LOG_CODES} extra log codes can be enabled")]
+    TooManyExtraLogCodes,
+    #[error("invalid log code {0:#x}, log codes are 16 bits")]
+    InvalidLogCode(u32),
+}
+
+/// Which log codes the modem should send
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogCodeSelection {
+    pub preset: LogCodePreset,
+    /// Log codes to enable in addition to the preset's
+    pub extra_log_codes: Vec<u32>,
+}
+
+impl LogCodeSelection {
+    /// The selected log codes, sorted and without duplicates
+    pub fn log_codes(&self) -> Vec<u32> {
+        let mut log_codes = match self.preset {
+            LogCodePreset::MinimalSignaling => SIGNALING_LOG_CODES.to_vec(),
+            LogCodePreset::Standard => LOG_CODES_FOR_RAW_PACKET_LOGGING.to_vec(),
+            LogCodePreset::SignalingAndMeasurements => {
+                [&SIGNALING_LOG_CODES[..], &MEASUREMENT_LOG_CODES[..]].concat()
+            }
+            LogCodePreset::Full => [
+                &SIGNALING_LOG_CODES[..],
+                &MEASUREMENT_LOG_CODES[..],
+                &IP_TRAFFIC_LOG_CODES[..],
+            ]
+            .concat(),
+        };
+        log_codes.extend(&self.extra_log_codes);
+        log_codes.sort_unstable();
+        log_codes.dedup();
+        log_codes
+    }
+
+    /// Checks that there aren't too many extra log codes, and that they fit in
+    /// 16 bits
+    pub fn validate(&self) -> Result<(), LogCodeSelectionError> {
+        if self.extra_log_codes.len() > MAX_EXTRA_LOG_CODES {
+            return Err(LogCodeSelectionError::TooManyExtraLogCodes);
+        }
+        match self.extra_log_codes.iter().find(|&&c| c > 0xffff) {
+            Some(&log_code) => Err(LogCodeSelectionError::InvalidLogCode(log_code)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Asks a [`DiagDevice`] that's being streamed from to enable a different set
+/// of log codes. The change is made before the next container is read.
+#[derive(Clone, Default)]
+pub struct LogCodeHandle {
+    pending: Arc<Mutex<Option<Vec<u32>>>>,
+}
+
+impl LogCodeHandle {
+    pub fn reconfigure(&self, log_codes: Vec<u32>) {
+        *self.pending.lock().unwrap() = Some(log_codes);
+    }
+
+    fn take(&self) -> Option<Vec<u32>> {
+        self.pending.lock().unwrap().take()
+    }
+}
+
+/// What the modem reports about itself, see [`DiagDevice::modem_info`].
+/// Whatever the modem didn't answer is left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModemInfo {
+    /// The firmware's build ID, from the extended build ID
+    pub firmware_build: Option<String>,
+    pub model: Option<String>,
+    /// The version directory from the version info
+    pub baseband_version: Option<String>,
+    pub compiled: Option<String>,
+    pub released: Option<String>,
+    pub imei: Option<String>,
+    pub esn: Option<String>,
+    /// The radio access technology the modem was using when queried
+    pub system_mode: Option<SystemMode>,
+}
+
+impl ModemInfo {
+    pub fn firmware(&self) -> ModemFirmware {
+        ModemFirmware {
+            firmware_build: self.firmware_build.clone(),
+            baseband_version: self.baseband_version.clone(),
+        }
+    }
+}
+
+/// Shares what's known about the modem. Querying it takes the modem's
+/// attention away from logging, so that's only done when the diag device is
+/// opened, and the answers are kept here.
+#[derive(Clone, Default)]
+pub struct ModemInfoHandle {
+    state: Arc<Mutex<ModemInfoState>>,
+}
+
+#[derive(Default)]
+struct ModemInfoState {
+    info: Option<ModemInfo>,
+    serving_cell: Option<ServingCell>,
+}
+
+impl ModemInfoHandle {
+    /// The modem info as of the last time it was queried
+    pub fn latest(&self) -> Option<ModemInfo> {
+        self.state.lock().unwrap().info.clone()
+    }
+
+    pub fn update(&self, info: ModemInfo) {
+        self.state.lock().unwrap().info = Some(info);
+    }
+
+    /// The LTE cell the modem was last measured on, which whoever analyzes
+    /// the streamed containers keeps up to date
+    pub fn serving_cell(&self) -> Option<ServingCell> {
+        self.state.lock().unwrap().serving_cell.clone()
+    }
+
+    pub fn set_serving_cell(&self, serving_cell: ServingCell) {
+        self.state.lock().unwrap().serving_cell = Some(serving_cell);
+    }
+}
+
+// While logging is enabled, responses to log config requests are interleaved
+// with logs, so this many containers are read looking for them
+const MAX_CONTAINERS_BEFORE_RESPONSE: usize = 1000;
+
 const BUFFER_LEN: usize = 1024 * 1024 * 10;
 const MEMORY_DEVICE_MODE: u32 = 2;
 
@@ -79,56 +272,16 @@ const DIAG_IOCTL_SWITCH_LOGGING: u64 = 7;
 #[cfg(all(not(target_env = "musl"), target_arch = "aarch64"))]
 const DIAG_IOCTL_SWITCH_LOGGING: u64 = 7;
 
-pub struct DiagDevice {
+/// The `/dev/diag` character device, as found on the hotspots Rayhunter
+/// runs on
+pub struct CharDeviceTransport {
     file: File,
     read_buf: Vec<u8>,
     use_mdm: i32,
 }
 
-impl DiagDevice {
-    pub async fn new(configured_device: &Device) -> DiagResult<Self> {
-        Self::new_with_retries(Duration::from_secs(30), configured_device).await
-    }
-
-    pub async fn new_with_retries(
-        max_duration: Duration,
-        configured_device: &Device,
-    ) -> DiagResult<Self> {
-        // For some reason the diag device needs a very long time to become available again with in
-        // the same process, on TP-Link M7350 v3. While process restart would reset it faster.
-
-        let start_time = std::time::Instant::now();
-        let max_delay = Duration::from_secs(5);
-
-        let mut delay = Duration::from_millis(100);
-        let mut num_retries = 0;
-
-        loop {
-            match Self::try_new(configured_device).await {
-                Ok(device) => {
-                    info!("Diag device initialization succeeded after {num_retries} retries");
-                    return Ok(device);
-                }
-                Err(e) => {
-                    num_retries += 1;
-                    if start_time.elapsed() >= max_duration {
-                        error!("Failed to initialize diag device after {max_duration:?}: {e}");
-                        return Err(e);
-                    }
-
-                    info!(
-                        "Diag device initialization failed {num_retries} times, retrying in {delay:?}: {e}"
-                    );
-                    sleep(delay).await;
-
-                    // Exponential backoff
-                    delay = std::cmp::min(delay * 2, max_delay);
-                }
-            }
-        }
-    }
-
-    async fn try_new(configured_device: &Device) -> DiagResult<Self> {
+impl CharDeviceTransport {
+    pub async fn open(configured_device: &Device) -> DiagResult<Self> {
         let diag_file = File::options()
             .read(true)
             .write(true)
@@ -140,23 +293,17 @@ impl DiagDevice {
         enable_frame_readwrite(fd, MEMORY_DEVICE_MODE, configured_device)?;
         let use_mdm = determine_use_mdm(fd)?;
 
-        Ok(DiagDevice {
+        Ok(CharDeviceTransport {
             read_buf: vec![0; BUFFER_LEN],
             file: diag_file,
             use_mdm,
         })
     }
+}
 
-    pub fn as_stream(
-        &mut self,
-    ) -> impl TryStream<Ok = MessagesContainer, Error = DiagDeviceError> + '_ {
-        futures::stream::try_unfold(self, |dev| async {
-            let container = dev.get_next_messages_container().await?;
-            Ok(Some((container, dev)))
-        })
-    }
-
-    async fn get_next_messages_container(&mut self) -> Result<MessagesContainer, DiagDeviceError> {
+#[async_trait]
+impl DiagTransport for CharDeviceTransport {
+    async fn read_container(&mut self) -> DiagResult<MessagesContainer> {
         let mut bytes_read = 0;
         // TP-Link M7350 sometimes sends too small messages, we need to be able to deal with short reads.
         while bytes_read <= 8 {
@@ -179,13 +326,12 @@ impl DiagDevice {
         }
     }
 
-    async fn write_request(&mut self, req: &Request) -> DiagResult<()> {
-        let req_bytes = &req.to_bytes().expect("Failed to serialize Request");
+    async fn write_request(&mut self, hdlc_encapsulated_request: Vec<u8>) -> DiagResult<()> {
         let buf = RequestContainer {
             data_type: DataType::UserSpace,
             use_mdm: self.use_mdm > 0,
             mdm_field: -1,
-            hdlc_encapsulated_request: hdlc_encapsulate(req_bytes, &CRC_CCITT),
+            hdlc_encapsulated_request,
         }
         .to_bytes()
         .expect("Failed to serialize RequestContainer");
@@ -205,15 +351,147 @@ impl DiagDevice {
         }
         Ok(())
     }
+}
+
+/// Reads diag messages from a modem, over any [`DiagTransport`]
+pub struct DiagDevice {
+    transport: Box<dyn DiagTransport>,
+    log_code_handle: LogCodeHandle,
+    // containers read while waiting for responses, which are streamed before
+    // anything new is read
+    pending: VecDeque<MessagesContainer>,
+}
+
+impl DiagDevice {
+    pub async fn new(
+        transport_config: &DiagTransportConfig,
+        configured_device: &Device,
+    ) -> DiagResult<Self> {
+        Self::new_with_retries(Duration::from_secs(30), transport_config, configured_device).await
+    }
+
+    pub async fn new_with_retries(
+        max_duration: Duration,
+        transport_config: &DiagTransportConfig,
+        configured_device: &Device,
+    ) -> DiagResult<Self> {
+        // For some reason the diag device needs a very long time to become available again with in
+        // the same process, on TP-Link M7350 v3. While process restart would reset it faster.
+
+        let start_time = std::time::Instant::now();
+        let max_delay = Duration::from_secs(5);
+
+        let mut delay = Duration::from_millis(100);
+        let mut num_retries = 0;
 
-    async fn read_response(&mut self) -> DiagResult<Vec<Result<Message, DiagParsingError>>> {
         loop {
-            let container = self.get_next_messages_container().await?;
+            match open_transport(transport_config, configured_device).await {
+                Ok(transport) => {
+                    info!("Diag device initialization succeeded after {num_retries} retries");
+                    return Ok(Self::from_transport(transport));
+                }
+                Err(e) => {
+                    num_retries += 1;
+                    if start_time.elapsed() >= max_duration {
+                        error!("Failed to initialize diag device after {max_duration:?}: {e}");
+                        return Err(e);
+                    }
+
+                    info!(
+                        "Diag device initialization failed {num_retries} times, retrying in {delay:?}: {e}"
+                    );
+                    sleep(delay).await;
+
+                    // Exponential backoff
+                    delay = std::cmp::min(delay * 2, max_delay);
+                }
+            }
+        }
+    }
+
+    pub fn from_transport(transport: Box<dyn DiagTransport>) -> Self {
+        DiagDevice {
+            transport,
+            log_code_handle: LogCodeHandle::default(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns a handle for changing the enabled log codes while streaming
+    pub fn log_code_handle(&self) -> LogCodeHandle {
+        self.log_code_handle.clone()
+    }
+
+    pub fn as_stream(
+        &mut self,
+    ) -> impl TryStream<Ok = MessagesContainer, Error = DiagDeviceError> + '_ {
+        futures::stream::try_unfold(self, |dev| async {
+            if let Some(log_codes) = dev.log_code_handle.take() {
+                // the old configuration keeps working if this fails
+                if let Err(e) = dev.config_logs(&log_codes).await {
+                    error!("failed to reconfigure diag logging: {e}");
+                }
+            }
+            if let Some(container) = dev.pending.pop_front() {
+                return Ok(Some((container, dev)));
+            }
+            match dev.transport.read_container().await {
+                Ok(container) => Ok(Some((container, dev))),
+                // the transport has nothing left to read, e.g. a replayed file
+                // that's been played back, so the stream ends
+                Err(DiagDeviceError::EndOfStream) => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+    }
+
+    async fn write_request(&mut self, req: &Request) -> DiagResult<()> {
+        let req_bytes = &req.to_bytes().expect("Failed to serialize Request");
+        self.transport
+            .write_request(hdlc_encapsulate(req_bytes, &CRC_CCITT))
+            .await
+    }
+
+    // Reads containers until one has a response in it, returning the messages
+    // making up the response. Everything else that's read is kept to be
+    // streamed later.
+    async fn read_response(&mut self) -> DiagResult<Vec<Result<Message, DiagParsingError>>> {
+        for _ in 0..MAX_CONTAINERS_BEFORE_RESPONSE {
+            let container = self.transport.read_container().await?;
             if container.data_type != DataType::UserSpace {
+                self.pending.push_back(container);
                 continue;
             }
-            return Ok(container.into_messages());
+            let mut responses = Vec::new();
+            let mut others = Vec::new();
+            for encapsulated in container.messages {
+                let messages = MessagesContainer {
+                    data_type: DataType::UserSpace,
+                    num_messages: 1,
+                    messages: vec![encapsulated.clone()],
+                }
+                .into_messages();
+                if messages
+                    .iter()
+                    .any(|msg| matches!(msg, Ok(message) if message.is_response()))
+                {
+                    responses.extend(messages);
+                } else {
+                    others.push(encapsulated);
+                }
+            }
+            if !others.is_empty() {
+                self.pending.push_back(MessagesContainer {
+                    data_type: DataType::UserSpace,
+                    num_messages: others.len() as u32,
+                    messages: others,
+                });
+            }
+            if !responses.is_empty() {
+                return Ok(responses);
+            }
         }
+        Ok(Vec::new())
     }
 
     async fn retrieve_id_ranges(&mut self) -> DiagResult<[u32; 16]> {
@@ -236,6 +514,7 @@ impl DiagDevice {
                     }
                     _ => info!("skipping non-LogConfigResponse response..."),
                 },
+                Ok(_) => info!("skipping non-LogConfigResponse response..."),
                 Err(e) => error!("error parsing message: {e:?}"),
             }
         }
@@ -243,12 +522,13 @@ impl DiagDevice {
         Err(DiagDeviceError::NoResponse(req))
     }
 
-    async fn set_log_mask(&mut self, log_type: u32, log_mask_bitsize: u32) -> DiagResult<()> {
-        let req = build_log_mask_request(
-            log_type,
-            log_mask_bitsize,
-            &LOG_CODES_FOR_RAW_PACKET_LOGGING,
-        );
+    async fn set_log_mask(
+        &mut self,
+        log_type: u32,
+        log_mask_bitsize: u32,
+        log_codes: &[u32],
+    ) -> DiagResult<()> {
+        let req = build_log_mask_request(log_type, log_mask_bitsize, log_codes);
         self.write_request(&req).await?;
 
         for msg in self.read_response().await? {
@@ -264,6 +544,7 @@ impl DiagDevice {
                         return Ok(());
                     }
                 }
+                Ok(_) => info!("skipping non-LogConfigResponse response..."),
                 Err(e) => error!("error parsing message: {e:?}"),
             }
         }
@@ -271,13 +552,64 @@ impl DiagDevice {
         Err(DiagDeviceError::NoResponse(req))
     }
 
-    pub async fn config_logs(&mut self) -> DiagResult<()> {
+    // Sends a request, returning the modem's response, or None if the modem
+    // rejected it or didn't answer
+    async fn query(&mut self, req: &Request) -> DiagResult<Option<Message>> {
+        self.write_request(req).await?;
+
+        for msg in self.read_response().await? {
+            match msg {
+                Ok(Message::Log { .. }) => info!("skipping log response..."),
+                Ok(Message::Rejected { code, .. }) => {
+                    info!("modem rejected {req:?} with error code {code}");
+                    return Ok(None);
+                }
+                Ok(response) => return Ok(Some(response)),
+                Err(e) => error!("error parsing message: {e:?}"),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Asks the modem for its firmware versions, identifiers and current
+    /// system mode. Not every modem answers every query.
+    pub async fn modem_info(&mut self) -> DiagResult<ModemInfo> {
+        let mut info = ModemInfo::default();
+        if let Some(Message::ExtendedBuildId(build_id)) =
+            self.query(&Request::ExtendedBuildId).await?
+        {
+            info.firmware_build = build_id.get_build_id();
+            info.model = build_id.get_model();
+        }
+        if let Some(Message::VersionInfo(version)) = self.query(&Request::VersionInfo).await? {
+            info.baseband_version = Some(version.get_version_dir()).filter(|v| !v.is_empty());
+            info.compiled = Some(version.get_compiled());
+            info.released = Some(version.get_released());
+        }
+        if let Some(Message::NvRead(item)) = self.query(&Request::nv_read(NV_ITEM_IMEI)).await? {
+            info.imei = item.get_imei();
+        }
+        if let Some(Message::NvRead(item)) = self.query(&Request::nv_read(NV_ITEM_ESN)).await? {
+            info.esn = item.get_esn();
+        }
+        if let Some(Message::Subsystem(response)) = self.query(&Request::cm_state_info()).await? {
+            info.system_mode = response
+                .get_cm_state_info()
+                .map(|state| state.get_system_mode());
+        }
+        Ok(info)
+    }
+
+    /// Enables the given log codes, and disables every other one
+    pub async fn config_logs(&mut self, log_codes: &[u32]) -> DiagResult<()> {
         info!("retrieving diag logging capabilities...");
         let log_mask_sizes = self.retrieve_id_ranges().await?;
 
         for (log_type, &log_mask_bitsize) in log_mask_sizes.iter().enumerate() {
             if log_mask_bitsize > 0 {
-                self.set_log_mask(log_type as u32, log_mask_bitsize).await?;
+                self.set_log_mask(log_type as u32, log_mask_bitsize, log_codes)
+                    .await?;
                 info!("enabled logging for log type {log_type}");
             }
         }
@@ -363,3 +695,229 @@ fn determine_use_mdm(fd: i32) -> DiagResult<i32> {
     }
     Ok(use_mdm)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diag::{LogBody, Nas4GMessageDirection, Timestamp};
+    use crate::hdlc::hdlc_decapsulate;
+    use futures::TryStreamExt;
+    use std::collections::VecDeque;
+    use std::pin::pin;
+    use std::sync::{Arc, Mutex};
+
+    const LTE_LOG_TYPE: usize = 11;
+
+    /// Pretends to be a modem that only has log codes of the LTE log type,
+    /// answering log config requests, rejecting any other, and recording
+    /// what they were
+    #[derive(Default)]
+    struct FakeTransport {
+        incoming: Arc<Mutex<VecDeque<MessagesContainer>>>,
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    fn response(subopcode: u32, payload: LogConfigResponse) -> MessagesContainer {
+        let message = Message::Response {
+            opcode: 115,
+            subopcode,
+            status: 0,
+            payload: ResponsePayload::LogConfig(payload),
+        };
+        MessagesContainer::from_messages(DataType::UserSpace, &[message]).unwrap()
+    }
+
+    #[async_trait]
+    impl DiagTransport for FakeTransport {
+        async fn read_container(&mut self) -> DiagResult<MessagesContainer> {
+            self.incoming
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(DiagDeviceError::DeviceReadFailed(
+                    ErrorKind::UnexpectedEof.into(),
+                ))
+        }
+
+        async fn write_request(&mut self, hdlc_encapsulated_request: Vec<u8>) -> DiagResult<()> {
+            let request = hdlc_decapsulate(&hdlc_encapsulated_request, &CRC_CCITT).unwrap();
+            self.requests.lock().unwrap().push(request.clone());
+            if request[0] != 115 {
+                let rejected = Message::Rejected { code: 19, request };
+                let container =
+                    MessagesContainer::from_messages(DataType::UserSpace, &[rejected]).unwrap();
+                self.incoming.lock().unwrap().push_back(container);
+                return Ok(());
+            }
+            let subopcode = u32::from_le_bytes(request[4..8].try_into().unwrap());
+            let payload = match subopcode {
+                1 => {
+                    let mut log_mask_sizes = [0; 16];
+                    log_mask_sizes[LTE_LOG_TYPE] = 0x200;
+                    LogConfigResponse::RetrieveIdRanges { log_mask_sizes }
+                }
+                3 => LogConfigResponse::SetMask,
+                _ => panic!("unexpected request {request:?}"),
+            };
+            self.incoming
+                .lock()
+                .unwrap()
+                .push_back(response(subopcode, payload));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_logs_and_stream() {
+        let transport = FakeTransport::default();
+        let incoming = transport.incoming.clone();
+        let requests = transport.requests.clone();
+        let mut dev = DiagDevice::from_transport(Box::new(transport));
+        let full = LogCodeSelection::default().log_codes();
+        dev.config_logs(&full).await.unwrap();
+
+        let retrieve_id_ranges = Request::LogConfig(LogConfigRequest::RetrieveIdRanges)
+            .to_bytes()
+            .unwrap();
+        let mask_request = |log_codes: &[u32]| {
+            build_log_mask_request(LTE_LOG_TYPE as u32, 0x200, log_codes)
+                .to_bytes()
+                .unwrap()
+        };
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![retrieve_id_ranges.clone(), mask_request(&full)]
+        );
+
+        // once configured, everything the transport reads is streamed
+        let log = Message::new_log(
+            0xb0ec,
+            Timestamp {
+                ts: 72659535985485082,
+            },
+            LogBody::Nas4GMessage {
+                direction: Nas4GMessageDirection::Downlink,
+                ext_header_version: 1,
+                rrc_rel: 0,
+                rrc_version_minor: 0,
+                rrc_version_major: 0,
+                msg: vec![0x07, 0x42],
+            },
+        )
+        .unwrap();
+        let container = MessagesContainer::from_messages(DataType::UserSpace, &[log]).unwrap();
+        incoming.lock().unwrap().push_back(container.clone());
+        let handle = dev.log_code_handle();
+        let mut stream = pin!(dev.as_stream());
+        assert_eq!(stream.try_next().await.unwrap(), Some(container.clone()));
+
+        // a new selection is applied before the next read
+        requests.lock().unwrap().clear();
+        handle.reconfigure(SIGNALING_LOG_CODES.to_vec());
+        incoming.lock().unwrap().push_back(container.clone());
+        assert_eq!(stream.try_next().await.unwrap(), Some(container));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![retrieve_id_ranges, mask_request(&SIGNALING_LOG_CODES)]
+        );
+        assert!(stream.try_next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_modem_info_rejected() {
+        let transport = FakeTransport::default();
+        let requests = transport.requests.clone();
+        let mut dev = DiagDevice::from_transport(Box::new(transport));
+        // a modem that doesn't support any of the queries still answers
+        assert_eq!(dev.modem_info().await.unwrap(), ModemInfo::default());
+        let opcodes: Vec<u8> = requests.lock().unwrap().iter().map(|r| r[0]).collect();
+        assert_eq!(opcodes, vec![124, 0, 38, 38, 75]);
+    }
+
+    #[tokio::test]
+    async fn test_logs_read_while_waiting_are_streamed() {
+        let transport = FakeTransport::default();
+        let incoming = transport.incoming.clone();
+        let mut dev = DiagDevice::from_transport(Box::new(transport));
+        let log = Message::new_log(
+            0xb0ec,
+            Timestamp {
+                ts: 72659535985485082,
+            },
+            LogBody::Nas4GMessage {
+                direction: Nas4GMessageDirection::Downlink,
+                ext_header_version: 1,
+                rrc_rel: 0,
+                rrc_version_minor: 0,
+                rrc_version_major: 0,
+                msg: vec![0x07, 0x42],
+            },
+        )
+        .unwrap();
+        let container = MessagesContainer::from_messages(DataType::UserSpace, &[log]).unwrap();
+        incoming.lock().unwrap().push_back(container.clone());
+
+        // the log arrives ahead of the first response, and is kept for the
+        // stream rather than dropped, while the responses aren't streamed
+        assert_eq!(dev.modem_info().await.unwrap(), ModemInfo::default());
+        let mut stream = pin!(dev.as_stream());
+        assert_eq!(stream.try_next().await.unwrap(), Some(container));
+        assert!(stream.try_next().await.is_err());
+    }
+
+    #[test]
+    fn test_log_code_presets() {
+        let minimal = LogCodeSelection {
+            preset: LogCodePreset::MinimalSignaling,
+            extra_log_codes: vec![0xb0c0, 0xb193],
+        };
+        let mut expected = SIGNALING_LOG_CODES.to_vec();
+        expected.push(0xb193);
+        expected.sort();
+        assert_eq!(minimal.log_codes(), expected);
+
+        let measurements = LogCodeSelection {
+            preset: LogCodePreset::SignalingAndMeasurements,
+            extra_log_codes: Vec::new(),
+        }
+        .log_codes();
+        assert!(measurements.contains(&log_codes::LOG_LTE_ML1_SERVING_CELL_MEAS_C));
+        assert!(!measurements.contains(&log_codes::LOG_DATA_PROTOCOL_LOGGING_C));
+
+        // the default is what's always been enabled
+        let mut standard = LOG_CODES_FOR_RAW_PACKET_LOGGING.to_vec();
+        standard.sort();
+        assert_eq!(LogCodeSelection::default().log_codes(), standard);
+
+        let full = LogCodeSelection {
+            preset: LogCodePreset::Full,
+            extra_log_codes: Vec::new(),
+        }
+        .log_codes();
+        assert_eq!(full.len(), 15);
+        for log_code in LOG_CODES_FOR_RAW_PACKET_LOGGING {
+            assert!(full.contains(&log_code));
+        }
+    }
+
+    #[test]
+    fn test_log_code_selection_validation() {
+        let mut selection = LogCodeSelection {
+            preset: LogCodePreset::Standard,
+            extra_log_codes: vec![0xb193],
+        };
+        assert_eq!(selection.validate(), Ok(()));
+
+        selection.extra_log_codes.push(0x1b193);
+        assert_eq!(
+            selection.validate(),
+            Err(LogCodeSelectionError::InvalidLogCode(0x1b193))
+        );
+
+        selection.extra_log_codes = vec![0xb193; MAX_EXTRA_LOG_CODES + 1];
+        assert_eq!(
+            selection.validate(),
+            Err(LogCodeSelectionError::TooManyExtraLogCodes)
+        );
+    }
+}
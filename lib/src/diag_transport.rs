@@ -0,0 +1,471 @@
+//! Ways of talking to a modem's diag interface. On the hotspots Rayhunter
+//! usually runs on, that's the `/dev/diag` character device (see
+//! [`CharDeviceTransport`]). Modems attached to another computer instead
+//! expose diag as a plain stream of HDLC frames, either on a serial/USB port
+//! (as QCSuper uses with USB modems) or over TCP through a diag bridge.
+//!
+//! For testing without a modem, [`ReplayTransport`] plays back a QMDL file as
+//! if it were being recorded live.
+
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, sleep_until};
+
+use crate::Device;
+use crate::diag::{
+    CRC_CCITT, DataType, HdlcEncapsulatedMessage, LogConfigResponse, MESSAGE_TERMINATOR, Message,
+    MessagesContainer, ResponsePayload,
+};
+use crate::diag_device::{CharDeviceTransport, DiagDeviceError, DiagResult};
+use crate::hdlc::hdlc_decapsulate;
+use crate::qmdl::QmdlReader;
+
+const READ_BUF_LEN: usize = 64 * 1024;
+
+/// Frames longer than this are assumed to be garbage, e.g. from connecting to
+/// the middle of a stream that isn't diag at all
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+fn default_baud_rate() -> u32 {
+    115200
+}
+
+/// Where to read diag messages from
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiagTransportConfig {
+    /// The `/dev/diag` character device
+    #[default]
+    CharDevice,
+    /// A serial or USB diag port, e.g. `/dev/ttyUSB0`
+    Serial {
+        path: String,
+        #[serde(default = "default_baud_rate")]
+        baud_rate: u32,
+    },
+    /// A diag-over-network bridge, e.g. `192.168.1.1:2500`
+    Tcp { address: String },
+    /// A QMDL file, played back as if it were being recorded
+    Replay {
+        path: String,
+        #[serde(default)]
+        pacing: ReplayPacing,
+    },
+}
+
+/// How fast a [`ReplayTransport`] plays back its file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayPacing {
+    /// Keep the time between messages as it was when they were recorded
+    #[default]
+    RealTime,
+    /// Play back messages as fast as they can be processed
+    AsFastAsPossible,
+}
+
+/// Moves diag messages to and from a modem
+#[async_trait]
+pub trait DiagTransport: Send {
+    /// Reads the next batch of messages from the modem
+    async fn read_container(&mut self) -> DiagResult<MessagesContainer>;
+
+    /// Sends an HDLC encapsulated request to the modem
+    async fn write_request(&mut self, hdlc_encapsulated_request: Vec<u8>) -> DiagResult<()>;
+}
+
+/// Opens the configured transport. `configured_device` is only needed for
+/// the character device, whose setup differs between devices.
+pub async fn open_transport(
+    config: &DiagTransportConfig,
+    configured_device: &Device,
+) -> DiagResult<Box<dyn DiagTransport>> {
+    match config {
+        DiagTransportConfig::CharDevice => Ok(Box::new(
+            CharDeviceTransport::open(configured_device).await?,
+        )),
+        DiagTransportConfig::Serial { path, baud_rate } => {
+            let file = open_serial_device(path, *baud_rate)?;
+            Ok(Box::new(HdlcStreamTransport::new(File::from_std(file))))
+        }
+        DiagTransportConfig::Tcp { address } => {
+            let stream = TcpStream::connect(address)
+                .await
+                .map_err(DiagDeviceError::OpenDiagDeviceError)?;
+            Ok(Box::new(HdlcStreamTransport::new(stream)))
+        }
+        DiagTransportConfig::Replay { path, pacing } => {
+            let file = File::open(path)
+                .await
+                .map_err(DiagDeviceError::OpenDiagDeviceError)?;
+            let size = file
+                .metadata()
+                .await
+                .map_err(DiagDeviceError::OpenDiagDeviceError)?
+                .len();
+            let reader = QmdlReader::new(file, Some(size as usize));
+            Ok(Box::new(ReplayTransport::new(reader, *pacing)))
+        }
+    }
+}
+
+/// Open a serial device in raw mode at the given baud rate
+fn open_serial_device(path: &str, baud_rate: u32) -> DiagResult<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let speed = match baud_rate {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        921600 => libc::B921600,
+        other => return Err(DiagDeviceError::UnsupportedBaudRate(other)),
+    };
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)
+        .map_err(DiagDeviceError::OpenDiagDeviceError)?;
+
+    let fd = file.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            let msg = format!("tcgetattr failed: {}", std::io::Error::last_os_error());
+            return Err(DiagDeviceError::InitializationFailed(msg));
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        if libc::cfsetispeed(&mut termios, speed) != 0
+            || libc::cfsetospeed(&mut termios, speed) != 0
+            || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0
+        {
+            let msg = format!("tcsetattr failed: {}", std::io::Error::last_os_error());
+            return Err(DiagDeviceError::InitializationFailed(msg));
+        }
+    }
+
+    Ok(file)
+}
+
+/// Diag as a raw stream of HDLC frames, each ending in
+/// [`MESSAGE_TERMINATOR`]. Frames are batched into [`MessagesContainer`]s so
+/// they can be recorded like the character device's.
+pub struct HdlcStreamTransport<S> {
+    stream: S,
+    read_buf: Vec<u8>,
+    // bytes of a frame that hasn't been completely read yet
+    pending: Vec<u8>,
+}
+
+impl<S> HdlcStreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        HdlcStreamTransport {
+            stream,
+            read_buf: vec![0; READ_BUF_LEN],
+            pending: Vec::new(),
+        }
+    }
+
+    // Takes every complete frame out of the pending bytes
+    fn take_frames(&mut self) -> Vec<HdlcEncapsulatedMessage> {
+        let Some(last_terminator) = self.pending.iter().rposition(|&b| b == MESSAGE_TERMINATOR)
+        else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(last_terminator + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        complete
+            .split_inclusive(|&b| b == MESSAGE_TERMINATOR)
+            // some modems also start each frame with a terminator
+            .filter(|frame| frame.len() > 1)
+            .map(|frame| HdlcEncapsulatedMessage {
+                len: frame.len() as u32,
+                data: frame.to_vec(),
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<S> DiagTransport for HdlcStreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn read_container(&mut self) -> DiagResult<MessagesContainer> {
+        loop {
+            let messages = self.take_frames();
+            if !messages.is_empty() {
+                return Ok(MessagesContainer {
+                    data_type: DataType::UserSpace,
+                    num_messages: messages.len() as u32,
+                    messages,
+                });
+            }
+            if self.pending.len() > MAX_FRAME_LEN {
+                warn!(
+                    "discarding {} bytes without a frame terminator",
+                    self.pending.len()
+                );
+                self.pending.clear();
+            }
+            let bytes_read = self
+                .stream
+                .read(&mut self.read_buf)
+                .await
+                .map_err(DiagDeviceError::DeviceReadFailed)?;
+            if bytes_read == 0 {
+                return Err(DiagDeviceError::DeviceReadFailed(
+                    ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+            self.pending.extend_from_slice(&self.read_buf[..bytes_read]);
+        }
+    }
+
+    async fn write_request(&mut self, hdlc_encapsulated_request: Vec<u8>) -> DiagResult<()> {
+        self.stream
+            .write_all(&hdlc_encapsulated_request)
+            .await
+            .map_err(DiagDeviceError::DeviceWriteFailed)?;
+        self.stream
+            .flush()
+            .await
+            .map_err(DiagDeviceError::DeviceWriteFailed)
+    }
+}
+
+/// Plays back a QMDL file. Log config requests are answered as though the
+/// modem had no log codes to configure, since the file already holds
+/// whichever logs were enabled when it was recorded. Once the whole file has
+/// been read, [`DiagDeviceError::EndOfStream`] is returned, which ends
+/// [`DiagDevice::as_stream`](crate::diag_device::DiagDevice::as_stream).
+pub struct ReplayTransport<T> {
+    reader: QmdlReader<T>,
+    pacing: ReplayPacing,
+    // responses to requests, returned before the file's messages
+    responses: VecDeque<MessagesContainer>,
+    // when the first message was played back, and when it was recorded
+    start: Option<(Instant, DateTime<FixedOffset>)>,
+}
+
+impl<T> ReplayTransport<T>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    pub fn new(reader: QmdlReader<T>, pacing: ReplayPacing) -> Self {
+        ReplayTransport {
+            reader,
+            pacing,
+            responses: VecDeque::new(),
+            start: None,
+        }
+    }
+
+    // Waits until the container's first log message is due
+    async fn pace(&mut self, container: &MessagesContainer) {
+        if self.pacing == ReplayPacing::AsFastAsPossible {
+            return;
+        }
+        let recorded_at = container
+            .clone()
+            .into_messages()
+            .into_iter()
+            .find_map(|message| match message {
+                Ok(Message::Log { timestamp, .. }) => Some(timestamp.to_datetime()),
+                _ => None,
+            });
+        let Some(recorded_at) = recorded_at else {
+            return;
+        };
+        let Some((started, first_recorded_at)) = self.start else {
+            self.start = Some((Instant::now(), recorded_at));
+            return;
+        };
+        // messages that were recorded out of order are played back right away
+        if let Ok(offset) = (recorded_at - first_recorded_at).to_std() {
+            sleep_until(started + offset).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<T> DiagTransport for ReplayTransport<T>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    async fn read_container(&mut self) -> DiagResult<MessagesContainer> {
+        if let Some(response) = self.responses.pop_front() {
+            return Ok(response);
+        }
+        let container = self
+            .reader
+            .get_next_messages_container()
+            .await
+            .map_err(DiagDeviceError::DeviceReadFailed)?;
+        match container {
+            Some(container) => {
+                self.pace(&container).await;
+                Ok(container)
+            }
+            None => {
+                info!("finished replaying QMDL file");
+                Err(DiagDeviceError::EndOfStream)
+            }
+        }
+    }
+
+    async fn write_request(&mut self, hdlc_encapsulated_request: Vec<u8>) -> DiagResult<()> {
+        let request = hdlc_decapsulate(&hdlc_encapsulated_request, &CRC_CCITT)
+            .map_err(|e| DiagDeviceError::InitializationFailed(format!("invalid request: {e}")))?;
+        // there's no modem to ask about anything but logging, so everything
+        // else is rejected, like a modem rejects commands it doesn't support
+        if request.first() != Some(&115) {
+            let rejected = Message::Rejected { code: 19, request };
+            let container = MessagesContainer::from_messages(DataType::UserSpace, &[rejected])
+                .map_err(DiagDeviceError::ParseMessagesContainerError)?;
+            self.responses.push_back(container);
+            return Ok(());
+        }
+        if request.len() < 8 {
+            return Ok(());
+        }
+        let opcode = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let subopcode = u32::from_le_bytes(request[4..8].try_into().unwrap());
+        let payload = match subopcode {
+            1 => LogConfigResponse::RetrieveIdRanges {
+                log_mask_sizes: [0; 16],
+            },
+            3 => LogConfigResponse::SetMask,
+            _ => return Ok(()),
+        };
+        let response = Message::Response {
+            opcode,
+            subopcode,
+            status: 0,
+            payload: ResponsePayload::LogConfig(payload),
+        };
+        let container = MessagesContainer::from_messages(DataType::UserSpace, &[response])
+            .map_err(DiagDeviceError::ParseMessagesContainerError)?;
+        self.responses.push_back(container);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diag::{LogBody, Nas4GMessageDirection, Timestamp};
+    use crate::diag_device::{DiagDevice, ModemInfo, SIGNALING_LOG_CODES};
+    use crate::qmdl::QmdlWriter;
+    use futures::TryStreamExt;
+    use std::io::Cursor;
+    use std::pin::pin;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_hdlc_stream_transport() {
+        let (client, mut modem) = tokio::io::duplex(1024);
+        let mut transport = HdlcStreamTransport::new(client);
+
+        // frames can be split across reads, and a read can end mid-frame
+        modem.write_all(b"\x7e\x01\x02").await.unwrap();
+        modem.write_all(b"\x7e\x03\x7e\x04").await.unwrap();
+        let container = transport.read_container().await.unwrap();
+        assert_eq!(container.data_type, DataType::UserSpace);
+        assert_eq!(container.num_messages, 2);
+        assert_eq!(container.messages[0].data, b"\x01\x02\x7e");
+        assert_eq!(container.messages[1].data, b"\x03\x7e");
+
+        modem.write_all(b"\x05\x7e").await.unwrap();
+        let container = transport.read_container().await.unwrap();
+        assert_eq!(container.messages.len(), 1);
+        assert_eq!(container.messages[0].data, b"\x04\x05\x7e");
+        assert_eq!(container.messages[0].len, 3);
+
+        transport.write_request(b"\x73\x7e".to_vec()).await.unwrap();
+        let mut request = [0; 2];
+        modem.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"\x73\x7e");
+
+        drop(modem);
+        assert!(matches!(
+            transport.read_container().await,
+            Err(DiagDeviceError::DeviceReadFailed(_))
+        ));
+    }
+
+    // Writes a QMDL file with a log message at each of the given times
+    async fn qmdl_with_logs(times: &[DateTime<FixedOffset>]) -> Vec<u8> {
+        let mut writer = QmdlWriter::new(Vec::new());
+        for time in times {
+            let message = Message::new_log(
+                0xb0ec,
+                Timestamp::from_datetime(time),
+                LogBody::Nas4GMessage {
+                    direction: Nas4GMessageDirection::Downlink,
+                    ext_header_version: 1,
+                    rrc_rel: 0,
+                    rrc_version_minor: 0,
+                    rrc_version_major: 0,
+                    msg: vec![0x07, 0x42],
+                },
+            )
+            .unwrap();
+            let container =
+                MessagesContainer::from_messages(DataType::UserSpace, &[message]).unwrap();
+            writer.write_container(&container).await.unwrap();
+        }
+        writer.get_ref().clone()
+    }
+
+    async fn replay(pacing: ReplayPacing) -> Duration {
+        let first = DateTime::parse_from_rfc3339("2024-03-01T12:00:00.000Z").unwrap();
+        let qmdl = qmdl_with_logs(&[first, first + chrono::Duration::milliseconds(200)]).await;
+        let reader = QmdlReader::new(Cursor::new(qmdl), None);
+        let mut dev = DiagDevice::from_transport(Box::new(ReplayTransport::new(reader, pacing)));
+        // the replay pretends to configure logging like a modem would
+        dev.config_logs(&SIGNALING_LOG_CODES).await.unwrap();
+        // and has nothing to say about the modem, without skipping any logs
+        assert_eq!(dev.modem_info().await.unwrap(), ModemInfo::default());
+
+        let started = std::time::Instant::now();
+        let mut stream = pin!(dev.as_stream());
+        for _ in 0..2 {
+            let container = stream.try_next().await.unwrap().unwrap();
+            assert!(matches!(
+                container.into_messages()[0],
+                Ok(Message::Log {
+                    log_type: 0xb0ec,
+                    ..
+                })
+            ));
+        }
+        // the stream ends with the file
+        assert!(stream.try_next().await.unwrap().is_none());
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_replay_transport() {
+        assert!(replay(ReplayPacing::AsFastAsPossible).await < Duration::from_millis(200));
+        assert!(replay(ReplayPacing::RealTime).await >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,399 @@
+//! Encryption of recordings at rest.
+//!
+//! Files are encrypted to an X25519 public key, so a device that only knows
+//! the public key can write recordings but can't read them back. An encrypted
+//! file is made up of segments. Each segment starts with
+//! [`ENCRYPTION_MAGIC`] and a fresh ephemeral public key, from which the
+//! segment's ChaCha20-Poly1305 key is derived using HKDF-SHA256. It's followed
+//! by records, each a little-endian u32 length and that many bytes of
+//! ciphertext, using a counter as the nonce. Starting a new segment whenever a
+//! file is reopened means files can be appended to without knowing the key
+//! used for what's already there.
+//!
+//! Every record is authenticated, but records can be dropped from the end of a
+//! segment without detection, same as a recording that's cut short.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+/// Start of every segment of an encrypted file
+pub const ENCRYPTION_MAGIC: &[u8; 8] = b"RHENC\x00\x00\x01";
+const KEY_LEN: usize = 32;
+const HKDF_INFO: &[u8] = b"rayhunter recording v1";
+/// Records are at most this long. This also keeps the start of a record from
+/// ever looking like [`ENCRYPTION_MAGIC`].
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+    #[error("Invalid encrypted file: {0}")]
+    InvalidFormat(String),
+    #[error("Decryption failed, the file is corrupt or was encrypted to a different key")]
+    DecryptionFailed,
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; KEY_LEN], EncryptionError> {
+    let bytes = BASE64
+        .decode(encoded.trim())
+        .map_err(|e| EncryptionError::InvalidKey(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| EncryptionError::InvalidKey(format!("expected {KEY_LEN} bytes")))
+}
+
+/// The public half of a recording key, which devices encrypt to
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RecordingPublicKey(PublicKey);
+
+impl RecordingPublicKey {
+    pub fn from_base64(encoded: &str) -> Result<Self, EncryptionError> {
+        Ok(Self(PublicKey::from(decode_key(encoded)?)))
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.0.as_bytes())
+    }
+}
+
+impl std::fmt::Debug for RecordingPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RecordingPublicKey({})", self.to_base64())
+    }
+}
+
+/// The secret half of a recording key, which never goes on the device
+pub struct RecordingSecretKey(StaticSecret);
+
+impl RecordingSecretKey {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self, EncryptionError> {
+        Ok(Self(StaticSecret::from(decode_key(encoded)?)))
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.0.as_bytes())
+    }
+
+    pub fn public_key(&self) -> RecordingPublicKey {
+        RecordingPublicKey(PublicKey::from(&self.0))
+    }
+}
+
+fn derive_cipher(
+    shared_secret: &[u8; KEY_LEN],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> ChaCha20Poly1305 {
+    let mut salt = [0; KEY_LEN * 2];
+    salt[..KEY_LEN].copy_from_slice(ephemeral.as_bytes());
+    salt[KEY_LEN..].copy_from_slice(recipient.as_bytes());
+    let mut key = [0; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(HKDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+struct SegmentCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+/// Writer which encrypts everything written through it to a public key, or
+/// passes it through unchanged if there's no key.
+///
+/// Each write becomes one record. Writes are always accepted in full and
+/// written out on later writes or flushes, so flush before relying on what's
+/// in the underlying writer.
+pub struct EncryptingWriter<W> {
+    inner: W,
+    cipher: Option<SegmentCipher>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    /// Whether a record has been written, and so the header can be too
+    started: bool,
+}
+
+impl<W> EncryptingWriter<W> {
+    pub fn new(inner: W, recipient: Option<&RecordingPublicKey>) -> Self {
+        let mut pending = Vec::new();
+        let cipher = recipient.map(|recipient| {
+            let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral = PublicKey::from(&ephemeral_secret);
+            let shared_secret = ephemeral_secret.diffie_hellman(&recipient.0);
+            pending.extend_from_slice(ENCRYPTION_MAGIC);
+            pending.extend_from_slice(ephemeral.as_bytes());
+            SegmentCipher {
+                cipher: derive_cipher(shared_secret.as_bytes(), &ephemeral, &recipient.0),
+                counter: 0,
+            }
+        });
+        Self {
+            inner,
+            cipher,
+            pending,
+            pending_pos: 0,
+            started: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> EncryptingWriter<W> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let written = match Pin::new(&mut self.inner)
+                .poll_write(cx, &self.pending[self.pending_pos..])
+            {
+                Poll::Ready(Ok(written)) => written,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += written;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // the header is held back until the first record, so nothing is
+        // written for a file that's opened and never written to
+        if this.started && !this.pending.is_empty() {
+            match this.poll_write_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let buf = &buf[..buf.len().min(MAX_RECORD_LEN - 16)];
+        let segment = this.cipher.as_mut().unwrap();
+        let ciphertext = segment
+            .cipher
+            .encrypt(&nonce(segment.counter), buf)
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+        segment.counter += 1;
+        this.pending
+            .extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        this.pending.extend_from_slice(&ciphertext);
+        this.started = true;
+        // the record's in our buffer now. If the inner writer isn't ready, the
+        // next write or flush will try again.
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.started {
+            match this.poll_write_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Whether `data`, the start of a file, is encrypted
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTION_MAGIC)
+}
+
+// Reads exactly `buf.len()` bytes, returning false if the reader was already
+// at EOF
+async fn read_exact_or_eof<R>(reader: &mut R, buf: &mut [u8]) -> Result<bool, EncryptionError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut read = 0;
+    while read < buf.len() {
+        let n = reader.read(&mut buf[read..]).await?;
+        if n == 0 {
+            if read == 0 {
+                return Ok(false);
+            }
+            return Err(EncryptionError::InvalidFormat(
+                "file ends in the middle of a record".to_string(),
+            ));
+        }
+        read += n;
+    }
+    Ok(true)
+}
+
+/// Decrypts an encrypted file from `reader` to `writer`, returning the number
+/// of plaintext bytes written
+pub async fn decrypt<R, W>(
+    mut reader: R,
+    mut writer: W,
+    key: &RecordingSecretKey,
+) -> Result<u64, EncryptionError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let recipient = PublicKey::from(&key.0);
+    let mut segment: Option<SegmentCipher> = None;
+    let mut total = 0;
+    let mut len_bytes = [0; 4];
+    loop {
+        if !read_exact_or_eof(&mut reader, &mut len_bytes).await? {
+            break;
+        }
+        if len_bytes == ENCRYPTION_MAGIC[..4] {
+            let mut header = [0; ENCRYPTION_MAGIC.len() - 4 + KEY_LEN];
+            if !read_exact_or_eof(&mut reader, &mut header).await?
+                || header[..4] != ENCRYPTION_MAGIC[4..]
+            {
+                return Err(EncryptionError::InvalidFormat(
+                    "invalid segment header".to_string(),
+                ));
+            }
+            let ephemeral_bytes: [u8; KEY_LEN] = header[4..].try_into().unwrap();
+            let ephemeral = PublicKey::from(ephemeral_bytes);
+            let shared_secret = key.0.diffie_hellman(&ephemeral);
+            segment = Some(SegmentCipher {
+                cipher: derive_cipher(shared_secret.as_bytes(), &ephemeral, &recipient),
+                counter: 0,
+            });
+            continue;
+        }
+
+        let Some(segment) = segment.as_mut() else {
+            return Err(EncryptionError::InvalidFormat(
+                "file isn't encrypted".to_string(),
+            ));
+        };
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(EncryptionError::InvalidFormat(format!(
+                "record of {len} bytes is too long"
+            )));
+        }
+        let mut ciphertext = vec![0; len];
+        if !read_exact_or_eof(&mut reader, &mut ciphertext).await? {
+            return Err(EncryptionError::InvalidFormat(
+                "file ends in the middle of a record".to_string(),
+            ));
+        }
+        let plaintext = segment
+            .cipher
+            .decrypt(&nonce(segment.counter), ciphertext.as_slice())
+            .map_err(|_| EncryptionError::DecryptionFailed)?;
+        segment.counter += 1;
+        writer.write_all(&plaintext).await?;
+        total += plaintext.len() as u64;
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encrypt_and_decrypt() {
+        let key = RecordingSecretKey::generate();
+        let public_key = RecordingPublicKey::from_base64(&key.public_key().to_base64()).unwrap();
+
+        let mut file = Vec::new();
+        let mut writer = EncryptingWriter::new(&mut file, Some(&public_key));
+        writer.write_all(b"first record\n").await.unwrap();
+        writer.write_all(b"second record\n").await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+        // appending starts a new segment with its own ephemeral key
+        let mut writer = EncryptingWriter::new(&mut file, Some(&public_key));
+        writer.write_all(b"appended\n").await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+
+        assert!(is_encrypted(&file));
+        assert!(!file.windows(6).any(|window| window == b"record"));
+
+        let mut plaintext = Vec::new();
+        let len = decrypt(file.as_slice(), &mut plaintext, &key).await.unwrap();
+        assert_eq!(plaintext, b"first record\nsecond record\nappended\n");
+        assert_eq!(len, plaintext.len() as u64);
+
+        let wrong_key = RecordingSecretKey::generate();
+        assert!(matches!(
+            decrypt(file.as_slice(), &mut Vec::new(), &wrong_key).await,
+            Err(EncryptionError::DecryptionFailed)
+        ));
+
+        let mut tampered = file.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(
+            decrypt(tampered.as_slice(), &mut Vec::new(), &key).await,
+            Err(EncryptionError::DecryptionFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_without_key() {
+        let mut file = Vec::new();
+        let mut writer = EncryptingWriter::new(&mut file, None);
+        writer.write_all(b"plain").await.unwrap();
+        writer.flush().await.unwrap();
+        assert!(!writer.is_encrypted());
+        drop(writer);
+        assert_eq!(file, b"plain");
+    }
+}
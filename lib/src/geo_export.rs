@@ -0,0 +1,403 @@
+//! Map exports (GeoJSON and KML) combining a recording's GPS track with its
+//! warnings and the cells it observed.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, FixedOffset};
+use deku::bitvec::*;
+use serde_json::{Value, json};
+use telcom_parser::lte_rrc::{BCCH_DL_SCH_MessageType, BCCH_DL_SCH_MessageType_c1};
+
+use crate::analysis::analyzer::{AnalysisRow, AnalyzerMetadata, EventType};
+use crate::analysis::information_element::{InformationElement, LteInformationElement};
+use crate::analysis::util::format_plmn;
+use crate::diag::MessagesContainer;
+use crate::gps::{EventLocation, GpsTrack, LocationOptions};
+use crate::gsmtap_parser;
+
+/// A located warning.
+#[derive(Debug, Clone)]
+pub struct GeoEvent {
+    pub timestamp: DateTime<FixedOffset>,
+    pub location: EventLocation,
+    pub severity: EventType,
+    pub analyzer: String,
+    pub message: String,
+}
+
+/// The first sighting of an LTE cell, taken from its SIB1 broadcast.
+#[derive(Debug, Clone)]
+pub struct GeoCell {
+    pub timestamp: DateTime<FixedOffset>,
+    pub location: EventLocation,
+    /// MCC-MNC, e.g. `310-260`
+    pub plmn: String,
+    pub tracking_area_code: u32,
+    pub cell_identity: u32,
+}
+
+/// Accumulates everything that goes on the map for one recording.
+pub struct GeoExport {
+    name: String,
+    track: GpsTrack,
+    options: LocationOptions,
+    events: Vec<GeoEvent>,
+    cells: Vec<GeoCell>,
+    seen_cells: HashSet<(String, u32)>,
+}
+
+impl GeoExport {
+    pub fn new(name: &str, track: GpsTrack, options: LocationOptions) -> Self {
+        Self {
+            name: name.to_string(),
+            track,
+            options,
+            events: Vec::new(),
+            cells: Vec::new(),
+            seen_cells: HashSet::new(),
+        }
+    }
+
+    pub fn events(&self) -> &[GeoEvent] {
+        &self.events
+    }
+
+    pub fn cells(&self) -> &[GeoCell] {
+        &self.cells
+    }
+
+    /// Add the warnings of an analysis row. Rows are located against the
+    /// full track, falling back to the location recorded in the row.
+    /// Informational events and rows without a location are left out.
+    pub fn add_analysis_row(&mut self, row: &AnalysisRow, analyzers: &[AnalyzerMetadata]) {
+        let Some(timestamp) = row.packet_timestamp else {
+            return;
+        };
+        let Some(location) = self
+            .track
+            .location_at(&timestamp, &self.options)
+            .or_else(|| row.location.clone())
+        else {
+            return;
+        };
+        for (index, event) in row.events.iter().enumerate() {
+            let Some(event) = event else { continue };
+            if event.event_type == EventType::Informational {
+                continue;
+            }
+            self.events.push(GeoEvent {
+                timestamp,
+                location: location.clone(),
+                severity: event.event_type,
+                analyzer: analyzers
+                    .get(index)
+                    .map(|analyzer| analyzer.name.clone())
+                    .unwrap_or_default(),
+                message: event.message.clone(),
+            });
+        }
+    }
+
+    /// Record the cells announced in a QMDL container.
+    pub fn add_qmdl_messages(&mut self, container: MessagesContainer) {
+        for message in container.into_messages().into_iter().flatten() {
+            let Ok(Some((timestamp, gsmtap_message))) = gsmtap_parser::parse(message) else {
+                continue;
+            };
+            if let Ok(element) = InformationElement::try_from(&gsmtap_message) {
+                self.add_information_element(timestamp.to_datetime(), &element);
+            }
+        }
+    }
+
+    /// Record the cell announced by a SIB1, the first time it's seen at a
+    /// known location.
+    pub fn add_information_element(
+        &mut self,
+        timestamp: DateTime<FixedOffset>,
+        ie: &InformationElement,
+    ) {
+        let InformationElement::LTE(lte_ie) = ie else {
+            return;
+        };
+        let LteInformationElement::BcchDlSch(sch_msg) = &**lte_ie else {
+            return;
+        };
+        let BCCH_DL_SCH_MessageType::C1(BCCH_DL_SCH_MessageType_c1::SystemInformationBlockType1(
+            sib1,
+        )) = &sch_msg.message
+        else {
+            return;
+        };
+        let access_info = &sib1.cell_access_related_info;
+        let Some(plmn) = access_info.plmn_identity_list.0.first() else {
+            return;
+        };
+        let plmn = format_plmn(&plmn.plmn_identity);
+        let cell_identity = access_info.cell_identity.0.as_bitslice().load_be::<u32>();
+        if self.seen_cells.contains(&(plmn.clone(), cell_identity)) {
+            return;
+        }
+        let Some(location) = self.track.location_at(&timestamp, &self.options) else {
+            return;
+        };
+        self.seen_cells.insert((plmn.clone(), cell_identity));
+        self.cells.push(GeoCell {
+            timestamp,
+            location,
+            plmn,
+            tracking_area_code: access_info
+                .tracking_area_code
+                .0
+                .as_bitslice()
+                .load_be::<u32>(),
+            cell_identity,
+        });
+    }
+
+    /// Render as a GeoJSON FeatureCollection: the track as a LineString, and
+    /// one Point per warning and per observed cell. Every feature has a
+    /// `kind` property of `track`, `event` or `cell`.
+    pub fn to_geojson(&self) -> Value {
+        let mut features = Vec::new();
+        if let (Some(first), Some(last)) = (self.track.fixes().first(), self.track.fixes().last())
+        {
+            let coordinates: Vec<Value> = self
+                .track
+                .fixes()
+                .iter()
+                .map(|fix| json!([fix.longitude, fix.latitude]))
+                .collect();
+            features.push(json!({
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": coordinates },
+                "properties": {
+                    "kind": "track",
+                    "recording": self.name,
+                    "start": first.timestamp.to_rfc3339(),
+                    "end": last.timestamp.to_rfc3339(),
+                },
+            }));
+        }
+        for event in &self.events {
+            features.push(json!({
+                "type": "Feature",
+                "geometry": point(&event.location),
+                "properties": {
+                    "kind": "event",
+                    "timestamp": event.timestamp.to_rfc3339(),
+                    "severity": event.severity,
+                    "analyzer": event.analyzer,
+                    "message": event.message,
+                    "interpolated": event.location.interpolated,
+                },
+            }));
+        }
+        for cell in &self.cells {
+            features.push(json!({
+                "type": "Feature",
+                "geometry": point(&cell.location),
+                "properties": {
+                    "kind": "cell",
+                    "timestamp": cell.timestamp.to_rfc3339(),
+                    "plmn": cell.plmn,
+                    "tac": cell.tracking_area_code,
+                    "cell_identity": cell.cell_identity,
+                },
+            }));
+        }
+        json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })
+    }
+
+    /// Render as a KML document with the same content as [`Self::to_geojson`].
+    pub fn to_kml(&self) -> String {
+        let mut kml = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n<Document>\n",
+        );
+        kml += &format!("<name>{}</name>\n", xml_escape(&self.name));
+        for (id, color) in [
+            ("track", "ffff7800"),
+            ("Low", "ff00ffff"),
+            ("Medium", "ff0080ff"),
+            ("High", "ff0000ff"),
+            ("cell", "ffaaaaaa"),
+        ] {
+            kml += &format!(
+                "<Style id=\"{id}\"><LineStyle><color>{color}</color><width>3</width></LineStyle>\
+                 <IconStyle><color>{color}</color></IconStyle></Style>\n"
+            );
+        }
+
+        if !self.track.is_empty() {
+            let coordinates: Vec<String> = self
+                .track
+                .fixes()
+                .iter()
+                .map(|fix| format!("{},{}", fix.longitude, fix.latitude))
+                .collect();
+            kml += &format!(
+                "<Placemark><name>Track</name><styleUrl>#track</styleUrl>\
+                 <LineString><tessellate>1</tessellate><coordinates>{}</coordinates></LineString></Placemark>\n",
+                coordinates.join(" ")
+            );
+        }
+        for event in &self.events {
+            kml += &format!(
+                "<Placemark><name>{:?}: {}</name><description>{}</description>\
+                 <TimeStamp><when>{}</when></TimeStamp><styleUrl>#{:?}</styleUrl>\
+                 <Point><coordinates>{},{}</coordinates></Point></Placemark>\n",
+                event.severity,
+                xml_escape(&event.analyzer),
+                xml_escape(&event.message),
+                event.timestamp.to_rfc3339(),
+                event.severity,
+                event.location.longitude,
+                event.location.latitude,
+            );
+        }
+        for cell in &self.cells {
+            kml += &format!(
+                "<Placemark><name>Cell {} {}</name><description>TAC {}</description>\
+                 <TimeStamp><when>{}</when></TimeStamp><styleUrl>#cell</styleUrl>\
+                 <Point><coordinates>{},{}</coordinates></Point></Placemark>\n",
+                cell.plmn,
+                cell.cell_identity,
+                cell.tracking_area_code,
+                cell.timestamp.to_rfc3339(),
+                cell.location.longitude,
+                cell.location.latitude,
+            );
+        }
+        kml += "</Document>\n</kml>\n";
+        kml
+    }
+}
+
+fn point(location: &EventLocation) -> Value {
+    json!({ "type": "Point", "coordinates": [location.longitude, location.latitude] })
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::analyzer::Event;
+    use crate::gps::GpsFix;
+
+    fn export() -> GeoExport {
+        let fixes = (0..3)
+            .map(|i| GpsFix {
+                timestamp: DateTime::from_timestamp(1_700_000_000 + i * 10, 0).unwrap(),
+                latitude: 52.0 + i as f64 * 0.001,
+                longitude: 13.0,
+                accuracy: None,
+                altitude: None,
+            })
+            .collect();
+        GeoExport::new(
+            "1700000000",
+            GpsTrack::from_fixes(fixes),
+            LocationOptions::default(),
+        )
+    }
+
+    fn row(timestamp: i64, events: Vec<Option<Event>>) -> AnalysisRow {
+        AnalysisRow {
+            packet_timestamp: Some(DateTime::from_timestamp(timestamp, 0).unwrap().into()),
+            skipped_message_reason: None,
+            events,
+            location: None,
+        }
+    }
+
+    fn analyzers() -> Vec<AnalyzerMetadata> {
+        vec![
+            AnalyzerMetadata {
+                name: "First".to_string(),
+                description: String::new(),
+                version: 1,
+            },
+            AnalyzerMetadata {
+                name: "Second <2G>".to_string(),
+                description: String::new(),
+                version: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_geojson_export() {
+        let mut export = export();
+        export.add_analysis_row(
+            &row(
+                1_700_000_005,
+                vec![
+                    Some(Event {
+                        event_type: EventType::Informational,
+                        message: "info".to_string(),
+                    }),
+                    Some(Event {
+                        event_type: EventType::High,
+                        message: "downgrade".to_string(),
+                    }),
+                ],
+            ),
+            &analyzers(),
+        );
+        // no fix within the max gap, so this warning can't be placed
+        export.add_analysis_row(
+            &row(
+                1_800_000_000,
+                vec![Some(Event {
+                    event_type: EventType::Low,
+                    message: "lost".to_string(),
+                })],
+            ),
+            &analyzers(),
+        );
+        assert_eq!(export.events().len(), 1);
+
+        let geojson = export.to_geojson();
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["geometry"]["type"], "LineString");
+        assert_eq!(features[0]["geometry"]["coordinates"][0], json!([13.0, 52.0]));
+        assert_eq!(features[1]["properties"]["kind"], "event");
+        assert_eq!(features[1]["properties"]["severity"], "High");
+        assert_eq!(features[1]["properties"]["analyzer"], "Second <2G>");
+        assert_eq!(features[1]["properties"]["interpolated"], true);
+    }
+
+    #[test]
+    fn test_kml_export_escapes_text() {
+        let mut export = export();
+        export.add_analysis_row(
+            &row(
+                1_700_000_010,
+                vec![
+                    None,
+                    Some(Event {
+                        event_type: EventType::Medium,
+                        message: "a & b".to_string(),
+                    }),
+                ],
+            ),
+            &analyzers(),
+        );
+        let kml = export.to_kml();
+        assert!(kml.contains("<LineString>"));
+        assert!(kml.contains("<name>Medium: Second &lt;2G&gt;</name>"));
+        assert!(kml.contains("<description>a &amp; b</description>"));
+        assert!(kml.contains("<coordinates>13,52.001</coordinates>"));
+    }
+}
@@ -0,0 +1,314 @@
+//! GPS tracks, used to attach a location to analysis results.
+
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A single position reported by a GPS receiver or phone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GpsFix {
+    pub timestamp: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Horizontal accuracy in meters, if known
+    pub accuracy: Option<f64>,
+    /// Altitude in meters, if known
+    pub altitude: Option<f64>,
+}
+
+/// Controls how a location is looked up for a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LocationOptions {
+    /// Fixes further than this from the requested time are ignored
+    pub max_gap_secs: u64,
+    /// Whether to linearly interpolate between the fixes on either side of
+    /// the requested time, rather than using the nearest one
+    pub interpolate: bool,
+}
+
+impl Default for LocationOptions {
+    fn default() -> Self {
+        Self {
+            max_gap_secs: 60,
+            interpolate: true,
+        }
+    }
+}
+
+/// Where a packet was observed, as attached to an analysis row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    /// Whether this position was interpolated between two fixes
+    pub interpolated: bool,
+    /// Seconds between the event and the closest fix used. Negative if that
+    /// fix was taken before the event.
+    pub fix_offset_secs: i64,
+}
+
+impl EventLocation {
+    /// The great-circle distance to another location, in meters
+    pub fn distance_meters(&self, other: &EventLocation) -> f64 {
+        const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let delta_lat = lat2 - lat1;
+        let delta_lon = (other.longitude - self.longitude).to_radians();
+        let a = (delta_lat / 2.0).sin().powi(2)
+            + lat1.cos() * lat2.cos() * (delta_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+    }
+}
+
+/// A time-ordered series of GPS fixes.
+#[derive(Debug, Clone, Default)]
+pub struct GpsTrack {
+    fixes: Vec<GpsFix>,
+}
+
+/// A [`GpsTrack`] that can be appended to while a [`crate::analysis::analyzer::Harness`]
+/// is reading from it.
+pub type SharedGpsTrack = Arc<RwLock<GpsTrack>>;
+
+impl GpsTrack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_fixes(mut fixes: Vec<GpsFix>) -> Self {
+        fixes.sort_by_key(|fix| fix.timestamp);
+        Self { fixes }
+    }
+
+    pub fn fixes(&self) -> &[GpsFix] {
+        &self.fixes
+    }
+
+    pub fn len(&self) -> usize {
+        self.fixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fixes.is_empty()
+    }
+
+    /// Add a fix, keeping the track sorted. Fixes usually arrive in order, so
+    /// this is normally an append.
+    pub fn push(&mut self, fix: GpsFix) {
+        let index = self
+            .fixes
+            .partition_point(|existing| existing.timestamp <= fix.timestamp);
+        self.fixes.insert(index, fix);
+    }
+
+    /// Drop all fixes taken before `timestamp`.
+    pub fn prune_before(&mut self, timestamp: DateTime<Utc>) {
+        let index = self
+            .fixes
+            .partition_point(|existing| existing.timestamp < timestamp);
+        self.fixes.drain(..index);
+    }
+
+    /// Look up the position at `timestamp`. Returns `None` if there's no fix
+    /// within `options.max_gap_secs`.
+    pub fn location_at<Tz: TimeZone>(
+        &self,
+        timestamp: &DateTime<Tz>,
+        options: &LocationOptions,
+    ) -> Option<EventLocation> {
+        let timestamp = timestamp.with_timezone(&Utc);
+        let max_gap = chrono::Duration::seconds(options.max_gap_secs as i64);
+        let index = self
+            .fixes
+            .partition_point(|fix| fix.timestamp <= timestamp);
+        let before = index
+            .checked_sub(1)
+            .map(|i| &self.fixes[i])
+            .filter(|fix| timestamp - fix.timestamp <= max_gap);
+        let after = self
+            .fixes
+            .get(index)
+            .filter(|fix| fix.timestamp - timestamp <= max_gap);
+
+        match (before, after) {
+            (Some(before), Some(after)) if options.interpolate => {
+                Some(interpolate(before, after, timestamp))
+            }
+            (Some(before), Some(after)) => {
+                if timestamp - before.timestamp <= after.timestamp - timestamp {
+                    Some(nearest(before, timestamp))
+                } else {
+                    Some(nearest(after, timestamp))
+                }
+            }
+            (Some(fix), None) | (None, Some(fix)) => Some(nearest(fix, timestamp)),
+            (None, None) => None,
+        }
+    }
+}
+
+fn nearest(fix: &GpsFix, timestamp: DateTime<Utc>) -> EventLocation {
+    EventLocation {
+        latitude: fix.latitude,
+        longitude: fix.longitude,
+        accuracy: fix.accuracy,
+        interpolated: false,
+        fix_offset_secs: (fix.timestamp - timestamp).num_seconds(),
+    }
+}
+
+fn interpolate(before: &GpsFix, after: &GpsFix, timestamp: DateTime<Utc>) -> EventLocation {
+    let span = (after.timestamp - before.timestamp).num_milliseconds();
+    let elapsed = (timestamp - before.timestamp).num_milliseconds();
+    if span == 0 || elapsed == 0 {
+        return nearest(before, timestamp);
+    }
+    let fraction = elapsed as f64 / span as f64;
+
+    // go the short way around if the fixes straddle the antimeridian
+    let mut longitude_delta = after.longitude - before.longitude;
+    if longitude_delta > 180.0 {
+        longitude_delta -= 360.0;
+    } else if longitude_delta < -180.0 {
+        longitude_delta += 360.0;
+    }
+    let mut longitude = before.longitude + longitude_delta * fraction;
+    if longitude > 180.0 {
+        longitude -= 360.0;
+    } else if longitude < -180.0 {
+        longitude += 360.0;
+    }
+
+    let to_before = timestamp - before.timestamp;
+    let to_after = after.timestamp - timestamp;
+    let fix_offset_secs = if to_before <= to_after {
+        -to_before.num_seconds()
+    } else {
+        to_after.num_seconds()
+    };
+
+    EventLocation {
+        latitude: before.latitude + (after.latitude - before.latitude) * fraction,
+        longitude,
+        accuracy: match (before.accuracy, after.accuracy) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        },
+        interpolated: true,
+        fix_offset_secs,
+    }
+}
+
+/// Looks up event locations in a (possibly still growing) GPS track.
+#[derive(Debug, Clone)]
+pub struct TrackLocator {
+    track: SharedGpsTrack,
+    options: LocationOptions,
+}
+
+impl TrackLocator {
+    pub fn new(track: SharedGpsTrack, options: LocationOptions) -> Self {
+        Self { track, options }
+    }
+
+    pub fn locate<Tz: TimeZone>(&self, timestamp: &DateTime<Tz>) -> Option<EventLocation> {
+        let track = self.track.read().ok()?;
+        track.location_at(timestamp, &self.options)
+    }
+
+    /// The most recent fix, however old it is. Its age relative to
+    /// `timestamp` is given by `fix_offset_secs`.
+    pub fn latest<Tz: TimeZone>(&self, timestamp: &DateTime<Tz>) -> Option<EventLocation> {
+        let track = self.track.read().ok()?;
+        let fix = track.fixes().last()?;
+        Some(nearest(fix, timestamp.with_timezone(&Utc)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(timestamp: i64, latitude: f64, longitude: f64) -> GpsFix {
+        GpsFix {
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            latitude,
+            longitude,
+            accuracy: None,
+            altitude: None,
+        }
+    }
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn test_push_keeps_track_sorted() {
+        let mut track = GpsTrack::new();
+        track.push(fix(20, 0.0, 0.0));
+        track.push(fix(10, 0.0, 0.0));
+        track.push(fix(30, 0.0, 0.0));
+        let timestamps: Vec<i64> = track.fixes().iter().map(|f| f.timestamp.timestamp()).collect();
+        assert_eq!(timestamps, vec![10, 20, 30]);
+
+        track.prune_before(at(20));
+        assert_eq!(track.len(), 2);
+    }
+
+    #[test]
+    fn test_location_interpolation() {
+        let track = GpsTrack::from_fixes(vec![fix(100, 10.0, 20.0), fix(120, 12.0, 22.0)]);
+        let options = LocationOptions {
+            max_gap_secs: 30,
+            interpolate: true,
+        };
+        let location = track.location_at(&at(105), &options).unwrap();
+        assert!(location.interpolated);
+        assert!((location.latitude - 10.5).abs() < 1e-9);
+        assert!((location.longitude - 20.5).abs() < 1e-9);
+        assert_eq!(location.fix_offset_secs, -5);
+
+        // without interpolation the nearest fix wins
+        let options = LocationOptions {
+            interpolate: false,
+            ..options
+        };
+        let location = track.location_at(&at(115), &options).unwrap();
+        assert!(!location.interpolated);
+        assert_eq!(location.latitude, 12.0);
+        assert_eq!(location.fix_offset_secs, 5);
+    }
+
+    #[test]
+    fn test_location_max_gap() {
+        let track = GpsTrack::from_fixes(vec![fix(100, 10.0, 20.0), fix(400, 12.0, 22.0)]);
+        let options = LocationOptions {
+            max_gap_secs: 60,
+            interpolate: true,
+        };
+        // only the first fix is close enough, so no interpolation happens
+        let location = track.location_at(&at(130), &options).unwrap();
+        assert!(!location.interpolated);
+        assert_eq!(location.latitude, 10.0);
+        assert!(track.location_at(&at(250), &options).is_none());
+        assert!(GpsTrack::new().location_at(&at(250), &options).is_none());
+
+        // the latest fix is returned regardless of the gap
+        let locator = TrackLocator::new(Arc::new(RwLock::new(track)), options);
+        let latest = locator.latest(&at(1000)).unwrap();
+        assert_eq!(latest.latitude, 12.0);
+        assert_eq!(latest.fix_offset_secs, -600);
+    }
+
+    #[test]
+    fn test_interpolation_across_antimeridian() {
+        let track = GpsTrack::from_fixes(vec![fix(0, 0.0, 179.0), fix(10, 0.0, -179.0)]);
+        let location = track
+            .location_at(&at(5), &LocationOptions::default())
+            .unwrap();
+        assert!((location.longitude.abs() - 180.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,232 @@
+//! The `.gps` logs written next to each recording.
+//!
+//! Older logs are headerless and come in one of several line formats. The
+//! structured format starts with a [`GpsLogHeader`] line so that readers don't
+//! have to guess. [`GpsLogReader`] reads all of them.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, Lines};
+
+use crate::gps::{GpsFix, GpsTrack};
+
+/// Value of [`GpsLogHeader::format`], which marks a line as a header
+pub const GPS_LOG_MAGIC: &str = "rayhunter-gps";
+
+/// The newest structured log version this crate can read and write
+pub const GPS_LOG_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum GpsLogError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unsupported GPS log version {0}")]
+    UnsupportedVersion(u32),
+}
+
+/// How fixes are written to a `.gps` log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpsLogFormat {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// `timestamp,latitude,longitude`
+    Csv,
+    /// `timestamp, latitude, longitude`
+    Raw,
+    /// `timestamp,latitude,longitude`. Identical to [`GpsLogFormat::Csv`] on disk.
+    Simple,
+    /// A [`GpsLogHeader`] line followed by one JSON object per line
+    Structured,
+}
+
+/// First line of a structured GPS log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GpsLogHeader {
+    /// Always [`GPS_LOG_MAGIC`]
+    pub format: String,
+    pub version: u32,
+    /// Where the fixes in this log came from, e.g. "api" or "gpsd"
+    pub source: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl GpsLogHeader {
+    pub fn new(source: &str) -> Self {
+        Self {
+            format: GPS_LOG_MAGIC.to_string(),
+            version: GPS_LOG_VERSION,
+            source: source.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        serde_json::from_str::<Self>(line)
+            .ok()
+            .filter(|header| header.format == GPS_LOG_MAGIC)
+    }
+}
+
+/// Reads fixes from a `.gps` log in any [`GpsLogFormat`].
+///
+/// The format is taken from the header if there is one, and otherwise
+/// detected from the first line. Lines that can't be parsed are skipped and
+/// counted in [`GpsLogReader::skipped_lines`].
+pub struct GpsLogReader<T>
+where
+    T: AsyncRead,
+{
+    lines: Lines<BufReader<T>>,
+    format: Option<GpsLogFormat>,
+    header: Option<GpsLogHeader>,
+    skipped_lines: usize,
+}
+
+impl<T> GpsLogReader<T>
+where
+    T: AsyncRead + Unpin,
+{
+    pub fn new(reader: T) -> Self {
+        GpsLogReader {
+            lines: BufReader::new(reader).lines(),
+            format: None,
+            header: None,
+            skipped_lines: 0,
+        }
+    }
+
+    /// The log's format, once its first line has been read. Csv logs are
+    /// reported as [`GpsLogFormat::Simple`], since the two can't be told apart.
+    pub fn format(&self) -> Option<GpsLogFormat> {
+        self.format
+    }
+
+    pub fn header(&self) -> Option<&GpsLogHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn skipped_lines(&self) -> usize {
+        self.skipped_lines
+    }
+
+    pub async fn next_fix(&mut self) -> Result<Option<GpsFix>, GpsLogError> {
+        while let Some(line) = self.lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let format = match self.format {
+                Some(format) => format,
+                None => {
+                    if let Some(header) = GpsLogHeader::parse(line) {
+                        if header.version > GPS_LOG_VERSION {
+                            return Err(GpsLogError::UnsupportedVersion(header.version));
+                        }
+                        self.header = Some(header);
+                        self.format = Some(GpsLogFormat::Structured);
+                        continue;
+                    }
+                    let format = detect_line_format(line);
+                    self.format = Some(format);
+                    format
+                }
+            };
+            match parse_line(line, format) {
+                Some(fix) => return Ok(Some(fix)),
+                None => self.skipped_lines += 1,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read every remaining fix into a track.
+    pub async fn read_track(&mut self) -> Result<GpsTrack, GpsLogError> {
+        let mut fixes = Vec::new();
+        while let Some(fix) = self.next_fix().await? {
+            fixes.push(fix);
+        }
+        Ok(GpsTrack::from_fixes(fixes))
+    }
+}
+
+fn detect_line_format(line: &str) -> GpsLogFormat {
+    if line.starts_with('{') {
+        GpsLogFormat::Json
+    } else if line.contains(", ") {
+        GpsLogFormat::Raw
+    } else {
+        GpsLogFormat::Simple
+    }
+}
+
+fn parse_line(line: &str, format: GpsLogFormat) -> Option<GpsFix> {
+    match format {
+        GpsLogFormat::Json | GpsLogFormat::Structured => serde_json::from_str(line).ok(),
+        GpsLogFormat::Csv | GpsLogFormat::Raw | GpsLogFormat::Simple => {
+            let mut fields = line.split(',').map(str::trim);
+            let timestamp = fields.next()?.parse().ok()?;
+            Some(GpsFix {
+                timestamp: DateTime::from_timestamp(timestamp, 0)?,
+                latitude: fields.next()?.parse().ok()?,
+                longitude: fields.next()?.parse().ok()?,
+                accuracy: None,
+                altitude: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(contents: &str) -> (GpsLogReader<&[u8]>, GpsTrack) {
+        let mut reader = GpsLogReader::new(contents.as_bytes());
+        let track = reader.read_track().await.unwrap();
+        (reader, track)
+    }
+
+    #[tokio::test]
+    async fn test_headerless_formats() {
+        let (reader, track) = read("1700000010,52.5,13.4\n1700000000,52.4,13.3\ngarbage\n").await;
+        assert_eq!(reader.format(), Some(GpsLogFormat::Simple));
+        assert_eq!(reader.skipped_lines(), 1);
+        assert_eq!(track.len(), 2);
+        assert_eq!(track.fixes()[0].latitude, 52.4);
+
+        let (reader, track) = read("1700000000, 52.4, 13.3\n").await;
+        assert_eq!(reader.format(), Some(GpsLogFormat::Raw));
+        assert_eq!(track.fixes()[0].longitude, 13.3);
+
+        let json = r#"{"timestamp":"2023-11-14T22:13:20+00:00","latitude":52.4,"longitude":13.3,"accuracy":5.0,"altitude":null,"speed":null,"heading":null,"device_id":null,"app_version":null,"request_id":null}"#;
+        let (reader, track) = read(json).await;
+        assert_eq!(reader.format(), Some(GpsLogFormat::Json));
+        assert!(reader.header().is_none());
+        assert_eq!(track.fixes()[0].timestamp.timestamp(), 1_700_000_000);
+        assert_eq!(track.fixes()[0].accuracy, Some(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_structured_log() {
+        let header = serde_json::to_string(&GpsLogHeader::new("gpsd")).unwrap();
+        let contents = format!(
+            "{header}\n{}\n",
+            r#"{"timestamp":"2023-11-14T22:13:20Z","latitude":52.4,"longitude":13.3,"accuracy":null,"altitude":34.0,"speed":1.5}"#
+        );
+        let (reader, track) = read(&contents).await;
+        assert_eq!(reader.format(), Some(GpsLogFormat::Structured));
+        assert_eq!(reader.header().unwrap().source, "gpsd");
+        assert_eq!(reader.skipped_lines(), 0);
+        assert_eq!(track.fixes()[0].altitude, Some(34.0));
+
+        let future_header = r#"{"format":"rayhunter-gps","version":99,"source":"api","created_at":"2023-11-14T22:13:20Z"}"#;
+        let mut reader = GpsLogReader::new(future_header.as_bytes());
+        assert!(matches!(
+            reader.next_fix().await,
+            Err(GpsLogError::UnsupportedVersion(99))
+        ));
+    }
+}
@@ -152,9 +152,121 @@ fn log_to_gsmtap(value: LogBody) -> Result<Option<GsmtapMessage>, GsmtapParserEr
                 payload: msg,
             }))
         }
+        // measurements and cell info have no GSMTAP equivalent
+        LogBody::LteMl1ServingCellMeas { .. }
+        | LogBody::LteMl1NeighborCellMeas { .. }
+        | LogBody::WcdmaCellId(_)
+        | LogBody::GsmCellInfo(_) => Ok(None),
         _ => {
             error!("gsmtap_sink: ignoring unhandled log type: {value:?}");
             Ok(None)
         }
     }
 }
+
+/// Wraps a GSMTAP message back up as a diag log message that [`parse`] turns
+/// into the same message, for converting captures made by other tools into
+/// QMDL. Only LTE RRC and plain LTE NAS messages are supported; anything else
+/// is `None`.
+pub fn to_log_message(timestamp: Timestamp, msg: GsmtapMessage) -> Option<Message> {
+    let (log_type, body) = match msg.header.gsmtap_type {
+        GsmtapType::LteRrc(subtype) => {
+            // PDU numbers as used by ext header version 0x14
+            let pdu_num = match subtype {
+                LteRrcSubtype::BcchBch => 1,
+                LteRrcSubtype::BcchDlSch => 2,
+                LteRrcSubtype::MCCH => 4,
+                LteRrcSubtype::PCCH => 5,
+                LteRrcSubtype::DlCcch => 6,
+                LteRrcSubtype::DlDcch => 7,
+                LteRrcSubtype::UlCcch => 8,
+                LteRrcSubtype::UlDcch => 9,
+                LteRrcSubtype::BcchBchNb => 54,
+                LteRrcSubtype::BcchDlSchNb => 55,
+                LteRrcSubtype::PcchNb => 56,
+                LteRrcSubtype::DlCcchNb => 57,
+                LteRrcSubtype::DlDcchNb => 58,
+                LteRrcSubtype::UlCcchNb => 59,
+                LteRrcSubtype::UlDcchNb => 61,
+                _ => return None,
+            };
+            let body = LogBody::LteRrcOtaMessage {
+                ext_header_version: 0x14,
+                packet: LteRrcOtaPacket::V8 {
+                    rrc_rel_maj: 0,
+                    rrc_rel_min: 0,
+                    bearer_id: 0,
+                    phy_cell_id: 0,
+                    earfcn: msg.header.arfcn as u32,
+                    sfn_subfn: ((msg.header.frame_number << 4) | (msg.header.subslot as u32 & 0xf))
+                        as u16,
+                    pdu_num,
+                    sib_mask: 0,
+                    len: msg.payload.len().try_into().ok()?,
+                    packet: msg.payload,
+                },
+            };
+            (0xb0c0, body)
+        }
+        GsmtapType::LteNas(LteNasSubtype::Plain) => {
+            let (log_type, direction) = if msg.header.uplink {
+                (0xb0ed, Nas4GMessageDirection::Uplink)
+            } else {
+                (0xb0ec, Nas4GMessageDirection::Downlink)
+            };
+            let body = LogBody::Nas4GMessage {
+                direction,
+                ext_header_version: 1,
+                rrc_rel: 0,
+                rrc_version_minor: 0,
+                rrc_version_major: 0,
+                msg: msg.payload,
+            };
+            (log_type, body)
+        }
+        _ => return None,
+    };
+    Message::new_log(log_type, timestamp, body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(msg: GsmtapMessage) {
+        let timestamp = Timestamp {
+            ts: 72659535985485082,
+        };
+        let log = to_log_message(timestamp.clone(), msg.clone()).unwrap();
+        // as if it were read from a QMDL file
+        let container = MessagesContainer::from_messages(DataType::UserSpace, &[log]).unwrap();
+        let log = container.into_messages().pop().unwrap().unwrap();
+        assert_eq!(parse(log).unwrap(), Some((timestamp, msg)));
+    }
+
+    #[test]
+    fn test_to_log_message() {
+        let mut header = GsmtapHeader::new(GsmtapType::LteRrc(LteRrcSubtype::PCCH));
+        header.arfcn = 2050;
+        header.frame_number = 253;
+        header.subslot = 9;
+        round_trip(GsmtapMessage {
+            header,
+            payload: vec![0x40, 0x01, 0xee, 0xad, 0xd5, 0x4d, 0xd0],
+        });
+
+        let mut header = GsmtapHeader::new(GsmtapType::LteNas(LteNasSubtype::Plain));
+        header.uplink = true;
+        round_trip(GsmtapMessage {
+            header,
+            payload: vec![0x07, 0x41, 0x71],
+        });
+
+        let header = GsmtapHeader::new(GsmtapType::LteNas(LteNasSubtype::Secure));
+        let unsupported = GsmtapMessage {
+            header,
+            payload: vec![0x17],
+        };
+        assert_eq!(to_log_message(Timestamp { ts: 0 }, unsupported), None);
+    }
+}
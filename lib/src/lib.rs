@@ -1,19 +1,27 @@
 use serde::{Deserialize, Serialize};
 
 pub mod analysis;
+pub mod baseline;
 pub mod diag;
+pub mod encryption;
+pub mod geo_export;
+pub mod gps;
+pub mod gps_log;
 pub mod gsmtap;
 pub mod gsmtap_parser;
 pub mod hdlc;
 pub mod log_codes;
 pub mod pcap;
 pub mod qmdl;
+pub mod signal;
 pub mod util;
 pub mod cellular_info;
 
 // bin/check.rs may target windows and does not use this mod
 #[cfg(target_family = "unix")]
 pub mod diag_device;
+#[cfg(target_family = "unix")]
+pub mod diag_transport;
 
 // re-export telcom_parser, since we use its types in our API
 pub use telcom_parser;
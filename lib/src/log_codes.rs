@@ -3,6 +3,7 @@
 // These are 2G-related log types.
 
 pub const LOG_GSM_RR_SIGNALING_MESSAGE_C: u32 = 0x512f;
+pub const LOG_GSM_RR_CELL_INFORMATION_C: u32 = 0x513a;
 
 pub const DCCH: u32 = 0x00;
 pub const BCCH: u32 = 0x01;
@@ -36,6 +37,9 @@ pub const LOG_LTE_NAS_ESM_OTA_OUT_MSG_LOG_C: u32 = 0xb0e3;
 pub const LOG_LTE_NAS_EMM_OTA_IN_MSG_LOG_C: u32 = 0xb0ec;
 pub const LOG_LTE_NAS_EMM_OTA_OUT_MSG_LOG_C: u32 = 0xb0ed;
 
+pub const LOG_LTE_ML1_NEIGHBOR_CELL_MEAS_C: u32 = 0xb0e1;
+pub const LOG_LTE_ML1_SERVING_CELL_MEAS_C: u32 = 0xb0e4;
+
 pub const LTE_BCCH_BCH_V0: u32 = 1;
 pub const LTE_BCCH_DL_SCH_V0: u32 = 2;
 pub const LTE_MCCH_V0: u32 = 3;
@@ -98,6 +102,8 @@ pub const RRCLOG_SIB_CONTAINER: u32 = 10;
 
 pub const WCDMA_SIGNALLING_MESSAGE: u32 = 0x412f;
 
+pub const WCDMA_CELL_ID: u32 = 0x4127;
+
 // Upper layers
 
 pub const LOG_DATA_PROTOCOL_LOGGING_C: u32 = 0x11eb;
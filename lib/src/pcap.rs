@@ -1,18 +1,21 @@
 //! Parse QMDL files and create a pcap file.
 //! Creates a plausible IP header and [GSMtap](https://osmocom.org/projects/baseband/wiki/GSMTAP) header and then puts the rest of the data under that for wireshark to parse.
-use crate::diag::Timestamp;
-use crate::gsmtap::GsmtapMessage;
+//! [`gsmtap_pcapng_to_qmdl`] goes the other way, for captures made by other tools.
+use crate::diag::{DataType, MessagesContainer, Timestamp};
+use crate::gsmtap::{GsmtapHeader, GsmtapMessage, GsmtapType};
+use crate::gsmtap_parser::to_log_message;
+use crate::qmdl::QmdlWriter;
 
 use chrono::prelude::*;
 use deku::prelude::*;
-use pcap_file_tokio::pcapng::PcapNgWriter;
+use pcap_file_tokio::pcapng::{Block, PcapNgReader, PcapNgWriter};
 use pcap_file_tokio::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
 use pcap_file_tokio::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
 use pcap_file_tokio::pcapng::blocks::section_header::{SectionHeaderBlock, SectionHeaderOption};
-use pcap_file_tokio::{Endianness, PcapError};
+use pcap_file_tokio::{DataLink, Endianness, PcapError};
 use std::borrow::Cow;
 use thiserror::Error;
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 #[derive(Error, Debug)]
 pub enum GsmtapPcapError {
@@ -53,6 +56,9 @@ struct IpHeader {
 
 const UDP_HEADER_LEN: u16 = 8;
 const GSMTAP_PORT: u16 = 4729;
+const GSMTAP_HEADER_LEN: usize = 16;
+// how many converted messages go in each QMDL container
+const MESSAGES_PER_CONTAINER: usize = 64;
 #[derive(DekuWrite)]
 #[deku(endian = "big")]
 struct UdpHeader {
@@ -149,3 +155,178 @@ where
         Ok(())
     }
 }
+
+/// Finds the GSMTAP message in a UDP packet captured on an IPv4 or Ethernet
+/// interface, along with when it was captured
+pub fn parse_gsmtap_packet(
+    linktype: DataLink,
+    packet: &EnhancedPacketBlock,
+) -> Option<(Timestamp, GsmtapMessage)> {
+    let data: &[u8] = &packet.data;
+    let ip = match linktype {
+        DataLink::ETHERNET if data.get(12..14)? == [0x08, 0x00] => &data[14..],
+        DataLink::IPV4 | DataLink::RAW => data,
+        _ => return None,
+    };
+    if ip.first()? >> 4 != 4 || *ip.get(9)? != 0x11 {
+        return None;
+    }
+    let udp = ip.get((ip[0] & 0xf) as usize * 4..)?;
+    let src_port = u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?);
+    let dst_port = u16::from_be_bytes(udp.get(2..4)?.try_into().ok()?);
+    if src_port != GSMTAP_PORT && dst_port != GSMTAP_PORT {
+        return None;
+    }
+
+    let gsmtap = udp.get(UDP_HEADER_LEN as usize..)?;
+    let header_len = *gsmtap.get(1)? as usize * 4;
+    if gsmtap[0] != 2 || header_len < GSMTAP_HEADER_LEN || gsmtap.len() < header_len {
+        return None;
+    }
+    let mut header = GsmtapHeader::new(GsmtapType::new(gsmtap[2], gsmtap[12]).ok()?);
+    let arfcn = u16::from_be_bytes([gsmtap[4], gsmtap[5]]);
+    header.timeslot = gsmtap[3];
+    header.pcs_band_indicator = arfcn & 0x8000 != 0;
+    header.uplink = arfcn & 0x4000 != 0;
+    header.arfcn = arfcn & 0x3fff;
+    header.signal_dbm = gsmtap[6] as i8;
+    header.signal_noise_ratio_db = gsmtap[7];
+    header.frame_number = u32::from_be_bytes(gsmtap[8..12].try_into().ok()?);
+    header.antenna_number = gsmtap[13];
+    header.subslot = gsmtap[14];
+
+    // pcap_file reads timestamps as nanoseconds, when they're actually
+    // microseconds (see GsmtapPcapWriter::write_gsmtap_message)
+    let micros = packet.timestamp.as_nanos().try_into().ok()?;
+    let captured = DateTime::UNIX_EPOCH + chrono::Duration::microseconds(micros);
+    let message = GsmtapMessage {
+        header,
+        payload: gsmtap[header_len..].to_vec(),
+    };
+    Some((Timestamp::from_datetime(&captured), message))
+}
+
+/// What [`gsmtap_pcapng_to_qmdl`] made of a capture
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PcapConversion {
+    /// Packets written to the QMDL file
+    pub converted: usize,
+    /// Packets that weren't GSMTAP, or held messages that can't be
+    /// represented as diag logs
+    pub skipped: usize,
+}
+
+/// Converts a pcapng file of GSMTAP packets, such as those written by
+/// GsmtapPcapWriter or QCSuper, into QMDL. Only LTE RRC and NAS messages are
+/// kept, since those are what the analyzers look at.
+pub async fn gsmtap_pcapng_to_qmdl<R, W>(
+    reader: R,
+    writer: &mut QmdlWriter<W>,
+) -> Result<PcapConversion, GsmtapPcapError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = PcapNgReader::new(reader).await?;
+    let mut linktypes = Vec::new();
+    let mut messages = Vec::new();
+    let mut conversion = PcapConversion::default();
+    while let Some(block) = reader.next_block().await {
+        match block? {
+            Block::InterfaceDescription(interface) => linktypes.push(interface.linktype),
+            Block::EnhancedPacket(packet) => {
+                let linktype = linktypes
+                    .get(packet.interface_id as usize)
+                    .copied()
+                    .unwrap_or(DataLink::IPV4);
+                let message = parse_gsmtap_packet(linktype, &packet)
+                    .and_then(|(timestamp, message)| to_log_message(timestamp, message));
+                match message {
+                    Some(message) => {
+                        messages.push(message);
+                        conversion.converted += 1;
+                    }
+                    None => conversion.skipped += 1,
+                }
+            }
+            _ => {}
+        }
+        if messages.len() >= MESSAGES_PER_CONTAINER {
+            let container = MessagesContainer::from_messages(DataType::UserSpace, &messages)?;
+            writer.write_container(&container).await?;
+            messages.clear();
+        }
+    }
+    if !messages.is_empty() {
+        let container = MessagesContainer::from_messages(DataType::UserSpace, &messages)?;
+        writer.write_container(&container).await?;
+    }
+    Ok(conversion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diag::{LogBody, LteRrcOtaPacket, Message};
+    use crate::gsmtap_parser;
+    use crate::qmdl::QmdlReader;
+
+    #[tokio::test]
+    async fn test_gsmtap_pcapng_to_qmdl() {
+        let timestamp = Timestamp {
+            ts: 72659535985485082,
+        };
+        let original = Message::new_log(
+            0xb0c0,
+            timestamp.clone(),
+            LogBody::LteRrcOtaMessage {
+                ext_header_version: 0x14,
+                packet: LteRrcOtaPacket::V8 {
+                    rrc_rel_maj: 14,
+                    rrc_rel_min: 48,
+                    bearer_id: 0,
+                    phy_cell_id: 160,
+                    earfcn: 2050,
+                    sfn_subfn: 4057,
+                    pdu_num: 5,
+                    sib_mask: 0,
+                    len: 7,
+                    packet: vec![0x40, 0x1, 0xee, 0xad, 0xd5, 0x4d, 0xd0],
+                },
+            },
+        )
+        .unwrap();
+        let (_, gsmtap_message) = gsmtap_parser::parse(original).unwrap().unwrap();
+
+        let mut pcap = Vec::new();
+        let mut pcap_writer = GsmtapPcapWriter::new(&mut pcap).await.unwrap();
+        pcap_writer.write_iface_header().await.unwrap();
+        pcap_writer
+            .write_gsmtap_message(gsmtap_message.clone(), timestamp.clone())
+            .await
+            .unwrap();
+        drop(pcap_writer);
+
+        let mut qmdl = Vec::new();
+        let mut qmdl_writer = QmdlWriter::new(&mut qmdl);
+        let conversion = gsmtap_pcapng_to_qmdl(pcap.as_slice(), &mut qmdl_writer)
+            .await
+            .unwrap();
+        assert_eq!(
+            conversion,
+            PcapConversion {
+                converted: 1,
+                skipped: 0
+            }
+        );
+
+        let qmdl_len = qmdl.len();
+        let mut reader = QmdlReader::new(qmdl.as_slice(), Some(qmdl_len));
+        let container = reader.get_next_messages_container().await.unwrap().unwrap();
+        let message = container.into_messages().pop().unwrap().unwrap();
+        let (converted_timestamp, converted_message) =
+            gsmtap_parser::parse(message).unwrap().unwrap();
+        assert_eq!(converted_message, gsmtap_message);
+        assert_eq!(converted_timestamp.to_datetime(), timestamp.to_datetime());
+    }
+}
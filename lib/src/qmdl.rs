@@ -2,18 +2,45 @@
 //! a series of of concatenated HDLC encapsulated diag::Message structs.
 //! QmdlReader and QmdlWriter can read and write MessagesContainers to and from
 //! QMDL files.
+//!
+//! To save space, QmdlWriter can instead compress each MessagesContainer into
+//! an LZ4 block. Such files start with [`COMPRESSED_QMDL_MAGIC`], followed by
+//! blocks made up of the compressed length and the uncompressed length (both
+//! little-endian u32s) and the compressed data. Blocks are only ever appended
+//! whole, so a file can be read up to the last block that was completely
+//! written. QmdlReader detects compressed files by their magic bytes.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
 
 use crate::diag::{DataType, HdlcEncapsulatedMessage, MESSAGE_TERMINATOR, MessagesContainer};
 
-use futures::TryStream;
+use futures::{Stream, TryStream};
 use log::error;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+pub const COMPRESSED_QMDL_MAGIC: &[u8; 8] = b"RHQMDLZ\x01";
+const BLOCK_HEADER_LEN: usize = 8;
+/// Blocks are at most this long, compressed or not. Each block holds one
+/// container, which is at most the 10 MiB the diag device reads at a time, so
+/// anything longer is a corrupt header rather than something to allocate.
+const MAX_BLOCK_LEN: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QmdlCompression {
+    #[default]
+    None,
+    Lz4,
+}
 
 pub struct QmdlWriter<T>
 where
     T: AsyncWrite + Unpin,
 {
     writer: T,
+    compression: QmdlCompression,
     pub total_written: usize,
 }
 
@@ -28,14 +55,67 @@ where
     pub fn new_with_existing_size(writer: T, existing_size: usize) -> Self {
         QmdlWriter {
             writer,
+            compression: QmdlCompression::None,
             total_written: existing_size,
         }
     }
 
+    pub fn get_ref(&self) -> &T {
+        &self.writer
+    }
+
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush().await
+    }
+
+    /// Compress what's written from now on. If the writer is appending to an
+    /// existing file, that file must use the same compression.
+    pub fn with_compression(mut self, compression: QmdlCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub async fn write_container(&mut self, container: &MessagesContainer) -> std::io::Result<()> {
-        for msg in &container.messages {
-            self.writer.write_all(&msg.data).await?;
-            self.total_written += msg.data.len();
+        match self.compression {
+            QmdlCompression::None => {
+                // written in one go, so a writer that frames each write (like
+                // an EncryptingWriter) doesn't add a frame per message
+                let data: Vec<u8> = container
+                    .messages
+                    .iter()
+                    .flat_map(|msg| msg.data.iter().copied())
+                    .collect();
+                self.writer.write_all(&data).await?;
+                self.total_written += data.len();
+            }
+            QmdlCompression::Lz4 => {
+                let data: Vec<u8> = container
+                    .messages
+                    .iter()
+                    .flat_map(|msg| msg.data.iter().copied())
+                    .collect();
+                if data.is_empty() {
+                    return Ok(());
+                }
+                if data.len() > MAX_BLOCK_LEN {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("container of {} bytes is too long", data.len()),
+                    ));
+                }
+                let mut block = Vec::new();
+                if self.total_written == 0 {
+                    block.extend_from_slice(COMPRESSED_QMDL_MAGIC);
+                }
+                let compressed = lz4_flex::block::compress(&data);
+                block.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                block.extend_from_slice(&compressed);
+                // write the whole block at once, so readers bounded by
+                // total_written never see part of one
+                self.writer.write_all(&block).await?;
+                self.total_written += block.len();
+            }
         }
         Ok(())
     }
@@ -48,6 +128,10 @@ where
     reader: BufReader<T>,
     bytes_read: usize,
     max_bytes: Option<usize>,
+    // detected from the first bytes of the file
+    compression: Option<QmdlCompression>,
+    // messages from the last decompressed block that haven't been returned yet
+    pending: VecDeque<Vec<u8>>,
 }
 
 impl<T> QmdlReader<T>
@@ -59,9 +143,31 @@ where
             reader: BufReader::new(reader),
             bytes_read: 0,
             max_bytes,
+            compression: None,
+            pending: VecDeque::new(),
         }
     }
 
+    /// The compression used by the file. Only known once reading has started.
+    pub fn compression(&self) -> Option<QmdlCompression> {
+        self.compression
+    }
+
+    /// Returns the uncompressed QMDL data, chunk by chunk
+    pub fn into_raw_stream(self) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> {
+        futures::stream::try_unfold(self, |mut reader| async move {
+            let maybe_container = reader.get_next_messages_container().await?;
+            Ok(maybe_container.map(|container| {
+                let data = container
+                    .messages
+                    .into_iter()
+                    .flat_map(|msg| msg.data)
+                    .collect();
+                (data, reader)
+            }))
+        })
+    }
+
     pub fn as_stream(
         &mut self,
     ) -> impl TryStream<Ok = MessagesContainer, Error = std::io::Error> + '_ {
@@ -77,21 +183,35 @@ where
     pub async fn get_next_messages_container(
         &mut self,
     ) -> Result<Option<MessagesContainer>, std::io::Error> {
-        if let Some(max_bytes) = self.max_bytes {
-            if self.bytes_read >= max_bytes {
-                if self.bytes_read > max_bytes {
-                    error!(
-                        "warning: {} bytes read, but max_bytes was {}",
-                        self.bytes_read, max_bytes
-                    );
-                }
-                return Ok(None);
-            }
-        }
+        let compression = match self.compression {
+            Some(compression) => compression,
+            None => self.detect_compression().await?,
+        };
 
-        let mut buf = Vec::new();
-        let bytes_read = self.reader.read_until(MESSAGE_TERMINATOR, &mut buf).await?;
-        self.bytes_read += bytes_read;
+        let buf = match self.pending.pop_front() {
+            Some(buf) => buf,
+            None if self.reached_max_bytes() => return Ok(None),
+            None => match compression {
+                QmdlCompression::None => {
+                    let mut buf = Vec::new();
+                    let bytes_read = self.reader.read_until(MESSAGE_TERMINATOR, &mut buf).await?;
+                    if bytes_read == 0 {
+                        return Ok(None);
+                    }
+                    self.bytes_read += bytes_read;
+                    buf
+                }
+                QmdlCompression::Lz4 => {
+                    if !self.read_block().await? {
+                        return Ok(None);
+                    }
+                    match self.pending.pop_front() {
+                        Some(buf) => buf,
+                        None => return Ok(None),
+                    }
+                }
+            },
+        };
 
         // Since QMDL is just a flat list of messages, we can't actually
         // reproduce the container structure they came from in the original
@@ -102,17 +222,88 @@ where
             data_type: DataType::UserSpace,
             num_messages: 1,
             messages: vec![HdlcEncapsulatedMessage {
-                len: bytes_read as u32,
+                len: buf.len() as u32,
                 data: buf,
             }],
         }))
     }
+
+    fn reached_max_bytes(&self) -> bool {
+        let Some(max_bytes) = self.max_bytes else {
+            return false;
+        };
+        if self.bytes_read > max_bytes {
+            error!(
+                "warning: {} bytes read, but max_bytes was {}",
+                self.bytes_read, max_bytes
+            );
+        }
+        self.bytes_read >= max_bytes
+    }
+
+    async fn detect_compression(&mut self) -> Result<QmdlCompression, std::io::Error> {
+        let compression = if self.reader.fill_buf().await?.starts_with(COMPRESSED_QMDL_MAGIC) {
+            self.reader.consume(COMPRESSED_QMDL_MAGIC.len());
+            self.bytes_read += COMPRESSED_QMDL_MAGIC.len();
+            QmdlCompression::Lz4
+        } else {
+            QmdlCompression::None
+        };
+        self.compression = Some(compression);
+        Ok(compression)
+    }
+
+    // Reads and decompresses the next block, queueing its messages. Returns
+    // false at the end of the file, including when the last block is only
+    // partially written.
+    async fn read_block(&mut self) -> Result<bool, std::io::Error> {
+        let mut header = [0; BLOCK_HEADER_LEN];
+        if !read_exact_or_eof(&mut self.reader, &mut header).await? {
+            return Ok(false);
+        }
+        let compressed_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let uncompressed_len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        if compressed_len > MAX_BLOCK_LEN || uncompressed_len > MAX_BLOCK_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "block of {compressed_len} bytes ({uncompressed_len} uncompressed) is too long"
+                ),
+            ));
+        }
+        let mut compressed = vec![0; compressed_len];
+        if !read_exact_or_eof(&mut self.reader, &mut compressed).await? {
+            return Ok(false);
+        }
+        self.bytes_read += BLOCK_HEADER_LEN + compressed_len;
+
+        let data = lz4_flex::block::decompress(&compressed, uncompressed_len)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        self.pending.extend(
+            data.split_inclusive(|byte| *byte == MESSAGE_TERMINATOR)
+                .map(|msg| msg.to_vec()),
+        );
+        Ok(true)
+    }
+}
+
+async fn read_exact_or_eof<R>(reader: &mut R, buf: &mut [u8]) -> Result<bool, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
 
+    use futures::TryStreamExt;
+
     use crate::diag::CRC_CCITT;
     use crate::hdlc::hdlc_encapsulate;
 
@@ -241,4 +432,57 @@ mod test {
             Ok(None)
         ));
     }
+
+    #[tokio::test]
+    async fn test_compressed_writing_and_reading() {
+        let mut buf = Vec::new();
+        let mut writer = QmdlWriter::new(&mut buf).with_compression(QmdlCompression::Lz4);
+        let expected_containers = get_test_containers();
+        writer.write_container(&expected_containers[0]).await.unwrap();
+        let first_container_size = writer.total_written;
+        writer.write_container(&expected_containers[1]).await.unwrap();
+        assert_eq!(writer.total_written, buf.len());
+        assert!(buf.starts_with(COMPRESSED_QMDL_MAGIC));
+        assert!(buf.len() < get_test_message_bytes().len());
+
+        // simulate a block that's still being written
+        let limit = Some(buf.len());
+        buf.extend_from_slice(&[0xff; 5]);
+
+        let mut reader = QmdlReader::new(Cursor::new(&buf), limit);
+        for message in get_test_messages() {
+            let expected_container = MessagesContainer {
+                data_type: DataType::UserSpace,
+                num_messages: 1,
+                messages: vec![message],
+            };
+            assert_eq!(
+                expected_container,
+                reader.get_next_messages_container().await.unwrap().unwrap()
+            );
+        }
+        assert!(matches!(
+            reader.get_next_messages_container().await,
+            Ok(None)
+        ));
+        assert_eq!(reader.compression(), Some(QmdlCompression::Lz4));
+
+        // reading only as far as the first block returns its messages
+        let reader = QmdlReader::new(Cursor::new(&buf), Some(first_container_size));
+        let chunks: Vec<Vec<u8>> = reader.into_raw_stream().try_collect().await.unwrap();
+        assert_eq!(chunks.len(), 5);
+        let data = chunks.concat();
+        assert_eq!(data, get_test_message_bytes()[..data.len()]);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_compressed_block() {
+        let mut buf = COMPRESSED_QMDL_MAGIC.to_vec();
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf.extend_from_slice(&[0; 16]);
+        let mut reader = QmdlReader::new(Cursor::new(&buf), None);
+        let err = reader.get_next_messages_container().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
 }
@@ -0,0 +1,436 @@
+//! A time series of the serving cell's signal strength and the neighbor cells
+//! it can hear, built from the LTE ML1 measurement logs.
+//!
+//! The modem logs serving cell measurements several times a second while
+//! connected, so [`SignalTracker`] only emits a [`SignalSample`] once per
+//! interval, or right away when the serving cell changes. Neighbor cell
+//! measurements, which are logged one frequency at a time, are collected into
+//! the next sample.
+//!
+//! There's no SINR: the serving cell measurement log only carries RSRP, RSRQ
+//! and RSSI. The modem reports SINR in a separate log, which isn't parsed.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use crate::cellular_info::{CellularInfoExtractor, NeighborCellInfo, SignalInfo};
+use crate::diag::{LogBody, Message};
+
+/// How often, in seconds, a sample is taken while the serving cell doesn't
+/// change
+pub const DEFAULT_SAMPLE_INTERVAL_SECS: i64 = 1;
+
+/// The serving cell's measurements at one point in time. A sample is taken
+/// about every second for as long as a recording runs, so it's serialized as
+/// an array rather than an object:
+/// `[timestamp, earfcn, pci, rsrp, rsrq, rssi, [[earfcn, pci, rsrp, rsrq], ...]]`,
+/// with the timestamp in milliseconds since the Unix epoch and the neighbor
+/// cells last.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(from = "CompactSample", into = "CompactSample")]
+pub struct SignalSample {
+    pub timestamp: DateTime<FixedOffset>,
+    pub earfcn: u32,
+    pub pci: u16,
+    pub signal: SignalInfo,
+    /// The neighbor cells measured since the previous sample
+    pub neighbor_cells: Vec<NeighborCellInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactSample(
+    i64,
+    u32,
+    u16,
+    Option<i16>,
+    Option<i16>,
+    Option<i16>,
+    Vec<CompactNeighbor>,
+);
+
+#[derive(Serialize, Deserialize)]
+struct CompactNeighbor(u32, u16, Option<i16>, Option<i16>);
+
+impl From<SignalSample> for CompactSample {
+    fn from(sample: SignalSample) -> Self {
+        let neighbors = sample
+            .neighbor_cells
+            .into_iter()
+            .map(|cell| {
+                CompactNeighbor(
+                    cell.earfcn.unwrap_or_default(),
+                    cell.physical_cell_id.unwrap_or_default(),
+                    cell.rsrp,
+                    cell.rsrq,
+                )
+            })
+            .collect();
+        CompactSample(
+            sample.timestamp.timestamp_millis(),
+            sample.earfcn,
+            sample.pci,
+            sample.signal.rsrp,
+            sample.signal.rsrq,
+            sample.signal.rssi,
+            neighbors,
+        )
+    }
+}
+
+impl From<CompactSample> for SignalSample {
+    fn from(sample: CompactSample) -> Self {
+        let CompactSample(millis, earfcn, pci, rsrp, rsrq, rssi, neighbors) = sample;
+        SignalSample {
+            timestamp: DateTime::from_timestamp_millis(millis)
+                .unwrap_or_default()
+                .fixed_offset(),
+            earfcn,
+            pci,
+            signal: SignalInfo {
+                rsrp,
+                rsrq,
+                rssi,
+                cqi: None,
+                bandwidth: None,
+            },
+            neighbor_cells: neighbors
+                .into_iter()
+                .map(
+                    |CompactNeighbor(earfcn, pci, rsrp, rsrq)| NeighborCellInfo {
+                        physical_cell_id: Some(pci),
+                        earfcn: Some(earfcn),
+                        rsrp,
+                        rsrq,
+                        plmn_info: None,
+                    },
+                )
+                .collect(),
+        }
+    }
+}
+
+/// The LTE cell the modem was last measured on
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServingCell {
+    pub timestamp: DateTime<FixedOffset>,
+    pub earfcn: u32,
+    pub pci: u16,
+    pub band: Option<u16>,
+}
+
+// The first downlink EARFCN of each band, and its last one, from 3GPP TS
+// 36.101 table 5.7.3-1
+const LTE_BANDS: [(u16, u32, u32); 53] = [
+    (1, 0, 599),
+    (2, 600, 1199),
+    (3, 1200, 1949),
+    (4, 1950, 2399),
+    (5, 2400, 2649),
+    (6, 2650, 2749),
+    (7, 2750, 3449),
+    (8, 3450, 3799),
+    (9, 3800, 4149),
+    (10, 4150, 4749),
+    (11, 4750, 4949),
+    (12, 5010, 5179),
+    (13, 5180, 5279),
+    (14, 5280, 5379),
+    (17, 5730, 5849),
+    (18, 5850, 5999),
+    (19, 6000, 6149),
+    (20, 6150, 6449),
+    (21, 6450, 6599),
+    (22, 6600, 7399),
+    (23, 7500, 7699),
+    (24, 7700, 8039),
+    (25, 8040, 8689),
+    (26, 8690, 9039),
+    (27, 9040, 9209),
+    (28, 9210, 9659),
+    (29, 9660, 9769),
+    (30, 9770, 9869),
+    (31, 9870, 9919),
+    (32, 9920, 10359),
+    (33, 36000, 36199),
+    (34, 36200, 36349),
+    (35, 36350, 36949),
+    (36, 36950, 37549),
+    (37, 37550, 37749),
+    (38, 37750, 38249),
+    (39, 38250, 38649),
+    (40, 38650, 39649),
+    (41, 39650, 41589),
+    (42, 41590, 43589),
+    (43, 43590, 45589),
+    (44, 45590, 46589),
+    (45, 46590, 46789),
+    (46, 46790, 54539),
+    (47, 54540, 55239),
+    (48, 55240, 56739),
+    (65, 65536, 66435),
+    (66, 66436, 67335),
+    (67, 67336, 67535),
+    (68, 67536, 67835),
+    (69, 67836, 68335),
+    (70, 68336, 68585),
+    (71, 68586, 68935),
+];
+
+/// The LTE band a downlink EARFCN belongs to
+pub fn lte_band(earfcn: u32) -> Option<u16> {
+    LTE_BANDS
+        .iter()
+        .find(|&&(_, first, last)| (first..=last).contains(&earfcn))
+        .map(|&(band, _, _)| band)
+}
+
+pub struct SignalTracker {
+    extractor: CellularInfoExtractor,
+    interval: Duration,
+    last_sample: Option<(DateTime<FixedOffset>, u32, u16)>,
+    // the latest measurement of each neighbor cell, by EARFCN and PCI
+    neighbor_cells: BTreeMap<(u32, u16), NeighborCellInfo>,
+}
+
+impl Default for SignalTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignalTracker {
+    pub fn new() -> Self {
+        Self {
+            extractor: CellularInfoExtractor::new(),
+            interval: Duration::seconds(DEFAULT_SAMPLE_INTERVAL_SECS),
+            last_sample: None,
+            neighbor_cells: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The serving cell as of the last sample
+    pub fn serving_cell(&self) -> Option<ServingCell> {
+        let (timestamp, earfcn, pci) = self.last_sample?;
+        Some(ServingCell {
+            timestamp,
+            earfcn,
+            pci,
+            band: lte_band(earfcn),
+        })
+    }
+
+    /// Processes a diag message, returning a new sample if it's a serving
+    /// cell measurement that's due to be sampled
+    pub fn process_message(&mut self, message: &Message) -> Option<SignalSample> {
+        let Message::Log {
+            timestamp, body, ..
+        } = message
+        else {
+            return None;
+        };
+        let timestamp = timestamp.to_datetime();
+        match body {
+            LogBody::LteMl1NeighborCellMeas { .. } => {
+                let info = self.extractor.extract_from_log_body(body, timestamp)?;
+                for cell in info.neighbor_cells {
+                    let key = (
+                        cell.earfcn.unwrap_or_default(),
+                        cell.physical_cell_id.unwrap_or_default(),
+                    );
+                    self.neighbor_cells.insert(key, cell);
+                }
+                None
+            }
+            LogBody::LteMl1ServingCellMeas { packet, .. } => {
+                let earfcn = packet.get_earfcn();
+                let pci = packet.get_pci();
+                if let Some((last_time, last_earfcn, last_pci)) = self.last_sample {
+                    let same_cell = (last_earfcn, last_pci) == (earfcn, pci);
+                    if same_cell && timestamp - last_time < self.interval {
+                        return None;
+                    }
+                }
+                let info = self.extractor.extract_from_log_body(body, timestamp)?;
+                self.last_sample = Some((timestamp, earfcn, pci));
+                let neighbor_cells = std::mem::take(&mut self.neighbor_cells)
+                    .into_values()
+                    // the serving cell shows up in some neighbor measurements
+                    .filter(|cell| {
+                        (cell.earfcn, cell.physical_cell_id) != (Some(earfcn), Some(pci))
+                    })
+                    .collect();
+                Some(SignalSample {
+                    timestamp,
+                    earfcn,
+                    pci,
+                    signal: info.signal_info?,
+                    neighbor_cells,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diag::{
+        LteMl1NeighborCell, LteMl1NeighborCellMeasPacket, LteMl1ServingCellMeasPacket, Timestamp,
+    };
+
+    fn serving_meas(secs: f64, earfcn: u32, pci: u16, rsrp_dbm: f32) -> Message {
+        let time = DateTime::parse_from_rfc3339("2024-05-01T12:00:00+00:00").unwrap()
+            + Duration::milliseconds((secs * 1000.0) as i64);
+        let body = LogBody::LteMl1ServingCellMeas {
+            version: 5,
+            packet: LteMl1ServingCellMeasPacket::V5 {
+                rrc_rel: 1,
+                reserved: 0,
+                earfcn,
+                pci_layer_priority: pci,
+                reserved2: 0,
+                meas_rsrp: ((rsrp_dbm + 180.0) * 16.0) as u32,
+                avg_rsrp: 0,
+                rsrq: 320 << 10,
+                rssi: 720 << 10,
+                rxlev: 0,
+                s_search: 0,
+                extra: Vec::new(),
+            },
+        };
+        Message::new_log(0xb0e4, Timestamp::from_datetime(&time), body).unwrap()
+    }
+
+    fn neighbor_meas(earfcn: u32, pcis: &[u16]) -> Message {
+        let cells = pcis
+            .iter()
+            .map(|&pci| LteMl1NeighborCell {
+                pci_ranking: pci as u32,
+                meas_rsrp: 1280,
+                rsrq: 288 << 10,
+                rssi: 0,
+            })
+            .collect::<Vec<_>>();
+        let body = LogBody::LteMl1NeighborCellMeas {
+            version: 5,
+            packet: LteMl1NeighborCellMeasPacket::V5 {
+                rrc_rel: 1,
+                reserved: 0,
+                earfcn,
+                num_cells: cells.len() as u8,
+                reserved2: [0; 3],
+                cells,
+                extra: Vec::new(),
+            },
+        };
+        Message::new_log(0xb0e1, Timestamp { ts: 0 }, body).unwrap()
+    }
+
+    #[test]
+    fn test_samples_once_per_interval() {
+        let mut tracker = SignalTracker::new();
+        let sample = tracker
+            .process_message(&serving_meas(0.0, 5230, 301, -95.0))
+            .unwrap();
+        assert_eq!((sample.earfcn, sample.pci), (5230, 301));
+        assert_eq!(sample.signal.rsrp, Some(-95));
+        assert_eq!(sample.signal.rsrq, Some(-10));
+        assert!(
+            tracker
+                .process_message(&serving_meas(0.5, 5230, 301, -96.0))
+                .is_none()
+        );
+        assert!(
+            tracker
+                .process_message(&serving_meas(1.0, 5230, 301, -97.0))
+                .is_some()
+        );
+
+        // a new serving cell is sampled right away
+        let sample = tracker
+            .process_message(&serving_meas(1.2, 2050, 160, -80.0))
+            .unwrap();
+        assert_eq!(sample.pci, 160);
+        let serving_cell = tracker.serving_cell().unwrap();
+        assert_eq!((serving_cell.earfcn, serving_cell.band), (2050, Some(4)));
+    }
+
+    #[test]
+    fn test_lte_band() {
+        assert_eq!(lte_band(0), Some(1));
+        assert_eq!(lte_band(5230), Some(13));
+        assert_eq!(lte_band(66586), Some(66));
+        // between bands 11 and 12
+        assert_eq!(lte_band(5000), None);
+        assert_eq!(lte_band(100000), None);
+    }
+
+    #[test]
+    fn test_collects_neighbor_cells() {
+        let mut tracker = SignalTracker::new();
+        assert!(
+            tracker
+                .process_message(&neighbor_meas(5230, &[12, 301]))
+                .is_none()
+        );
+        assert!(
+            tracker
+                .process_message(&neighbor_meas(2050, &[7]))
+                .is_none()
+        );
+        let sample = tracker
+            .process_message(&serving_meas(0.0, 5230, 301, -95.0))
+            .unwrap();
+        let neighbors: Vec<_> = sample
+            .neighbor_cells
+            .iter()
+            .map(|cell| {
+                (
+                    cell.earfcn.unwrap(),
+                    cell.physical_cell_id.unwrap(),
+                    cell.rsrp,
+                )
+            })
+            .collect();
+        assert_eq!(
+            neighbors,
+            vec![(2050, 7, Some(-100)), (5230, 12, Some(-100))]
+        );
+
+        // neighbors are only reported once
+        let sample = tracker
+            .process_message(&serving_meas(2.0, 5230, 301, -95.0))
+            .unwrap();
+        assert!(sample.neighbor_cells.is_empty());
+    }
+
+    #[test]
+    fn test_sample_serialization() {
+        let mut tracker = SignalTracker::new();
+        let sample = tracker
+            .process_message(&serving_meas(0.0, 5230, 301, -95.0))
+            .unwrap();
+        let json = serde_json::to_string(&sample).unwrap();
+        assert_eq!(json, "[1714564800000,5230,301,-95,-10,-65,[]]");
+        assert_eq!(serde_json::from_str::<SignalSample>(&json).unwrap(), sample);
+
+        tracker.process_message(&neighbor_meas(5230, &[12]));
+        let sample = tracker
+            .process_message(&serving_meas(2.0, 5230, 301, -95.0))
+            .unwrap();
+        let json = serde_json::to_string(&sample).unwrap();
+        assert_eq!(
+            json,
+            "[1714564802000,5230,301,-95,-10,-65,[[5230,12,-100,-12]]]"
+        );
+        assert_eq!(serde_json::from_str::<SignalSample>(&json).unwrap(), sample);
+    }
+}
@@ -49,3 +49,24 @@ impl RuntimeMetadata {
         }
     }
 }
+
+/// User-editable notes about a recording, kept in its manifest entry and
+/// included in its analysis report's metadata
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RecordingAnnotations {
+    /// A short, human-readable name for the recording
+    pub label: Option<String>,
+    /// Free-text notes
+    pub notes: Option<String>,
+    /// Where the recording was made, e.g. "Main St & 5th Ave"
+    pub location: Option<String>,
+    /// Lowercase tags such as "protest", "control" or "false-positive"
+    pub tags: Vec<String>,
+}
+
+impl RecordingAnnotations {
+    pub fn is_empty(&self) -> bool {
+        self.label.is_none() && self.notes.is_none() && self.location.is_none() && self.tags.is_empty()
+    }
+}